@@ -0,0 +1,158 @@
+// [aggregate.rs] - KRUST - Combine numeric output from every host into one value
+use clap::ValueEnum;
+use regex::Regex;
+use std::sync::OnceLock;
+
+use crate::HostResult;
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AggregateOp {
+    Sum,
+    Avg,
+    Min,
+    Max,
+    Count,
+}
+
+impl AggregateOp {
+    fn name(self) -> &'static str {
+        match self {
+            AggregateOp::Sum => "sum",
+            AggregateOp::Avg => "avg",
+            AggregateOp::Min => "min",
+            AggregateOp::Max => "max",
+            AggregateOp::Count => "count",
+        }
+    }
+
+    fn apply(self, values: &[f64]) -> Option<f64> {
+        if values.is_empty() {
+            return (self == AggregateOp::Count).then_some(0.0);
+        }
+        let sum: f64 = values.iter().sum();
+        Some(match self {
+            AggregateOp::Sum => sum,
+            AggregateOp::Avg => sum / values.len() as f64,
+            AggregateOp::Min => values.iter().copied().fold(f64::INFINITY, f64::min),
+            AggregateOp::Max => values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+            AggregateOp::Count => values.len() as f64,
+        })
+    }
+}
+
+/// What a host contributed to the aggregate
+enum HostValue {
+    Number(f64),
+    NonNumeric(String),
+    Failed,
+}
+
+/// First whitespace-separated token that is a plain decimal number (no thousands separators)
+fn parse_number(stdout: &str) -> Option<f64> {
+    static NUMBER: OnceLock<Regex> = OnceLock::new();
+    let number = NUMBER.get_or_init(|| Regex::new(r"^[-+]?(\d+\.?\d*|\.\d+)([eE][-+]?\d+)?$").unwrap());
+
+    stdout.split_whitespace()
+        .find(|token| number.is_match(token))
+        .and_then(|token| token.parse().ok())
+}
+
+fn host_value(result: &HostResult) -> HostValue {
+    if !result.success {
+        return HostValue::Failed;
+    }
+    let stdout = result.stdout.as_deref().unwrap_or("").trim();
+    match parse_number(stdout) {
+        Some(value) => HostValue::Number(value),
+        None => HostValue::NonNumeric(stdout.lines().next().unwrap_or("").chars().take(40).collect()),
+    }
+}
+
+fn format_number(value: f64) -> String {
+    if value.fract() == 0.0 && value.abs() < 1e15 {
+        format!("{:.0}", value)
+    } else {
+        let text = format!("{:.3}", value);
+        text.trim_end_matches('0').trim_end_matches('.').to_string()
+    }
+}
+
+/// Per-host values and the combined result of --aggregate
+pub struct Aggregate {
+    op: AggregateOp,
+    hosts: Vec<(String, HostValue)>,
+}
+
+impl Aggregate {
+    pub fn new(op: AggregateOp, results: &[HostResult]) -> Self {
+        let hosts = results.iter()
+            .map(|r| (r.hostname.clone(), host_value(r)))
+            .collect();
+        Aggregate { op, hosts }
+    }
+
+    fn values(&self) -> Vec<f64> {
+        self.hosts.iter()
+            .filter_map(|(_, value)| match value {
+                HostValue::Number(n) => Some(*n),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn skipped(&self, non_numeric: bool) -> Vec<&str> {
+        self.hosts.iter()
+            .filter(|(_, value)| match value {
+                HostValue::NonNumeric(_) => non_numeric,
+                HostValue::Failed => !non_numeric,
+                HostValue::Number(_) => false,
+            })
+            .map(|(host, _)| host.as_str())
+            .collect()
+    }
+
+    pub fn print(&self, use_color: bool) {
+        let width = self.hosts.iter().map(|(host, _)| host.len()).max().unwrap_or(4).max(4);
+        outln!("{:<width$}  VALUE", "HOST");
+        for (host, value) in &self.hosts {
+            let shown = match value {
+                HostValue::Number(n) => format_number(*n),
+                HostValue::NonNumeric(text) => format!("(non-numeric: {:?})", text),
+                HostValue::Failed => "(failed)".to_string(),
+            };
+            match value {
+                HostValue::Number(_) => outln!("{:<width$}  {}", host, shown),
+                _ if use_color => outln!("{:<width$}  \x1b[90m{}\x1b[0m", host, shown),
+                _ => outln!("{:<width$}  {}", host, shown),
+            }
+        }
+
+        let values = self.values();
+        let result = self.op.apply(&values).map(format_number).unwrap_or_else(|| "n/a".to_string());
+        let mut line = format!("{}: {} over {} hosts", self.op.name(), result, values.len());
+        let (non_numeric, failed) = (self.skipped(true).len(), self.skipped(false).len());
+        if non_numeric + failed > 0 {
+            line.push_str(&format!(" (skipped: {} non-numeric, {} failed)", non_numeric, failed));
+        }
+        outln!();
+        if use_color {
+            outln!("\x1b[1m{}\x1b[0m", line);
+        } else {
+            outln!("{}", line);
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let values = self.values();
+        serde_json::json!({
+            "summary": {
+                "mode": "aggregate",
+                "op": self.op.name(),
+                "value": self.op.apply(&values),
+                "hosts": values.len(),
+                "non_numeric": self.skipped(true),
+                "failed": self.skipped(false),
+            }
+        })
+    }
+}
@@ -0,0 +1,109 @@
+// [assertions.rs] - KRUST - Expected exit code and output checks applied to each host result
+use anyhow::{Context, Result};
+use regex::Regex;
+
+use crate::{Cli, HostResult};
+
+const EXCERPT_LINES: usize = 3;
+const EXCERPT_CHARS: usize = 200;
+
+/// Expectations from --expect-* flags, validated before any host is contacted
+pub struct Assertions {
+    exit_code: Option<i32>,
+    stdout_regex: Option<Regex>,
+    baseline: Option<(String, String)>,
+}
+
+impl Assertions {
+    pub fn from_args(args: &Cli) -> Result<Option<Self>> {
+        let stdout_regex = args.expect_stdout_regex.as_deref()
+            .map(|pattern| Regex::new(pattern)
+                .with_context(|| format!("Invalid --expect-stdout-regex: {}", pattern)))
+            .transpose()?;
+        let baseline = args.expect_stdout_equals_file.as_ref()
+            .map(|path| std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read baseline: {}", path))
+                .map(|content| (path.clone(), content)))
+            .transpose()?;
+
+        if args.expect_exit_code.is_none() && stdout_regex.is_none() && baseline.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(Assertions {
+            exit_code: args.expect_exit_code,
+            stdout_regex,
+            baseline,
+        }))
+    }
+
+    /// Judge a result that ran to completion; connection failures are left alone
+    pub fn check(&self, result: &mut HostResult) {
+        let Some(exit_code) = result.exit_code else {
+            return;
+        };
+        let stdout = result.stdout.as_deref().unwrap_or("");
+
+        let failure = self.check_exit_code(exit_code)
+            .or_else(|| self.check_regex(stdout))
+            .or_else(|| self.check_baseline(stdout));
+
+        match failure {
+            Some(reason) => {
+                result.success = false;
+                result.assertion_error = Some(reason);
+            }
+            // An expected non-zero exit code replaces the default "0 means success"
+            None if self.exit_code.is_some() && exit_code != 0 => result.success = true,
+            None => {}
+        }
+    }
+
+    fn check_exit_code(&self, exit_code: i32) -> Option<String> {
+        match self.exit_code {
+            Some(expected) if expected != exit_code => {
+                Some(format!("exit code {}, expected {}", exit_code, expected))
+            }
+            _ => None,
+        }
+    }
+
+    fn check_regex(&self, stdout: &str) -> Option<String> {
+        let regex = self.stdout_regex.as_ref()?;
+        if regex.is_match(stdout) {
+            return None;
+        }
+        Some(format!("stdout does not match /{}/: {}", regex.as_str(), excerpt(stdout)))
+    }
+
+    fn check_baseline(&self, stdout: &str) -> Option<String> {
+        let (path, baseline) = self.baseline.as_ref()?;
+        let expected: Vec<&str> = baseline.trim_end().lines().collect();
+        let actual: Vec<&str> = stdout.trim_end().lines().collect();
+        if expected == actual {
+            return None;
+        }
+
+        let line = expected.iter().zip(&actual).position(|(e, a)| e != a)
+            .unwrap_or(expected.len().min(actual.len()));
+        let shown = |lines: &[&str]| lines.get(line).map(|l| format!("{:?}", l)).unwrap_or_else(|| "end of output".to_string());
+        Some(format!(
+            "stdout differs from {} at line {}: expected {}, got {}",
+            path,
+            line + 1,
+            shown(&expected),
+            shown(&actual)
+        ))
+    }
+}
+
+fn excerpt(stdout: &str) -> String {
+    if stdout.trim().is_empty() {
+        return "(empty output)".to_string();
+    }
+    let joined = stdout.lines().take(EXCERPT_LINES).collect::<Vec<_>>().join(" | ");
+    let mut text: String = joined.chars().take(EXCERPT_CHARS).collect();
+    if stdout.lines().count() > EXCERPT_LINES || text.len() < joined.len() {
+        text.push_str(" ...");
+    }
+    text
+}
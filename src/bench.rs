@@ -0,0 +1,116 @@
+// [bench.rs] - KRUST - Repeated timed runs per host and latency statistics
+use serde::Serialize;
+use std::time::Duration;
+
+use crate::json_array;
+use crate::{Cli, HostResult};
+
+/// --bench N with --bench-warmup W: W untimed runs, then N measured ones, over one connection
+#[derive(Debug, Clone, Copy)]
+pub struct Bench {
+    pub runs: usize,
+    pub warmup: usize,
+}
+
+impl Bench {
+    pub fn from_args(args: &Cli) -> Option<Self> {
+        args.bench.map(|runs| Bench { runs, warmup: args.bench_warmup })
+    }
+
+    pub fn total_runs(self) -> usize {
+        self.warmup + self.runs
+    }
+
+    /// The measured runs, warm-up excluded
+    pub fn measured(self, timings: &[(Duration, i32)]) -> Vec<Iteration> {
+        timings.iter()
+            .skip(self.warmup)
+            .map(|&(duration, exit_code)| Iteration { duration_ms: duration.as_millis() as u64, exit_code })
+            .collect()
+    }
+}
+
+/// One measured run of the command
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct Iteration {
+    pub duration_ms: u64,
+    pub exit_code: i32,
+}
+
+/// Minimum, average, 95th percentile and maximum, in milliseconds
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Latency {
+    pub min: u64,
+    pub avg: u64,
+    pub p95: u64,
+    pub max: u64,
+}
+
+impl Latency {
+    pub fn of(values: impl Iterator<Item = u64>) -> Option<Self> {
+        let mut values: Vec<u64> = values.collect();
+        if values.is_empty() {
+            return None;
+        }
+        values.sort_unstable();
+        Some(Latency {
+            min: values[0],
+            avg: values.iter().sum::<u64>() / values.len() as u64,
+            p95: values[(values.len() * 95).div_ceil(100) - 1],
+            max: values[values.len() - 1],
+        })
+    }
+}
+
+/// Per-host latency table and the fleet-wide roll-up; in JSON, only the roll-up (hosts carry their own)
+pub fn print_report(results: &[HostResult], bench: Bench, json: bool, use_color: bool) {
+    let measured: Vec<&HostResult> = results.iter().filter(|r| r.bench.is_some()).collect();
+    let fleet = Latency::of(
+        measured.iter().flat_map(|r| r.iterations.iter().flatten()).map(|i| i.duration_ms)
+    );
+    let runs: usize = measured.iter().map(|r| r.iterations.as_ref().map_or(0, Vec::len)).sum();
+
+    if json {
+        json_array::record(&serde_json::json!({
+            "bench": {
+                "hosts": measured.len(),
+                "runs": runs,
+                "warmup": bench.warmup,
+                "latency_ms": fleet,
+            }
+        }));
+        return;
+    }
+
+    outln!();
+    let warmup = match bench.warmup {
+        0 => String::new(),
+        1 => " after 1 warm-up".to_string(),
+        n => format!(" after {} warm-ups", n),
+    };
+    if use_color {
+        outln!("\x1b[1mBench:\x1b[0m {} runs per host{}", bench.runs, warmup);
+    } else {
+        outln!("Bench: {} runs per host{}", bench.runs, warmup);
+    }
+    let width = measured.iter().map(|r| r.hostname.len()).max().unwrap_or(4).max(4);
+    outln!("{:<width$}  {:>7}  {:>7}  {:>7}  {:>7}", "HOST", "MIN", "AVG", "P95", "MAX");
+    for result in &measured {
+        if let Some(latency) = result.bench {
+            outln!(
+                "{:<width$}  {:>5}ms  {:>5}ms  {:>5}ms  {:>5}ms",
+                result.hostname, latency.min, latency.avg, latency.p95, latency.max
+            );
+        }
+    }
+    if let Some(fleet) = fleet {
+        outln!(
+            "Fleet: min {}ms, avg {}ms, p95 {}ms, max {}ms over {} runs on {} hosts",
+            fleet.min, fleet.avg, fleet.p95, fleet.max, runs, measured.len()
+        );
+    }
+    let unmeasured = results.len() - measured.len();
+    if unmeasured > 0 {
+        outln!("{} hosts could not be measured", unmeasured);
+    }
+}
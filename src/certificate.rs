@@ -0,0 +1,120 @@
+// [certificate.rs] - KRUST - OpenSSH user certificate detection and validity check
+use anyhow::{anyhow, bail, Context, Result};
+use base64::Engine;
+use chrono::{DateTime, TimeZone, Utc};
+use std::path::{Path, PathBuf};
+
+/// The parts of an OpenSSH certificate (PROTOCOL.certkeys) needed to explain an auth failure
+#[derive(Debug, Clone)]
+pub struct Certificate {
+    pub path: PathBuf,
+    pub key_id: String,
+    pub valid_after: Option<DateTime<Utc>>,
+    pub valid_before: Option<DateTime<Utc>>,
+}
+
+/// `id_ed25519` -> `id_ed25519-cert.pub`, the name ssh-keygen -s writes
+pub fn sibling_of(key: &Path) -> Option<PathBuf> {
+    let mut name = key.file_name()?.to_os_string();
+    name.push("-cert.pub");
+    let path = key.with_file_name(name);
+    path.exists().then_some(path)
+}
+
+impl Certificate {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read certificate: {}", path.display()))?;
+        Self::parse(path, &content)
+            .with_context(|| format!("Invalid certificate: {}", path.display()))
+    }
+
+    fn parse(path: &Path, content: &str) -> Result<Self> {
+        let mut fields = content.split_whitespace();
+        let key_type = fields.next().ok_or_else(|| anyhow!("empty file"))?;
+        if !key_type.contains("-cert-v01@openssh.com") {
+            bail!("{} is not a certificate type", key_type);
+        }
+        let blob = base64::engine::general_purpose::STANDARD
+            .decode(fields.next().ok_or_else(|| anyhow!("missing certificate data"))?)
+            .context("certificate data is not base64")?;
+
+        let mut reader = Reader(&blob);
+        reader.string()?; // key type, repeated
+        reader.string()?; // nonce
+        let public_key_fields = match key_type {
+            t if t.starts_with("ssh-rsa-") => 2,
+            t if t.starts_with("ssh-dss-") => 4,
+            t if t.starts_with("ecdsa-") => 2,
+            t if t.starts_with("ssh-ed25519-") => 1,
+            t if t.starts_with("sk-ecdsa-") => 3,
+            t if t.starts_with("sk-ssh-ed25519-") => 2,
+            t => bail!("unsupported certificate type {}", t),
+        };
+        for _ in 0..public_key_fields {
+            reader.string()?;
+        }
+        reader.u64()?; // serial
+        reader.u32()?; // certificate type
+        let key_id = String::from_utf8_lossy(reader.string()?).into_owned();
+        reader.string()?; // valid principals
+        let valid_after = reader.u64()?;
+        let valid_before = reader.u64()?;
+
+        // 0 and u64::MAX mean unbounded
+        let timestamp = |seconds: u64| match seconds {
+            0 | u64::MAX => None,
+            seconds => i64::try_from(seconds).ok().and_then(|s| Utc.timestamp_opt(s, 0).single()),
+        };
+        Ok(Certificate {
+            path: path.to_path_buf(),
+            key_id,
+            valid_after: timestamp(valid_after),
+            valid_before: timestamp(valid_before),
+        })
+    }
+
+    /// Fails with the validity window when the certificate is expired or not yet valid
+    pub fn check_validity(&self, now: DateTime<Utc>) -> Result<()> {
+        if let Some(before) = self.valid_before.filter(|before| *before <= now) {
+            bail!(
+                "Certificate {} ({}) expired at {}",
+                self.path.display(), self.key_id, before.format("%Y-%m-%d %H:%M:%S UTC")
+            );
+        }
+        if let Some(after) = self.valid_after.filter(|after| *after > now) {
+            bail!(
+                "Certificate {} ({}) is not valid before {}",
+                self.path.display(), self.key_id, after.format("%Y-%m-%d %H:%M:%S UTC")
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Reads the SSH wire encoding: big-endian integers and length-prefixed strings
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.0.len() < len {
+            bail!("certificate data is truncated");
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn string(&mut self) -> Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+}
@@ -0,0 +1,88 @@
+// [completions.rs] - KRUST - Shell completion scripts with inventory-backed host completion
+use anyhow::Result;
+use clap::CommandFactory;
+use clap_complete::Shell;
+use std::io::Write;
+
+use crate::config::Config;
+use crate::inventory::Inventory;
+use crate::Cli;
+
+/// Hostnames for the hidden `__complete-hosts` helper: $KRUST_INVENTORY, else the config's inventory
+pub fn print_hosts() -> Result<()> {
+    let inventory = match std::env::var("KRUST_INVENTORY") {
+        Ok(path) => Some(path),
+        Err(_) => Config::load(None).ok().and_then(|config| config.inventory),
+    };
+    let Some(inventory) = inventory.and_then(|path| Inventory::load(&path, true).ok()) else {
+        return Ok(());
+    };
+
+    let mut out = std::io::stdout().lock();
+    for entry in &inventory.hosts {
+        // An alias only means something on the command line together with its address
+        match entry.host.hostname == entry.host.address {
+            true => writeln!(out, "{}", entry.host)?,
+            false => writeln!(out, "{}={}", entry.host.hostname, entry.host.target())?,
+        }
+    }
+    Ok(())
+}
+
+// Completing the part after the last comma lets `--hosts web1,we<TAB>` work
+const BASH_HOSTS: &str = r#"
+_krust_with_hosts() {
+    local cur prev
+    cur="${COMP_WORDS[COMP_CWORD]}"
+    prev="${COMP_WORDS[COMP_CWORD-1]}"
+    if [[ "$prev" == "--hosts" || "$prev" == "-t" ]]; then
+        local done="" last="$cur"
+        if [[ "$cur" == *,* ]]; then
+            done="${cur%,*},"
+            last="${cur##*,}"
+        fi
+        COMPREPLY=($(compgen -P "$done" -W "$(krust __complete-hosts 2>/dev/null)" -- "$last"))
+        compopt -o nospace 2>/dev/null
+        return 0
+    fi
+    _krust "$@"
+}
+complete -F _krust_with_hosts -o bashdefault -o default krust
+"#;
+
+const ZSH_HOSTS: &str = r#"
+_krust_hosts() {
+    local -a hosts
+    hosts=(${(f)"$(krust __complete-hosts 2>/dev/null)"})
+    _values -s , 'host' $hosts
+}
+"#;
+
+const FISH_HOSTS: &str = r#"
+complete -c krust -s t -l hosts -x -a '(krust __complete-hosts 2>/dev/null)'
+"#;
+
+/// Write the completion script for `shell` to stdout
+pub fn generate(shell: Shell) -> Result<()> {
+    let mut script = Vec::new();
+    clap_complete::generate(shell, &mut Cli::command(), "krust", &mut script);
+    let mut script = String::from_utf8(script)?;
+
+    match shell {
+        Shell::Bash => script.push_str(BASH_HOSTS),
+        Shell::Zsh => {
+            // Point the --hosts value at the inventory helper instead of file names
+            script = script.replace(":TARGET_HOSTS:_default", ":TARGET_HOSTS:_krust_hosts");
+            // Keep "#compdef krust" as the first line so the file can be autoloaded
+            let body = script.split_once('\n').map(|(first, rest)| (first.to_string(), rest.to_string()));
+            if let Some((first, rest)) = body {
+                script = format!("{}\n{}{}", first, ZSH_HOSTS, rest);
+            }
+        }
+        Shell::Fish => script.push_str(FISH_HOSTS),
+        _ => {}
+    }
+
+    out!("{}", script);
+    Ok(())
+}
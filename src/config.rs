@@ -0,0 +1,47 @@
+// [config.rs] - KRUST - Optional user configuration file
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::path::PathBuf;
+use tracing::debug;
+
+/// Settings read from config.toml; every field is optional
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Ask for confirmation when a run targets more hosts than this
+    pub confirm_threshold: Option<usize>,
+    /// Inventory used when neither --inventory nor --hosts is given
+    pub inventory: Option<String>,
+    /// Regexes masked in all output, like --redact
+    #[serde(default)]
+    pub redact: Vec<String>,
+}
+
+/// $KRUST_CONFIG, else $XDG_CONFIG_HOME/krust/config.toml, else ~/.config/krust/config.toml
+fn default_path() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("KRUST_CONFIG") {
+        return Some(PathBuf::from(path));
+    }
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(base.join("krust").join("config.toml"))
+}
+
+impl Config {
+    /// Load the config file; an explicit path must exist, the default one may be missing
+    pub fn load(explicit: Option<&str>) -> Result<Self> {
+        let path = match explicit {
+            Some(path) => PathBuf::from(path),
+            None => match default_path() {
+                Some(path) if path.exists() => path,
+                _ => return Ok(Config::default()),
+            },
+        };
+
+        debug!("Loading config from {}", path.display());
+        let content = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read config: {}", path.display()))?;
+        toml::from_str(&content).with_context(|| format!("Invalid config: {}", path.display()))
+    }
+}
@@ -0,0 +1,89 @@
+// [confirm.rs] - KRUST - Interactive "type yes" gate before large or risky runs
+use anyhow::{bail, Context, Result};
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+
+use crate::failures;
+use crate::ssh_executor::SshHost;
+use crate::HostResult;
+
+const SHOWN_HOSTS: usize = 5;
+
+/// Why the prompt is shown; also decides the message when there is no terminal
+pub enum Reason {
+    Requested,
+    Threshold(usize),
+}
+
+/// Describe the run on the controlling terminal and require "yes".
+/// Reads /dev/tty so the prompt works even when stdin is piped into --stdin.
+pub fn confirm_run(jobs: &[(SshHost, String)], user: &str, reason: Reason) -> Result<()> {
+    let Ok(tty) = OpenOptions::new().read(true).write(true).open("/dev/tty") else {
+        match reason {
+            Reason::Requested => bail!("--confirm needs a terminal; pass --yes to run non-interactively"),
+            Reason::Threshold(threshold) => bail!(
+                "{} hosts exceeds confirm_threshold {} and there is no terminal to confirm on; pass --yes to proceed",
+                jobs.len(),
+                threshold
+            ),
+        }
+    };
+    let mut out = tty.try_clone().context("Failed to open terminal")?;
+
+    let hosts: Vec<&str> = jobs.iter().take(SHOWN_HOSTS).map(|(host, _)| host.hostname.as_str()).collect();
+    let more = match jobs.len().saturating_sub(SHOWN_HOSTS) {
+        0 => String::new(),
+        n => format!(" and {} more", n),
+    };
+    let command = jobs.first().map(|(_, command)| command.as_str()).unwrap_or("");
+
+    writeln!(out, "About to run on {} hosts as {}:", jobs.len(), user)?;
+    writeln!(out, "  hosts:   {}{}", hosts.join(", "), more)?;
+    writeln!(out, "  command: {}", command)?;
+    // Overrides and {fact} placeholders give some hosts a command of their own
+    let differing = jobs.iter().filter(|(_, other)| other != command).count();
+    if differing > 0 {
+        writeln!(out, "           ({} hosts run a different command; --list-hosts shows overrides)", differing)?;
+    }
+    write!(out, "Type 'yes' to continue: ")?;
+    out.flush()?;
+
+    let mut answer = String::new();
+    BufReader::new(tty).read_line(&mut answer).context("Failed to read confirmation")?;
+    if answer.trim() != "yes" {
+        bail!("Aborted: confirmation not given");
+    }
+    Ok(())
+}
+
+/// "12 hosts failed - retry them now? [y/N/show]" after an interactive run. `show` lists the
+/// failed hosts and why before asking again; without a terminal the answer is no.
+pub fn ask_retry(failed: &[&HostResult]) -> Result<bool> {
+    let Ok(tty) = OpenOptions::new().read(true).write(true).open("/dev/tty") else {
+        return Ok(false);
+    };
+    let mut out = tty.try_clone().context("Failed to open terminal")?;
+    let mut input = BufReader::new(tty);
+    let hosts = match failed.len() {
+        1 => "1 host".to_string(),
+        n => format!("{} hosts", n),
+    };
+    loop {
+        write!(out, "{} failed - retry them now? [y/N/show] ", hosts)?;
+        out.flush()?;
+        let mut answer = String::new();
+        if input.read_line(&mut answer).context("Failed to read answer")? == 0 {
+            return Ok(false);
+        }
+        match answer.trim().to_ascii_lowercase().as_str() {
+            "y" | "yes" => return Ok(true),
+            "show" | "s" => {
+                let width = failed.iter().map(|r| r.hostname.len()).max().unwrap_or(0);
+                for result in failed {
+                    writeln!(out, "  {:<width$}  {}", result.hostname, failures::label(result))?;
+                }
+            }
+            _ => return Ok(false),
+        }
+    }
+}
@@ -0,0 +1,137 @@
+// [encoding.rs] - KRUST - Turning a command's raw output bytes into result fields
+use base64::Engine;
+
+/// --output-encoding: how output bytes become the result's stdout
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputEncoding {
+    /// Invalid UTF-8 is replaced with U+FFFD and the result is flagged non_utf8
+    #[default]
+    Utf8Lossy,
+    /// Invalid UTF-8 fails the host
+    Utf8Strict,
+    /// The exact bytes, base64-encoded, in stdout_b64
+    Base64,
+    /// Each byte is one ISO-8859-1 character
+    Latin1,
+}
+
+/// --stdout-lines: when results carry stdout split into lines
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StdoutLines {
+    /// Only when the output has more than one line
+    #[default]
+    Auto,
+    /// Always, as an empty array for empty output
+    Always,
+    Never,
+}
+
+impl StdoutLines {
+    pub fn split(self, text: &str) -> Option<Vec<String>> {
+        match self {
+            StdoutLines::Auto if !text.trim_end_matches('\n').contains('\n') => None,
+            StdoutLines::Never => None,
+            _ => Some(text.lines().map(str::to_string).collect()),
+        }
+    }
+}
+
+/// Output ready for a HostResult
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Decoded {
+    pub text: Option<String>,
+    pub base64: Option<String>,
+    /// The text differs from the bytes the host sent
+    pub non_utf8: bool,
+}
+
+impl OutputEncoding {
+    /// Text output loses CRLF line endings, and trailing whitespace when `trim` is set;
+    /// base64 keeps every byte
+    pub fn decode(self, raw: &[u8], trim: bool) -> Result<Decoded, String> {
+        if self == OutputEncoding::Base64 {
+            return Ok(Decoded {
+                base64: Some(base64::engine::general_purpose::STANDARD.encode(raw)),
+                ..Decoded::default()
+            });
+        }
+        let (text, non_utf8) = match (raw, self) {
+            // Windows tools write UTF-16 with a byte order mark
+            ([0xFF, 0xFE, rest @ ..], _) => (utf16(rest, u16::from_le_bytes), false),
+            ([0xFE, 0xFF, rest @ ..], _) => (utf16(rest, u16::from_be_bytes), false),
+            (_, OutputEncoding::Latin1) => (raw.iter().map(|&byte| char::from(byte)).collect(), false),
+            _ => {
+                let raw = raw.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(raw);
+                match std::str::from_utf8(raw) {
+                    Ok(text) => (text.to_string(), false),
+                    Err(e) if self == OutputEncoding::Utf8Strict => {
+                        return Err(format!(
+                            "Output is not valid UTF-8 (byte {}); use --output-encoding base64 or latin1",
+                            e.valid_up_to()
+                        ));
+                    }
+                    Err(_) => (String::from_utf8_lossy(raw).into_owned(), true),
+                }
+            }
+        };
+        let text = if text.contains('\r') { text.replace("\r\n", "\n") } else { text };
+        let text = if trim { text.trim_end().to_string() } else { text };
+        Ok(Decoded {
+            text: Some(text),
+            base64: None,
+            non_utf8,
+        })
+    }
+}
+
+fn utf16(bytes: &[u8], unit: fn([u8; 2]) -> u16) -> String {
+    let units: Vec<u16> = bytes.chunks_exact(2).map(|pair| unit([pair[0], pair[1]])).collect();
+    String::from_utf16_lossy(&units)
+}
+
+/// Remove terminal escape sequences: CSI (colors, cursor movement), OSC (titles, hyperlinks)
+/// and other two-character escapes
+pub fn strip_ansi(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\x1b' => match chars.next() {
+                Some('[') => skip_csi(&mut chars),
+                Some(']') => skip_osc(&mut chars),
+                // Character set selection, e.g. ESC ( B, carries one more character
+                Some('(' | ')' | '*' | '+') => {
+                    chars.next();
+                }
+                Some(_) | None => {}
+            },
+            '\u{9b}' => skip_csi(&mut chars),
+            '\u{9d}' => skip_osc(&mut chars),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Parameters and intermediates up to the final byte (@ to ~)
+fn skip_csi(chars: &mut impl Iterator<Item = char>) {
+    for c in chars {
+        if ('@'..='~').contains(&c) {
+            break;
+        }
+    }
+}
+
+/// Up to BEL or the string terminator ESC \
+fn skip_osc(chars: &mut std::iter::Peekable<impl Iterator<Item = char>>) {
+    while let Some(c) = chars.next() {
+        match c {
+            '\x07' | '\u{9c}' => break,
+            '\x1b' if chars.peek() == Some(&'\\') => {
+                chars.next();
+                break;
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,128 @@
+// [escalation.rs] - KRUST - Run commands as root through sudo, doas or su (--become)
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use regex::bytes::Regex;
+use std::sync::OnceLock;
+
+use crate::modules::shell_quote;
+use crate::secret::Secret;
+
+/// What sudo prints when it reads the password, so it can be taken back out of the output
+const SUDO_PROMPT: &str = "[krust-become] ";
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BecomeMethod {
+    #[default]
+    Sudo,
+    Doas,
+    Su,
+}
+
+impl BecomeMethod {
+    fn name(self) -> &'static str {
+        match self {
+            BecomeMethod::Sudo => "sudo",
+            BecomeMethod::Doas => "doas",
+            BecomeMethod::Su => "su",
+        }
+    }
+
+    /// Output that means the method itself refused, with what to do about it. Only looked at
+    /// when the command failed, so a command printing the same words is not mistaken for it.
+    fn refusal(self, output: &str, password: bool) -> Option<&'static str> {
+        let found = |needles: &[&str]| needles.iter().any(|needle| output.contains(needle));
+        match self {
+            BecomeMethod::Sudo if found(&["incorrect password attempt", "Sorry, try again."]) => {
+                Some("wrong password")
+            }
+            BecomeMethod::Sudo if found(&["a password is required", "a terminal is required"]) => {
+                Some("sudo wants a password; add --ask-become-pass")
+            }
+            BecomeMethod::Sudo if found(&["is not in the sudoers file", "is not allowed to execute"]) => {
+                Some("the user may not run this with sudo")
+            }
+            BecomeMethod::Doas if found(&["doas: Authorization required", "doas: a password is required"]) => {
+                Some("doas wants a password; doas only reads one from a terminal, so the host needs a nopass rule")
+            }
+            BecomeMethod::Doas if found(&["doas: Operation not permitted", "doas: not permitted"]) => {
+                Some("the user may not run this with doas")
+            }
+            BecomeMethod::Su if password && found(&["Authentication failure", "incorrect password", "su: Sorry"]) => {
+                Some("wrong password")
+            }
+            BecomeMethod::Su if found(&["must be run from a terminal"]) => {
+                Some("su on this host only reads passwords from a terminal; use sudo or doas")
+            }
+            _ => None,
+        }
+    }
+}
+
+/// --become: how commands are wrapped to run as root, and how the method's own prompts and
+/// refusals are told apart from the command's output
+#[derive(Debug, Clone)]
+pub struct Escalation {
+    pub method: BecomeMethod,
+    pub password: Option<Secret>,
+}
+
+impl Escalation {
+    /// Check the combination before any host is tried: doas cannot take a password without a
+    /// terminal, and su always needs one
+    pub fn new(method: BecomeMethod, password: Option<Secret>, stdin: bool) -> Result<Self> {
+        match (method, &password) {
+            (BecomeMethod::Doas, Some(_)) => {
+                bail!("doas only reads passwords from a terminal; give the user a nopass rule instead of --ask-become-pass")
+            }
+            (BecomeMethod::Su, None) => bail!("--become-method su needs the root password: add --ask-become-pass"),
+            // PAM reads the password in blocks and may swallow the input that follows it
+            (BecomeMethod::Su, Some(_)) if stdin => bail!("--become-method su cannot pass --stdin input to the command"),
+            _ => Ok(Escalation { method, password }),
+        }
+    }
+
+    /// The command as root. It runs under sh -c, so pipes and lists stay inside the escalation.
+    pub fn wrap(&self, command: &str) -> String {
+        let quoted = shell_quote(command);
+        match (self.method, self.password.is_some()) {
+            (BecomeMethod::Sudo, false) => format!("sudo -n -- sh -c {}", quoted),
+            (BecomeMethod::Sudo, true) => format!("sudo -S -p {} -- sh -c {}", shell_quote(SUDO_PROMPT), quoted),
+            (BecomeMethod::Doas, _) => format!("doas -n sh -c {}", quoted),
+            (BecomeMethod::Su, _) => format!("su root -c {}", quoted),
+        }
+    }
+
+    /// The command's stdin: the password line first, then whatever the command itself reads
+    pub fn input(&self, input: Option<&[u8]>) -> Option<Vec<u8>> {
+        let Some(ref password) = self.password else {
+            return input.map(<[u8]>::to_vec);
+        };
+        let mut bytes = Vec::with_capacity(password.expose().len() + 1 + input.map_or(0, <[u8]>::len));
+        bytes.extend_from_slice(password.expose().as_bytes());
+        bytes.push(b'\n');
+        bytes.extend_from_slice(input.unwrap_or_default());
+        Some(bytes)
+    }
+
+    /// Take the password prompt out of the output, and turn a refusal by the method into an
+    /// error: a wrong password is not the command failing
+    pub fn settle(&self, output: &mut Vec<u8>, exit_code: i32) -> Result<(), String> {
+        static SUDO: OnceLock<Regex> = OnceLock::new();
+        // su's prompt comes from PAM and may be translated: "Password: ", "Passwort: ", "Senha: "
+        static SU: OnceLock<Regex> = OnceLock::new();
+        let prompt = match self.method {
+            BecomeMethod::Sudo => SUDO.get_or_init(|| Regex::new(&regex::escape(SUDO_PROMPT)).unwrap()),
+            _ => SU.get_or_init(|| Regex::new(r"^(?i)[^\n:]{0,30}(pass|wort|senha|contrase)[^\n:]{0,20}: ?\n?").unwrap()),
+        };
+        if self.password.is_some() && prompt.is_match(output) {
+            *output = prompt.replace_all(output, &b""[..]).into_owned();
+        }
+        if exit_code == 0 {
+            return Ok(());
+        }
+        match self.method.refusal(&String::from_utf8_lossy(output), self.password.is_some()) {
+            Some(reason) => Err(format!("{} refused: {}", self.method.name(), reason)),
+            None => Ok(()),
+        }
+    }
+}
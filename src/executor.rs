@@ -0,0 +1,121 @@
+// [executor.rs] - KRUST - Seam between the run loop and the SSH transport
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::modules::copy::Upload;
+use crate::resolver::Resolver;
+use crate::ssh_executor::{self, Connection, ExecError, ExecOutput, HostAuth, SshAuth, SshHost};
+
+/// Runs one command on one host over a fresh connection. Called on a blocking thread.
+pub trait CommandExecutor: Send + Sync + 'static {
+    fn execute(
+        &self,
+        host: &SshHost,
+        addrs: Vec<SocketAddr>,
+        auth: &SshAuth,
+        command: &str,
+        input: Option<&[u8]>,
+    ) -> Result<ExecOutput, ExecError>;
+
+    /// Runs the command `runs` times, timing each run with its exit code; the output is the last
+    /// run's. This default connects once per run; executors that can keep a session open override it.
+    fn execute_repeated(
+        &self,
+        host: &SshHost,
+        addrs: Vec<SocketAddr>,
+        auth: &SshAuth,
+        command: &str,
+        input: Option<&[u8]>,
+        runs: usize,
+    ) -> Result<(ExecOutput, Vec<(Duration, i32)>), ExecError> {
+        let mut timings = Vec::with_capacity(runs);
+        let mut last = None;
+        for _ in 0..runs {
+            let start = Instant::now();
+            let output = self.execute(host, addrs.clone(), auth, command, input)?;
+            timings.push((start.elapsed(), output.exit_code));
+            last = Some(output);
+        }
+        let output = last.unwrap_or_else(|| ExecOutput::empty(0, String::new(), None));
+        Ok((output, timings))
+    }
+
+    /// Copies the remote file `remote` to `local` over a fresh connection; returns the bytes copied
+    fn download(
+        &self,
+        host: &SshHost,
+        addrs: Vec<SocketAddr>,
+        auth: &SshAuth,
+        remote: &str,
+        local: &Path,
+    ) -> Result<u64, ExecError>;
+
+    /// Applies `changes` in order over a fresh connection; each change's outcome is in its slot
+    /// (bytes written, or why it failed), so one bad file does not stop the rest
+    fn upload(
+        &self,
+        host: &SshHost,
+        addrs: Vec<SocketAddr>,
+        auth: &SshAuth,
+        changes: &[Upload],
+    ) -> Result<Vec<Result<u64, String>>, ExecError>;
+}
+
+/// The real thing: ssh2 over TCP, directly or through --proxy
+pub struct SshExecutor {
+    pub connection: Connection,
+}
+
+impl CommandExecutor for SshExecutor {
+    fn execute(
+        &self,
+        host: &SshHost,
+        addrs: Vec<SocketAddr>,
+        auth: &SshAuth,
+        command: &str,
+        input: Option<&[u8]>,
+    ) -> Result<ExecOutput, ExecError> {
+        ssh_executor::execute_command_with_input(host, addrs, &self.connection, auth, command, input)
+    }
+
+    fn execute_repeated(
+        &self,
+        host: &SshHost,
+        addrs: Vec<SocketAddr>,
+        auth: &SshAuth,
+        command: &str,
+        input: Option<&[u8]>,
+        runs: usize,
+    ) -> Result<(ExecOutput, Vec<(Duration, i32)>), ExecError> {
+        ssh_executor::execute_repeated(host, addrs, &self.connection, auth, command, input, runs)
+    }
+
+    fn download(
+        &self,
+        host: &SshHost,
+        addrs: Vec<SocketAddr>,
+        auth: &SshAuth,
+        remote: &str,
+        local: &Path,
+    ) -> Result<u64, ExecError> {
+        ssh_executor::download(host, addrs, &self.connection, auth, remote, local)
+    }
+
+    fn upload(
+        &self,
+        host: &SshHost,
+        addrs: Vec<SocketAddr>,
+        auth: &SshAuth,
+        changes: &[Upload],
+    ) -> Result<Vec<Result<u64, String>>, ExecError> {
+        ssh_executor::upload(host, addrs, &self.connection, auth, changes)
+    }
+}
+
+/// How a run reaches its hosts: the executor, per-host authentication and the DNS cache
+pub struct Transport<E> {
+    pub executor: E,
+    pub auth: HostAuth,
+    pub resolver: Resolver,
+}
@@ -0,0 +1,147 @@
+// [facts_cache.rs] - KRUST - Facts saved by collect-facts --save-cache, for --where and {fact} placeholders
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::HostResult;
+
+/// Facts derived from collected ones, so expressions need not do unit conversions
+const DERIVED_GB: [(&str, &str); 3] = [
+    ("memory_total_gb", "memory_total_mb"),
+    ("memory_available_gb", "memory_available_mb"),
+    ("swap_total_gb", "swap_total_mb"),
+];
+
+/// The cache file: the latest facts of every host ever saved into it, by `hostname[:port]`
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct FactsCache {
+    pub hosts: BTreeMap<String, CachedFacts>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedFacts {
+    pub collected_at: DateTime<Utc>,
+    pub facts: Map<String, Value>,
+}
+
+impl FactsCache {
+    pub fn load(path: &str) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read facts cache: {}", path))?;
+        serde_json::from_str(&text).with_context(|| format!("Invalid facts cache: {}", path))
+    }
+
+    /// Merge the facts of the hosts that succeeded into the cache at `path`, keeping the
+    /// entries of hosts not in this run. Returns how many hosts were saved.
+    pub fn save(path: &str, results: &[HostResult]) -> Result<usize> {
+        let mut cache = match std::path::Path::new(path).exists() {
+            true => FactsCache::load(path)?,
+            false => FactsCache::default(),
+        };
+        let mut saved = 0;
+        for result in results.iter().filter(|result| result.success) {
+            let Some(Ok(Value::Object(facts))) = result.stdout.as_deref().map(serde_json::from_str::<Value>) else {
+                continue;
+            };
+            cache.hosts.insert(result.target.clone(), CachedFacts { collected_at: result.timestamp, facts });
+            saved += 1;
+        }
+        let text = serde_json::to_string_pretty(&cache)?;
+        std::fs::write(path, text + "\n").with_context(|| format!("Failed to write facts cache: {}", path))?;
+        Ok(saved)
+    }
+
+    /// A host's facts plus the derived ones; None when the host was never collected
+    pub fn variables(&self, hostname: &str) -> Option<Map<String, Value>> {
+        let mut facts = self.hosts.get(hostname)?.facts.clone();
+        for (derived, source) in DERIVED_GB {
+            if let Some(mb) = facts.get(source).and_then(Value::as_f64) {
+                // One decimal, and none for whole sizes so `{memory_total_gb}` reads "16"
+                let gb = (mb / 1024.0 * 10.0).round() / 10.0;
+                let value = match gb.fract() == 0.0 {
+                    true => Value::from(gb as u64),
+                    false => Value::from(gb),
+                };
+                facts.insert(derived.to_string(), value);
+            }
+        }
+        Some(facts)
+    }
+
+    /// Whether any host has this fact, to tell a typo from a fact some hosts lack
+    pub fn knows(&self, name: &str) -> bool {
+        DERIVED_GB.iter().any(|(derived, _)| *derived == name)
+            || self.hosts.values().any(|cached| cached.facts.contains_key(name))
+    }
+
+    /// Warn once about hosts whose facts are older than --facts-max-age
+    pub fn warn_stale<'a>(&self, hostnames: impl Iterator<Item = &'a str>, max_age: Duration) {
+        let now = Utc::now();
+        let stale: Vec<(&str, DateTime<Utc>)> = hostnames
+            .filter_map(|hostname| self.hosts.get(hostname).map(|cached| (hostname, cached.collected_at)))
+            .filter(|(_, collected_at)| (now - *collected_at).to_std().is_ok_and(|age| age > max_age))
+            .collect();
+        let Some((oldest, collected_at)) = stale.iter().min_by_key(|(_, collected_at)| *collected_at) else {
+            return;
+        };
+        let age = (now - *collected_at).to_std().unwrap_or_default();
+        warn!(
+            "Cached facts for {} host(s) are older than --facts-max-age (oldest: {}, {} ago); refresh with collect-facts --save-cache",
+            stale.len(),
+            oldest,
+            humantime(age)
+        );
+    }
+}
+
+fn humantime(age: Duration) -> String {
+    let secs = age.as_secs();
+    match secs {
+        s if s >= 86400 => format!("{}d", s / 86400),
+        s if s >= 3600 => format!("{}h", s / 3600),
+        s if s >= 60 => format!("{}m", s / 60),
+        s => format!("{}s", s),
+    }
+}
+
+/// Replace `{fact}` placeholders in `command` with the host's values. Only names some
+/// cached host has are placeholders, so shell braces and `${VAR}` pass through untouched.
+pub fn render(command: &str, hostname: &str, cache: &FactsCache) -> Result<String> {
+    let variables = cache.variables(hostname);
+    let mut rendered = String::with_capacity(command.len());
+    let mut rest = command;
+    while let Some(open) = rest.find('{') {
+        let (before, after) = rest.split_at(open);
+        rendered.push_str(before);
+        let name = after[1..].find('}').map(|close| &after[1..close + 1]);
+        let placeholder = name.filter(|name| {
+            !before.ends_with('$')
+                && !name.is_empty()
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                && cache.knows(name)
+        });
+        let Some(name) = placeholder else {
+            rendered.push('{');
+            rest = &after[1..];
+            continue;
+        };
+        let value = match variables.as_ref().map(|facts| facts.get(name)) {
+            None => bail!("{}: no cached facts for {{{}}}; run collect-facts --save-cache on it first", hostname, name),
+            Some(None | Some(Value::Null)) => bail!("{}: fact {{{}}} was not collected", hostname, name),
+            Some(Some(Value::String(text))) => text.clone(),
+            Some(Some(Value::Array(items))) => items.iter()
+                .map(|item| item.as_str().map(str::to_string).unwrap_or_else(|| item.to_string()))
+                .collect::<Vec<_>>()
+                .join(" "),
+            Some(Some(other)) => other.to_string(),
+        };
+        rendered.push_str(&value);
+        rest = &after[name.len() + 2..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
@@ -0,0 +1,131 @@
+// [failures.rs] - KRUST - Failed hosts grouped by cause, with a hint for the common causes
+use serde::Serialize;
+
+use crate::host_error::LocalErrorKind;
+use crate::ssh_executor::ExecErrorKind;
+use crate::HostResult;
+
+/// Hostnames listed per bucket in the text summary; JSON lists them all
+const EXAMPLES: usize = 3;
+
+/// Why a host failed, coarser than the result itself
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Cause {
+    Kind(ExecErrorKind),
+    Timeout,
+    CommandFailed,
+    Assertion,
+    Other,
+}
+
+impl Cause {
+    fn of(result: &HostResult) -> Self {
+        match (result.error_kind, result.exit_code) {
+            (Some(kind), _) => Cause::Kind(kind),
+            _ if result.assertion_error.is_some() => Cause::Assertion,
+            (None, Some(code)) if code != 0 => Cause::CommandFailed,
+            _ if result.error.as_ref().is_some_and(|error| error.is(LocalErrorKind::Timeout)) => Cause::Timeout,
+            _ => Cause::Other,
+        }
+    }
+
+    /// Name in the JSON summary, e.g. "auth" or "command_failed"
+    fn key(self) -> String {
+        match self {
+            Cause::Kind(kind) => serde_json::json!(kind).as_str().unwrap_or("other").to_string(),
+            Cause::Timeout => "timeout".to_string(),
+            Cause::CommandFailed => "command_failed".to_string(),
+            Cause::Assertion => "assertion".to_string(),
+            Cause::Other => "other".to_string(),
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Cause::Kind(ExecErrorKind::Unreachable) => "unreachable (pre-check)",
+            Cause::Kind(ExecErrorKind::Deadline) => "timed out by deadline",
+            Cause::Kind(ExecErrorKind::DnsNotFound) => "name not found",
+            Cause::Kind(ExecErrorKind::DnsTimeout) | Cause::Kind(ExecErrorKind::Resolve) => "DNS failure",
+            Cause::Kind(ExecErrorKind::Connect) => "connect failed",
+            Cause::Kind(ExecErrorKind::Proxy) => "proxy failed",
+            Cause::Kind(ExecErrorKind::ProxyCommand) => "proxy command failed",
+            Cause::Kind(ExecErrorKind::Handshake) => "SSH handshake failed",
+            Cause::Kind(ExecErrorKind::Auth) => "auth failed",
+            Cause::Kind(ExecErrorKind::Channel) => "session error",
+            Cause::Kind(ExecErrorKind::Become) => "privilege escalation refused",
+            Cause::Timeout => "command timeout",
+            Cause::CommandFailed => "command non-zero",
+            Cause::Assertion => "assertion failed",
+            Cause::Other => "other",
+        }
+    }
+
+    fn hint(self) -> Option<&'static str> {
+        match self {
+            Cause::Kind(ExecErrorKind::Unreachable) | Cause::Kind(ExecErrorKind::Connect) => {
+                Some("check the hosts are up and the SSH port is open")
+            }
+            Cause::Kind(ExecErrorKind::Deadline) => Some("raise --deadline or --concurrency"),
+            Cause::Kind(ExecErrorKind::DnsNotFound) => Some("check the hostnames, or pin addresses with --resolve"),
+            Cause::Kind(ExecErrorKind::DnsTimeout) | Cause::Kind(ExecErrorKind::Resolve) => {
+                Some("check the resolver, or pin addresses with --resolve")
+            }
+            Cause::Kind(ExecErrorKind::Proxy) => Some("check --proxy is up and allows the SSH ports"),
+            Cause::Kind(ExecErrorKind::ProxyCommand) => Some("run the --proxy-command by hand for one host"),
+            Cause::Kind(ExecErrorKind::Auth) => Some("check ssh-add -l / --user / --private-key"),
+            Cause::Kind(ExecErrorKind::Become) => Some("check --become-method, --ask-become-pass and the sudoers/doas rules"),
+            Cause::Timeout => Some("raise --timeout"),
+            _ => None,
+        }
+    }
+}
+
+/// Short reason a host failed, e.g. "auth failed"
+pub fn label(result: &HostResult) -> &'static str {
+    Cause::of(result).label()
+}
+
+/// Failed hosts sharing a cause
+#[derive(Serialize, Debug)]
+pub struct Bucket {
+    pub cause: String,
+    pub label: &'static str,
+    pub count: usize,
+    pub hosts: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint: Option<&'static str>,
+}
+
+/// Buckets of failed hosts, largest first
+pub fn buckets(results: &[HostResult]) -> Vec<Bucket> {
+    let mut grouped: Vec<(Cause, Vec<String>)> = Vec::new();
+    for result in results.iter().filter(|r| !r.success) {
+        let cause = Cause::of(result);
+        match grouped.iter_mut().find(|(known, _)| *known == cause) {
+            Some((_, hosts)) => hosts.push(result.target.clone()),
+            None => grouped.push((cause, vec![result.target.clone()])),
+        }
+    }
+    grouped.sort_by_key(|(_, hosts)| std::cmp::Reverse(hosts.len()));
+    grouped.into_iter()
+        .map(|(cause, hosts)| Bucket {
+            cause: cause.key(),
+            label: cause.label(),
+            count: hosts.len(),
+            hosts,
+            hint: cause.hint(),
+        })
+        .collect()
+}
+
+/// "auth failed: 12 (web01, web02, web03, ...) - check ssh-add -l / --user / --private-key"
+pub fn describe(bucket: &Bucket) -> String {
+    let mut examples = bucket.hosts.iter().take(EXAMPLES).cloned().collect::<Vec<_>>().join(", ");
+    if bucket.count > EXAMPLES {
+        examples.push_str(", ...");
+    }
+    match bucket.hint {
+        Some(hint) => format!("{}: {} ({}) - {}", bucket.label, bucket.count, examples, hint),
+        None => format!("{}: {} ({})", bucket.label, bucket.count, examples),
+    }
+}
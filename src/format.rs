@@ -0,0 +1,118 @@
+// [format.rs] - KRUST - One line per host from a --format template
+use crate::HostResult;
+
+/// Placeholders accepted in --format templates
+const FIELDS: &[&str] = &[
+    "hostname",
+    "target",
+    "address",
+    "tags",
+    "source",
+    "success",
+    "stdout",
+    "stdout_raw",
+    "stdout_b64",
+    "non_utf8",
+    "stdout_file",
+    "stderr",
+    "error",
+    "exit_code",
+    "remote_pid",
+    "timestamp",
+    "finished_at",
+    "duration_ms",
+    "bytes_stdout",
+    "bytes_stderr",
+    "reboot_required",
+    "assertion_error",
+    "matched",
+    "post_error",
+    "iteration",
+    "recovered",
+    "resolve_ms",
+    "auth_ms",
+    "auth_method",
+];
+
+#[derive(Debug, Clone)]
+enum Piece {
+    Literal(String),
+    Field(String),
+}
+
+/// Parsed --format template such as '{hostname},{exit_code},{duration_ms}'
+#[derive(Debug, Clone)]
+pub struct ResultFormat {
+    pieces: Vec<Piece>,
+}
+
+impl ResultFormat {
+    pub fn parse(template: &str) -> Result<Self, String> {
+        let mut pieces = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(format!("Unclosed '{{{}' in format", name)),
+                        }
+                    }
+                    if !FIELDS.contains(&name.as_str()) {
+                        return Err(format!("Unknown field '{{{}}}' (valid: {})", name, FIELDS.join(", ")));
+                    }
+                    if !literal.is_empty() {
+                        pieces.push(Piece::Literal(std::mem::take(&mut literal)));
+                    }
+                    pieces.push(Piece::Field(name));
+                }
+                '}' => return Err("Unmatched '}' in format (use '}}' for a literal brace)".to_string()),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            pieces.push(Piece::Literal(literal));
+        }
+        Ok(ResultFormat { pieces })
+    }
+
+    pub fn render(&self, result: &HostResult) -> String {
+        let record = serde_json::to_value(result).unwrap_or_default();
+        let mut line = String::new();
+
+        for piece in &self.pieces {
+            match piece {
+                Piece::Literal(text) => line.push_str(text),
+                Piece::Field(name) if name == "address" => line.push_str(&result.address),
+                Piece::Field(name) if name == "error" => {
+                    line.push_str(result.error_message().unwrap_or(""));
+                }
+                Piece::Field(name) if name == "stdout_raw" => {
+                    line.push_str(result.stdout.as_deref().unwrap_or(""));
+                }
+                Piece::Field(name) => match record.get(name) {
+                    // Text fields are collapsed so each host stays on one line
+                    Some(serde_json::Value::String(text)) => {
+                        line.push_str(&text.lines().map(str::trim_end).collect::<Vec<_>>().join(" "));
+                    }
+                    Some(serde_json::Value::Null) | None => {}
+                    Some(value) => line.push_str(&value.to_string()),
+                },
+            }
+        }
+        line
+    }
+}
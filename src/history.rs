@@ -0,0 +1,253 @@
+// [history.rs] - KRUST - Local audit log of runs, with per-host journals and replay
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, Local, Utc};
+use clap::{Args, Subcommand};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tracing::{debug, warn};
+
+use crate::HostResult;
+
+/// Rotate history.jsonl to history.jsonl.1 past this size
+const MAX_HISTORY_BYTES: u64 = 1024 * 1024;
+/// Per-host journals kept in runs/, oldest removed first
+const MAX_JOURNALS: usize = 100;
+
+#[derive(Args, Debug)]
+pub struct HistoryArgs {
+    #[command(subcommand)]
+    pub action: Option<HistoryAction>,
+
+    /// Number of recent runs to list
+    #[arg(long, default_value_t = 20)]
+    pub limit: usize,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum HistoryAction {
+    /// Print the per-host results recorded for a run
+    Show { id: String },
+    /// Run the same command against the same host selection again
+    Rerun { id: String },
+}
+
+/// One line of history.jsonl
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RunRecord {
+    pub id: String,
+    pub timestamp: DateTime<Utc>,
+    pub user: String,
+    /// Raw command, or "module <name>"
+    pub command: String,
+    pub hosts: usize,
+    pub failed: usize,
+    pub duration_ms: u64,
+    pub exit_code: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub inventory: Option<String>,
+    /// --annotate labels
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
+    /// Command-line arguments with secrets removed, used by `history rerun`
+    pub args: Vec<String>,
+}
+
+/// $XDG_DATA_HOME/krust, else ~/.local/share/krust
+pub fn data_dir() -> Result<PathBuf> {
+    let base = std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("share")))
+        .ok_or_else(|| anyhow!("Cannot locate the history directory: HOME is not set"))?;
+    Ok(base.join("krust"))
+}
+
+pub fn new_run_id(started: DateTime<Utc>) -> String {
+    format!("{}-{:04x}", started.format("%Y%m%d%H%M%S"), std::process::id() & 0xffff)
+}
+
+/// Whether a run with this ID was recorded, in the history or as a journal
+pub fn is_taken(id: &str) -> Result<bool> {
+    Ok(journal_path(id)?.exists() || load_records()?.iter().any(|record| record.id == id))
+}
+
+/// `id`, or with -2, -3, ... appended when a run was already recorded under it
+pub fn unused_run_id(id: String) -> String {
+    let records = load_records().unwrap_or_default();
+    let taken = |candidate: &str| {
+        journal_path(candidate).is_ok_and(|path| path.exists()) || records.iter().any(|record| record.id == candidate)
+    };
+    match taken(&id) {
+        false => id,
+        true => (2..).map(|n| format!("{}-{}", id, n)).find(|candidate| !taken(candidate)).unwrap_or(id),
+    }
+}
+
+/// Drop password values so they never reach the history file. The trailing
+/// `command_len` arguments are the remote command and are kept verbatim.
+pub fn redact_args(args: &[String], command_len: usize) -> Vec<String> {
+    let (options, command) = args.split_at(args.len().saturating_sub(command_len));
+    let mut redacted = Vec::with_capacity(args.len());
+    let mut iter = options.iter();
+    while let Some(arg) = iter.next() {
+        let shorts = arg.strip_prefix('-').filter(|shorts| !shorts.starts_with('-'));
+        match arg.as_str() {
+            "--password" => {
+                iter.next();
+            }
+            _ if arg.starts_with("--password=") => {}
+            _ => match shorts.and_then(short_value) {
+                // -p, -vp: the password is the next argument; -pSECRET, -vpSECRET: the rest of this one
+                Some((flags, 'p', value)) => {
+                    if !flags.is_empty() {
+                        redacted.push(format!("-{}", flags));
+                    }
+                    if value.is_empty() {
+                        iter.next();
+                    }
+                }
+                _ => redacted.push(arg.clone()),
+            },
+        }
+    }
+    redacted.extend_from_slice(command);
+    redacted
+}
+
+/// Split a cluster of short flags (without its '-') at the first flag taking a value, as clap
+/// reads it: the flags before it, the flag, and the rest of the cluster, which is its value
+fn short_value(shorts: &str) -> Option<(&str, char, &str)> {
+    let (at, flag) = shorts.char_indices().find(|&(_, c)| crate::flag_takes_value(|arg| arg.get_short() == Some(c)))?;
+    Some((&shorts[..at], flag, &shorts[at + flag.len_utf8()..]))
+}
+
+/// Replace --command-file FILE or --command - with --command=TEXT
+pub fn inline_command(args: &[String], command: &str) -> Vec<String> {
+    let mut inlined = Vec::with_capacity(args.len());
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--command-file" | "--command" => {
+                iter.next();
+            }
+            _ if arg.starts_with("--command-file=") || arg.starts_with("--command=") => {}
+            _ => inlined.push(arg.clone()),
+        }
+    }
+    inlined.push(format!("--command={}", command));
+    inlined
+}
+
+/// Append the run to history.jsonl and keep its per-host journal; failures only warn
+pub fn record(run: &RunRecord, results: &[HostResult]) {
+    if let Err(e) = write_record(run, results) {
+        warn!("Could not write run history: {:#}", e);
+    }
+}
+
+fn write_record(run: &RunRecord, results: &[HostResult]) -> Result<()> {
+    let dir = data_dir()?;
+    let runs = dir.join("runs");
+    fs::create_dir_all(&runs).with_context(|| format!("Failed to create {}", runs.display()))?;
+
+    let history = dir.join("history.jsonl");
+    if fs::metadata(&history).map(|m| m.len() > MAX_HISTORY_BYTES).unwrap_or(false) {
+        fs::rename(&history, dir.join("history.jsonl.1")).context("Failed to rotate history")?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(&history)
+        .with_context(|| format!("Failed to open {}", history.display()))?;
+    writeln!(file, "{}", serde_json::to_string(run)?)?;
+
+    let mut journal = String::new();
+    for result in results {
+        journal.push_str(&serde_json::to_string(result)?);
+        journal.push('\n');
+    }
+    fs::write(runs.join(format!("{}.jsonl", run.id)), journal)?;
+
+    prune_journals(&runs, MAX_JOURNALS)
+}
+
+/// Keep the `keep` journals written last; --run-id names do not sort by time, so go by mtime
+pub(crate) fn prune_journals(runs: &Path, keep: usize) -> Result<()> {
+    let mut journals: Vec<(SystemTime, PathBuf)> = fs::read_dir(runs)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| (entry.metadata().and_then(|m| m.modified()).unwrap_or(SystemTime::UNIX_EPOCH), entry.path()))
+        .collect();
+    if journals.len() > keep {
+        journals.sort();
+        for (_, old) in &journals[..journals.len() - keep] {
+            debug!("Removing old journal {}", old.display());
+            fs::remove_file(old)?;
+        }
+    }
+    Ok(())
+}
+
+/// All recorded runs, oldest first, including the rotated file
+fn load_records() -> Result<Vec<RunRecord>> {
+    let dir = data_dir()?;
+    let mut records = Vec::new();
+    for name in ["history.jsonl.1", "history.jsonl"] {
+        let path = dir.join(name);
+        let Ok(content) = fs::read_to_string(&path) else {
+            continue;
+        };
+        for line in content.lines().filter(|line| !line.trim().is_empty()) {
+            match serde_json::from_str(line) {
+                Ok(record) => records.push(record),
+                Err(e) => debug!("Skipping unreadable history line in {}: {}", path.display(), e),
+            }
+        }
+    }
+    Ok(records)
+}
+
+pub fn find(id: &str) -> Result<RunRecord> {
+    load_records()?.into_iter().rev()
+        .find(|record| record.id == id)
+        .ok_or_else(|| anyhow!("No run with id {} in the history", id))
+}
+
+/// Where the per-host results of run `id` are kept
+pub fn journal_path(id: &str) -> Result<PathBuf> {
+    Ok(data_dir()?.join("runs").join(format!("{}.jsonl", id)))
+}
+
+pub fn list(limit: usize) -> Result<()> {
+    let records = load_records()?;
+    if records.is_empty() {
+        outln!("No runs recorded yet");
+        return Ok(());
+    }
+
+    outln!("{:<19}  {:<19}  {:<10}  {:>5}  {:>6}  {:>4}  COMMAND", "ID", "TIME", "USER", "HOSTS", "FAILED", "EXIT");
+    for record in records.iter().rev().take(limit).rev() {
+        let command: String = record.command.chars().take(60).collect();
+        outln!(
+            "{:<19}  {:<19}  {:<10}  {:>5}  {:>6}  {:>4}  {}",
+            record.id,
+            record.timestamp.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S"),
+            record.user,
+            record.hosts,
+            record.failed,
+            record.exit_code,
+            command
+        );
+    }
+    Ok(())
+}
+
+pub fn show(id: &str) -> Result<()> {
+    let record = find(id)?;
+    outln!("{}", serde_json::to_string_pretty(&record)?);
+
+    let Ok(content) = fs::read_to_string(journal_path(&record.id)?) else {
+        bail!("No per-host journal kept for run {} (it may have been rotated out)", id);
+    };
+    out!("{}", content);
+    Ok(())
+}
@@ -0,0 +1,56 @@
+// [host_error.rs] - KRUST - Why a host failed on krust's side, kept apart from what the host printed
+use serde::Serialize;
+
+use crate::ssh_executor::ExecErrorKind;
+
+/// Failures that happen around the SSH session rather than in it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LocalErrorKind {
+    /// No result within --timeout
+    Timeout,
+    /// The output could not be decoded under --output-encoding utf8-strict
+    Decode,
+    /// The blocking task running the session panicked
+    Panic,
+    /// A module's controller-side step (download, upload, reboot wait, ...) failed
+    Module,
+    /// The --pre-cmd failed, so the command was not run
+    Hook,
+    /// Not run: the rollout was aborted before the host's turn
+    Skipped,
+}
+
+/// `error.kind`: an executor stage such as "connect" or "auth", or one of the local kinds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(untagged)]
+pub enum HostErrorKind {
+    Exec(ExecErrorKind),
+    Local(LocalErrorKind),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HostError {
+    pub kind: HostErrorKind,
+    pub message: String,
+    /// Whether another attempt could succeed: krust's own --retries retry exactly these
+    pub retryable: bool,
+    /// The attempt that failed, from 1; absent when the host was never tried
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attempt: Option<u32>,
+}
+
+impl HostError {
+    pub fn exec(kind: ExecErrorKind, message: impl Into<String>, attempt: Option<u32>) -> Self {
+        HostError { kind: HostErrorKind::Exec(kind), message: message.into(), retryable: kind.is_connection(), attempt }
+    }
+
+    pub fn local(kind: LocalErrorKind, message: impl Into<String>, attempt: Option<u32>) -> Self {
+        let retryable = kind == LocalErrorKind::Timeout;
+        HostError { kind: HostErrorKind::Local(kind), message: message.into(), retryable, attempt }
+    }
+
+    pub fn is(&self, kind: LocalErrorKind) -> bool {
+        self.kind == HostErrorKind::Local(kind)
+    }
+}
@@ -0,0 +1,384 @@
+// [inventory.rs] - KRUST - Inventory files: hosts, groups and per-host connection settings
+use anyhow::{anyhow, bail, Context, Result};
+use std::collections::HashMap;
+use std::process::{Command, Stdio};
+use tracing::{debug, warn};
+
+use crate::secret::Secret;
+use crate::ssh_executor::{normalize_name, AuthMethod, HostSource, SshAuth, SshHost};
+
+/// Host variables an inventory may set, for error messages
+const VARIABLES: &[&str] = &["user", "identity_file", "auth", "password_env", "password_file", "tags", "max_concurrency"];
+
+/// How a host must authenticate, replacing the command-line choice
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AuthKind {
+    Password,
+    Key,
+    Agent,
+}
+
+/// Connection settings from `key=value` pairs on a host line or in a `[group:vars]` section
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct HostVars {
+    pub user: Option<String>,
+    pub identity_file: Option<String>,
+    pub auth: Option<AuthKind>,
+    /// Environment variable holding the password
+    pub password_env: Option<String>,
+    /// File whose first line is the password
+    pub password_file: Option<String>,
+}
+
+impl HostVars {
+    fn set(&mut self, pair: &str) -> Result<()> {
+        let (key, value) = pair.split_once('=')
+            .ok_or_else(|| anyhow!("Expected key=value, got '{}'", pair))?;
+        let value = value.to_string();
+        match key {
+            "user" => self.user = Some(value),
+            "identity_file" => self.identity_file = Some(expand_home(&value)),
+            "auth" => {
+                self.auth = Some(match value.as_str() {
+                    "password" => AuthKind::Password,
+                    "key" => AuthKind::Key,
+                    "agent" => AuthKind::Agent,
+                    other => bail!("Unknown auth '{}' (valid: password, key, agent)", other),
+                })
+            }
+            "password_env" => self.password_env = Some(value),
+            "password_file" => self.password_file = Some(expand_home(&value)),
+            "password" => bail!("Passwords may not be written in the inventory; use password_env or password_file"),
+            other => bail!("Unknown host variable '{}' (valid: {})", other, VARIABLES.join(", ")),
+        }
+        Ok(())
+    }
+
+    /// Fill the settings this host leaves unset from `defaults`
+    fn inherit(&mut self, defaults: &HostVars) {
+        let HostVars { user, identity_file, auth, password_env, password_file } = defaults;
+        self.user = self.user.take().or_else(|| user.clone());
+        self.identity_file = self.identity_file.take().or_else(|| identity_file.clone());
+        self.auth = self.auth.or(*auth);
+        self.password_env = self.password_env.take().or_else(|| password_env.clone());
+        self.password_file = self.password_file.take().or_else(|| password_file.clone());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == HostVars::default()
+    }
+
+    /// Build the authentication for hosts with these settings; anything unset comes from the command line
+    pub fn auth(&self, user: &str, password: Option<&Secret>, keys: &[String], certificates: &[String]) -> Result<SshAuth> {
+        let user = self.user.clone().unwrap_or_else(|| user.to_string());
+        let password = match (&self.password_env, &self.password_file) {
+            (Some(var), _) => Some(Secret::new(std::env::var(var)
+                .with_context(|| format!("password_env: ${} is not set", var))?)),
+            (None, Some(path)) => {
+                let content = Secret::new(std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read password_file: {}", path))?);
+                Some(Secret::new(content.expose().lines().next().unwrap_or_default().to_string()))
+            }
+            (None, None) => password.cloned(),
+        };
+        // Certificates given on the command line belong to the command-line keys
+        let (keys, certificates) = match self.identity_file {
+            Some(ref key) => (vec![key.clone()], Vec::new()),
+            None => (keys.to_vec(), certificates.to_vec()),
+        };
+
+        match self.auth {
+            None => SshAuth::new(user, password, keys, certificates, true),
+            Some(AuthKind::Key) => SshAuth::new(user, None, keys, certificates, false),
+            Some(AuthKind::Agent) => Ok(SshAuth::with_methods(user, vec![AuthMethod::Agent])),
+            Some(AuthKind::Password) => {
+                let password = password
+                    .ok_or_else(|| anyhow!("auth=password needs password_env, password_file or -p/--ask-pass"))?;
+                Ok(SshAuth::with_methods(user, vec![AuthMethod::Password(password)]))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct InventoryHost {
+    pub host: SshHost,
+    pub groups: Vec<String>,
+    pub vars: HostVars,
+    /// From `tags=a,b` on the host line and its groups' vars, for --tags/--any-tag
+    pub tags: Vec<String>,
+    /// From the `[command_overrides]` section: run this instead of the run's command
+    pub command: Option<String>,
+}
+
+/// Hosts in file order, one entry per host even if it is listed in several groups
+#[derive(Debug, Default)]
+pub struct Inventory {
+    pub hosts: Vec<InventoryHost>,
+    /// `max_concurrency=N` from `[group:vars]`, by group
+    pub group_concurrency: HashMap<String, usize>,
+    /// Lines that could not be parsed, as (line number, reason)
+    pub invalid: Vec<(usize, String)>,
+}
+
+enum Section {
+    Hosts(Option<String>),
+    Vars(String),
+    /// `[command_overrides]`: `HOST COMMAND...` lines
+    Overrides,
+}
+
+impl Inventory {
+    /// With `skip_invalid`, bad lines are logged and left out; otherwise they are all listed in the error
+    pub fn load(path: &str, skip_invalid: bool) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read inventory: {}", path))?;
+        Self::parse(&content, Some(path))
+            .with_context(|| format!("Invalid inventory: {}", path))?
+            .check(path, skip_invalid)
+    }
+
+    /// Hosts printed by a local discovery command, run through the user's shell so pipes work
+    pub fn from_command(command: &str, skip_invalid: bool) -> Result<Self> {
+        let shell = std::env::var("SHELL").unwrap_or_else(|_| "sh".to_string());
+        let output = Command::new(&shell)
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::null())
+            .output()
+            .with_context(|| format!("Failed to run --hosts-from-cmd with {}", shell))?;
+        if !output.status.success() {
+            bail!(
+                "--hosts-from-cmd failed ({}): {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim_end()
+            );
+        }
+        let inventory = Self::parse(&String::from_utf8_lossy(&output.stdout), None)
+            .context("Invalid output from --hosts-from-cmd")?
+            .check("--hosts-from-cmd output", skip_invalid)?;
+        debug!("--hosts-from-cmd found {} hosts", inventory.hosts.len());
+        Ok(inventory)
+    }
+
+    /// One host per line, optionally followed by `key=value` settings.
+    /// `[group]` starts a group of hosts, `[group:vars]` sets defaults for the group's hosts,
+    /// `[command_overrides]` gives hosts a command of their own, one `HOST COMMAND...` per line.
+    /// `file` is the inventory path, recorded as each host's source; None for --hosts-from-cmd.
+    fn parse(content: &str, file: Option<&str>) -> Result<Self> {
+        let mut inventory = Inventory::default();
+        let mut index: HashMap<SshHost, usize> = HashMap::new();
+        let mut group_vars: HashMap<String, HostVars> = HashMap::new();
+        let mut group_tags: HashMap<String, Vec<String>> = HashMap::new();
+        let mut section = Section::Hosts(None);
+        // (line number, hostname, command), matched to hosts once every host line is read
+        let mut overrides: Vec<(usize, String, String)> = Vec::new();
+
+        for (number, line) in content.lines().enumerate() {
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            // A bad line is recorded and skipped so every problem in the file is reported at once
+            let mut parse_line = || -> Result<()> {
+                if let Some(header) = line.strip_prefix('[') {
+                    let name = header.strip_suffix(']')
+                        .map(str::trim)
+                        .filter(|name| !name.is_empty())
+                        .ok_or_else(|| anyhow!("Malformed group header '{}'", line))?;
+                    section = match name.strip_suffix(":vars") {
+                        _ if name == "command_overrides" => Section::Overrides,
+                        Some(group) => Section::Vars(group.to_string()),
+                        None => Section::Hosts(Some(name.to_string())),
+                    };
+                    return Ok(());
+                }
+
+                match section {
+                    Section::Overrides => {
+                        let (name, command) = line.split_once(char::is_whitespace)
+                            .ok_or_else(|| anyhow!("Expected 'HOST COMMAND' in [command_overrides], got '{}'", line))?;
+                        overrides.push((number + 1, name.to_string(), command.trim().to_string()));
+                    }
+                    Section::Vars(ref group) => {
+                        let vars = group_vars.entry(group.clone()).or_default();
+                        let tags = group_tags.entry(group.clone()).or_default();
+                        for pair in line.split_whitespace() {
+                            if let Some(limit) = pair.strip_prefix("max_concurrency=") {
+                                let limit = limit.parse().ok().filter(|&limit: &usize| limit > 0)
+                                    .ok_or_else(|| anyhow!("max_concurrency must be a positive number, got '{}'", limit))?;
+                                inventory.group_concurrency.insert(group.clone(), limit);
+                                continue;
+                            }
+                            match pair.strip_prefix("tags=") {
+                                Some(list) => add_tags(tags, list),
+                                None => vars.set(pair)?,
+                            }
+                        }
+                    }
+                    Section::Hosts(ref group) => {
+                        let mut fields = line.split_whitespace();
+                        let (target, user) = split_user(fields.next().unwrap_or_default());
+                        let mut host = SshHost::from_target(&target, None)?;
+                        host.source = match file {
+                            Some(file) => HostSource::Inventory { file: file.to_string(), line: number + 1, group: group.clone() },
+                            None => HostSource::Command { line: number + 1, group: group.clone() },
+                        };
+                        let mut vars = HostVars { user: user.map(str::to_string), ..HostVars::default() };
+                        let mut tags = Vec::new();
+                        for pair in fields {
+                            if pair.starts_with("max_concurrency=") {
+                                bail!("max_concurrency is a group setting; put it in a [group:vars] section");
+                            }
+                            match pair.strip_prefix("tags=") {
+                                Some(list) => add_tags(&mut tags, list),
+                                None => vars.set(pair)?,
+                            }
+                        }
+
+                        let position = *index.entry(host.clone()).or_insert_with(|| {
+                            inventory.hosts.push(InventoryHost {
+                                host,
+                                groups: Vec::new(),
+                                vars: HostVars::default(),
+                                tags: Vec::new(),
+                                command: None,
+                            });
+                            inventory.hosts.len() - 1
+                        });
+                        let entry = &mut inventory.hosts[position];
+                        // Tags add up over every line that names the host
+                        add_tags(&mut entry.tags, &tags.join(","));
+                        if let (Some(kept), Some(ignored)) = (&entry.vars.user, &vars.user) {
+                            if kept != ignored {
+                                warn!("line {}: {} is listed again with user={}; keeping user={}",
+                                      number + 1, entry.host, ignored, kept);
+                            }
+                        }
+                        // Settings from the first line that names the host win
+                        entry.vars.inherit(&vars);
+                        if let Some(group) = group {
+                            if !entry.groups.contains(group) {
+                                entry.groups.push(group.clone());
+                            }
+                        }
+                    }
+                }
+                Ok(())
+            };
+            if let Err(e) = parse_line() {
+                inventory.invalid.push((number + 1, format!("{:#}", e)));
+            }
+        }
+
+        for (line, name, command) in overrides {
+            let name = normalize_name(&name);
+            match inventory.hosts.iter_mut().find(|entry| entry.host.hostname == name) {
+                Some(entry) if entry.command.is_some() => {
+                    inventory.invalid.push((line, format!("{} already has a command override", name)));
+                }
+                Some(entry) => entry.command = Some(command),
+                None => inventory.invalid.push((line, format!("Command override for unknown host '{}'", name))),
+            }
+        }
+        inventory.invalid.sort_by_key(|(line, _)| *line);
+
+        for group in group_vars.keys().chain(group_tags.keys()).chain(inventory.group_concurrency.keys()) {
+            if !inventory.hosts.iter().any(|entry| entry.groups.contains(group)) {
+                bail!("[{}:vars] refers to a group with no hosts", group);
+            }
+        }
+        // Host settings win over group settings; the first group listed wins over later ones
+        for entry in &mut inventory.hosts {
+            for group in &entry.groups {
+                if let Some(defaults) = group_vars.get(group) {
+                    entry.vars.inherit(defaults);
+                }
+                if let Some(tags) = group_tags.get(group) {
+                    add_tags(&mut entry.tags, &tags.join(","));
+                }
+            }
+        }
+        Ok(inventory)
+    }
+
+    /// Fail on invalid lines, naming every one of them, or warn and go on without them
+    fn check(self, source: &str, skip_invalid: bool) -> Result<Self> {
+        if self.invalid.is_empty() {
+            return Ok(self);
+        }
+        let lines: Vec<String> = self.invalid.iter()
+            .map(|(line, reason)| format!("{}:{}: {}", source, line, reason))
+            .collect();
+        if !skip_invalid {
+            bail!(
+                "{} invalid lines in {} (--skip-invalid-hosts runs the valid hosts anyway):\n  {}",
+                lines.len(), source, lines.join("\n  ")
+            );
+        }
+        for line in &lines {
+            warn!("Skipping {}", line);
+        }
+        Ok(self)
+    }
+
+    /// Keep only the hosts matching the tag selection: every tag in `all`, at least one in `any`.
+    /// A tag that no host carries is an error, as is a selection that matches nothing.
+    pub fn select_tags(&mut self, all: &[String], any: &[String]) -> Result<()> {
+        if all.is_empty() && any.is_empty() {
+            return Ok(());
+        }
+        if let Some(unknown) = all.iter().chain(any).find(|tag| !self.hosts.iter().any(|entry| entry.tags.contains(tag))) {
+            bail!("No inventory host has tag '{}'", unknown);
+        }
+        self.hosts.retain(|entry| {
+            all.iter().all(|tag| entry.tags.contains(tag))
+                && (any.is_empty() || any.iter().any(|tag| entry.tags.contains(tag)))
+        });
+        if self.hosts.is_empty() {
+            bail!("No inventory host matches the tag selection");
+        }
+        Ok(())
+    }
+}
+
+/// Add the comma-separated `list` to `tags`, skipping empty names and repeats
+fn add_tags(tags: &mut Vec<String>, list: &str) {
+    for tag in list.split(',').map(str::trim).filter(|tag| !tag.is_empty()) {
+        if !tags.iter().any(|known| known == tag) {
+            tags.push(tag.to_string());
+        }
+    }
+}
+
+/// `[name=]user@host[:port]` -> (`[name=]host[:port]`, user); the user is a host setting
+fn split_user(target: &str) -> (String, Option<&str>) {
+    let (alias, rest) = match target.split_once('=') {
+        Some((alias, rest)) => (Some(alias), rest),
+        None => (None, target),
+    };
+    match (rest.split_once('@'), alias) {
+        (Some((user, host)), Some(alias)) => (format!("{}={}", alias, host), Some(user)),
+        (Some((user, host)), None) => (host.to_string(), Some(user)),
+        (None, _) => (target.to_string(), None),
+    }
+}
+
+/// Drop a `#` comment: at the start of the line or after whitespace
+fn strip_comment(line: &str) -> &str {
+    let mut previous = ' ';
+    for (i, c) in line.char_indices() {
+        if c == '#' && previous.is_whitespace() {
+            return &line[..i];
+        }
+        previous = c;
+    }
+    line
+}
+
+fn expand_home(path: &str) -> String {
+    match (path.strip_prefix("~/"), std::env::var("HOME")) {
+        (Some(rest), Ok(home)) => format!("{}/{}", home, rest),
+        _ => path.to_string(),
+    }
+}
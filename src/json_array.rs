@@ -0,0 +1,105 @@
+// [json_array.rs] - KRUST - --json-array: JSON records streamed as the elements of one array
+//
+// Once opened, every JSON record (host results, summary, stats, ...) becomes an array element as it
+// is written, so adding "]" to what has been printed so far always gives a valid document. Errors,
+// Ctrl-C and a cut-short run close the array with a last record saying why.
+use serde_json::{json, Value};
+use std::sync::Mutex;
+
+/// The array being written; None prints records as NDJSON lines
+struct Array {
+    pretty: bool,
+    /// Host results written so far, for the closing record of an interrupted run
+    results: usize,
+    empty: bool,
+}
+
+#[cfg(not(test))]
+static ARRAY: Mutex<Option<Array>> = Mutex::new(None);
+
+// Tests run in parallel and each reads its own captured stdout, so each gets its own array
+#[cfg(test)]
+thread_local! {
+    static ARRAY: Mutex<Option<Array>> = const { Mutex::new(None) };
+}
+
+#[cfg(not(test))]
+fn with_array<R>(f: impl FnOnce(&mut Option<Array>) -> R) -> R {
+    f(&mut ARRAY.lock().unwrap())
+}
+
+#[cfg(test)]
+fn with_array<R>(f: impl FnOnce(&mut Option<Array>) -> R) -> R {
+    ARRAY.with(|array| f(&mut array.lock().unwrap()))
+}
+
+/// Print "[" and make later records its elements, indented with `pretty` (--pretty-json)
+pub fn open(pretty: bool) {
+    with_array(|array| {
+        out!("[");
+        *array = Some(Array { pretty, results: 0, empty: true });
+    });
+    #[cfg(not(test))]
+    tokio::spawn(async {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            let results = with_array(|array| array.as_ref().map_or(0, |array| array.results));
+            close(Some(incomplete("interrupted", results)));
+            // 128 + SIGINT, as if the signal had not been caught
+            std::process::exit(130);
+        }
+    });
+}
+
+/// Write one JSON record: an element of the open array, else an NDJSON line
+pub fn record(value: &Value) {
+    with_array(|array| match array {
+        Some(array) => write_element(array, value),
+        None => outln!("{}", value),
+    });
+}
+
+/// Write one host result; counted for the record that closes an interrupted array
+pub fn result(value: &Value) {
+    with_array(|array| match array {
+        Some(array) => {
+            array.results += 1;
+            write_element(array, value);
+        }
+        None => outln!("{}", value),
+    });
+}
+
+fn write_element(array: &mut Array, value: &Value) {
+    let separator = if array.empty { "\n" } else { ",\n" };
+    array.empty = false;
+    match array.pretty {
+        true => {
+            let text = serde_json::to_string_pretty(value).unwrap_or_default();
+            let indented: Vec<String> = text.lines().map(|line| format!("  {}", line)).collect();
+            out!("{}{}", separator, indented.join("\n"));
+        }
+        false => out!("{}{}", separator, value),
+    }
+}
+
+/// Print `last` as the final element, if any, and "]"; nothing when no array is open
+pub fn close(last: Option<Value>) {
+    with_array(|array| {
+        if let Some(mut open) = array.take() {
+            if let Some(ref last) = last {
+                write_element(&mut open, last);
+            }
+            outln!("{}]", if open.empty { "" } else { "\n" });
+        }
+    });
+}
+
+/// Close the array with the error that ended the run
+pub fn fail(error: &anyhow::Error) {
+    close(Some(json!({ "error": { "message": format!("{:#}", error) } })));
+}
+
+/// The record closing a run that did not finish: why, and how many host results came before it
+pub fn incomplete(reason: &str, results: usize) -> Value {
+    json!({ "incomplete": { "reason": reason, "results": results } })
+}
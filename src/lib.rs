@@ -0,0 +1,2704 @@
+// [lib.rs] - KRUST - Pure SSH Command Executor
+use anyhow::{anyhow, Context, Result};
+use clap::error::ErrorKind;
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use std::time::{Duration, Instant};
+use tokio::time::timeout;
+use tracing::{info, debug, error, warn};
+use tracing::{info_span, Instrument};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::Arc;
+use futures::stream::StreamExt;
+use std::io::{stdout, IsTerminal, Read};
+
+#[macro_use]
+mod output;
+
+mod aggregate;
+mod assertions;
+mod bench;
+mod certificate;
+mod completions;
+mod config;
+mod confirm;
+mod encoding;
+mod escalation;
+mod executor;
+mod facts_cache;
+mod failures;
+mod format;
+mod history;
+mod host_error;
+mod inventory;
+mod json_array;
+mod limiter;
+mod lock;
+mod modules;
+mod order;
+mod output_filter;
+mod persist;
+mod precheck;
+mod proxy;
+mod proxy_command;
+mod query;
+mod redact;
+mod sort;
+mod reboot;
+mod resolver;
+mod retry;
+mod secret;
+#[cfg(feature = "serve")]
+mod serve;
+mod shell;
+mod spill;
+mod ssh_executor;
+mod stream;
+mod timings;
+mod watch;
+mod where_clause;
+
+#[cfg(test)]
+mod test_support;
+#[cfg(test)]
+mod tests;
+
+use crate::aggregate::{Aggregate, AggregateOp};
+use crate::assertions::Assertions;
+use crate::bench::{Bench, Iteration, Latency};
+use crate::config::Config;
+use crate::encoding::{Decoded, OutputEncoding, StdoutLines};
+use crate::escalation::{BecomeMethod, Escalation};
+use crate::executor::{CommandExecutor, SshExecutor, Transport};
+use crate::facts_cache::FactsCache;
+use crate::format::ResultFormat;
+use crate::history::{HistoryAction, HistoryArgs, RunRecord};
+use crate::inventory::{HostVars, Inventory};
+use crate::limiter::{ConcurrencyScope, ConnectRate, GroupLimits, Limiter, SetupSlot};
+use crate::output_filter::{MatchMode, OutputFilter};
+use crate::query::Query;
+use crate::proxy::Proxy;
+use crate::proxy_command::ProxyCommand;
+use crate::redact::Redactor;
+use crate::resolver::Resolver;
+use crate::retry::{Attempt, RetryExit};
+use crate::secret::Secret;
+use crate::shell::RemoteShell;
+use crate::sort::SortKey;
+use crate::modules::contract;
+use crate::modules::{Module, ModulesAction, ModulesArgs};
+use crate::order::HostOrder;
+use crate::output::EXIT_BROKEN_PIPE;
+use crate::ssh_executor::{Connection, ExecOutput, HostAuth, MethodPrefs, SshAuth, SshDetails, SshHost};
+use crate::timings::{PhaseClock, Started, Timings};
+
+pub use crate::host_error::{HostError, HostErrorKind, LocalErrorKind};
+pub use crate::modules::collect_facts::Facts;
+pub use crate::modules::contract::ModuleResult;
+pub use crate::ssh_executor::ExecErrorKind;
+pub use crate::stream::{Progress, Run};
+pub use tokio_util::sync::CancellationToken;
+
+/// Exit code when the run was cut short (e.g. by --deadline) rather than completed
+pub const EXIT_ABORTED: i32 = 3;
+
+/// Exit code when a second Ctrl-C quits without waiting, as a shell reports SIGINT
+const EXIT_INTERRUPTED: i32 = 130;
+
+/// --timeout when neither the user nor the module sets one
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long hosts already running may continue once --deadline has passed
+const DEADLINE_GRACE: Duration = Duration::from_secs(5);
+
+/// The group reboot-wait --per-group puts hosts in no inventory group in
+const UNGROUPED: &str = "ungrouped";
+
+#[derive(Parser, Debug)]
+#[command(name = "krust", version, about = "Pure parallel SSH command executor")]
+#[command(subcommand_negates_reqs = true)]
+pub struct Cli {
+    /// SSH username (defaults to current user)
+    #[arg(short, long, global = true)]
+    pub user: Option<String>,
+    
+    /// SSH password (use --ask-pass for interactive); hidden from `ps` once parsed
+    #[arg(short, long, value_parser = secret::parse, global = true)]
+    pub password: Option<Secret>,
+    
+    /// Prompt for password interactively
+    #[arg(long, global = true)]
+    pub ask_pass: bool,
+    
+    /// Run commands and module scripts as root through --become-method
+    #[arg(long = "become", global = true)]
+    pub become_root: bool,
+    
+    /// How --become gets root
+    #[arg(long, value_enum, default_value = "sudo", requires = "become_root", global = true)]
+    pub become_method: BecomeMethod,
+    
+    /// Prompt for the --become password (the user's for sudo, root's for su)
+    #[arg(long, requires = "become_root", global = true)]
+    pub ask_become_pass: bool,
+    
+    /// Path to SSH private key; repeat to try several keys in order
+    #[arg(short = 'k', long, global = true)]
+    pub private_key: Vec<String>,
+    
+    /// OpenSSH certificate for the --private-key at the same position (default: a sibling KEY-cert.pub)
+    #[arg(long, requires = "private_key", global = true)]
+    pub certificate: Vec<String>,
+    
+    /// Target hosts (comma-separated)
+    #[arg(short, long = "hosts", value_delimiter = ',', global = true)]
+    pub target_hosts: Vec<String>,
+    
+    /// Read hosts from inventory file
+    #[arg(short, long, global = true)]
+    pub inventory: Option<String>,
+    
+    /// Warn about invalid inventory lines and run the valid hosts, instead of stopping
+    #[arg(long, global = true)]
+    pub skip_invalid_hosts: bool,
+    
+    /// Run this local command through your shell and read hosts from its output, in inventory format
+    #[arg(long, value_name = "COMMAND", global = true)]
+    pub hosts_from_cmd: Option<String>,
+    
+    /// Print the hosts that would be run and exit, with any command override; with -v, also where each one was defined
+    #[arg(long, global = true)]
+    pub list_hosts: bool,
+    
+    /// Run this command on one host instead of the run's command, e.g. 'web07:systemctl restart nginx-custom'
+    /// (repeatable; wins over the inventory's [command_overrides])
+    #[arg(long = "override", value_name = "HOST:COMMAND", value_parser = parse_command_override, global = true)]
+    pub overrides: Vec<(String, String)>,
+    
+    /// Only inventory hosts that carry all of these tags (comma-separated)
+    #[arg(long, value_delimiter = ',', global = true)]
+    pub tags: Vec<String>,
+    
+    /// Only inventory hosts that carry at least one of these tags (comma-separated)
+    #[arg(long, value_delimiter = ',', global = true)]
+    pub any_tag: Vec<String>,
+    
+    /// Facts saved by `collect-facts --save-cache`, for --where and {fact} placeholders in the command
+    #[arg(long, value_name = "FILE", global = true)]
+    pub facts_cache: Option<String>,
+    
+    /// Only hosts whose cached facts match, e.g. 'os_name == "Ubuntu" && memory_total_gb < 8'
+    #[arg(long = "where", value_name = "EXPR", value_parser = where_clause::parse, requires = "facts_cache", global = true)]
+    pub where_clause: Option<where_clause::Expr>,
+    
+    /// Warn when cached facts of selected hosts are older than this
+    #[arg(long, default_value = "24h", value_parser = parse_duration, global = true)]
+    pub facts_max_age: Duration,
+    
+    /// Maximum concurrent connections
+    #[arg(short, long, default_value_t = 10, global = true)]
+    pub concurrency: usize,
+    
+    /// Command timeout (e.g., 30s, 5m, 1h) [default: 30s, 10s for ping]
+    #[arg(long, value_parser = parse_duration, global = true)]
+    pub timeout: Option<Duration>,
+    
+    /// Number of retries for failed connections
+    #[arg(long, default_value_t = 3, global = true)]
+    pub retries: u8,
+    
+    /// Also retry commands exiting with these codes (comma-separated, e.g. 75,100), within --retries
+    #[arg(long, value_name = "CODES", value_delimiter = ',', value_parser = clap::value_parser!(u8).range(1..),
+          conflicts_with_all = ["retry_on_nonzero", "bench"], global = true)]
+    pub retry_on_exit_code: Vec<u8>,
+    
+    /// Also retry commands exiting non-zero, within --retries; only for commands safe to run twice
+    #[arg(long, conflicts_with = "bench", global = true)]
+    pub retry_on_nonzero: bool,
+    
+    /// Output as NDJSON (one line per host)
+    #[arg(long, global = true)]
+    pub json: bool,
+    
+    /// Output as pretty-printed JSON
+    #[arg(long, conflicts_with = "json", global = true)]
+    pub pretty_json: bool,
+    
+    /// Stream results as the elements of one JSON array, closed even when the run is cut short
+    /// (indented with --pretty-json)
+    #[arg(long, conflicts_with_all = ["json", "watch"], global = true)]
+    pub json_array: bool,
+    
+    /// Select output fields (comma-separated: hostname,success,stdout,stderr,error,exit_code,duration_ms)
+    #[arg(long, value_delimiter = ',', global = true)]
+    pub fields: Option<Vec<String>>,
+    
+    /// Also write krust's own error messages into stderr, as before the error field (deprecated)
+    #[arg(long, global = true)]
+    pub legacy_stderr: bool,
+    
+    /// JMESPath expression applied to each --json or --json-array record, or to the --pretty-json array
+    #[arg(long, value_parser = query::parse_query, global = true)]
+    pub query: Option<Query>,
+    
+    /// Order in which hosts are started (duplicates are always dropped)
+    #[arg(long, value_enum, default_value = "sorted", global = true)]
+    pub order: HostOrder,
+    
+    /// Seed for --order shuffle, to repeat a previous run's order
+    #[arg(long, global = true)]
+    pub seed: Option<u64>,
+    
+    /// Hosts to start before all others, in this order (comma-separated), e.g. canaries
+    #[arg(long, value_delimiter = ',', global = true)]
+    pub prefer: Vec<String>,
+    
+    /// Order of collected output (--pretty-json, tables); streamed output stays in completion order
+    #[arg(long, value_enum, default_value = "hostname", global = true)]
+    pub sort: SortKey,
+    
+    /// Print one line per host from a template, e.g. '{hostname},{exit_code},{duration_ms}'
+    #[arg(long, value_parser = ResultFormat::parse, conflicts_with_all = ["json", "pretty_json", "json_array", "watch", "aggregate"], global = true)]
+    pub format: Option<ResultFormat>,
+    
+    /// Enable verbose logging
+    #[arg(short, long, global = true)]
+    pub verbose: bool,
+    
+    /// Log format; json writes one event per line to stderr, with host and attempt from the span
+    #[arg(long, value_enum, default_value = "text", global = true)]
+    pub log_format: LogFormat,
+    
+    /// Disable color output (auto-detected for pipes)
+    #[arg(long, global = true)]
+    pub no_color: bool,
+    
+    /// Only show hosts with a stdout line matching this regex
+    #[arg(long = "match", value_name = "REGEX", global = true)]
+    pub match_regex: Option<String>,
+    
+    /// Hide hosts with a stdout line matching this regex
+    #[arg(long = "no-match", value_name = "REGEX", global = true)]
+    pub no_match_regex: Option<String>,
+    
+    /// In JSON output, drop non-matching hosts or tag every host with "matched"
+    #[arg(long, value_enum, default_value = "filter", global = true)]
+    pub match_mode: MatchMode,
+    
+    /// Fail hosts whose command exits with a different code (replaces the default of 0)
+    #[arg(long, allow_hyphen_values = true, global = true)]
+    pub expect_exit_code: Option<i32>,
+    
+    /// Fail hosts whose stdout does not match this regex
+    #[arg(long, global = true)]
+    pub expect_stdout_regex: Option<String>,
+    
+    /// Fail hosts whose stdout differs from this file
+    #[arg(long, global = true)]
+    pub expect_stdout_equals_file: Option<String>,
+    
+    /// Parse each host's stdout as a number and combine them (e.g. sum of free disk)
+    #[arg(long, value_enum, conflicts_with_all = ["first_success", "watch"], global = true)]
+    pub aggregate: Option<AggregateOp>,
+    
+    /// Show the hosts and command and require typing "yes" before running
+    #[arg(long, global = true)]
+    pub confirm: bool,
+    
+    /// Never ask for confirmation (overrides --confirm and confirm_threshold)
+    #[arg(long, global = true)]
+    pub yes: bool,
+    
+    /// Config file (default: $KRUST_CONFIG or ~/.config/krust/config.toml)
+    #[arg(long, global = true)]
+    pub config: Option<String>,
+    
+    /// End --json output with a {"summary": ...} record that groups failed hosts by cause
+    #[arg(long, global = true)]
+    pub json_summary: bool,
+    
+    /// Do not record this run in the local history (e.g. for commands with secrets)
+    #[arg(long, global = true)]
+    pub no_history: bool,
+    
+    /// ID of this run in every JSON record, the logs and the history, e.g. deploy-2024-06-01
+    /// [default: generated]
+    #[arg(long, value_name = "ID", value_parser = parse_run_id, global = true)]
+    pub run_id: Option<String>,
+    
+    /// Label the run with KEY=VALUE wherever its ID appears (repeatable), e.g. ticket=OPS-1234
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_annotation, global = true)]
+    pub annotate: Vec<(String, String)>,
+    
+    /// Refuse to overlap with another krust run on the same inventory or hosts (default for modules)
+    #[arg(long, conflicts_with = "no_lock", global = true)]
+    pub lock: bool,
+    
+    /// Do not take the run lock, even for modules
+    #[arg(long, global = true)]
+    pub no_lock: bool,
+    
+    /// How long to wait for another run to release the lock
+    #[arg(long, default_value = "30s", value_parser = parse_duration, global = true)]
+    pub lock_wait: Duration,
+    
+    /// Start at min(concurrency, 20), grow on success and halve when connection failures spike
+    #[arg(long, global = true)]
+    pub adaptive_concurrency: bool,
+    
+    /// What --concurrency limits: whole hosts, or only connecting and authenticating so slow commands
+    /// run side by side (bounded by --max-sessions)
+    #[arg(long, value_enum, default_value = "all", global = true)]
+    pub concurrency_scope: ConcurrencyScope,
+    
+    /// Most hosts in progress at once, whatever --concurrency-scope; unlimited by default
+    #[arg(long, global = true)]
+    pub max_sessions: Option<usize>,
+    
+    /// Per-group limits under --concurrency (e.g. db=1,web=20); overrides the inventory's max_concurrency
+    #[arg(long, value_delimiter = ',', value_parser = limiter::parse_group_limit, global = true)]
+    pub group_concurrency: Vec<(String, usize)>,
+    
+    /// Limit how fast new connections are opened (e.g. 10/s, 300/m), independent of --concurrency
+    #[arg(long, value_parser = limiter::parse_rate, global = true)]
+    pub connect_rate: Option<ConnectRate>,
+    
+    /// Wall-clock budget for the whole run (e.g. 5m); unfinished hosts are reported and the run exits 3
+    #[arg(long, value_parser = parse_duration, global = true)]
+    pub deadline: Option<Duration>,
+    
+    /// Skip hosts that do not answer a quick TCP connect, before any SSH is attempted
+    #[arg(long, global = true)]
+    pub pre_check: bool,
+    
+    /// Timeout for the --pre-check TCP probe
+    #[arg(long, default_value = "2s", value_parser = parse_duration, requires = "pre_check", global = true)]
+    pub pre_check_timeout: Duration,
+    
+    /// Use this address for a hostname instead of DNS (HOST=IP, repeatable), e.g. before DNS is updated
+    #[arg(long, value_name = "HOST=IP", value_parser = resolver::parse_override, global = true)]
+    pub resolve: Vec<(String, IpAddr)>,
+    
+    /// Reach hosts through a proxy: socks5://[user:pass@]host:port, socks5h:// (the proxy resolves names) or http:// (CONNECT)
+    #[arg(long, value_parser = proxy::parse, conflicts_with = "pre_check", global = true)]
+    pub proxy: Option<Proxy>,
+    
+    /// Reach each host through a local command speaking SSH on its stdin/stdout, like ssh's ProxyCommand;
+    /// {host} and {port} are filled in per host, e.g. 'aws ssm start-session --target {host} ...'
+    #[arg(long, value_parser = proxy_command::parse, conflicts_with_all = ["proxy", "pre_check"], global = true)]
+    pub proxy_command: Option<ProxyCommand>,
+    
+    /// Compress the SSH transport (zlib), e.g. for large outputs over slow links; ignored by servers without it
+    #[arg(short = 'C', long, global = true)]
+    pub compress: bool,
+    
+    /// Pin SSH algorithms in preference order, e.g. kex=curve25519-sha256,cipher=aes256-gcm@openssh.com,aes256-ctr
+    /// (kex, hostkey, cipher, mac, compression; repeatable)
+    #[arg(long, value_name = "NAME=ALGORITHMS", value_parser = ssh_executor::parse_ssh_option, global = true)]
+    pub ssh_option: Vec<MethodPrefs>,
+    
+    /// Keep authenticated sessions in a local daemon for this long after last use (e.g. 10m) and
+    /// reuse them in later --persist runs; `krust persist-stop` closes them
+    #[arg(long, value_parser = parse_duration, conflicts_with_all = ["proxy", "proxy_command", "compress", "ssh_option"], global = true)]
+    pub persist: Option<Duration>,
+    
+    /// Resolve every host first and run once per address, e.g. when web01 and web01.example.com are listed
+    #[arg(long, global = true)]
+    pub dedup_by_ip: bool,
+    
+    /// Keep host names as written; by default they are lowercased, as DNS ignores case
+    #[arg(long, global = true)]
+    pub keep_host_case: bool,
+    
+    /// Print run statistics (wall time, effective concurrency) after the summary
+    #[arg(long, global = true)]
+    pub stats: bool,
+    
+    /// Stop as soon as any host succeeds; fail only if every host fails
+    #[arg(long, global = true)]
+    pub first_success: bool,
+    
+    /// Re-run the command on every host at this interval, redrawing a status table (e.g. 10s)
+    #[arg(long, value_parser = parse_duration, conflicts_with_all = ["first_success", "pretty_json"], global = true)]
+    pub watch: Option<Duration>,
+    
+    /// With --watch, stop once every host succeeds in the same iteration
+    #[arg(long, requires = "watch", global = true)]
+    pub until_success: bool,
+    
+    /// With --watch, stop as soon as any host fails
+    #[arg(long, requires = "watch", global = true)]
+    pub until_fail: bool,
+    
+    /// With --watch, stop after this many iterations
+    #[arg(long, requires = "watch", global = true)]
+    pub iterations: Option<u64>,
+    
+    /// Run the command N times per host over one connection and report min/avg/p95/max latency
+    #[arg(long, value_name = "N", value_parser = parse_bench_runs, conflicts_with_all = ["watch", "first_success"], global = true)]
+    pub bench: Option<usize>,
+    
+    /// With --bench, untimed runs before the measured ones
+    #[arg(long, value_name = "N", default_value_t = 0, requires = "bench", global = true)]
+    pub bench_warmup: usize,
+    
+    /// Send local standard input to the command on every host
+    #[arg(long, conflicts_with = "stdin_file", global = true)]
+    pub stdin: bool,
+    
+    /// Send this file's contents to the command's stdin on every host
+    #[arg(long, global = true)]
+    pub stdin_file: Option<String>,
+    
+    /// Refuse --stdin/--stdin-file input larger than this (e.g. 512K, 10M)
+    #[arg(long, default_value = "10M", value_parser = parse_size, global = true)]
+    pub max_stdin_bytes: u64,
+    
+    /// Keep at most this much host output in memory (e.g. 1G); later output goes to temp files (stdout_file)
+    #[arg(long, value_parser = parse_size, global = true)]
+    pub max_total_output: Option<u64>,
+    
+    /// Leave the --max-total-output spill files in place when krust exits
+    #[arg(long, requires = "max_total_output", global = true)]
+    pub keep_spill: bool,
+    
+    /// Command run on each host before the main command (e.g. a drain); failure skips the host
+    #[arg(long, global = true)]
+    pub pre_cmd: Option<String>,
+    
+    /// Run --pre-cmd on this machine instead of the host (KRUST_HOST, KRUST_ADDRESS and KRUST_PORT are set)
+    #[arg(long, requires = "pre_cmd", global = true)]
+    pub pre_local: bool,
+    
+    /// Command run on each host after the main command succeeded (e.g. an undrain)
+    #[arg(long, global = true)]
+    pub post_cmd: Option<String>,
+    
+    /// Run --post-cmd on this machine instead of the host (KRUST_HOST, KRUST_ADDRESS and KRUST_PORT are set)
+    #[arg(long, requires = "post_cmd", global = true)]
+    pub post_local: bool,
+    
+    /// How output bytes become stdout: utf8-lossy flags replaced bytes with non_utf8, utf8-strict
+    /// fails the host, base64 puts the exact bytes in stdout_b64, latin1 maps each byte to a character
+    #[arg(long, value_enum, default_value_t = OutputEncoding::Utf8Lossy, global = true)]
+    pub output_encoding: OutputEncoding,
+    
+    /// Remove terminal escape sequences (colors, cursor movement, titles) from the output
+    #[arg(long, global = true)]
+    pub strip_ansi: bool,
+    
+    /// Replace matches of REGEX in output with ***REDACTED*** before any result is shown or saved (repeatable)
+    #[arg(long, value_name = "REGEX", global = true)]
+    pub redact: Vec<regex::Regex>,
+    
+    /// When results include stdout_lines: auto (multi-line output only), always, never
+    #[arg(long, value_enum, default_value_t = StdoutLines::Auto, global = true)]
+    pub stdout_lines: StdoutLines,
+    
+    /// Keep trailing whitespace and newlines in stdout, e.g. to compare file contents exactly
+    #[arg(long, global = true)]
+    pub no_trim: bool,
+    
+    /// Report the remote command's PID as remote_pid, for a later `krust kill --from-journal`
+    #[arg(long, global = true)]
+    pub capture_pid: bool,
+    
+    /// Shell that runs the command on the hosts; powershell and cmd are for Windows OpenSSH
+    #[arg(long = "shell", id = "remote_shell", value_enum, default_value_t = RemoteShell::Auto, global = true)]
+    pub shell: RemoteShell,
+    
+    /// Command text to run as it is, newlines and spacing included; '-' reads it from standard input
+    #[arg(long = "command", value_name = "TEXT", conflicts_with = "command_file", global = true)]
+    pub command_text: Option<String>,
+    
+    /// Run the contents of this file as the command, as they are
+    #[arg(long, value_name = "FILE", global = true)]
+    pub command_file: Option<String>,
+    
+    /// Shell-quote each command argument instead of joining them with spaces, so the host
+    /// receives exactly the arguments given
+    #[arg(long, conflicts_with_all = ["command_text", "command_file"], global = true)]
+    pub quote_args: bool,
+    
+    /// Command to execute on remote hosts
+    #[arg(required_unless_present_any = ["list_hosts", "command_text", "command_file"], trailing_var_arg = true)]
+    pub command: Vec<String>,
+    
+    /// Built-in module to run instead of a raw command
+    #[command(subcommand)]
+    pub subcommand: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Run a command on the hosts: `krust run [flags] -- CMD`, the same as `krust [flags] CMD`
+    Run(RunArgs),
+    /// List past runs, show their per-host results, or run one again
+    History(HistoryArgs),
+    /// Inspect the built-in modules: `krust module show <name> [args]` prints a module's script
+    #[command(name = "module")]
+    Modules(ModulesArgs),
+    /// Print a shell completion script (bash, zsh, fish, ...)
+    Completions {
+        shell: clap_complete::Shell,
+    },
+    /// Print inventory hostnames for shell completion
+    #[command(name = "__complete-hosts", hide = true)]
+    CompleteHosts,
+    /// Accept runs over HTTP: POST /runs, then GET /runs/{id} and /runs/{id}/summary
+    #[cfg(feature = "serve")]
+    Serve(serve::ServeArgs),
+    /// Close the sessions kept by --persist and stop its daemon
+    PersistStop,
+    /// The --persist daemon itself, started on demand
+    #[command(name = "__persist-daemon", hide = true)]
+    PersistDaemon,
+    #[command(flatten)]
+    Module(Module),
+}
+
+#[derive(Args, Debug)]
+pub struct RunArgs {
+    /// Command to execute on remote hosts (required unless --command, --command-file or --list-hosts)
+    #[arg(trailing_var_arg = true)]
+    pub command: Vec<String>,
+}
+
+impl Cli {
+    /// Parse the command line. `krust run [flags] -- CMD` is folded into the bare
+    /// `krust [flags] CMD` form, so the rest of krust only ever sees one of them.
+    pub fn try_parse_args<I, T>(argv: I) -> Result<Self, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone,
+    {
+        let argv: Vec<std::ffi::OsString> = argv.into_iter().map(Into::into).collect();
+        let mut cli = match Cli::try_parse_from(&argv) {
+            Ok(cli) => cli,
+            // `krust [flags] ping -c1 8.8.8.8` is the remote ping when the ping module cannot take it
+            Err(e) => match bare_form(&argv) {
+                Some(bare) => Cli::try_parse_from(bare).map_err(|_| e)?,
+                None => return Err(e),
+            },
+        };
+        match cli.subcommand.take() {
+            Some(Command::Run(run)) => cli.command = run.command,
+            other => cli.subcommand = other,
+        }
+        // Checked here rather than by clap: the command arguments may sit under `run`, and
+        // shared flags given before `run` are only seen by clap after it validated `run`
+        let given = cli.command_text.is_some() || cli.command_file.is_some() || cli.list_hosts;
+        if cli.subcommand.is_none() && cli.command.is_empty() && !given {
+            return Err(Cli::command().error(ErrorKind::MissingRequiredArgument, "a command to run is required"));
+        }
+        let flag = match (&cli.command_text, &cli.command_file) {
+            (Some(_), _) => "--command",
+            (None, Some(_)) => "--command-file",
+            (None, None) => return Ok(cli),
+        };
+        match cli.command.is_empty() {
+            true => Ok(cli),
+            false => Err(Cli::command().error(ErrorKind::ArgumentConflict, format!("{} cannot be used with command arguments", flag))),
+        }
+    }
+
+    pub fn module(&self) -> Option<&Module> {
+        match self.subcommand {
+            Some(Command::Module(ref module)) => Some(module),
+            _ => None,
+        }
+    }
+    
+    /// Results are printed as JSON (--json, --pretty-json or --json-array)
+    pub fn json_output(&self) -> bool {
+        self.json || self.pretty_json || self.json_array
+    }
+}
+
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.to_lowercase();
+    let num_part = s.trim_end_matches(char::is_alphabetic);
+    let value = num_part.parse::<u64>()
+        .map_err(|_| format!("Invalid duration: {}", s))?;
+    
+    if s.ends_with("ms") {
+        return Ok(Duration::from_millis(value));
+    }
+    
+    match &s[num_part.len()..] {
+        "s" | "" => Ok(Duration::from_secs(value)),
+        "m" => Ok(Duration::from_secs(value * 60)),
+        "h" => Ok(Duration::from_secs(value * 3600)),
+        _ => Err(format!("Invalid duration: {} (use ms, s, m or h)", s)),
+    }
+}
+
+/// `argv` with the command made explicit (`krust [flags] run -- CMD`) when its first free word
+/// names a module: the form clap is given again once the module's own parse failed
+fn bare_form(argv: &[std::ffi::OsString]) -> Option<Vec<std::ffi::OsString>> {
+    let at = first_free_word(argv)?;
+    if !Module::has_subcommand(argv[at].to_str()?) {
+        return None;
+    }
+    let mut bare = argv[..at].to_vec();
+    bare.extend(["run".into(), "--".into()]);
+    bare.extend_from_slice(&argv[at..]);
+    Some(bare)
+}
+
+/// Whether the krust flag picked by `is` takes a value
+fn flag_takes_value(is: impl Fn(&clap::Arg) -> bool) -> bool {
+    Cli::command().get_arguments().any(|arg| is(arg) && arg.get_action().takes_values())
+}
+
+/// Index of the first argument that is neither a flag nor a flag's value
+fn first_free_word(argv: &[std::ffi::OsString]) -> Option<usize> {
+    let mut i = 1;
+    while i < argv.len() {
+        let word = argv[i].to_str()?;
+        if word == "--" {
+            return None;
+        }
+        if let Some(long) = word.strip_prefix("--") {
+            if !long.contains('=') && flag_takes_value(|arg| arg.get_long() == Some(long)) {
+                i += 1;
+            }
+        } else if let Some(shorts) = word.strip_prefix('-').filter(|shorts| !shorts.is_empty()) {
+            // The first short flag taking a value ends the cluster: its value is the rest, or the next word
+            let value_at = shorts.char_indices().find(|&(_, c)| flag_takes_value(|arg| arg.get_short() == Some(c)));
+            if let Some((at, c)) = value_at {
+                if at + c.len_utf8() == shorts.len() {
+                    i += 1;
+                }
+            }
+        } else {
+            return Some(i);
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Run IDs name the history journal file, so only characters that are safe there
+fn parse_run_id(s: &str) -> Result<String, String> {
+    let safe = s.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'));
+    match !s.is_empty() && s.len() <= 128 && safe && !s.starts_with(['.', '-']) {
+        true => Ok(s.to_string()),
+        false => Err(format!("Invalid run ID: {} (use letters, digits, '.', '-' and '_')", s)),
+    }
+}
+
+fn parse_annotation(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => Ok((key.trim().to_string(), value.to_string())),
+        _ => Err(format!("Invalid annotation: {} (expected KEY=VALUE)", s)),
+    }
+}
+
+fn parse_command_override(s: &str) -> Result<(String, String), String> {
+    match s.split_once(':') {
+        Some((host, command)) if !host.trim().is_empty() && !command.trim().is_empty() => {
+            Ok((host.trim().to_string(), command.trim().to_string()))
+        }
+        _ => Err(format!("Invalid override: {} (expected HOST:COMMAND)", s)),
+    }
+}
+
+fn parse_bench_runs(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(0) | Err(_) => Err(format!("Invalid run count: {} (expected a positive number)", s)),
+        Ok(runs) => Ok(runs),
+    }
+}
+
+fn parse_size(s: &str) -> Result<u64, String> {
+    let upper = s.trim().to_uppercase();
+    let upper = upper.strip_suffix('B').unwrap_or(&upper);
+    let (number, multiplier) = match upper.chars().last() {
+        Some('K') => (&upper[..upper.len() - 1], 1024),
+        Some('M') => (&upper[..upper.len() - 1], 1024 * 1024),
+        Some('G') => (&upper[..upper.len() - 1], 1024 * 1024 * 1024),
+        _ => (upper, 1),
+    };
+    
+    number.parse::<u64>()
+        .map(|value| value * multiplier)
+        .map_err(|_| format!("Invalid size: {} (use bytes or K, M, G)", s))
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct HostResult {
+    hostname: String,
+    /// `hostname[:port]`: what tells apart one name listed on several ports, for files, keys and
+    /// consumers; hostname is for display
+    target: String,
+    /// The run this result belongs to (--run-id, or generated)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    run_id: Option<String>,
+    /// Connection target, for --fields address; hostname is the display name
+    #[serde(skip)]
+    address: String,
+    /// Inventory tags, so results can be grouped downstream
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tags: Option<Vec<String>>,
+    /// --annotate labels of the run
+    #[serde(skip_serializing_if = "Option::is_none")]
+    annotations: Option<BTreeMap<String, String>>,
+    /// Where the host was defined, e.g. "inventory:prod.txt:12"; absent for --hosts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+    /// The command this host ran, when --override or the inventory replaced the run's command
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command: Option<String>,
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stdout: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stdout_lines: Option<Vec<String>>,
+    /// --output-encoding base64: the exact output bytes, in place of stdout
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stdout_b64: Option<String>,
+    /// Set when invalid UTF-8 in the output was replaced, so stdout is not what the host sent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    non_utf8: Option<bool>,
+    /// Past --max-total-output: the file holding the output, in place of stdout
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stdout_file: Option<String>,
+    /// What the command wrote to stderr on the host, read apart from stdout (the first 1 MiB);
+    /// krust's own failures go to `error`, and only --legacy-stderr appends them here
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stderr: Option<String>,
+    /// Why the host failed on krust's side: connection, timeout, decoding, a module step, ...
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<HostError>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exit_code: Option<i32>,
+    /// --capture-pid: PID of the command on the host
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remote_pid: Option<u32>,
+    /// When the host's run started
+    timestamp: DateTime<Utc>,
+    /// When it ended: `timestamp` plus `duration_ms`, which is measured on a monotonic clock
+    finished_at: DateTime<Utc>,
+    duration_ms: u64,
+    /// Size of the output as received, before decoding or trimming
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes_stdout: Option<u64>,
+    /// Size of what the host wrote to stderr, counted past the part kept in `stderr`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes_stderr: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reboot_required: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    assertion_error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    matched: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    post_error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    iteration: Option<u64>,
+    /// Stage at which the connection failed (unreachable, connect, auth, ...)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_kind: Option<ExecErrorKind>,
+    /// Time spent resolving the hostname, summed over attempts (near zero when cached)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resolve_ms: Option<u64>,
+    /// Time spent authenticating, including any wait for the SSH agent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    auth_ms: Option<u64>,
+    /// Authentication method that worked: "publickey:PATH", "certificate:PATH", "agent:COMMENT" or "password"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    auth_method: Option<String>,
+    /// Per-phase times of the last attempt, for --fields timings and --stats
+    #[serde(skip)]
+    timings: Option<Timings>,
+    /// Negotiated algorithms, with -C or --ssh-option
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ssh_details: Option<SshDetails>,
+    /// Each attempt, when the host took more than one (--retries)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    attempts: Option<Vec<Attempt>>,
+    /// Retried at the end-of-run prompt: whether the second attempt succeeded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recovered: Option<bool>,
+    /// --bench: each measured run, warm-up runs excluded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    iterations: Option<Vec<Iteration>>,
+    /// --bench: latency of the measured runs in milliseconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bench: Option<Latency>,
+    /// Status, data and metrics a module reported through KRUST:: marker lines
+    #[serde(skip_serializing_if = "Option::is_none")]
+    module_result: Option<ModuleResult>,
+    /// Structured fields added by a module's post-processing, emitted inline in JSON output
+    #[serde(flatten)]
+    module_fields: serde_json::Map<String, serde_json::Value>,
+}
+
+impl HostResult {
+    /// The display name: an inventory alias, or the address as given
+    pub fn hostname(&self) -> &str {
+        &self.hostname
+    }
+
+    /// `hostname[:port]`, which no other host in the run shares
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    pub fn success(&self) -> bool {
+        self.success
+    }
+
+    /// The output as decoded; None when it went to `stdout_file` or `stdout_b64`, or the host never ran
+    pub fn stdout(&self) -> Option<&str> {
+        self.stdout.as_deref()
+    }
+
+    /// What the command wrote to stderr on the host; None when it wrote nothing
+    pub fn stderr(&self) -> Option<&str> {
+        self.stderr.as_deref()
+    }
+
+    /// None when the command never exited: the host failed to connect, timed out or was skipped
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    /// Why the host failed on krust's side, apart from what the command printed
+    pub fn error(&self) -> Option<&HostError> {
+        self.error.as_ref()
+    }
+
+    pub fn duration(&self) -> Duration {
+        Duration::from_millis(self.duration_ms)
+    }
+
+    /// What a module reported through its marker lines
+    pub fn module_result(&self) -> Option<&ModuleResult> {
+        self.module_result.as_ref()
+    }
+
+    /// The facts collect-facts gathered from this host
+    pub fn facts(&self) -> Option<Facts> {
+        let facts = self.module_result.as_ref()?.fact("facts")?;
+        serde_json::from_value(facts.clone()).ok()
+    }
+
+    /// Replace stdout once the run is done with it, keeping stdout_lines in step
+    fn set_stdout(&mut self, text: String) {
+        if self.stdout_lines.is_some() {
+            self.stdout_lines = Some(text.lines().map(str::to_string).collect());
+        }
+        self.stdout = Some(text);
+    }
+
+    /// Fail the host for a reason of krust's own
+    fn fail(&mut self, error: HostError) {
+        self.success = false;
+        self.error = Some(error);
+    }
+
+    /// krust's message for a failed host; the command's own stderr is never taken for it
+    fn error_message(&self) -> Option<&str> {
+        self.error.as_ref().map(|error| error.message.as_str())
+    }
+
+    /// Mask secrets everywhere output ended up, before any sink sees the result
+    fn redact(&mut self, redactor: &Redactor) {
+        let error = self.error.as_mut().map(|error| &mut error.message);
+        let texts = [self.stdout.as_mut(), self.stderr.as_mut(), self.assertion_error.as_mut(), self.post_error.as_mut(), error];
+        for text in texts.into_iter().flatten() {
+            redactor.string(text);
+        }
+        for line in self.stdout_lines.iter_mut().flatten() {
+            redactor.string(line);
+        }
+        if let Some(ref mut encoded) = self.stdout_b64 {
+            use base64::Engine;
+            let engine = base64::engine::general_purpose::STANDARD;
+            if let Ok(mut raw) = engine.decode(&*encoded) {
+                redactor.bytes(&mut raw);
+                *encoded = engine.encode(raw);
+            }
+        }
+        if let Some(ref mut module_result) = self.module_result {
+            module_result.data.values_mut().for_each(|value| redactor.value(value));
+        }
+        self.module_fields.values_mut().for_each(|value| redactor.value(value));
+    }
+    
+    fn deadline_exceeded(host: SshHost) -> Self {
+        HostResult::not_started(host, HostError::exec(ExecErrorKind::Deadline, "Timed out by --deadline", None))
+    }
+    
+    fn not_started(host: SshHost, error: HostError) -> Self {
+        let error_kind = match error.kind {
+            host_error::HostErrorKind::Exec(kind) => Some(kind),
+            host_error::HostErrorKind::Local(_) => None,
+        };
+        let now = Utc::now();
+        HostResult {
+            address: host.target(),
+            source: host.source.recorded(),
+            command: None,
+            target: host.to_string(),
+            hostname: host.hostname,
+            tags: None,
+            run_id: None,
+            annotations: None,
+            success: false,
+            stdout: None,
+            stdout_lines: None,
+            stderr: None,
+            error: Some(error),
+            exit_code: None,
+            remote_pid: None,
+            timestamp: now,
+            finished_at: now,
+            duration_ms: 0,
+            bytes_stdout: None,
+            bytes_stderr: None,
+            reboot_required: None,
+            assertion_error: None,
+            matched: None,
+            post_error: None,
+            iteration: None,
+            error_kind,
+            resolve_ms: None,
+            auth_ms: None,
+            auth_method: None,
+            timings: None,
+            ssh_details: None,
+            attempts: None,
+            recovered: None,
+            iterations: None,
+            bench: None,
+            stdout_b64: None,
+            non_utf8: None,
+            stdout_file: None,
+            module_result: None,
+            module_fields: serde_json::Map::new(),
+        }
+    }
+    
+    /// Hidden from output by --match/--no-match
+    fn is_filtered(&self) -> bool {
+        self.matched == Some(false)
+    }
+    
+    fn filter_fields(&self, fields: &[String]) -> serde_json::Value {
+        let mut map = serde_json::Map::new();
+        
+        for field in fields {
+            match field.as_str() {
+                "hostname" | "host" => {
+                    map.insert("hostname".to_string(), serde_json::json!(self.hostname));
+                }
+                "target" => {
+                    map.insert("target".to_string(), serde_json::json!(self.target));
+                }
+                "address" => {
+                    map.insert("address".to_string(), serde_json::json!(self.address));
+                }
+                "remote_pid" => {
+                    if let Some(pid) = self.remote_pid {
+                        map.insert("remote_pid".to_string(), serde_json::json!(pid));
+                    }
+                }
+                "source" => {
+                    if let Some(ref source) = self.source {
+                        map.insert("source".to_string(), serde_json::json!(source));
+                    }
+                }
+                "command" => {
+                    if let Some(ref command) = self.command {
+                        map.insert("command".to_string(), serde_json::json!(command));
+                    }
+                }
+                "run_id" => {
+                    if let Some(ref run_id) = self.run_id {
+                        map.insert("run_id".to_string(), serde_json::json!(run_id));
+                    }
+                }
+                "annotations" => {
+                    if let Some(ref annotations) = self.annotations {
+                        map.insert("annotations".to_string(), serde_json::json!(annotations));
+                    }
+                }
+                "tags" => {
+                    if let Some(ref tags) = self.tags {
+                        map.insert("tags".to_string(), serde_json::json!(tags));
+                    }
+                }
+                "success" => {
+                    map.insert("success".to_string(), serde_json::json!(self.success));
+                }
+                "stdout" => {
+                    if let Some(ref stdout) = self.stdout {
+                        map.insert("stdout".to_string(), serde_json::json!(stdout));
+                    }
+                }
+                "stdout_lines" => {
+                    if let Some(ref lines) = self.stdout_lines {
+                        map.insert("stdout_lines".to_string(), serde_json::json!(lines));
+                    }
+                }
+                "stdout_b64" => {
+                    if let Some(ref encoded) = self.stdout_b64 {
+                        map.insert("stdout_b64".to_string(), serde_json::json!(encoded));
+                    }
+                }
+                "non_utf8" => {
+                    if let Some(non_utf8) = self.non_utf8 {
+                        map.insert("non_utf8".to_string(), serde_json::json!(non_utf8));
+                    }
+                }
+                "stdout_file" => {
+                    if let Some(ref path) = self.stdout_file {
+                        map.insert("stdout_file".to_string(), serde_json::json!(path));
+                    }
+                }
+                "bytes_stdout" => {
+                    if let Some(bytes) = self.bytes_stdout {
+                        map.insert("bytes_stdout".to_string(), serde_json::json!(bytes));
+                    }
+                }
+                "bytes_stderr" => {
+                    if let Some(bytes) = self.bytes_stderr {
+                        map.insert("bytes_stderr".to_string(), serde_json::json!(bytes));
+                    }
+                }
+                "stderr" => {
+                    if let Some(ref stderr) = self.stderr {
+                        map.insert("stderr".to_string(), serde_json::json!(stderr));
+                    }
+                }
+                "error" => {
+                    if let Some(ref error) = self.error {
+                        map.insert("error".to_string(), serde_json::json!(error));
+                    }
+                }
+                "exit_code" => {
+                    if let Some(code) = self.exit_code {
+                        map.insert("exit_code".to_string(), serde_json::json!(code));
+                    }
+                }
+                "timestamp" => {
+                    map.insert("timestamp".to_string(), serde_json::json!(self.timestamp));
+                }
+                "finished_at" => {
+                    map.insert("finished_at".to_string(), serde_json::json!(self.finished_at));
+                }
+                "duration_ms" | "duration" => {
+                    map.insert("duration_ms".to_string(), serde_json::json!(self.duration_ms));
+                }
+                "reboot_required" => {
+                    if let Some(required) = self.reboot_required {
+                        map.insert("reboot_required".to_string(), serde_json::json!(required));
+                    }
+                }
+                "iteration" => {
+                    if let Some(iteration) = self.iteration {
+                        map.insert("iteration".to_string(), serde_json::json!(iteration));
+                    }
+                }
+                "assertion_error" => {
+                    if let Some(ref error) = self.assertion_error {
+                        map.insert("assertion_error".to_string(), serde_json::json!(error));
+                    }
+                }
+                "matched" => {
+                    if let Some(matched) = self.matched {
+                        map.insert("matched".to_string(), serde_json::json!(matched));
+                    }
+                }
+                "error_kind" => {
+                    if let Some(kind) = self.error_kind {
+                        map.insert("error_kind".to_string(), serde_json::json!(kind));
+                    }
+                }
+                "resolve_ms" => {
+                    if let Some(ms) = self.resolve_ms {
+                        map.insert("resolve_ms".to_string(), serde_json::json!(ms));
+                    }
+                }
+                "auth_ms" => {
+                    if let Some(ms) = self.auth_ms {
+                        map.insert("auth_ms".to_string(), serde_json::json!(ms));
+                    }
+                }
+                "auth_method" => {
+                    if let Some(ref method) = self.auth_method {
+                        map.insert("auth_method".to_string(), serde_json::json!(method));
+                    }
+                }
+                "timings" => {
+                    if let Some(ref timings) = self.timings {
+                        map.insert("timings".to_string(), serde_json::json!(timings));
+                    }
+                }
+                "ssh_details" => {
+                    if let Some(ref details) = self.ssh_details {
+                        map.insert("ssh_details".to_string(), serde_json::json!(details));
+                    }
+                }
+                "attempts" => {
+                    if let Some(ref attempts) = self.attempts {
+                        map.insert("attempts".to_string(), serde_json::json!(attempts));
+                    }
+                }
+                "recovered" => {
+                    if let Some(recovered) = self.recovered {
+                        map.insert("recovered".to_string(), serde_json::json!(recovered));
+                    }
+                }
+                "module_result" => {
+                    if let Some(ref module_result) = self.module_result {
+                        map.insert("module_result".to_string(), serde_json::json!(module_result));
+                    }
+                }
+                "iterations" => {
+                    if let Some(ref iterations) = self.iterations {
+                        map.insert("iterations".to_string(), serde_json::json!(iterations));
+                    }
+                }
+                "bench" => {
+                    if let Some(latency) = self.bench {
+                        map.insert("bench".to_string(), serde_json::json!(latency));
+                    }
+                }
+                "post_error" => {
+                    if let Some(ref error) = self.post_error {
+                        map.insert("post_error".to_string(), serde_json::json!(error));
+                    }
+                }
+                other => {
+                    if let Some(value) = self.module_fields.get(other) {
+                        map.insert(other.to_string(), value.clone());
+                    }
+                }
+            }
+        }
+        
+        serde_json::Value::Object(map)
+    }
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+fn setup_logging(args: &Cli) {
+    let json_results = args.json_output();
+    let json_logs = args.log_format == LogFormat::Json;
+    
+    // Text logs in JSON result mode are limited to errors, and --query's warnings since it only
+    // applies there; JSON logs were asked for explicitly
+    let filter = if json_results && !json_logs {
+        "error,krust::query=warn"
+    } else if args.verbose {
+        "debug"
+    } else {
+        "info"
+    };
+    
+    // Logs never share stdout with JSON results
+    let writer = if json_results || json_logs {
+        BoxMakeWriter::new(std::io::stderr)
+    } else {
+        BoxMakeWriter::new(std::io::stdout)
+    };
+    
+    let (text_layer, json_layer) = if json_logs {
+        let json_layer = tracing_subscriber::fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .with_writer(writer);
+        (None, Some(json_layer))
+    } else {
+        let fmt_layer = tracing_subscriber::fmt::layer()
+            .with_target(false)
+            .compact()
+            .with_writer(writer);
+        
+        // Apply color settings
+        let fmt_layer = if args.no_color || !stdout().is_terminal() || json_results {
+            fmt_layer.with_ansi(false)
+        } else {
+            fmt_layer.with_ansi(true)
+        };
+        (Some(fmt_layer), None)
+    };
+    
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::EnvFilter::new(filter))
+        .with(text_layer)
+        .with(json_layer)
+        .init();
+}
+
+/// How each host's command is run and its output kept
+#[derive(Debug, Clone, Copy)]
+struct ExecSettings {
+    timeout: Duration,
+    retries: u8,
+    /// Exit codes retried like connection failures; none by default
+    retry_exit: RetryExit,
+    bench: Option<Bench>,
+    encoding: OutputEncoding,
+    strip_ansi: bool,
+    stdout_lines: StdoutLines,
+    trim: bool,
+    /// Modules report through KRUST:: lines, taken out of stdout into module_result
+    module_markers: bool,
+    /// --capture-pid: the first output line carries the PID
+    capture_pid: bool,
+}
+
+impl ExecSettings {
+    fn from_args(args: &Cli) -> Self {
+        ExecSettings {
+            timeout: args.timeout
+                .or_else(|| args.module().and_then(Module::default_timeout))
+                .unwrap_or(DEFAULT_TIMEOUT),
+            retries: args.retries,
+            retry_exit: RetryExit::new(&args.retry_on_exit_code, args.retry_on_nonzero),
+            bench: Bench::from_args(args),
+            encoding: args.output_encoding,
+            strip_ansi: args.strip_ansi,
+            stdout_lines: args.stdout_lines,
+            trim: !args.no_trim,
+            module_markers: args.module().is_some(),
+            capture_pid: args.capture_pid,
+        }
+    }
+    
+    /// A single run with text output, e.g. for hooks
+    fn plain(timeout: Duration, retries: u8) -> Self {
+        ExecSettings {
+            timeout,
+            retries,
+            retry_exit: RetryExit::default(),
+            bench: None,
+            encoding: OutputEncoding::default(),
+            strip_ansi: false,
+            stdout_lines: StdoutLines::default(),
+            trim: true,
+            module_markers: false,
+            capture_pid: false,
+        }
+    }
+}
+
+/// `setup` is released by the SSH thread once authenticated, under --concurrency-scope connect
+async fn execute_with_retries<E: CommandExecutor>(
+    transport: &Arc<Transport<E>>,
+    host: SshHost,
+    command: String,
+    input: Option<Arc<Vec<u8>>>,
+    settings: ExecSettings,
+    setup: SetupSlot,
+) -> HostResult {
+    let start = Started::now();
+    let ExecSettings { timeout: timeout_duration, retries: max_retries, bench, encoding, .. } = settings;
+    // --timeout applies to each run of a benchmark
+    let timeout_duration = match bench {
+        Some(bench) => timeout_duration * bench.total_runs() as u32,
+        None => timeout_duration,
+    };
+    let mut last_error: Option<HostError> = None;
+    let mut error_kind = None;
+    let mut remote_pid = None;
+    let mut resolve_ms = 0;
+    let mut timings = Timings::default();
+    let mut attempts = Vec::new();
+    
+    for attempt in 0..=max_retries {
+        if attempt > 0 {
+            debug!("Retry {}/{} for {}", attempt, max_retries, host.hostname);
+            tokio::time::sleep(Duration::from_millis(500 * attempt as u64)).await;
+        }
+        
+        // Everything logged for this attempt, including on the blocking SSH thread, carries host and attempt
+        let span = info_span!("attempt", host = %host.hostname, attempt = attempt + 1);
+        let resolve_start = Instant::now();
+        let resolved = transport.resolver.resolve(&host).instrument(span.clone()).await;
+        resolve_ms += resolve_start.elapsed().as_millis() as u64;
+        timings = Timings { resolve_ms: Some(resolve_ms), ..Timings::default() };
+        let addrs = match resolved {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                last_error = Some(HostError::exec(e.kind, e.to_string(), Some(attempt as u32 + 1)));
+                error_kind = Some(e.kind);
+                attempts.push(Attempt::failed(error_kind, &e.to_string(), resolve_start.elapsed().as_millis() as u64));
+                if !e.kind.is_connection() {
+                    span.in_scope(|| error!("Non-retryable error for {}: {}", host.hostname, e));
+                    break;
+                }
+                continue;
+            }
+        };
+        
+        let clock = Arc::new(PhaseClock::default());
+        let outcome = timeout(
+            timeout_duration,
+            tokio::task::spawn_blocking({
+                let host = host.clone();
+                let transport = Arc::clone(transport);
+                let cmd = command.clone();
+                let input = input.clone();
+                let span = span.clone();
+                let clock = Arc::clone(&clock);
+                let setup = setup.clone();
+                move || {
+                    let _entered = span.enter();
+                    let _phases = clock.enter();
+                    let _setup = setup.enter();
+                    let auth = transport.auth.for_host(&host);
+                    let input = input.as_deref().map(Vec::as_slice);
+                    match bench {
+                        Some(bench) => transport.executor.execute_repeated(&host, addrs, &auth, &cmd, input, bench.total_runs()),
+                        None => transport.executor.execute(&host, addrs, &auth, &cmd, input).map(|output| (output, Vec::new())),
+                    }
+                }
+            })
+        ).instrument(span.clone()).await;
+        // Read even after a timeout: the phase the thread was stuck in has its time so far
+        timings = Timings { resolve_ms: Some(resolve_ms), ..clock.snapshot() };
+        match outcome {
+            Ok(Ok(Ok((ExecOutput { output, spilled, stderr, bytes_stderr, exit_code, auth_ms, auth_method, ssh_details, .. }, runs)))) => {
+                let duration_ms = start.elapsed_ms();
+                let iterations = bench.map(|bench| bench.measured(&runs));
+                // A benchmark fails if any measured run failed
+                let exit_code = iterations.iter().flatten()
+                    .map(|iteration| iteration.exit_code)
+                    .find(|&code| code != 0)
+                    .unwrap_or(exit_code);
+                if settings.retry_exit.matches(exit_code) && attempt < max_retries {
+                    span.in_scope(|| warn!("{} exited {}, retrying", host.hostname, exit_code));
+                    attempts.push(Attempt::exited(exit_code, resolve_start.elapsed().as_millis() as u64));
+                    spill::budget().discard(spill::Captured { output, spilled });
+                    continue;
+                }
+                if !attempts.is_empty() {
+                    attempts.push(Attempt::exited(exit_code, resolve_start.elapsed().as_millis() as u64));
+                }
+                let latency = iterations.as_deref()
+                    .and_then(|iterations| Latency::of(iterations.iter().map(|iteration| iteration.duration_ms)));
+                let (output, remote_pid) = match settings.capture_pid {
+                    true => shell::take_pid(output),
+                    false => (output, None),
+                };
+                let bytes_stdout = spilled.as_ref().map_or(output.len() as u64, |spilled| spilled.bytes);
+                // Spilled output stays in its file; stdout_file points there instead
+                let (mut decoded, decode_error) = match spilled {
+                    Some(_) => (Decoded::default(), None),
+                    None => match encoding.decode(&output, settings.trim) {
+                        Ok(decoded) => (decoded, None),
+                        Err(e) => (Decoded::default(), Some(e)),
+                    },
+                };
+                drop(output);
+                if settings.strip_ansi {
+                    decoded.text = decoded.text.map(|text| encoding::strip_ansi(&text));
+                }
+                let module_result = match settings.module_markers {
+                    true => decoded.text.as_mut().and_then(contract::extract),
+                    false => None,
+                };
+                let stdout_lines = decoded.text.as_deref().and_then(|text| settings.stdout_lines.split(text));
+                let stderr = String::from_utf8_lossy(&stderr).trim_end().to_string();
+                
+                return HostResult {
+                    address: host.target(),
+                    source: host.source.recorded(),
+                    command: None,
+                    target: host.to_string(),
+                    hostname: host.hostname,
+                    tags: None,
+                    run_id: None,
+                    annotations: None,
+                    success: exit_code == 0 && decode_error.is_none(),
+                    stdout: decoded.text,
+                    stdout_lines,
+                    bytes_stderr: (bytes_stderr > 0).then_some(bytes_stderr),
+                    stderr: (!stderr.is_empty()).then_some(stderr),
+                    error: decode_error.map(|e| HostError::local(LocalErrorKind::Decode, e, Some(attempt as u32 + 1))),
+                    exit_code: Some(exit_code),
+                    remote_pid,
+                    timestamp: start.at,
+                    finished_at: start.finished_at(duration_ms),
+                    duration_ms,
+                    bytes_stdout: Some(bytes_stdout),
+                    reboot_required: None,
+                    assertion_error: None,
+                    matched: None,
+                    post_error: None,
+                    iteration: None,
+                    error_kind: None,
+                    resolve_ms: Some(resolve_ms),
+                    auth_ms: Some(auth_ms),
+                    auth_method: Some(auth_method),
+                    timings: Some(timings),
+                    ssh_details,
+                    attempts: (!attempts.is_empty()).then_some(attempts),
+                    recovered: None,
+                    iterations,
+                    bench: latency,
+                    stdout_b64: decoded.base64,
+                    non_utf8: decoded.non_utf8.then_some(true),
+                    stdout_file: spilled.map(|spilled| spilled.path.display().to_string()),
+                    module_result,
+                    module_fields: serde_json::Map::new(),
+                };
+            }
+            Ok(Ok(Err(e))) => {
+                last_error = Some(HostError::exec(e.kind, e.to_string(), Some(attempt as u32 + 1)));
+                error_kind = Some(e.kind);
+                attempts.push(Attempt::failed(error_kind, &e.to_string(), resolve_start.elapsed().as_millis() as u64));
+                // Only retry failures that happened before the command could have started
+                if !e.kind.is_connection() {
+                    span.in_scope(|| error!("Non-retryable error for {}: {}", host.hostname, e));
+                    break;
+                }
+            }
+            Ok(Err(e)) => {
+                last_error = Some(HostError::local(LocalErrorKind::Panic, format!("Task panic: {}", e), Some(attempt as u32 + 1)));
+                break;
+            }
+            Err(_) => {
+                last_error = Some(HostError::local(LocalErrorKind::Timeout, "Command timeout", Some(attempt as u32 + 1)));
+                error_kind = None;
+                attempts.push(Attempt::failed(None, "Command timeout", resolve_start.elapsed().as_millis() as u64));
+                clock.stop_proxy();
+                clock.abandon();
+                // Left running on the host; `krust kill --from-journal` can still reach it
+                if settings.capture_pid {
+                    remote_pid = clock.remote_pid();
+                }
+            }
+        }
+    }
+    
+    let duration_ms = start.elapsed_ms();
+    HostResult {
+        address: host.target(),
+        source: host.source.recorded(),
+        command: None,
+        target: host.to_string(),
+        hostname: host.hostname,
+        tags: None,
+        run_id: None,
+        annotations: None,
+        success: false,
+        stdout: None,
+        stdout_lines: None,
+        bytes_stderr: None,
+        stderr: None,
+        error: last_error,
+        exit_code: None,
+        remote_pid,
+        timestamp: start.at,
+        finished_at: start.finished_at(duration_ms),
+        duration_ms,
+        bytes_stdout: None,
+        reboot_required: None,
+        assertion_error: None,
+        matched: None,
+        post_error: None,
+        iteration: None,
+        error_kind,
+        resolve_ms: Some(resolve_ms),
+        auth_ms: None,
+        auth_method: None,
+        timings: Some(timings),
+        ssh_details: None,
+        // A single failed attempt says no more than the result itself
+        attempts: (attempts.len() > 1).then_some(attempts),
+        recovered: None,
+        iterations: None,
+        bench: None,
+        stdout_b64: None,
+        non_utf8: None,
+        stdout_file: None,
+        module_result: None,
+        module_fields: serde_json::Map::new(),
+    }
+}
+
+/// A --pre-cmd/--post-cmd hook and where it runs
+#[derive(Clone)]
+struct Hook {
+    command: String,
+    local: bool,
+}
+
+impl Hook {
+    fn new(command: &Option<String>, local: bool, shell: RemoteShell) -> Option<Self> {
+        command.as_ref().map(|command| Hook {
+            command: if local { command.clone() } else { shell.wrap(command) },
+            local,
+        })
+    }
+    
+    /// Run the hook for one host; the error carries the hook's output
+    async fn run<E: CommandExecutor>(
+        &self,
+        transport: &Arc<Transport<E>>,
+        host: &SshHost,
+        timeout_duration: Duration,
+        retries: u8,
+    ) -> Result<(), String> {
+        if self.local {
+            let child = tokio::process::Command::new("sh")
+                .arg("-c")
+                .arg(&self.command)
+                .env("KRUST_HOST", &host.hostname)
+                .env("KRUST_ADDRESS", &host.address)
+                .env("KRUST_PORT", host.port.to_string())
+                .kill_on_drop(true)
+                .output();
+            return match timeout(timeout_duration, child).await {
+                Ok(Ok(output)) if output.status.success() => Ok(()),
+                Ok(Ok(output)) => Err(format!(
+                    "{}: {}",
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim_end()
+                )),
+                Ok(Err(e)) => Err(e.to_string()),
+                Err(_) => Err("Command timeout".to_string()),
+            };
+        }
+        
+        let result = execute_with_retries(
+            transport,
+            host.clone(),
+            self.command.clone(),
+            None,
+            ExecSettings::plain(timeout_duration, retries),
+            SetupSlot::default(),
+        ).await;
+        if result.success {
+            return Ok(());
+        }
+        Err(match (result.exit_code, result.error, result.stdout) {
+            (Some(code), _, stdout) => {
+                let said: Vec<String> = [stdout, result.stderr].into_iter().flatten().filter(|text| !text.is_empty()).collect();
+                format!("exit code {}: {}", code, said.join("\n"))
+            }
+            (None, error, _) => error.map_or_else(|| "Unknown error".to_string(), |error| error.message),
+        })
+    }
+}
+
+/// Redaction, assertions and output filters, built from the CLI before any host is contacted
+pub struct ResultChecks {
+    redactor: Option<Redactor>,
+    assertions: Option<Assertions>,
+    filter: Option<OutputFilter>,
+    /// Inventory tags by `hostname[:port]`, copied onto each result
+    tags: HashMap<String, Vec<String>>,
+    /// Overridden commands by hostname, recorded on each result as run
+    commands: HashMap<String, String>,
+    run_id: Option<String>,
+    annotations: BTreeMap<String, String>,
+    /// --legacy-stderr: append error.message to stderr for consumers not reading error yet
+    legacy_stderr: bool,
+}
+
+impl ResultChecks {
+    /// Runs first, so module parsing, assertions and every output see the masked text
+    fn redact(&self, result: &mut HostResult) {
+        if let Some(ref redactor) = self.redactor {
+            result.redact(redactor);
+        }
+    }
+    
+    fn apply(&self, result: &mut HostResult) {
+        result.run_id = self.run_id.clone();
+        result.annotations = (!self.annotations.is_empty()).then(|| self.annotations.clone());
+        result.tags = self.tags.get(&result.target).cloned();
+        result.command = self.commands.get(&result.hostname).cloned();
+        if let (Some(ref redactor), Some(ref mut command)) = (&self.redactor, &mut result.command) {
+            redactor.string(command);
+        }
+        if let (true, Some(error)) = (self.legacy_stderr, &result.error) {
+            result.stderr = Some(match result.stderr.take() {
+                Some(stderr) => format!("{}\n{}", stderr, error.message),
+                None => error.message.clone(),
+            });
+        }
+        if let Some(ref assertions) = self.assertions {
+            assertions.check(result);
+        }
+        if let Some(ref filter) = self.filter {
+            result.matched = Some(filter.matches(result));
+        }
+    }
+    
+    /// A {"summary": ...} record, carrying the run's ID and labels like each host record
+    fn summary(&self, mut summary: serde_json::Value) -> serde_json::Value {
+        summary["run_id"] = serde_json::json!(self.run_id);
+        if !self.annotations.is_empty() {
+            summary["annotations"] = serde_json::json!(self.annotations);
+        }
+        serde_json::json!({ "summary": summary })
+    }
+    
+    /// JSON output keeps non-matching hosts, tagged with "matched": false
+    fn annotates(&self) -> bool {
+        self.filter.as_ref().is_some_and(|f| f.mode == MatchMode::Annotate)
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn run_parallel<E: CommandExecutor>(
+    jobs: Vec<(SshHost, String)>,
+    transport: Arc<Transport<E>>,
+    input: Option<Arc<Vec<u8>>>,
+    limiter: Arc<Limiter>,
+    args: &Cli,
+    checks: &ResultChecks,
+    iteration: Option<u64>,
+    cancel: &CancellationToken,
+) -> (Vec<HostResult>, i32) {
+    let total_hosts = jobs.len();
+    let use_json = args.json_output();
+    let use_color = !args.no_color && stdout().is_terminal() && !use_json;
+    // Watch mode and --aggregate print their own tables instead of streaming text results
+    let quiet_text = (iteration.is_some() || args.aggregate.is_some()) && !use_json;
+    let mut stream = std::pin::pin!(stream::results(jobs, transport, input, Arc::clone(&limiter), args, checks, cancel));
+    
+    // Track results for summary
+    let mut results = Vec::with_capacity(total_hosts);
+    let mut progress = Progress::new(total_hosts);
+    
+    // Clear line for progress updates
+    if !use_json && !quiet_text {
+        eprint!("\r\x1b[K");
+    }
+    
+    while let Some(mut result) = stream.next().await {
+        result.iteration = iteration;
+        progress.record(&result);
+        
+        if result.is_filtered() && !checks.annotates() {
+            // Hidden by --match/--no-match, still counted above
+        } else if args.json || args.json_array {
+            // Stream NDJSON (or array elements) immediately
+            let output = if let Some(ref fields) = args.fields {
+                result.filter_fields(fields)
+            } else {
+                serde_json::to_value(&result).unwrap()
+            };
+            let output = match args.query {
+                Some(ref query) => query::apply_to_record(query, output),
+                None => Some(output),
+            };
+            
+            if let Some(output) = output {
+                json_array::result(&output);
+            }
+        } else if args.pretty_json || quiet_text {
+            // Collect for pretty printing (or the watch table) later
+        } else {
+            // Stream text output immediately
+            match args.format {
+                Some(ref format) => outln!("{}", format.render(&result)),
+                None => print_single_result(&result, use_color, checks.filter.as_ref()),
+            }
+            
+            // Update progress
+            if progress.completed < total_hosts && !output::stdout_closed() {
+                let bottleneck = limiter.bottleneck().map(|b| format!(", {}", b)).unwrap_or_default();
+                let groups = limiter.group_load().map(|load| format!(" [{}]", load)).unwrap_or_default();
+                let memory = spill::budget().describe().map(|memory| format!(", {}", memory)).unwrap_or_default();
+                output::progress(if use_color {
+                    format!("\r\x1b[K\x1b[90m[{}/{}] completed, {} failed{}{}{}\x1b[0m", 
+                            progress.completed, progress.total, progress.failed, bottleneck, memory, groups)
+                } else {
+                    format!("\r[{}/{}] completed, {} failed{}{}{}", 
+                            progress.completed, progress.total, progress.failed, bottleneck, memory, groups)
+                });
+            }
+        }
+        
+        let winner = args.first_success && result.success;
+        results.push(result);
+        if winner {
+            // Dropping the remaining futures abandons hosts that have not answered yet
+            break;
+        }
+        if output::stdout_closed() {
+            // Nobody is reading: abandon the remaining hosts the same way
+            debug!("stdout closed, abandoning {} hosts", total_hosts - progress.completed);
+            break;
+        }
+    }
+    
+    output::clear_progress();
+    if !use_json && !quiet_text {
+        eprintln!("\r\x1b[K"); // Clear progress line
+    }
+    
+    // Return results and exit code
+    let exit_code = if output::stdout_closed() {
+        EXIT_BROKEN_PIPE
+    } else if cancel.is_cancelled() || results.iter().any(|r| r.error_kind == Some(ExecErrorKind::Deadline)) {
+        EXIT_ABORTED
+    } else if args.first_success {
+        if results.iter().any(|r| r.success) { 0 } else { 1 }
+    } else if progress.failed > 0 {
+        1
+    } else {
+        0
+    };
+    (results, exit_code)
+}
+
+/// Built whole and written at once, so a host's block is never split by other output
+fn print_single_result(result: &HostResult, use_color: bool, filter: Option<&OutputFilter>) {
+    use std::fmt::Write as _;
+    let mut block = String::new();
+    if result.success {
+        if use_color {
+            let _ = write!(block, "\x1b[32m✓\x1b[0m \x1b[1m{}\x1b[0m ", result.hostname);
+        } else {
+            let _ = write!(block, "OK {} ", result.hostname);
+        }
+        
+        if use_color {
+            let _ = write!(block, "\x1b[90m({}ms)\x1b[0m", result.duration_ms);
+        } else {
+            let _ = write!(block, "({}ms)", result.duration_ms);
+        }
+        
+        if let Some(ref path) = result.stdout_file {
+            let size = spill::format_size(result.bytes_stdout.unwrap_or_default());
+            let _ = writeln!(block, ": {} of output in {}", size, path);
+        } else if let Some(ref output) = result.stdout {
+            let lines: Vec<&str> = output.lines().collect();
+            if lines.len() == 1 && lines[0].len() < 80 {
+                // Single short line - print inline
+                let _ = writeln!(block, ": {}", lines[0]);
+            } else if lines.is_empty() {
+                block.push('\n');
+            } else {
+                // Multi-line or long output
+                let _ = writeln!(block, ":");
+                for line in lines.iter().take(5) {
+                    if use_color && filter.is_some_and(|f| f.highlights(line)) {
+                        let _ = writeln!(block, "  \x1b[1;33m{}\x1b[0m", line);
+                    } else {
+                        let _ = writeln!(block, "  {}", line);
+                    }
+                }
+                if lines.len() > 5 {
+                    if use_color {
+                        let _ = writeln!(block, "  \x1b[90m... {} more lines\x1b[0m", lines.len() - 5);
+                    } else {
+                        let _ = writeln!(block, "  ... {} more lines", lines.len() - 5);
+                    }
+                }
+            }
+        } else {
+            block.push('\n');
+        }
+        
+        if let Some(ref stderr) = result.stderr {
+            write_stderr(&mut block, stderr, use_color);
+        }
+        
+        if let Some(ref err) = result.post_error {
+            if use_color {
+                let _ = writeln!(block, "  \x1b[33mpost-cmd failed: {}\x1b[0m", err);
+            } else {
+                let _ = writeln!(block, "  post-cmd failed: {}", err);
+            }
+        }
+    } else {
+        // Failed result
+        if use_color {
+            let _ = write!(block, "\x1b[31m✗\x1b[0m \x1b[1m{}\x1b[0m", result.hostname);
+        } else {
+            let _ = write!(block, "FAIL {} ", result.hostname);
+        }
+        
+        // A command that exited non-zero has no error of krust's; its exit code stands in
+        let error = match result.assertion_error {
+            Some(ref reason) => Some(format!("assertion failed: {}", reason)),
+            None => result.error_message().map(str::to_string)
+                .or_else(|| result.exit_code.map(|code| format!("exit code {}", code))),
+        };
+        if let Some(ref err) = error {
+            // Cut at a character, not a byte: output quoted in the message may be any UTF-8
+            let err_preview = match err.char_indices().nth(100) {
+                Some((end, _)) => format!("{}...", &err[..end]),
+                None => err.to_string(),
+            };
+            
+            if use_color {
+                let _ = writeln!(block, ": \x1b[31m{}\x1b[0m", err_preview);
+            } else {
+                let _ = writeln!(block, ": {}", err_preview);
+            }
+        } else {
+            let _ = writeln!(block, ": Unknown error");
+        }
+        if let Some(ref stderr) = result.stderr {
+            write_stderr(&mut block, stderr, use_color);
+        }
+    }
+    out!("{}", block);
+}
+
+/// The command's stderr under its host's block, the first lines of it like stdout
+fn write_stderr(block: &mut String, stderr: &str, use_color: bool) {
+    use std::fmt::Write as _;
+    let lines: Vec<&str> = stderr.lines().collect();
+    for line in lines.iter().take(5) {
+        if use_color {
+            let _ = writeln!(block, "  \x1b[33mstderr:\x1b[0m {}", line);
+        } else {
+            let _ = writeln!(block, "  stderr: {}", line);
+        }
+    }
+    if lines.len() > 5 {
+        let _ = writeln!(block, "  stderr: ... {} more lines", lines.len() - 5);
+    }
+}
+
+
+/// The krust command line; the binary is only this
+#[tokio::main]
+pub async fn main() -> Result<()> {
+    let args = Cli::try_parse_args(std::env::args_os()).unwrap_or_else(|e| e.exit());
+    if let Some(ref password) = args.password {
+        secret::scrub_argv(password);
+    }
+    setup_logging(&args);
+    
+    let exit_code = match args.subcommand {
+        Some(Command::History(ref history)) => run_history(history, &args).await.inspect_err(json_array::fail)?,
+        Some(Command::Modules(ModulesArgs { action: ModulesAction::Show { ref module } })) => {
+            out!("{}", module.script()?.text());
+            0
+        }
+        Some(Command::Completions { shell }) => {
+            completions::generate(shell)?;
+            0
+        }
+        Some(Command::CompleteHosts) => {
+            completions::print_hosts()?;
+            0
+        }
+        #[cfg(feature = "serve")]
+        Some(Command::Serve(ref serve)) => serve::run(serve, &args).await?,
+        Some(Command::PersistStop) => {
+            match persist::stop()? {
+                Some(sessions) => info!("Persist daemon stopped, closed {} session(s)", sessions),
+                None => info!("No persist daemon running"),
+            }
+            0
+        }
+        Some(Command::PersistDaemon) => {
+            persist::run_daemon()?;
+            0
+        }
+        _ => execute(args, std::env::args().skip(1).collect()).await.inspect_err(json_array::fail)?,
+    };
+    std::process::exit(if output::stdout_closed() { EXIT_BROKEN_PIPE } else { exit_code });
+}
+
+async fn run_history(history: &HistoryArgs, outer: &Cli) -> Result<i32> {
+    match history.action {
+        None => history::list(history.limit)?,
+        Some(HistoryAction::Show { ref id }) => history::show(id)?,
+        Some(HistoryAction::Rerun { ref id }) => {
+            let record = history::find(id)?;
+            info!("Re-running {}: {}", record.id, record.command);
+            let mut args = Cli::try_parse_args(std::iter::once("krust".to_string()).chain(record.args.iter().cloned()))
+                .with_context(|| format!("Cannot replay run {}", record.id))?;
+            // Passwords are never recorded; take them from `krust -p/--ask-pass history rerun`
+            args.password = args.password.take().or_else(|| outer.password.clone());
+            args.ask_pass |= outer.ask_pass;
+            // A rerun is a run of its own
+            args.run_id = None;
+            return execute(args, record.args).await;
+        }
+    }
+    Ok(0)
+}
+
+/// Replace the results of hosts run again after the retry prompt, marking whether each recovered
+fn merge_rerun(results: &mut [HostResult], rerun: Vec<HostResult>) {
+    for mut second in rerun {
+        second.recovered = Some(second.success);
+        if let Some(first) = results.iter_mut().find(|r| r.hostname == second.hostname && r.address == second.address) {
+            *first = second;
+        }
+    }
+}
+
+/// The command line sent to each host, with the --capture-pid wrapper when asked for
+fn remote_command(args: &Cli, command: &str) -> Result<String> {
+    match args.capture_pid {
+        true => Ok(args.shell.wrap(&args.shell.with_pid(command)?)),
+        false => Ok(args.shell.wrap(command)),
+    }
+}
+
+/// Ask for an encrypted key's passphrase on the terminal; None when there is none to ask on
+fn ask_passphrase(key: &std::path::Path) -> Result<Option<Secret>> {
+    if std::fs::OpenOptions::new().read(true).write(true).open("/dev/tty").is_err() {
+        return Ok(None);
+    }
+    let passphrase = rpassword::prompt_password(format!("Passphrase for {}: ", key.display()))?;
+    Ok(Some(Secret::new(passphrase)))
+}
+
+/// --list-hosts: the final host list and any command override, with where each host was defined under -v
+fn list_hosts(hosts: &[SshHost], overrides: &HashMap<String, String>, verbose: bool) {
+    let width = hosts.iter().map(|host| host.to_string().len()).max().unwrap_or(0);
+    for host in hosts {
+        let command = overrides.get(&host.hostname)
+            .map(|command| format!("  [override: {}]", command))
+            .unwrap_or_default();
+        match verbose {
+            true => outln!("{:<width$}  {}{}", host.to_string(), host.source, command),
+            false if command.is_empty() => outln!("{}", host),
+            false => outln!("{:<width$}{}", host.to_string(), command),
+        }
+    }
+}
+
+/// --where: keep the hosts whose cached facts match; hosts never collected are left out
+fn select_by_facts(hosts: Vec<SshHost>, facts: &FactsCache, expr: &where_clause::Expr) -> Result<Vec<SshHost>> {
+    if let Some(unknown) = expr.fields().into_iter().find(|name| !facts.knows(name)) {
+        return Err(anyhow!("--where: no cached host has a fact named '{}'", unknown));
+    }
+    let (known, uncached): (Vec<SshHost>, Vec<SshHost>) = hosts.into_iter()
+        .partition(|host| facts.hosts.contains_key(&host.to_string()));
+    if !uncached.is_empty() {
+        warn!("--where: skipping {} host(s) with no cached facts (e.g. {})", uncached.len(), uncached[0].hostname);
+    }
+    Ok(known.into_iter()
+        .filter(|host| facts.variables(&host.to_string()).is_some_and(|vars| expr.matches(&vars)))
+        .collect())
+}
+
+/// Run a command or module across the selected hosts and report; returns the exit code
+async fn execute(args: Cli, argv: Vec<String>) -> Result<i32> {
+    let started = Started::now();
+    // A --run-id already recorded is refused; a generated one taken within the same second gets a suffix
+    let run_id = match (args.run_id.clone(), args.no_history) {
+        (Some(id), false) if history::is_taken(&id)? => return Err(anyhow!("Run ID {} is already in the run history", id)),
+        (Some(id), _) => id,
+        (None, false) => history::unused_run_id(history::new_run_id(started.at)),
+        (None, true) => history::new_run_id(started.at),
+    };
+    // Every log line of the run, including those of the SSH threads, carries its ID
+    let span = info_span!("run", run_id = %run_id);
+    execute_run(args, argv, started, run_id).instrument(span).await
+}
+
+/// A run checked and built from the flags, before any host is contacted: what `krust` and
+/// embedding programs (`Run`) execute
+struct Prepared {
+    jobs: Vec<(SshHost, String)>,
+    transport: Arc<Transport<SshExecutor>>,
+    input: Option<Arc<Vec<u8>>>,
+    limiter: Arc<Limiter>,
+    checks: ResultChecks,
+    ssh_user: String,
+    inventory: Option<String>,
+    command_line: String,
+    concurrency: usize,
+    /// Inventory lines left out by --skip-invalid-hosts
+    invalid_hosts: usize,
+    _spill: spill::SpillGuard,
+    _lock: Option<lock::RunLock>,
+}
+
+/// Check the flags, load the hosts and build every job; None when --list-hosts printed them instead
+async fn prepare(args: &Cli, run_id: &str) -> Result<Option<Prepared>> {
+    let spill = spill::configure(args.max_total_output, args.keep_spill);
+    ssh_executor::keep_host_case(args.keep_host_case);
+    let config = Config::load(args.config.as_deref())?;
+    
+    // Get system username for default
+    let system_user = std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "root".to_string());
+    
+    let ssh_user = args.user.clone().unwrap_or_else(|| system_user.clone());
+    
+    if args.stdin && args.ask_pass && args.password.is_none() {
+        return Err(anyhow!("--stdin and --ask-pass both read from the terminal; use --stdin-file or key/agent authentication"));
+    }
+    
+    if args.stdin && args.ask_become_pass {
+        return Err(anyhow!("--stdin and --ask-become-pass both read from the terminal; use --stdin-file"));
+    }
+    
+    // Get password if needed
+    let password = if args.ask_pass && args.password.is_none() {
+        Some(Secret::new(rpassword::prompt_password("SSH password: ")?))
+    } else {
+        args.password.clone()
+    };
+    let escalation = match args.become_root {
+        true => {
+            let password = match args.ask_become_pass {
+                true => Some(Secret::new(rpassword::prompt_password("BECOME password: ")?)),
+                false => None,
+            };
+            Some(Escalation::new(args.become_method, password, args.stdin_file.is_some())?)
+        }
+        false => None,
+    };
+    
+    // Build auth
+    let mut auth = SshAuth::new(
+        ssh_user.clone(),
+        password.clone(),
+        args.private_key.clone(),
+        args.certificate.clone(),
+        true,
+    )?;
+    
+    // Parse hosts
+    let mut hosts = Vec::new();
+    
+    // From command line
+    for host_spec in &args.target_hosts {
+        hosts.push(SshHost::from_target(host_spec, None)?);
+    }
+    
+    // From inventory file, falling back to the config's inventory when no hosts were given
+    let no_hosts = args.target_hosts.is_empty() && args.hosts_from_cmd.is_none();
+    let inventory = match (&args.inventory, no_hosts) {
+        (Some(path), _) => Some(path.clone()),
+        (None, true) => config.inventory.clone(),
+        (None, false) => None,
+    };
+    let mut loaded = match inventory {
+        Some(ref path) => Inventory::load(path, args.skip_invalid_hosts)?,
+        None => Inventory::default(),
+    };
+    if let Some(ref command) = args.hosts_from_cmd {
+        let found = Inventory::from_command(command, args.skip_invalid_hosts)?;
+        loaded.hosts.extend(found.hosts);
+        loaded.invalid.extend(found.invalid);
+        loaded.group_concurrency.extend(found.group_concurrency);
+    }
+    for (group, limit) in &args.group_concurrency {
+        if !loaded.hosts.iter().any(|entry| entry.groups.contains(group)) {
+            return Err(anyhow!("--group-concurrency: no inventory group named '{}'", group));
+        }
+        loaded.group_concurrency.insert(group.clone(), *limit);
+    }
+    // Tags select among inventory hosts; hosts named with --hosts are always run
+    loaded.select_tags(&args.tags, &args.any_tag)?;
+    let tags: HashMap<String, Vec<String>> = loaded.hosts.iter()
+        .filter(|entry| !entry.tags.is_empty())
+        .map(|entry| (entry.host.to_string(), entry.tags.clone()))
+        .collect();
+    // Commands replacing the run's command, by hostname: the inventory's, then --override
+    let mut overrides: HashMap<String, String> = loaded.hosts.iter()
+        .filter_map(|entry| entry.command.clone().map(|command| (entry.host.hostname.clone(), command)))
+        .collect();
+    hosts.extend(loaded.hosts.iter().map(|entry| entry.host.clone()));
+    
+    // Fail here rather than on every host when no way to authenticate can work; listing needs none
+    let default_used = !args.target_hosts.is_empty()
+        || loaded.hosts.iter().any(|entry| entry.vars.is_empty())
+        || args.module().is_some_and(Module::brings_hosts);
+    if default_used && !args.list_hosts {
+        auth.check(ask_passphrase)?;
+    }
+    
+    // Inventory settings replace the command-line auth for their hosts; equal settings share one SshAuth
+    let mut host_auth = HostAuth::new(Arc::new(auth));
+    let mut built: HashMap<&HostVars, Arc<SshAuth>> = HashMap::new();
+    for entry in loaded.hosts.iter().filter(|entry| !entry.vars.is_empty()) {
+        let auth = match built.get(&entry.vars) {
+            Some(auth) => Arc::clone(auth),
+            None => {
+                let mut auth = entry.vars.auth(&ssh_user, password.as_ref(), &args.private_key, &args.certificate)
+                    .with_context(|| format!("Invalid settings for {}", entry.host.hostname))?;
+                if !args.list_hosts {
+                    auth.check(ask_passphrase).with_context(|| format!("Cannot authenticate to {}", entry.host.hostname))?;
+                }
+                let auth = Arc::new(auth);
+                built.insert(&entry.vars, Arc::clone(&auth));
+                auth
+            }
+        };
+        host_auth.set(entry.host.clone(), auth);
+    }
+    // A proxy command is handed the name, which often only means something at the other end
+    let resolver = match args.proxy {
+        Some(ref proxy) if proxy.resolves_remotely() => Resolver::new(&args.resolve).remote(),
+        _ if args.proxy_command.is_some() => Resolver::new(&args.resolve).remote(),
+        _ => Resolver::new(&args.resolve),
+    };
+    let transport = Arc::new(Transport {
+        executor: SshExecutor {
+            connection: Connection {
+                proxy: args.proxy.clone(),
+                proxy_command: args.proxy_command.clone(),
+                persist: args.persist,
+                compress: args.compress,
+                methods: args.ssh_option.iter().flat_map(|prefs| prefs.0.iter().cloned()).collect(),
+                escalation,
+            },
+        },
+        auth: host_auth,
+        resolver,
+    });
+    
+    let hosts = match args.dedup_by_ip {
+        true => transport.resolver.dedup_by_address(hosts).await,
+        false => hosts,
+    };
+    let facts = match args.facts_cache {
+        Some(ref path) => Some(FactsCache::load(path)?),
+        None => None,
+    };
+    let hosts = match (&facts, &args.where_clause) {
+        (Some(facts), Some(expr)) => select_by_facts(hosts, facts, expr)?,
+        _ => hosts,
+    };
+    if let Some(ref facts) = facts {
+        let targets: Vec<String> = hosts.iter().map(SshHost::to_string).collect();
+        facts.warn_stale(targets.iter().map(String::as_str), args.facts_max_age);
+    }
+    let hosts = order::order_hosts(hosts, args.order, args.seed, &args.prefer)?;
+    
+    if hosts.is_empty() && !args.module().is_some_and(Module::brings_hosts) {
+        return Err(anyhow!("No hosts specified"));
+    }
+    if args.module().is_some() {
+        if !args.overrides.is_empty() {
+            return Err(anyhow!("--override applies to commands, not modules"));
+        }
+        if !overrides.is_empty() {
+            debug!("Ignoring {} inventory command override(s) for a module run", overrides.len());
+            overrides.clear();
+        }
+    }
+    for (name, command) in &args.overrides {
+        let name = ssh_executor::normalize_name(name);
+        if !hosts.iter().any(|host| host.hostname == name) {
+            return Err(anyhow!("--override: no selected host is named '{}'", name));
+        }
+        overrides.insert(name, command.clone());
+    }
+    if args.list_hosts {
+        list_hosts(&hosts, &overrides, args.verbose);
+        return Ok(None);
+    }
+    
+    let command_line = match args.module() {
+        Some(_) if args.command_text.is_some() || args.command_file.is_some() => {
+            return Err(anyhow!("--command and --command-file apply to commands, not modules"));
+        }
+        Some(_) => String::new(),
+        None => command_text(args)?,
+    };
+    let input = read_input(args)?.map(Arc::new);
+    let become_password = transport.executor.connection.escalation.as_ref().and_then(|escalation| escalation.password.as_ref());
+    let passwords: Vec<&Secret> = transport.auth.passwords().chain(become_password).collect();
+    let mut checks = ResultChecks {
+        redactor: Redactor::new(&args.redact, &config.redact, &passwords)?,
+        assertions: Assertions::from_args(args)?,
+        filter: OutputFilter::from_args(args)?,
+        tags,
+        commands: HashMap::new(),
+        run_id: Some(run_id.to_string()),
+        annotations: args.annotate.iter().cloned().collect(),
+        legacy_stderr: args.legacy_stderr,
+    };
+    
+    if args.capture_pid && args.module().is_some() {
+        return Err(anyhow!("--capture-pid applies to commands, not modules"));
+    }
+    
+    // Modules validate their arguments locally before any host is contacted
+    let jobs = match args.module() {
+        Some(module) => {
+            args.shell.check_module(module)?;
+            let color = !args.no_color && stdout().is_terminal() && !args.json_output() && args.format.is_none();
+            let jobs = module.build_jobs(hosts, color)?;
+            // Every host's template is rendered before any SSH, so a missing variable fails the run
+            if let Some(source) = module.template_source() {
+                for (host, _) in &jobs {
+                    let tags = checks.tags.get(&host.to_string()).map_or(&[][..], Vec::as_slice);
+                    source.render(host, &transport.auth.for_host(host).user, tags)?;
+                }
+            }
+            jobs
+        }
+        // Placeholders are filled in before wrapping, which may encode the command
+        None => {
+            let template = command_line.clone();
+            debug!("Command to execute: {}", template);
+            let mut jobs = Vec::with_capacity(hosts.len());
+            for host in hosts {
+                let own = overrides.get(&host.hostname);
+                let command = match facts {
+                    Some(ref facts) => facts_cache::render(own.unwrap_or(&template), &host.to_string(), facts)?,
+                    None => own.unwrap_or(&template).clone(),
+                };
+                if own.is_some() {
+                    debug!("{}: command overridden: {}", host.hostname, command);
+                    checks.commands.insert(host.hostname.clone(), command.clone());
+                }
+                jobs.push((host, remote_command(args, &command)?));
+            }
+            jobs
+        }
+    };
+    
+    if !args.yes {
+        let threshold = config.confirm_threshold.filter(|&threshold| jobs.len() > threshold);
+        if let Some(threshold) = threshold {
+            confirm::confirm_run(&jobs, &ssh_user, confirm::Reason::Threshold(threshold))?;
+        } else if args.confirm {
+            confirm::confirm_run(&jobs, &ssh_user, confirm::Reason::Requested)?;
+        }
+    }
+    
+    // Dropped with the Prepared run; the OS releases it if krust dies first
+    let lock = match (args.lock || args.module().is_some_and(Module::locks_by_default)) && !args.no_lock {
+        true => {
+            let targets: Vec<String> = jobs.iter().map(|(host, _)| host.target()).collect();
+            let command = match args.module() {
+                Some(module) => format!("module {}", module.name()),
+                None => command_line.clone(),
+            };
+            let key = lock::key(inventory.as_deref(), &targets);
+            Some(lock::acquire(&key, args.lock_wait, &system_user, &command).await?)
+        }
+        false => None,
+    };
+    
+    let concurrency = match args.module().and_then(|m| m.max_in_flight(jobs.len())) {
+        Some(max) => max.min(args.concurrency),
+        None => args.concurrency,
+    };
+    
+    if !args.json_output() {
+        info!("Executing on {} hosts with concurrency {} as user {}", 
+              jobs.len(), concurrency, ssh_user);
+    }
+    
+    let reboots = args.module().and_then(Module::reboot_policy).is_some();
+    if reboots && args.concurrency_scope == ConcurrencyScope::Connect && args.max_sessions.is_none() {
+        return Err(anyhow!("--concurrency-scope connect would let every host reboot at once; add --max-sessions to bound it"));
+    }
+    if args.max_sessions == Some(0) {
+        return Err(anyhow!("--max-sessions must be at least 1"));
+    }
+    
+    // Execute
+    let limiter = Arc::new(if args.adaptive_concurrency {
+        Limiter::adaptive(concurrency)
+    } else {
+        Limiter::fixed(concurrency)
+    }
+        .with_rate(args.connect_rate)
+        .with_deadline(args.deadline)
+        .with_groups(group_limits(&loaded, &jobs, args.module()))
+        .with_scope(args.concurrency_scope, args.max_sessions));
+    Ok(Some(Prepared {
+        jobs,
+        transport,
+        input,
+        limiter,
+        checks,
+        ssh_user,
+        inventory,
+        command_line,
+        concurrency,
+        invalid_hosts: loaded.invalid.len(),
+        _spill: spill,
+        _lock: lock,
+    }))
+}
+
+async fn execute_run(args: Cli, argv: Vec<String>, started: Started, run_id: String) -> Result<i32> {
+    let Some(prepared) = prepare(&args, &run_id).await? else {
+        return Ok(0);
+    };
+    let Prepared { jobs, transport, input, limiter, checks, ssh_user, inventory, command_line, concurrency, invalid_hosts, _spill, _lock } = prepared;
+    // Only a person watching a plain text run is asked about retrying its failures
+    let interactive = !args.yes && !args.json_output() && args.format.is_none()
+        && args.aggregate.is_none() && !args.first_success && args.watch.is_none()
+        && Bench::from_args(&args).is_none() && stdout().is_terminal();
+    let rerun_jobs = interactive.then(|| jobs.clone());
+    if args.json_array {
+        json_array::open(args.pretty_json);
+    }
+    let cancel = CancellationToken::new();
+    cancel_on_ctrl_c(&cancel);
+    let (mut results, mut exit_code) = match args.watch {
+        Some(interval) => watch::run(jobs, Arc::clone(&transport), input.clone(), Arc::clone(&limiter), &args, &checks, interval, &cancel).await,
+        None => run_parallel(jobs, Arc::clone(&transport), input.clone(), Arc::clone(&limiter), &args, &checks, None, &cancel).await,
+    };
+    if let (Some(jobs), 1) = (rerun_jobs, exit_code) {
+        let failed: Vec<&HostResult> = results.iter().filter(|r| !r.success).collect();
+        if !failed.is_empty() && confirm::ask_retry(&failed)? {
+            let jobs: Vec<(SshHost, String)> = jobs.into_iter()
+                .filter(|(host, _)| failed.iter().any(|r| r.hostname == host.hostname && r.address == host.target()))
+                .collect();
+            let (rerun, rerun_code) = run_parallel(jobs, transport, input, Arc::clone(&limiter), &args, &checks, None, &cancel).await;
+            merge_rerun(&mut results, rerun);
+            exit_code = match results.iter().all(|r| r.success) {
+                true => 0,
+                false => rerun_code.max(1),
+            };
+        }
+    }
+    
+    sort::sort_results(&mut results, args.sort);
+    
+    // Output final summary or pretty JSON
+    if args.pretty_json && !args.json_array {
+        // Pretty print all results at once
+        let shown = results.iter().filter(|r| !r.is_filtered() || checks.annotates());
+        let output: Vec<_> = if let Some(ref fields) = args.fields {
+            shown.map(|r| r.filter_fields(fields)).collect()
+        } else {
+            shown.map(|r| serde_json::to_value(r).unwrap()).collect()
+        };
+        let output = match args.query {
+            Some(ref query) => query::apply_to_array(query, serde_json::Value::Array(output)),
+            None => serde_json::Value::Array(output),
+        };
+        
+        if let Ok(json) = serde_json::to_string_pretty(&output) {
+            outln!("{}", json);
+        }
+    } else if !args.json_output() && args.format.is_none() {
+        // Print summary for text output
+        let use_color = !args.no_color && stdout().is_terminal();
+        if let Some(op) = args.aggregate {
+            Aggregate::new(op, &results).print(use_color);
+        } else if args.first_success {
+            print_first_success_summary(&results, use_color);
+        } else {
+            print_summary(&results, invalid_hosts, use_color);
+        }
+    }
+    
+    if let (Some(op), true) = (args.aggregate, args.json_output()) {
+        json_array::record(&Aggregate::new(op, &results).to_json());
+    }
+    
+    if args.json_summary && args.json_output() {
+        json_array::record(&checks.summary(serde_json::json!({
+            "total": results.len(),
+            "succeeded": results.iter().filter(|r| r.success).count(),
+            "failed": results.iter().filter(|r| !r.success).count(),
+            "failures": failures::buckets(&results),
+        })));
+    }
+    
+    if args.first_success && args.json_output() {
+        let winner = results.iter().find(|r| r.success).map(|r| r.hostname.as_str());
+        json_array::record(&checks.summary(serde_json::json!({
+            "mode": "first_success",
+            "winner": winner,
+            "attempted": results.len(),
+        })));
+    }
+    
+    if let Some(bench) = Bench::from_args(&args) {
+        let use_color = !args.no_color && stdout().is_terminal();
+        bench::print_report(&results, bench, args.json_output(), use_color);
+    }
+    
+    if let Some(module) = args.module() {
+        module.finish(&results, args.json_output())?;
+    }
+    
+    if args.stats {
+        print_stats(&results, &limiter, concurrency, started, args.json_output());
+    }
+    let reason = if cancel.is_cancelled() { "cancelled" } else { "deadline" };
+    json_array::close((exit_code == EXIT_ABORTED).then(|| json_array::incomplete(reason, results.len())));
+    
+    if !args.no_history {
+        // The remote command, or the module and its arguments, is kept verbatim
+        let (command, verbatim_tail) = match args.module() {
+            Some(module) => (
+                format!("module {}", module.name()),
+                argv.iter().position(|arg| arg == module.name()).map_or(0, |i| argv.len() - i),
+            ),
+            None => (command_line, args.command.len()),
+        };
+        // Read from a file or standard input, the command is recorded as text so a rerun runs the same
+        let argv = match args.command_text.as_deref() == Some("-") || args.command_file.is_some() {
+            true => history::inline_command(&argv, &command),
+            false => argv,
+        };
+        history::record(&RunRecord {
+            id: run_id,
+            timestamp: started.at,
+            user: ssh_user.to_string(),
+            command,
+            hosts: results.len(),
+            failed: results.iter().filter(|r| !r.success).count(),
+            duration_ms: started.elapsed_ms(),
+            exit_code,
+            inventory,
+            annotations: checks.annotations.clone(),
+            args: history::redact_args(&argv, verbatim_tail),
+        }, &results);
+    }
+    
+    Ok(exit_code)
+}
+
+/// Average, 95th percentile and maximum of per-host auth times
+fn auth_time_summary(results: &[HostResult]) -> Option<(u64, u64, u64)> {
+    Latency::of(results.iter().filter_map(|r| r.auth_ms))
+        .map(|latency| (latency.avg, latency.p95, latency.max))
+}
+
+/// The first Ctrl-C starts no more hosts: those running finish and are reported, the rest are
+/// skipped. The second quits at once.
+fn cancel_on_ctrl_c(cancel: &CancellationToken) {
+    let cancel = cancel.clone();
+    tokio::spawn(async move {
+        while tokio::signal::ctrl_c().await.is_ok() {
+            if cancel.is_cancelled() {
+                std::process::exit(EXIT_INTERRUPTED);
+            }
+            warn!("Interrupted: waiting for the running hosts, skipping the rest (Ctrl-C again to quit)");
+            cancel.cancel();
+        }
+    });
+}
+
+/// Group limits from the inventory and --group-concurrency, if any apply
+fn group_limits(inventory: &Inventory, jobs: &[(SshHost, String)], module: Option<&Module>) -> Option<GroupLimits> {
+    let mut limits = inventory.group_concurrency.clone();
+    let mut members: HashMap<&SshHost, Vec<String>> = inventory.hosts.iter()
+        .map(|entry| (&entry.host, entry.groups.clone()))
+        .collect();
+    // reboot-wait --per-group: each group's share of --max-unavailable, from its hosts in this run
+    if let Some(module) = module.filter(|module| module.max_in_flight_per_group(1).is_some()) {
+        let mut sizes: HashMap<String, usize> = HashMap::new();
+        for (host, _) in jobs {
+            let groups = members.entry(host).or_default();
+            if groups.is_empty() {
+                groups.push(UNGROUPED.to_string());
+            }
+            for group in groups.iter() {
+                *sizes.entry(group.clone()).or_default() += 1;
+            }
+        }
+        for (group, size) in sizes {
+            let limit = module.max_in_flight_per_group(size).unwrap_or(size);
+            limits.entry(group).and_modify(|own| *own = limit.min(*own)).or_insert(limit);
+        }
+    }
+    if limits.is_empty() {
+        return None;
+    }
+    let members = members.iter().map(|(host, groups)| (*host, groups.as_slice()));
+    Some(GroupLimits::new(&limits, members))
+}
+
+fn print_stats(results: &[HostResult], limiter: &Limiter, concurrency: usize, started: Started, json: bool) {
+    let wall_ms = started.elapsed_ms();
+    let stats = limiter.stats();
+    let (effective, throttle_events) = stats.as_ref()
+        .map_or((concurrency, 0), |stats| (stats.effective, stats.throttle_events));
+    let auth_times = auth_time_summary(results);
+    let phases = timings::summarize(results);
+    // The phase with the highest p95 is where a slow run spends its time
+    let slowest = phases.iter()
+        .filter_map(|(name, latency)| latency.map(|latency| (*name, latency.p95)))
+        .max_by_key(|&(_, p95)| p95);
+    let (spilled_hosts, spilled_bytes) = spill::budget().spilled();
+    
+    if json {
+        json_array::record(&serde_json::json!({
+            "stats": {
+                "wall_ms": wall_ms,
+                "concurrency": effective,
+                "max_concurrency": concurrency,
+                "adaptive": stats.is_some(),
+                "throttle_events": throttle_events,
+                "auth_ms": auth_times.map(|(average, p95, max)| serde_json::json!({
+                    "avg": average,
+                    "p95": p95,
+                    "max": max,
+                })),
+                "phases_ms": phases.iter()
+                    .filter_map(|(name, latency)| latency.map(|latency| (name.to_string(), serde_json::json!(latency))))
+                    .collect::<serde_json::Map<_, _>>(),
+                "slowest_phase": slowest.map(|(name, _)| name),
+                "output_bytes": results.iter().filter_map(|r| r.bytes_stdout).sum::<u64>(),
+                "spilled_hosts": spilled_hosts,
+                "spilled_bytes": spilled_bytes,
+            }
+        }));
+        return;
+    }
+    
+    if let Some(stats) = stats {
+        outln!(
+            "Stats: {:.1}s wall time, adaptive concurrency ended at {} of {} ({} throttle events)",
+            wall_ms as f64 / 1000.0, stats.effective, stats.max, stats.throttle_events
+        );
+    } else {
+        outln!("Stats: {:.1}s wall time, concurrency {}", wall_ms as f64 / 1000.0, concurrency);
+    }
+    if let Some((average, p95, max)) = auth_times {
+        outln!("Auth time: avg {}ms, p95 {}ms, max {}ms", average, p95, max);
+    }
+    if let Some((name, p95)) = slowest {
+        let all: Vec<String> = phases.iter()
+            .filter_map(|(name, latency)| latency.map(|latency| format!("{} {}ms", name, latency.p95)))
+            .collect();
+        outln!("Phase p95: {}; {} is the bottleneck ({:.1}s)", all.join(", "), name, p95 as f64 / 1000.0);
+    }
+    if spilled_hosts > 0 {
+        outln!(
+            "Output: {} in memory, {} from {} host{} spilled to disk",
+            spill::format_size(spill::budget().held()),
+            spill::format_size(spilled_bytes),
+            spilled_hosts,
+            if spilled_hosts == 1 { "" } else { "s" }
+        );
+    }
+}
+
+fn print_first_success_summary(results: &[HostResult], use_color: bool) {
+    outln!();
+    match results.iter().find(|r| r.success) {
+        Some(winner) if use_color => outln!(
+            "\x1b[1mFirst success:\x1b[0m \x1b[32m{}\x1b[0m after {} hosts attempted",
+            winner.hostname, results.len()
+        ),
+        Some(winner) => outln!("First success: {} after {} hosts attempted", winner.hostname, results.len()),
+        None if use_color => outln!(
+            "\x1b[1mFirst success:\x1b[0m \x1b[31mnone\x1b[0m, all {} hosts failed",
+            results.len()
+        ),
+        None => outln!("First success: none, all {} hosts failed", results.len()),
+    }
+}
+
+/// The remote command: --command or --command-file as they are, else the arguments joined with
+/// spaces (shell-quoted with --quote-args)
+fn command_text(args: &Cli) -> Result<String> {
+    let text = match (&args.command_text, &args.command_file) {
+        (Some(text), _) if text == "-" => {
+            if args.stdin {
+                return Err(anyhow!("--command - and --stdin both read standard input; use --stdin-file"));
+            }
+            let mut text = String::new();
+            std::io::stdin().take(args.max_stdin_bytes + 1).read_to_string(&mut text)
+                .context("Failed to read the command from standard input")?;
+            if text.len() as u64 > args.max_stdin_bytes {
+                return Err(anyhow!("The command on standard input is larger than --max-stdin-bytes {}", args.max_stdin_bytes));
+            }
+            text
+        }
+        (Some(text), _) => text.clone(),
+        (None, Some(path)) => std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read command file: {}", path))?,
+        (None, None) if args.quote_args => return args.shell.quote_args(&args.command),
+        (None, None) => return Ok(args.command.join(" ")),
+    };
+    match text.trim().is_empty() {
+        true => Err(anyhow!("The command is empty")),
+        false => Ok(text),
+    }
+}
+
+/// Load the --stdin/--stdin-file payload once, enforcing --max-stdin-bytes
+fn read_input(args: &Cli) -> Result<Option<Vec<u8>>> {
+    let limit = args.max_stdin_bytes;
+    let data = if let Some(ref path) = args.stdin_file {
+        let size = std::fs::metadata(path)
+            .with_context(|| format!("Failed to read stdin file: {}", path))?
+            .len();
+        if size > limit {
+            return Err(anyhow!("{} is {} bytes, over --max-stdin-bytes {}", path, size, limit));
+        }
+        std::fs::read(path).with_context(|| format!("Failed to read stdin file: {}", path))?
+    } else if args.stdin {
+        let mut data = Vec::new();
+        std::io::stdin().take(limit + 1).read_to_end(&mut data)
+            .context("Failed to read standard input")?;
+        if data.len() as u64 > limit {
+            return Err(anyhow!("Standard input is larger than --max-stdin-bytes {}", limit));
+        }
+        data
+    } else {
+        return Ok(None);
+    };
+    
+    debug!("Sending {} bytes of input to each host", data.len());
+    Ok(Some(data))
+}
+
+/// `skipped`: invalid inventory lines left out by --skip-invalid-hosts
+fn print_summary(results: &[HostResult], skipped: usize, use_color: bool) {
+    let total = results.len();
+    let successful = results.iter().filter(|r| r.success).count();
+    let failed = total - successful;
+    let count_kind = |kind| results.iter().filter(|r| r.error_kind == Some(kind)).count();
+    let unreachable = count_kind(ExecErrorKind::Unreachable);
+    let deadline = count_kind(ExecErrorKind::Deadline);
+    let mut breakdown = Vec::new();
+    if unreachable > 0 {
+        breakdown.push(format!("{} unreachable (pre-check)", unreachable));
+    }
+    if deadline > 0 {
+        breakdown.push(format!("{} timed out by deadline", deadline));
+    }
+    let breakdown = match breakdown.is_empty() {
+        true => String::new(),
+        false => format!(" ({}, {} command failed)", breakdown.join(", "), failed - unreachable - deadline),
+    };
+    let filtered = if results.iter().any(|r| r.matched.is_some()) {
+        format!(", {} filtered", results.iter().filter(|r| r.is_filtered()).count())
+    } else {
+        String::new()
+    };
+    let skipped = match skipped {
+        0 => String::new(),
+        1 => ", 1 invalid inventory line skipped".to_string(),
+        n => format!(", {} invalid inventory lines skipped", n),
+    };
+    
+    outln!();
+    if use_color {
+        outln!("\x1b[1mSummary:\x1b[0m {} total, \x1b[32m{} succeeded\x1b[0m, \x1b[31m{} failed\x1b[0m{}{}{}", 
+                 total, successful, failed, breakdown, filtered, skipped);
+    } else {
+        outln!("Summary: {} total, {} succeeded, {} failed{}{}{}", total, successful, failed, breakdown, filtered, skipped);
+    }
+    let retried: Vec<bool> = results.iter().filter_map(|r| r.recovered).collect();
+    if !retried.is_empty() {
+        outln!("  re-run: {} of {} recovered", retried.iter().filter(|&&recovered| recovered).count(), retried.len());
+    }
+    for bucket in failures::buckets(results) {
+        outln!("  {}", failures::describe(&bucket));
+    }
+}
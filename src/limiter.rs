@@ -0,0 +1,408 @@
+// [limiter.rs] - KRUST - Concurrency limit, optionally adapting to connection failures
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::warn;
+
+use crate::ssh_executor::SshHost;
+use crate::HostResult;
+
+/// Adaptive mode starts at most this many hosts at once and grows from there
+const ADAPTIVE_START: usize = 20;
+/// Connection failures within FAILURE_WINDOW that count as a spike
+const FAILURE_SPIKE: usize = 3;
+const FAILURE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Additive-increase/multiplicative-decrease state for --adaptive-concurrency
+struct Aimd {
+    limit: usize,
+    max: usize,
+    /// Permits still owed after a decrease; taken back as running hosts finish
+    debt: usize,
+    failures: VecDeque<Instant>,
+    throttle_events: u64,
+}
+
+/// What a --concurrency slot covers
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConcurrencyScope {
+    /// The whole host: connect, run the command, hooks and reboot waits
+    #[default]
+    All,
+    /// Connecting and authenticating only; the command then runs outside the limit
+    Connect,
+}
+
+/// --connect-rate: new connections per second
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectRate {
+    interval: Duration,
+}
+
+/// Parse "10/s" or "300/m"
+pub fn parse_rate(s: &str) -> Result<ConnectRate, String> {
+    let invalid = || format!("Invalid rate: {} (use N/s or N/m)", s);
+    let (count, unit) = s.trim().split_once('/').ok_or_else(invalid)?;
+    let count: u32 = count.trim().parse().map_err(|_| invalid())?;
+    let period = match unit.trim() {
+        "s" => Duration::from_secs(1),
+        "m" => Duration::from_secs(60),
+        _ => return Err(invalid()),
+    };
+    if count == 0 {
+        return Err(format!("Invalid rate: {} (must be at least 1)", s));
+    }
+    Ok(ConnectRate { interval: period / count })
+}
+
+/// Parse one "group=N" of --group-concurrency
+pub fn parse_group_limit(s: &str) -> Result<(String, usize), String> {
+    let invalid = || format!("Invalid group limit: {} (use GROUP=N)", s);
+    let (group, limit) = s.trim().split_once('=').ok_or_else(invalid)?;
+    let limit: usize = limit.trim().parse().map_err(|_| invalid())?;
+    if group.trim().is_empty() {
+        return Err(invalid());
+    }
+    if limit == 0 {
+        return Err(format!("Invalid group limit: {} (must be at least 1)", s));
+    }
+    Ok((group.trim().to_string(), limit))
+}
+
+/// One inventory group's own concurrency limit
+struct GroupSlot {
+    name: String,
+    limit: usize,
+    semaphore: Arc<Semaphore>,
+    in_flight: AtomicUsize,
+}
+
+/// Per-group limits, layered under the global one; each host counts against one group
+pub struct GroupLimits {
+    slots: Vec<Arc<GroupSlot>>,
+    by_host: HashMap<SshHost, Arc<GroupSlot>>,
+}
+
+impl GroupLimits {
+    /// `limits` by group name; a host in several limited groups takes the most restrictive one
+    pub fn new<'a>(limits: &HashMap<String, usize>, members: impl Iterator<Item = (&'a SshHost, &'a [String])>) -> Self {
+        let mut slots: Vec<Arc<GroupSlot>> = limits.iter()
+            .map(|(name, &limit)| Arc::new(GroupSlot {
+                name: name.clone(),
+                limit,
+                semaphore: Arc::new(Semaphore::new(limit)),
+                in_flight: AtomicUsize::new(0),
+            }))
+            .collect();
+        slots.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut by_host = HashMap::new();
+        for (host, groups) in members {
+            let strictest = slots.iter()
+                .filter(|slot| groups.contains(&slot.name))
+                .min_by_key(|slot| slot.limit);
+            if let Some(slot) = strictest {
+                by_host.insert(host.clone(), Arc::clone(slot));
+            }
+        }
+        GroupLimits { slots, by_host }
+    }
+
+    /// "db 1/1, web 12/20" for the progress line
+    fn in_flight(&self) -> String {
+        self.slots.iter()
+            .map(|slot| format!("{} {}/{}", slot.name, slot.in_flight.load(Ordering::SeqCst), slot.limit))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+pub struct Limiter {
+    semaphore: Arc<Semaphore>,
+    scope: ConcurrencyScope,
+    /// --max-sessions: hosts in progress, whatever the scope
+    sessions: Option<Arc<Semaphore>>,
+    adaptive: Option<Mutex<Aimd>>,
+    rate: Option<ConnectRate>,
+    /// Earliest moment the next connection may start under --connect-rate
+    next_connect: Mutex<Option<tokio::time::Instant>>,
+    waiting_for_permit: AtomicUsize,
+    waiting_for_session: AtomicUsize,
+    waiting_for_rate: AtomicUsize,
+    /// --deadline: no host is admitted after this moment
+    deadline: Option<tokio::time::Instant>,
+    groups: Option<GroupLimits>,
+}
+
+/// Effective concurrency at the end of a run and how often it was cut
+pub struct LimiterStats {
+    pub effective: usize,
+    pub max: usize,
+    pub throttle_events: u64,
+}
+
+/// Held while a host is in progress
+pub struct LimiterPermit {
+    connection: SetupSlot,
+    scope: ConcurrencyScope,
+    _session: Option<OwnedSemaphorePermit>,
+}
+
+/// The concurrency and group slots of one host
+struct ConnectionPermits {
+    permit: Option<OwnedSemaphorePermit>,
+    limiter: Arc<Limiter>,
+    group: Option<(Arc<GroupSlot>, OwnedSemaphorePermit)>,
+}
+
+/// A host's connection slots, which `connected` releases early under --concurrency-scope connect
+#[derive(Clone, Default)]
+pub struct SetupSlot(Arc<Mutex<Option<ConnectionPermits>>>);
+
+thread_local! {
+    static SETUP: RefCell<Option<SetupSlot>> = const { RefCell::new(None) };
+}
+
+/// Forgets the thread's slot when dropped
+pub struct Entered(());
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        SETUP.with(|current| current.borrow_mut().take());
+    }
+}
+
+impl SetupSlot {
+    /// Make this the slot that `connected` releases on the current (SSH) thread
+    pub fn enter(&self) -> Entered {
+        SETUP.with(|current| *current.borrow_mut() = Some(self.clone()));
+        Entered(())
+    }
+}
+
+/// Authentication succeeded: give the connection slot back if the scope is connect
+pub fn connected() {
+    SETUP.with(|current| {
+        if let Some(ref slot) = *current.borrow() {
+            slot.0.lock().unwrap().take();
+        }
+    });
+}
+
+impl LimiterPermit {
+    /// The slot the SSH thread releases once connected; an empty one when the scope is all
+    pub fn setup_slot(&self) -> SetupSlot {
+        match self.scope {
+            ConcurrencyScope::All => SetupSlot::default(),
+            ConcurrencyScope::Connect => self.connection.clone(),
+        }
+    }
+}
+
+impl Drop for ConnectionPermits {
+    fn drop(&mut self) {
+        if let Some((ref slot, _)) = self.group {
+            slot.in_flight.fetch_sub(1, Ordering::SeqCst);
+        }
+        if let (Some(permit), Some(aimd)) = (self.permit.take(), &self.limiter.adaptive) {
+            let mut aimd = aimd.lock().unwrap();
+            if aimd.debt > 0 {
+                aimd.debt -= 1;
+                permit.forget();
+            }
+        }
+    }
+}
+
+impl Limiter {
+    fn new(permits: usize, adaptive: Option<Aimd>) -> Self {
+        Limiter {
+            semaphore: Arc::new(Semaphore::new(permits)),
+            scope: ConcurrencyScope::All,
+            sessions: None,
+            adaptive: adaptive.map(Mutex::new),
+            rate: None,
+            next_connect: Mutex::new(None),
+            waiting_for_permit: AtomicUsize::new(0),
+            waiting_for_session: AtomicUsize::new(0),
+            waiting_for_rate: AtomicUsize::new(0),
+            deadline: None,
+            groups: None,
+        }
+    }
+
+    pub fn fixed(concurrency: usize) -> Self {
+        Limiter::new(concurrency, None)
+    }
+
+    /// Start at min(concurrency, 20) and never exceed `concurrency`
+    pub fn adaptive(concurrency: usize) -> Self {
+        let start = concurrency.min(ADAPTIVE_START);
+        Limiter::new(start, Some(Aimd {
+            limit: start,
+            max: concurrency,
+            debt: 0,
+            failures: VecDeque::new(),
+            throttle_events: 0,
+        }))
+    }
+
+    pub fn with_rate(mut self, rate: Option<ConnectRate>) -> Self {
+        self.rate = rate;
+        self
+    }
+
+    /// Stop admitting hosts once `budget` has passed from now
+    pub fn with_deadline(mut self, budget: Option<Duration>) -> Self {
+        self.deadline = budget.map(|budget| tokio::time::Instant::now() + budget);
+        self
+    }
+
+    pub fn with_groups(mut self, groups: Option<GroupLimits>) -> Self {
+        self.groups = groups;
+        self
+    }
+
+    /// --concurrency-scope and --max-sessions
+    pub fn with_scope(mut self, scope: ConcurrencyScope, max_sessions: Option<usize>) -> Self {
+        self.scope = scope;
+        self.sessions = max_sessions.map(|sessions| Arc::new(Semaphore::new(sessions)));
+        self
+    }
+
+    pub fn deadline(&self) -> Option<tokio::time::Instant> {
+        self.deadline
+    }
+
+    pub fn deadline_passed(&self) -> bool {
+        self.deadline.is_some_and(|deadline| tokio::time::Instant::now() >= deadline)
+    }
+
+    /// Wait for a session under --max-sessions, a slot in the host's group, then a concurrency slot,
+    /// then for the connection rate to allow a new connection. The group comes first so hosts held
+    /// back by their group do not sit on global slots; the rate is checked last so slots freeing up
+    /// at once cannot burst past it. Returns None when the deadline passes first.
+    pub async fn acquire(self: &Arc<Self>, host: &SshHost) -> Option<LimiterPermit> {
+        let session = match self.sessions {
+            Some(ref sessions) => {
+                self.waiting_for_session.fetch_add(1, Ordering::SeqCst);
+                let session = Arc::clone(sessions).acquire_owned();
+                let session = match self.deadline {
+                    Some(deadline) => tokio::time::timeout_at(deadline, session).await.ok(),
+                    None => Some(session.await),
+                };
+                self.waiting_for_session.fetch_sub(1, Ordering::SeqCst);
+                Some(session?.unwrap())
+            }
+            None => None,
+        };
+        self.waiting_for_permit.fetch_add(1, Ordering::SeqCst);
+        let permits = async {
+            let group = match self.groups.as_ref().and_then(|groups| groups.by_host.get(host)) {
+                Some(slot) => {
+                    let permit = Arc::clone(&slot.semaphore).acquire_owned().await.unwrap();
+                    Some((Arc::clone(slot), permit))
+                }
+                None => None,
+            };
+            (group, Arc::clone(&self.semaphore).acquire_owned().await.unwrap())
+        };
+        let permits = match self.deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, permits).await.ok(),
+            None => Some(permits.await),
+        };
+        self.waiting_for_permit.fetch_sub(1, Ordering::SeqCst);
+        let (group, permit) = permits?;
+
+        if let Some(rate) = self.rate {
+            let slot = {
+                let mut next = self.next_connect.lock().unwrap();
+                let now = tokio::time::Instant::now();
+                let slot = next.map_or(now, |next| next.max(now));
+                *next = Some(slot + rate.interval);
+                slot
+            };
+            self.waiting_for_rate.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep_until(slot).await;
+            self.waiting_for_rate.fetch_sub(1, Ordering::SeqCst);
+        }
+        if self.deadline_passed() {
+            return None;
+        }
+        if let Some((ref slot, _)) = group {
+            slot.in_flight.fetch_add(1, Ordering::SeqCst);
+        }
+        let permits = ConnectionPermits { permit: Some(permit), limiter: Arc::clone(self), group };
+        Some(LimiterPermit {
+            connection: SetupSlot(Arc::new(Mutex::new(Some(permits)))),
+            scope: self.scope,
+            _session: session,
+        })
+    }
+
+    /// In-flight hosts per limited group, when --group-concurrency or max_concurrency is in use
+    pub fn group_load(&self) -> Option<String> {
+        self.groups.as_ref().map(GroupLimits::in_flight)
+    }
+
+    /// What hosts that have not started yet are waiting on, for the progress line
+    pub fn bottleneck(&self) -> Option<&'static str> {
+        if self.waiting_for_rate.load(Ordering::SeqCst) > 0 {
+            Some("rate-limited")
+        } else if self.waiting_for_session.load(Ordering::SeqCst) > 0 {
+            Some("session-limited")
+        } else if self.waiting_for_permit.load(Ordering::SeqCst) > 0 {
+            Some("concurrency-limited")
+        } else {
+            None
+        }
+    }
+
+    /// Grow by one permit per success; halve when connection failures spike
+    pub fn record(&self, result: &HostResult) {
+        let Some(ref aimd) = self.adaptive else {
+            return;
+        };
+        let mut aimd = aimd.lock().unwrap();
+
+        if result.success {
+            if aimd.limit < aimd.max {
+                aimd.limit += 1;
+                if aimd.debt > 0 {
+                    aimd.debt -= 1;
+                } else {
+                    self.semaphore.add_permits(1);
+                }
+            }
+            return;
+        }
+        if !result.error_kind.is_some_and(|kind| kind.is_connection()) {
+            return;
+        }
+
+        let now = Instant::now();
+        aimd.failures.push_back(now);
+        while aimd.failures.front().is_some_and(|&t| now - t > FAILURE_WINDOW) {
+            aimd.failures.pop_front();
+        }
+        if aimd.failures.len() < FAILURE_SPIKE {
+            return;
+        }
+
+        // Start a fresh window so one burst only halves the limit once
+        aimd.failures.clear();
+        let reduced = (aimd.limit / 2).max(1);
+        let cut = aimd.limit - reduced;
+        aimd.limit = reduced;
+        aimd.debt += cut - self.semaphore.forget_permits(cut);
+        aimd.throttle_events += 1;
+        warn!("Connection failures are spiking, reducing concurrency to {}", reduced);
+    }
+
+    pub fn stats(&self) -> Option<LimiterStats> {
+        let aimd = self.adaptive.as_ref()?.lock().unwrap();
+        Some(LimiterStats { effective: aimd.limit, max: aimd.max, throttle_events: aimd.throttle_events })
+    }
+}
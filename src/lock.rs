@@ -0,0 +1,112 @@
+// [lock.rs] - KRUST - Advisory run lock so two runs cannot work on the same hosts at once
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+use tracing::{debug, info};
+
+use crate::history;
+
+/// How often a waiting run retries the lock
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Written into the lock file so a waiting run can say who holds it
+#[derive(Debug, Serialize, Deserialize)]
+struct Holder {
+    pid: u32,
+    user: String,
+    command: String,
+    since: DateTime<Utc>,
+}
+
+/// Held for the whole run. The operating system drops the lock when the file is closed,
+/// including when krust exits on Ctrl-C or a panic, so a lock is never left stale.
+pub struct RunLock {
+    _file: File,
+}
+
+/// The lock for an inventory file, or for the exact set of hosts when there is none
+pub fn key(inventory: Option<&str>, targets: &[String]) -> String {
+    let source = match inventory {
+        Some(path) => {
+            let path = fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+            format!("inventory:{}", path.display())
+        }
+        None => {
+            let mut targets = targets.to_vec();
+            targets.sort_unstable();
+            targets.dedup();
+            format!("hosts:{}", targets.join(","))
+        }
+    };
+    format!("{:016x}", fnv1a(source.as_bytes()))
+}
+
+/// Take the lock named `key`, waiting up to `wait` for another run to release it
+pub async fn acquire(key: &str, wait: Duration, user: &str, command: &str) -> Result<RunLock> {
+    let dir = history::data_dir()?.join("locks");
+    fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    let path = dir.join(format!("{}.lock", key));
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .with_context(|| format!("Failed to open lock file {}", path.display()))?;
+
+    let deadline = tokio::time::Instant::now() + wait;
+    let mut announced = false;
+    loop {
+        match file.try_lock() {
+            Ok(()) => break,
+            Err(TryLockError::WouldBlock) => {}
+            Err(TryLockError::Error(e)) => {
+                return Err(e).with_context(|| format!("Failed to lock {}", path.display()));
+            }
+        }
+        if tokio::time::Instant::now() >= deadline {
+            bail!("Another krust run is working on these hosts ({}); use --lock-wait to wait longer or --no-lock to run anyway",
+                  describe_holder(&mut file));
+        }
+        if !announced {
+            info!("Waiting up to {}s for another krust run ({})", wait.as_secs(), describe_holder(&mut file));
+            announced = true;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+
+    let holder = Holder {
+        pid: std::process::id(),
+        user: user.to_string(),
+        command: command.to_string(),
+        since: Utc::now(),
+    };
+    file.set_len(0)?;
+    file.rewind()?;
+    file.write_all(serde_json::to_string(&holder)?.as_bytes())?;
+    file.flush()?;
+    debug!("Holding run lock {}", path.display());
+    Ok(RunLock { _file: file })
+}
+
+fn describe_holder(file: &mut File) -> String {
+    let mut content = String::new();
+    let holder = file.rewind().and_then(|()| file.read_to_string(&mut content)).ok()
+        .and_then(|_| serde_json::from_str::<Holder>(&content).ok());
+    match holder {
+        Some(holder) => format!(
+            "pid {}, user {}, running '{}' since {}",
+            holder.pid, holder.user, holder.command, holder.since.format("%Y-%m-%d %H:%M:%S UTC")
+        ),
+        None => "holder unknown".to_string(),
+    }
+}
+
+/// 64-bit FNV-1a: stable across builds, unlike std's hasher
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
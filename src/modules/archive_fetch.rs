@@ -0,0 +1,230 @@
+// [modules/archive_fetch.rs] - KRUST - Pack a remote directory into a tarball and download it
+use anyhow::{bail, Result};
+use clap::Args;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::timeout;
+use tracing::{debug, warn};
+
+use super::contract::{DATA, METRIC, RESULT};
+use super::script::Script;
+use super::{run_follow_up, shell_quote};
+use crate::executor::{CommandExecutor, Transport};
+use crate::host_error::{HostError, LocalErrorKind};
+use crate::ssh_executor::SshHost;
+use crate::HostResult;
+
+/// Directory on the host holding the tarball until it is downloaded; mktemp fills in the X's
+const REMOTE_DIR: &str = "/tmp/krust-archive.XXXXXX";
+const REMOTE_NAME: &str = "archive.tar.gz";
+const CLEANUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Args, Debug)]
+pub struct ArchiveFetchArgs {
+    /// Absolute path of the directory (or file) to archive on each host
+    pub path: String,
+
+    /// Local directory receiving <hostname>.tar.gz
+    #[arg(long, default_value = ".")]
+    pub out: PathBuf,
+
+    /// tar --exclude pattern, e.g. 'cache/*' (repeatable)
+    #[arg(long)]
+    pub exclude: Vec<String>,
+
+    /// Fail hosts whose compressed archive would be larger than this (e.g. 500M)
+    #[arg(long, value_parser = crate::parse_size)]
+    pub max_size: Option<u64>,
+}
+
+/// Where the controller puts the archives the module leaves on the hosts
+#[derive(Debug, Clone)]
+pub struct FetchPolicy {
+    pub out: PathBuf,
+}
+
+pub fn fetch_policy(args: &ArchiveFetchArgs) -> FetchPolicy {
+    FetchPolicy { out: args.out.clone() }
+}
+
+pub fn build_command(args: &ArchiveFetchArgs) -> Result<Script> {
+    let path = match args.path.trim_end_matches('/') {
+        "" => bail!("archive-fetch will not archive the whole filesystem (/)"),
+        path => path,
+    };
+    if !path.starts_with('/') {
+        bail!("archive-fetch path must be absolute: {}", args.path);
+    }
+    if path.contains('\n') || path.contains('\0') {
+        bail!("archive-fetch path must be a single line");
+    }
+    if let Some(pattern) = args.exclude.iter().find(|p| p.is_empty() || p.contains('\n') || p.contains('\0')) {
+        bail!("Invalid --exclude pattern: {:?}", pattern);
+    }
+    if args.max_size == Some(0) {
+        bail!("--max-size must be greater than zero");
+    }
+    if args.out.exists() && !args.out.is_dir() {
+        bail!("--out must be a directory: {}", args.out.display());
+    }
+
+    // tar's exit code 1 means some files could not be read or changed while being read; the
+    // archive holds everything else. 2 or more is fatal. GNU tar needs --ignore-failed-read to
+    // treat unreadable files that way.
+    let body = format!(r#"if [ ! -e "$src" ]; then
+    echo "failed: $src does not exist" >&2
+    exit 1
+fi
+dir=$(mktemp -d {REMOTE_DIR}) || exit 1
+archive="$dir/{REMOTE_NAME}"
+flags=()
+tar --help 2>&1 | grep -q -- --ignore-failed-read && flags+=(--ignore-failed-read)
+for pattern in "${{excludes[@]}}"; do
+    flags+=(--exclude="$pattern")
+done
+if [ "$max_bytes" -gt 0 ]; then
+    limit() {{ head -c $((max_bytes + 1)); }}
+else
+    limit() {{ cat; }}
+fi
+tar "${{flags[@]}}" -czf - -C "$(dirname "$src")" "$(basename "$src")" 2> "$dir/errors" | limit > "$archive"
+code=${{PIPESTATUS[0]}}
+bytes=$(wc -c < "$archive" | tr -d ' ')
+if [ "$max_bytes" -gt 0 ] && [ "$bytes" -gt "$max_bytes" ]; then
+    echo "failed: the archive of $src is over --max-size ($max_bytes bytes)" >&2
+    rm -rf "$dir"
+    exit 1
+fi
+if [ "$code" -gt 1 ] || [ "$bytes" = 0 ]; then
+    echo "failed: tar exited with $code" >&2
+    cat "$dir/errors" >&2
+    rm -rf "$dir"
+    exit 1
+fi
+unreadable=$(grep -c 'Permission denied' "$dir/errors") || true
+grep 'Permission denied' "$dir/errors" | sed 's/^/unreadable: /'
+grep -v 'Permission denied' "$dir/errors" | sed 's/^/warning: /'
+if command -v sha256sum >/dev/null 2>&1; then
+    sum=$(sha256sum "$archive" | cut -d' ' -f1)
+else
+    sum=$(shasum -a 256 "$archive" | cut -d' ' -f1)
+fi
+echo "archived: $src ($bytes bytes, $unreadable unreadable)"
+echo "{DATA}{{\"archive_path\": \"$archive\", \"archive_sha256\": \"$sum\"}}"
+echo "{METRIC}archive_bytes=$bytes unreadable_files=$unreadable"
+echo "{RESULT}status=ok"
+"#);
+
+    Ok(Script::new()
+        .var("src", path)
+        .var("max_bytes", args.max_size.unwrap_or(0))
+        .array("excludes", &args.exclude)
+        .section(&body))
+}
+
+/// The temp directory the module created, checked before anything is removed on its account
+fn remote_dir(archive: &str) -> Option<&str> {
+    let dir = archive.strip_suffix(REMOTE_NAME)?.strip_suffix('/')?;
+    let suffix = dir.strip_prefix(REMOTE_DIR.trim_end_matches('X'))?;
+    (suffix.len() == 6 && suffix.chars().all(|c| c.is_ascii_alphanumeric())).then_some(dir)
+}
+
+fn sha256_file(path: &Path) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Download the archive into `<out>/<hostname>.tar.gz`, check it against the host's sha256 and
+/// remove the host's copy. Fails the result when the archive does not arrive intact.
+pub async fn fetch<E: CommandExecutor>(
+    transport: &Arc<Transport<E>>,
+    host: &SshHost,
+    result: &mut HostResult,
+    policy: &FetchPolicy,
+    limit: Duration,
+) {
+    if !result.success {
+        return;
+    }
+    let reported = |key: &str| result.module_result.as_ref()
+        .and_then(|module| module.data.get(key))
+        .and_then(|value| value.as_str())
+        .map(str::to_string);
+    let (Some(archive), Some(expected)) = (reported("archive_path"), reported("archive_sha256")) else {
+        result.fail(HostError::local(LocalErrorKind::Module, "archive-fetch: the host reported no archive", None));
+        return;
+    };
+    let Some(dir) = remote_dir(&archive).map(str::to_string) else {
+        result.fail(HostError::local(LocalErrorKind::Module, format!("archive-fetch: unexpected archive path {}", archive), None));
+        return;
+    };
+
+    let local = policy.out.join(format!("{}.tar.gz", host.safe_file_name()));
+    let fetched = download(transport, host, &archive, &local, &expected, limit).await;
+
+    // The host's copy goes whatever happened to ours
+    let cleanup = format!("rm -rf {}", shell_quote(&dir));
+    if let Err(e) = run_follow_up(transport, host, cleanup, CLEANUP_TIMEOUT).await {
+        warn!("{}: could not remove {}: {}", host.hostname, dir, e);
+    }
+
+    match fetched {
+        Ok(bytes) => {
+            debug!("{}: {} bytes fetched to {}", host.hostname, bytes, local.display());
+            let mut stdout = result.stdout.take().unwrap_or_default();
+            if !stdout.is_empty() && !stdout.ends_with('\n') {
+                stdout.push('\n');
+            }
+            stdout.push_str(&format!("fetched: {}", local.display()));
+            result.set_stdout(stdout);
+            result.module_fields.insert("archive_file".to_string(), local.display().to_string().into());
+            result.module_fields.insert("archive_bytes".to_string(), bytes.into());
+            result.module_fields.insert("archive_sha256".to_string(), expected.into());
+        }
+        Err(e) => {
+            result.fail(HostError::local(LocalErrorKind::Module, format!("archive-fetch: {}", e), None));
+        }
+    }
+}
+
+/// Download to a .part file, and only give it the final name once the checksum matches
+async fn download<E: CommandExecutor>(
+    transport: &Arc<Transport<E>>,
+    host: &SshHost,
+    archive: &str,
+    local: &Path,
+    expected: &str,
+    limit: Duration,
+) -> Result<u64, String> {
+    std::fs::create_dir_all(local.parent().unwrap_or(Path::new(".")))
+        .map_err(|e| format!("Cannot create {}: {}", local.display(), e))?;
+    let partial = local.with_extension("gz.part");
+    let addrs = transport.resolver.resolve(host).await.map_err(|e| e.to_string())?;
+    let task = tokio::task::spawn_blocking({
+        let (host, transport) = (host.clone(), Arc::clone(transport));
+        let (archive, partial, local, expected) = (archive.to_string(), partial.clone(), local.to_path_buf(), expected.to_string());
+        move || {
+            let auth = transport.auth.for_host(&host);
+            let bytes = transport.executor.download(&host, addrs, &auth, &archive, &partial).map_err(|e| e.to_string())?;
+            let actual = sha256_file(&partial).map_err(|e| e.to_string())?;
+            if actual != expected {
+                return Err(format!("checksum mismatch after download: expected {}, got {}", expected, actual));
+            }
+            std::fs::rename(&partial, &local).map_err(|e| format!("Cannot write {}: {}", local.display(), e))?;
+            Ok(bytes)
+        }
+    });
+
+    let outcome = match timeout(limit, task).await {
+        Ok(Ok(outcome)) => outcome,
+        Ok(Err(e)) => Err(format!("Task panic: {}", e)),
+        Err(_) => Err(format!("download timed out after {}s", limit.as_secs())),
+    };
+    if outcome.is_err() {
+        let _ = std::fs::remove_file(&partial);
+    }
+    outcome
+}
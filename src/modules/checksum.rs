@@ -0,0 +1,144 @@
+// [modules/checksum.rs] - KRUST - File checksum verification across the fleet
+use anyhow::{bail, Context, Result};
+use clap::{Args, ValueEnum};
+use std::collections::BTreeMap;
+
+use super::script::Script;
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgo {
+    Sha256,
+    Sha512,
+    Md5,
+}
+
+impl HashAlgo {
+    fn name(self) -> &'static str {
+        match self {
+            HashAlgo::Sha256 => "sha256",
+            HashAlgo::Sha512 => "sha512",
+            HashAlgo::Md5 => "md5",
+        }
+    }
+
+    fn hex_len(self) -> usize {
+        match self {
+            HashAlgo::Sha256 => 64,
+            HashAlgo::Sha512 => 128,
+            HashAlgo::Md5 => 32,
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct ChecksumArgs {
+    /// Remote files to hash
+    pub paths: Vec<String>,
+
+    /// Expected hash as path=hash (repeatable); a mismatch fails the host
+    #[arg(long)]
+    pub expect: Vec<String>,
+
+    /// File of expected hashes in sha256sum format ("<hash>  <path>")
+    #[arg(long)]
+    pub expect_file: Option<String>,
+
+    /// Hash algorithm
+    #[arg(long, value_enum, default_value_t = HashAlgo::Sha256)]
+    pub algo: HashAlgo,
+}
+
+fn validate_hash(algo: HashAlgo, path: &str, hash: &str) -> Result<String> {
+    let hash = hash.trim().to_lowercase();
+    if hash.len() != algo.hex_len() || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("Invalid {} hash for {}: {}", algo.name(), path, hash);
+    }
+    Ok(hash)
+}
+
+fn load_expectations(args: &ChecksumArgs) -> Result<BTreeMap<String, String>> {
+    let mut expected = BTreeMap::new();
+
+    if let Some(ref file) = args.expect_file {
+        let content = std::fs::read_to_string(file)
+            .with_context(|| format!("Failed to read expect file: {}", file))?;
+        for (index, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (hash, path) = line.split_once(char::is_whitespace)
+                .with_context(|| format!("{}:{}: expected \"<hash>  <path>\"", file, index + 1))?;
+            // sha256sum marks binary-mode entries with a leading '*'
+            let path = path.trim_start().trim_start_matches('*');
+            expected.insert(path.to_string(), validate_hash(args.algo, path, hash)?);
+        }
+    }
+
+    for spec in &args.expect {
+        let (path, hash) = spec.rsplit_once('=')
+            .with_context(|| format!("Invalid --expect (expected path=hash): {}", spec))?;
+        expected.insert(path.to_string(), validate_hash(args.algo, path, hash)?);
+    }
+
+    Ok(expected)
+}
+
+// Hashes each of $paths, comparing with the matching $expects entry when it is not empty
+const HASH: &str = r#"failed=0
+hash_file() {
+    if command -v "${algo}sum" >/dev/null 2>&1; then
+        "${algo}sum" -- "$1" | cut -d' ' -f1
+    else
+        openssl dgst "-$algo" -r "$1" | cut -d' ' -f1
+    fi
+}
+for i in "${!paths[@]}"; do
+    path=${paths[$i]}
+    expect=${expects[$i]}
+    if [ ! -e "$path" ]; then
+        echo "$algo MISSING $path"
+        failed=1
+    elif [ ! -r "$path" ] || [ -d "$path" ]; then
+        echo "$algo UNREADABLE $path"
+        failed=1
+    elif ! hash=$(hash_file "$path" 2>/dev/null) || [ -z "$hash" ]; then
+        echo "$algo UNREADABLE $path"
+        failed=1
+    elif [ -n "$expect" ] && [ "$hash" != "$expect" ]; then
+        echo "$algo $hash $path MISMATCH expected=$expect"
+        failed=1
+    else
+        echo "$algo $hash $path"
+    fi
+done
+exit $failed
+"#;
+
+pub fn build_command(args: &ChecksumArgs) -> Result<Script> {
+    let expected = load_expectations(args)?;
+
+    let mut paths: Vec<&str> = args.paths.iter().map(String::as_str).collect();
+    for path in expected.keys() {
+        if !paths.contains(&path.as_str()) {
+            paths.push(path);
+        }
+    }
+
+    if paths.is_empty() {
+        bail!("checksum needs at least one path (or --expect/--expect-file)");
+    }
+    for path in &paths {
+        if path.is_empty() || path.contains('\n') {
+            bail!("Invalid path: {:?}", path);
+        }
+    }
+
+    let expects = paths.iter().map(|p| expected.get(*p).map(String::as_str).unwrap_or(""));
+
+    Ok(Script::new()
+        .var("algo", args.algo.name())
+        .array("paths", &paths)
+        .array("expects", expects)
+        .section(HASH))
+}
@@ -0,0 +1,729 @@
+// [modules/collect_facts.rs] - KRUST - Gather host facts and build a local inventory
+use anyhow::{bail, Context, Result};
+use clap::{Args, ValueEnum};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+use tracing::{info, warn};
+
+use super::contract::RESULT;
+use super::script::Script;
+use crate::facts_cache::FactsCache;
+use crate::json_array;
+use crate::HostResult;
+
+const DEFAULT_CSV_COLUMNS: &[&str] = &[
+    "hostname", "os_name", "os_version", "kernel", "arch", "cpu_count",
+    "memory_total_mb", "uptime_seconds", "ip_addresses", "package_manager",
+];
+
+const KNOWN_COLUMNS: &[&str] = &[
+    "hostname", "fqdn", "os_name", "os_version", "kernel", "arch", "cpu_count",
+    "memory_total_mb", "memory_available_mb", "swap_total_mb", "uptime_seconds",
+    "load_average", "timezone", "ip_addresses", "default_gateway", "disks",
+    "package_manager", "docker_version", "os_family", "services",
+    "vendor", "product", "serial", "virtualization", "cpu_model", "disk_count", "disk_total_gb",
+    "selinux", "apparmor", "firewall", "ssh_permit_root_login",
+];
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FactCategory {
+    System,
+    Memory,
+    Network,
+    Disks,
+    Packages,
+    Services,
+    Security,
+    Hardware,
+}
+
+const DEFAULT_CATEGORIES: &[FactCategory] = &[
+    FactCategory::System,
+    FactCategory::Memory,
+    FactCategory::Network,
+    FactCategory::Disks,
+    FactCategory::Packages,
+];
+
+impl FactCategory {
+    fn section(self) -> &'static str {
+        match self {
+            FactCategory::System => SYSTEM_SECTION,
+            FactCategory::Memory => MEMORY_SECTION,
+            FactCategory::Network => NETWORK_SECTION,
+            FactCategory::Disks => DISKS_SECTION,
+            FactCategory::Packages => PACKAGES_SECTION,
+            FactCategory::Services => SERVICES_SECTION,
+            FactCategory::Security => SECURITY_SECTION,
+            FactCategory::Hardware => HARDWARE_SECTION,
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct CollectFactsArgs {
+    /// Fact categories to collect (comma-separated) [default: system,memory,network,disks,packages]
+    #[arg(long, value_enum, value_delimiter = ',')]
+    pub facts: Option<Vec<FactCategory>>,
+
+    /// Deprecated: use --facts without network
+    #[arg(long)]
+    pub no_network: bool,
+
+    /// Deprecated: use --facts without disks
+    #[arg(long)]
+    pub no_disks: bool,
+
+    /// Add the services category (running services via systemd, launchctl or rc.d)
+    #[arg(long)]
+    pub include_services: bool,
+
+    /// Add the hardware category (vendor, product, serial, virtualization, CPU model, disks)
+    #[arg(long)]
+    pub include_hardware: bool,
+
+    /// Assemble every host's facts into one local CSV file
+    #[arg(long)]
+    pub csv_output: Option<String>,
+
+    /// Columns written to --csv-output (comma-separated)
+    #[arg(long, value_delimiter = ',', requires = "csv_output")]
+    pub csv_columns: Option<Vec<String>>,
+
+    /// Print an aggregate fleet report after collection
+    #[arg(long)]
+    pub report: bool,
+
+    /// Free-space percentage below which a filesystem is flagged in --report
+    #[arg(long, default_value_t = 10)]
+    pub disk_free_threshold: u8,
+
+    /// Merge the collected facts into this JSON file, for later --facts-cache runs
+    #[arg(long, value_name = "FILE")]
+    pub save_cache: Option<String>,
+}
+
+/// Facts reported by a single host; sections that were skipped are left empty
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Facts {
+    pub hostname: String,
+    pub fqdn: Option<String>,
+    pub os_name: Option<String>,
+    pub os_version: Option<String>,
+    pub kernel: Option<String>,
+    pub arch: Option<String>,
+    pub cpu_count: Option<u32>,
+    pub memory_total_mb: Option<u64>,
+    pub memory_available_mb: Option<u64>,
+    pub swap_total_mb: Option<u64>,
+    pub uptime_seconds: Option<u64>,
+    pub load_average: Option<String>,
+    pub timezone: Option<String>,
+    pub package_manager: Option<String>,
+    pub docker_version: Option<String>,
+    pub os_family: Option<String>,
+    pub services: Vec<String>,
+    pub vendor: Option<String>,
+    pub product: Option<String>,
+    pub serial: Option<String>,
+    pub virtualization: Option<String>,
+    pub cpu_model: Option<String>,
+    pub disk_count: Option<u32>,
+    pub disk_total_gb: Option<u64>,
+    pub selinux: Option<String>,
+    pub apparmor: Option<String>,
+    pub firewall: Option<String>,
+    pub ssh_permit_root_login: Option<String>,
+    pub ip_addresses: Vec<String>,
+    pub default_gateway: Option<String>,
+    pub disks: Vec<DiskFacts>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct DiskFacts {
+    pub mount: String,
+    pub filesystem: Option<String>,
+    pub size_mb: Option<u64>,
+    pub used_mb: Option<u64>,
+    pub available_mb: Option<u64>,
+    pub use_percent: Option<u8>,
+}
+
+impl Facts {
+    /// Parse the JSON object printed by the collection script
+    pub fn parse(stdout: &str) -> Result<Facts, serde_json::Error> {
+        serde_json::from_str(stdout.trim())
+    }
+}
+
+#[derive(Serialize, Debug, Default)]
+struct LowDisk {
+    hostname: String,
+    mount: String,
+    free_percent: u8,
+}
+
+#[derive(Serialize, Debug)]
+struct HostProblem {
+    hostname: String,
+    error: String,
+}
+
+#[derive(Serialize, Debug, Default)]
+struct FleetReport {
+    hosts_total: usize,
+    hosts_reporting: usize,
+    os_versions: BTreeMap<String, usize>,
+    kernels: BTreeMap<String, usize>,
+    cpu_total: u64,
+    cpu_average: f64,
+    memory_total_mb: u64,
+    memory_average_mb: f64,
+    docker_hosts: usize,
+    low_disk: Vec<LowDisk>,
+    malformed: Vec<HostProblem>,
+    failed: Vec<HostProblem>,
+}
+
+fn build_report(results: &[HostResult], disk_free_threshold: u8) -> FleetReport {
+    let mut report = FleetReport {
+        hosts_total: results.len(),
+        ..Default::default()
+    };
+
+    let mut facts = Vec::new();
+    for result in results {
+        match (result.success, result.stdout.as_deref()) {
+            (true, Some(stdout)) => match Facts::parse(stdout) {
+                Ok(parsed) => facts.push((result.hostname.clone(), parsed)),
+                Err(e) => report.malformed.push(HostProblem {
+                    hostname: result.hostname.clone(),
+                    error: e.to_string(),
+                }),
+            },
+            _ => report.failed.push(HostProblem {
+                hostname: result.hostname.clone(),
+                error: result.error_message().unwrap_or("Fact collection failed").to_string(),
+            }),
+        }
+    }
+
+    report.hosts_reporting = facts.len();
+    for (hostname, host_facts) in &facts {
+        let os = format!(
+            "{} {}",
+            host_facts.os_name.as_deref().unwrap_or("unknown"),
+            host_facts.os_version.as_deref().unwrap_or("")
+        );
+        *report.os_versions.entry(os.trim().to_string()).or_default() += 1;
+        *report.kernels.entry(host_facts.kernel.clone().unwrap_or_else(|| "unknown".to_string())).or_default() += 1;
+
+        report.cpu_total += host_facts.cpu_count.unwrap_or(0) as u64;
+        report.memory_total_mb += host_facts.memory_total_mb.unwrap_or(0);
+        if host_facts.docker_version.as_deref().is_some_and(|v| v != "none") {
+            report.docker_hosts += 1;
+        }
+
+        for disk in &host_facts.disks {
+            if let Some(used) = disk.use_percent {
+                let free_percent = 100u8.saturating_sub(used);
+                if free_percent < disk_free_threshold {
+                    report.low_disk.push(LowDisk {
+                        hostname: hostname.clone(),
+                        mount: disk.mount.clone(),
+                        free_percent,
+                    });
+                }
+            }
+        }
+    }
+
+    if !facts.is_empty() {
+        report.cpu_average = report.cpu_total as f64 / facts.len() as f64;
+        report.memory_average_mb = report.memory_total_mb as f64 / facts.len() as f64;
+    }
+
+    report
+}
+
+fn print_report(report: &FleetReport, disk_free_threshold: u8) {
+    outln!();
+    outln!("Fleet report: {} hosts, {} reporting facts", report.hosts_total, report.hosts_reporting);
+
+    outln!();
+    outln!("  {:<40} {:>6}", "OS", "HOSTS");
+    for (os, count) in &report.os_versions {
+        outln!("  {:<40} {:>6}", os, count);
+    }
+
+    outln!();
+    outln!("  {:<40} {:>6}", "KERNEL", "HOSTS");
+    for (kernel, count) in &report.kernels {
+        outln!("  {:<40} {:>6}", kernel, count);
+    }
+
+    outln!();
+    outln!("  CPUs:   {} total, {:.1} average", report.cpu_total, report.cpu_average);
+    outln!("  Memory: {} MB total, {:.0} MB average", report.memory_total_mb, report.memory_average_mb);
+    outln!("  Docker: {}/{} hosts", report.docker_hosts, report.hosts_reporting);
+
+    if !report.low_disk.is_empty() {
+        outln!();
+        outln!("  Filesystems below {}% free:", disk_free_threshold);
+        for disk in &report.low_disk {
+            outln!("    {} {} ({}% free)", disk.hostname, disk.mount, disk.free_percent);
+        }
+    }
+
+    if !report.malformed.is_empty() {
+        outln!();
+        outln!("  Malformed facts:");
+        for problem in &report.malformed {
+            outln!("    {}: {}", problem.hostname, problem.error);
+        }
+    }
+
+    if !report.failed.is_empty() {
+        outln!();
+        outln!("  Collection failed:");
+        for problem in &report.failed {
+            outln!("    {}: {}", problem.hostname, problem.error);
+        }
+    }
+}
+
+pub fn build_command(args: &CollectFactsArgs) -> Result<Script> {
+    if let Some(ref columns) = args.csv_columns {
+        if let Some(unknown) = columns.iter().find(|c| !KNOWN_COLUMNS.contains(&c.as_str())) {
+            bail!("Unknown CSV column: {} (valid: {})", unknown, KNOWN_COLUMNS.join(","));
+        }
+    }
+
+    let mut script = Script::new().section(PRELUDE).section(IDENTITY_SECTION);
+    for category in selected_categories(args) {
+        script = script.section(category.section());
+    }
+    Ok(script.section(&format!("echo '{RESULT}status=ok'")))
+}
+
+fn selected_categories(args: &CollectFactsArgs) -> Vec<FactCategory> {
+    let mut categories = args.facts.clone().unwrap_or_else(|| DEFAULT_CATEGORIES.to_vec());
+
+    if args.no_network {
+        warn!("--no-network is deprecated, use --facts to choose categories");
+        categories.retain(|c| *c != FactCategory::Network);
+    }
+    if args.no_disks {
+        warn!("--no-disks is deprecated, use --facts to choose categories");
+        categories.retain(|c| *c != FactCategory::Disks);
+    }
+    if args.include_services {
+        categories.push(FactCategory::Services);
+    }
+    if args.include_hardware {
+        categories.push(FactCategory::Hardware);
+    }
+
+    categories.sort();
+    categories.dedup();
+    categories
+}
+
+// Hosts print one "key<TAB>value" line per fact and the controller builds
+// the JSON (see facts_from_lines). Quotes, backslashes or a missing tool on
+// the host can therefore never produce invalid JSON.
+const PRELUDE: &str = r#"kv() {
+    printf '%s\t%s\n' "$1" "$(printf '%s' "$2" | tr '\t\n' '  ')"
+}
+os=$(uname -s)
+"#;
+
+// Linux, macOS (Darwin) and FreeBSD report the same keys so the CSV and
+// report code never needs to know which platform a host runs. The hostname
+// is always reported so every host produces at least one fact line.
+const IDENTITY_SECTION: &str = r#"kv hostname "$(hostname)"
+"#;
+
+const SYSTEM_SECTION: &str = r#"kv fqdn "$(hostname -f 2>/dev/null || hostname)"
+kv os_family "$os"
+kv kernel "$(uname -r)"
+kv arch "$(uname -m)"
+case "$os" in
+Darwin)
+    kv os_name "$(sw_vers -productName 2>/dev/null || echo macOS)"
+    kv os_version "$(sw_vers -productVersion 2>/dev/null)"
+    kv cpu_count "$(sysctl -n hw.ncpu 2>/dev/null)"
+    boot=$(sysctl -n kern.boottime 2>/dev/null | sed 's/.*sec = \([0-9]*\).*/\1/')
+    [ -n "$boot" ] && kv uptime_seconds "$(( $(date +%s) - boot ))"
+    kv load_average "$(sysctl -n vm.loadavg 2>/dev/null | awk '{ print $2, $3, $4 }')"
+    kv timezone "$(readlink /etc/localtime 2>/dev/null | sed 's|.*/zoneinfo/||')"
+    ;;
+FreeBSD)
+    kv os_name FreeBSD
+    kv os_version "$(freebsd-version 2>/dev/null || uname -r)"
+    kv cpu_count "$(sysctl -n hw.ncpu 2>/dev/null)"
+    boot=$(sysctl -n kern.boottime 2>/dev/null | sed 's/.*sec = \([0-9]*\).*/\1/')
+    [ -n "$boot" ] && kv uptime_seconds "$(( $(date +%s) - boot ))"
+    kv load_average "$(sysctl -n vm.loadavg 2>/dev/null | awk '{ print $2, $3, $4 }')"
+    kv timezone "$(date +%Z)"
+    ;;
+*)
+    [ -r /etc/os-release ] && . /etc/os-release
+    kv os_name "${NAME:-unknown}"
+    kv os_version "${VERSION_ID:-unknown}"
+    kv cpu_count "$(nproc 2>/dev/null || grep -c ^processor /proc/cpuinfo)"
+    kv uptime_seconds "$(awk '{ printf "%d", $1 }' /proc/uptime 2>/dev/null)"
+    kv load_average "$(cut -d' ' -f1-3 /proc/loadavg 2>/dev/null)"
+    if command -v timedatectl >/dev/null 2>&1 && tz=$(timedatectl show -p Timezone --value 2>/dev/null) && [ -n "$tz" ]; then
+        kv timezone "$tz"
+    elif [ -r /etc/timezone ]; then
+        kv timezone "$(cat /etc/timezone)"
+    else
+        kv timezone "$(date +%Z)"
+    fi
+    ;;
+esac
+"#;
+
+const MEMORY_SECTION: &str = r#"case "$os" in
+Darwin)
+    kv memory_total_mb "$(sysctl -n hw.memsize 2>/dev/null | awk '{ printf "%d", $1 / 1048576 }')"
+    kv memory_available_mb "$(vm_stat 2>/dev/null | awk '
+        /page size of/ { size = $8 }
+        /^Pages (free|inactive|speculative)/ { gsub("\\.", "", $NF); pages += $NF }
+        END { if (size) printf "%d", pages * size / 1048576 }')"
+    kv swap_total_mb "$(sysctl -n vm.swapusage 2>/dev/null | awk '{ sub("M", "", $3); printf "%d", $3 }')"
+    ;;
+FreeBSD)
+    kv memory_total_mb "$(sysctl -n hw.physmem 2>/dev/null | awk '{ printf "%d", $1 / 1048576 }')"
+    kv memory_available_mb "$(sysctl -n vm.stats.vm.v_free_count vm.stats.vm.v_inactive_count hw.pagesize 2>/dev/null |
+        awk 'NR < 3 { pages += $1 } NR == 3 { printf "%d", pages * $1 / 1048576 }')"
+    kv swap_total_mb "$(swapinfo -m 2>/dev/null | awk 'NR > 1 { total += $2 } END { printf "%d", total }')"
+    ;;
+*)
+    kv memory_total_mb "$(awk '/^MemTotal:/ { printf "%d", $2 / 1024 }' /proc/meminfo 2>/dev/null)"
+    kv memory_available_mb "$(awk '/^MemAvailable:/ { printf "%d", $2 / 1024 }' /proc/meminfo 2>/dev/null)"
+    kv swap_total_mb "$(awk '/^SwapTotal:/ { printf "%d", $2 / 1024 }' /proc/meminfo 2>/dev/null)"
+    ;;
+esac
+"#;
+
+const PACKAGES_SECTION: &str = r#"case "$os" in
+Darwin) managers="brew port" ;;
+FreeBSD) managers="pkg" ;;
+*) managers="apt-get dnf yum zypper apk pacman" ;;
+esac
+pm=none
+for candidate in $managers; do
+    if command -v "$candidate" >/dev/null 2>&1; then pm=${candidate%-get}; break; fi
+done
+kv package_manager "$pm"
+if command -v docker >/dev/null 2>&1; then
+    kv docker_version "$(docker --version 2>/dev/null | awk '{ gsub(",", "", $3); print $3 }')"
+else
+    kv docker_version none
+fi
+"#;
+
+const SECURITY_SECTION: &str = r#"if command -v getenforce >/dev/null 2>&1; then
+    kv selinux "$(getenforce 2>/dev/null | tr 'A-Z' 'a-z')"
+else
+    kv selinux none
+fi
+if [ -r /sys/module/apparmor/parameters/enabled ] && [ "$(cat /sys/module/apparmor/parameters/enabled)" = Y ]; then
+    kv apparmor enabled
+else
+    kv apparmor none
+fi
+firewall=none
+if command -v ufw >/dev/null 2>&1 && ufw status 2>/dev/null | grep -q 'Status: active'; then
+    firewall=ufw
+elif command -v firewall-cmd >/dev/null 2>&1 && firewall-cmd --state >/dev/null 2>&1; then
+    firewall=firewalld
+elif [ "$os" = Darwin ] && /usr/libexec/ApplicationFirewall/socketfilterfw --getglobalstate 2>/dev/null | grep -q enabled; then
+    firewall=application-firewall
+elif [ "$os" = FreeBSD ] && [ "$(sysctl -n net.inet.ip.fw.enable 2>/dev/null)" = 1 ]; then
+    firewall=ipfw
+elif command -v nft >/dev/null 2>&1 && [ -n "$(nft list ruleset 2>/dev/null)" ]; then
+    firewall=nftables
+fi
+kv firewall "$firewall"
+if [ -r /etc/ssh/sshd_config ]; then
+    kv ssh_permit_root_login "$(awk 'tolower($1) == "permitrootlogin" { print $2; exit }' /etc/ssh/sshd_config)"
+else
+    kv ssh_permit_root_login permission-denied
+fi
+"#;
+
+const NETWORK_SECTION: &str = r#"if [ "$os" = Linux ] && command -v ip >/dev/null 2>&1; then
+    addresses=$(ip -o addr show scope global 2>/dev/null | awk '{ split($4, a, "/"); print a[1] }')
+    kv default_gateway "$(ip route show default 2>/dev/null | awk '/default/ { print $3; exit }')"
+elif [ "$os" = Linux ]; then
+    addresses=$(hostname -I 2>/dev/null || ifconfig 2>/dev/null | awk '/inet / && $2 !~ /^127\./ { sub("addr:", "", $2); print $2 }')
+    kv default_gateway "$(route -n 2>/dev/null | awk '$1 == "0.0.0.0" { print $2; exit }')"
+else
+    addresses=$(ifconfig 2>/dev/null | awk '($1 == "inet" && $2 !~ /^127\./) || ($1 == "inet6" && $2 !~ /^(::1|fe80)/) { sub("%.*", "", $2); print $2 }')
+    kv default_gateway "$(route -n get default 2>/dev/null | awk '/gateway:/ { print $2; exit }')"
+fi
+for ip in $addresses; do
+    kv ip_address "$ip"
+done
+"#;
+
+const DISKS_SECTION: &str = r#"df -P -m 2>/dev/null | awk 'NR > 1 && $1 !~ /^(tmpfs|devtmpfs|overlay|shm|none|udev|devfs|fdescfs|procfs|map)/ {
+    sub("%", "", $5); printf "disk\t%s\t%s\t%s\t%s\t%s\t%s\n", $6, $1, $2, $3, $4, $5 }'
+"#;
+
+// DMI data is world-readable except the serial; anything we cannot read
+// without root is reported as "permission-denied" rather than failing.
+const HARDWARE_SECTION: &str = r#"case "$os" in
+Darwin)
+    kv vendor Apple
+    kv product "$(sysctl -n hw.model 2>/dev/null)"
+    kv serial "$(ioreg -rd1 -c IOPlatformExpertDevice 2>/dev/null | awk -F'"' '/IOPlatformSerialNumber/ { print $4 }')"
+    if [ "$(sysctl -n kern.hv_vmm_present 2>/dev/null)" = 1 ]; then kv virtualization vm; else kv virtualization none; fi
+    kv cpu_model "$(sysctl -n machdep.cpu.brand_string 2>/dev/null)"
+    kv disk_count "$(diskutil list physical 2>/dev/null | grep -c '^/dev/')"
+    ;;
+FreeBSD)
+    kv vendor "$(kenv -q smbios.system.maker 2>/dev/null)"
+    kv product "$(kenv -q smbios.system.product 2>/dev/null)"
+    kv serial "$(kenv -q smbios.system.serial 2>/dev/null)"
+    kv virtualization "$(sysctl -n kern.vm_guest 2>/dev/null)"
+    kv cpu_model "$(sysctl -n hw.model 2>/dev/null)"
+    kv disk_count "$(sysctl -n kern.disks 2>/dev/null | wc -w | tr -d ' ')"
+    ;;
+*)
+    dmi() {
+        f=/sys/class/dmi/id/$1
+        if [ -r "$f" ]; then
+            cat "$f"
+        elif command -v dmidecode >/dev/null 2>&1 && [ "$(id -u)" = 0 ]; then
+            dmidecode -s "$2" 2>/dev/null
+        elif [ -e "$f" ] || command -v dmidecode >/dev/null 2>&1; then
+            echo permission-denied
+        fi
+    }
+    product=$(dmi product_name system-product-name) || true
+    kv vendor "$(dmi sys_vendor system-manufacturer)"
+    kv product "$product"
+    kv serial "$(dmi product_serial system-serial-number)"
+    if command -v systemd-detect-virt >/dev/null 2>&1; then
+        virt=$(systemd-detect-virt 2>/dev/null) || true
+    else
+        case "$product" in
+            *VMware*) virt=vmware ;;
+            *VirtualBox*) virt=oracle ;;
+            *KVM*|*QEMU*|*"Standard PC"*) virt=kvm ;;
+            *"Virtual Machine"*) virt=microsoft ;;
+            *) if grep -qw hypervisor /proc/cpuinfo 2>/dev/null; then virt=vm-unknown; else virt=none; fi ;;
+        esac
+    fi
+    kv virtualization "${virt:-none}"
+    kv cpu_model "$(awk -F': ' '/^model name/ { print $2; exit }' /proc/cpuinfo 2>/dev/null)"
+    if command -v lsblk >/dev/null 2>&1; then
+        disks_raw=$(lsblk -dnb -o SIZE,TYPE 2>/dev/null | awk '$2 == "disk" { print $1 }')
+    else
+        disks_raw=$(for d in /sys/block/*; do
+            case "${d##*/}" in loop*|ram*|dm-*|zram*) continue ;; esac
+            [ -r "$d/size" ] && echo $(( $(cat "$d/size") * 512 ))
+        done) || true
+    fi
+    kv disk_count "$(echo "$disks_raw" | grep -c .)"
+    kv disk_total_gb "$(echo "$disks_raw" | awk '{ total += $1 } END { printf "%d", total / 1073741824 }')"
+    ;;
+esac
+"#;
+
+const SERVICES_SECTION: &str = r#"case "$os" in
+Darwin)
+    launchctl list 2>/dev/null | awk 'NR > 1 && $1 != "-" { print $3 }' ;;
+FreeBSD)
+    service -e 2>/dev/null | sed 's|.*/||' ;;
+*)
+    if command -v systemctl >/dev/null 2>&1; then
+        systemctl list-units --type=service --state=running --no-legend --plain 2>/dev/null | awk '{ sub("\\.service$", "", $1); print $1 }'
+    elif command -v rc-status >/dev/null 2>&1; then
+        rc-status -s 2>/dev/null | awk '/started/ { print $1 }'
+    else
+        service --status-all 2>/dev/null | awk '$2 == "+" { print $4 }'
+    fi ;;
+esac | while read -r name; do kv service "$name"; done
+"#;
+
+const NUMERIC_FACTS: &[&str] = &[
+    "cpu_count", "memory_total_mb", "memory_available_mb", "swap_total_mb", "uptime_seconds",
+    "disk_count", "disk_total_gb",
+];
+
+fn fact_number(value: &str) -> serde_json::Value {
+    value.trim().parse::<u64>()
+        .map(serde_json::Value::from)
+        .unwrap_or(serde_json::Value::Null)
+}
+
+/// Convert the host's "key<TAB>value" lines into the facts JSON object
+pub fn facts_from_lines(output: &str) -> Option<serde_json::Map<String, serde_json::Value>> {
+    let mut facts = serde_json::Map::new();
+    let mut addresses = Vec::new();
+    let mut disks = Vec::new();
+    let mut services = Vec::new();
+    let mut seen = false;
+
+    for line in output.lines() {
+        let mut fields = line.trim_end_matches('\r').split('\t');
+        let (Some(key), Some(value)) = (fields.next(), fields.next()) else {
+            continue;
+        };
+        seen = true;
+
+        match key {
+            "ip_address" => addresses.push(serde_json::Value::from(value)),
+            "service" => services.push(serde_json::Value::from(value)),
+            "disk" => {
+                // disk<TAB>mount<TAB>filesystem<TAB>size<TAB>used<TAB>available<TAB>use%
+                let rest: Vec<&str> = fields.collect();
+                let field = |index: usize| rest.get(index).copied().unwrap_or("");
+                disks.push(serde_json::json!({
+                    "mount": value,
+                    "filesystem": field(0),
+                    "size_mb": fact_number(field(1)),
+                    "used_mb": fact_number(field(2)),
+                    "available_mb": fact_number(field(3)),
+                    "use_percent": fact_number(field(4)),
+                }));
+            }
+            _ if NUMERIC_FACTS.contains(&key) => {
+                facts.insert(key.to_string(), fact_number(value));
+            }
+            _ => {
+                let value = value.trim();
+                let value = if value.is_empty() || value == "unknown" {
+                    serde_json::Value::Null
+                } else {
+                    serde_json::Value::from(value)
+                };
+                facts.insert(key.to_string(), value);
+            }
+        }
+    }
+
+    if !seen {
+        return None;
+    }
+    if !addresses.is_empty() || facts.contains_key("default_gateway") {
+        facts.insert("ip_addresses".to_string(), serde_json::Value::Array(addresses));
+    }
+    if !disks.is_empty() {
+        facts.insert("disks".to_string(), serde_json::Value::Array(disks));
+    }
+    if !services.is_empty() {
+        facts.insert("services".to_string(), serde_json::Value::Array(services));
+    }
+    Some(facts)
+}
+
+/// Replace the raw fact lines with the JSON object consumers expect
+pub fn post_process(result: &mut HostResult) {
+    let Some(ref stdout) = result.stdout else {
+        return;
+    };
+
+    // Output that isn't in the line protocol is left alone and reported as malformed later
+    if let Some(facts) = facts_from_lines(stdout) {
+        let facts = serde_json::Value::Object(facts);
+        result.stdout = Some(facts.to_string());
+        result.stdout_lines = None;
+        result.module_result.get_or_insert_with(Default::default).data.insert("facts".to_string(), facts);
+    }
+}
+
+fn csv_escape(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn csv_value(value: Option<&serde_json::Value>) -> String {
+    match value {
+        None | Some(serde_json::Value::Null) => String::new(),
+        Some(serde_json::Value::String(s)) => s.clone(),
+        Some(serde_json::Value::Array(items)) if items.iter().all(|i| !i.is_object() && !i.is_array()) => {
+            items.iter()
+                .map(|item| item.as_str().map(str::to_string).unwrap_or_else(|| item.to_string()))
+                .collect::<Vec<_>>()
+                .join(";")
+        }
+        Some(other) => other.to_string(),
+    }
+}
+
+/// Assemble the local CSV and fleet report from every host's facts
+pub fn finish(args: &CollectFactsArgs, results: &[HostResult], json: bool) -> Result<()> {
+    if let Some(ref path) = args.csv_output {
+        write_csv(args, path, results)?;
+    }
+
+    if let Some(ref path) = args.save_cache {
+        let saved = FactsCache::save(path, results)?;
+        info!("Saved facts for {} hosts to {}", saved, path);
+    }
+
+    if args.report {
+        let report = build_report(results, args.disk_free_threshold);
+        if json {
+            json_array::record(&serde_json::json!({ "report": report }));
+        } else {
+            print_report(&report, args.disk_free_threshold);
+        }
+    }
+
+    Ok(())
+}
+
+/// Write one CSV row per host from the facts JSON each host printed
+fn write_csv(args: &CollectFactsArgs, path: &str, results: &[HostResult]) -> Result<()> {
+    let columns: Vec<&str> = match args.csv_columns {
+        Some(ref columns) => columns.iter().map(String::as_str).collect(),
+        None => DEFAULT_CSV_COLUMNS.to_vec(),
+    };
+
+    let mut file = std::fs::File::create(path)
+        .with_context(|| format!("Failed to create CSV output: {}", path))?;
+
+    let mut header: Vec<&str> = columns.clone();
+    if !header.contains(&"hostname") {
+        header.insert(0, "hostname");
+    }
+    header.push("error");
+    writeln!(file, "{}", header.join(","))?;
+
+    for result in results {
+        let facts = match (result.success, result.stdout.as_deref()) {
+            (true, Some(stdout)) => serde_json::from_str::<serde_json::Value>(stdout)
+                .map_err(|e| format!("Invalid facts JSON: {}", e)),
+            _ => Err(result.error_message().unwrap_or("Fact collection failed").to_string()),
+        };
+
+        let row: Vec<String> = header.iter()
+            .map(|column| match (*column, &facts) {
+                // The inventory name is what the user targeted, not what the host calls itself
+                ("hostname", _) => result.hostname.clone(),
+                ("error", Ok(_)) => String::new(),
+                ("error", Err(e)) => e.clone(),
+                (_, Ok(facts)) => csv_value(facts.get(*column)),
+                (_, Err(_)) => String::new(),
+            })
+            .map(|value| csv_escape(&value))
+            .collect();
+        writeln!(file, "{}", row.join(","))?;
+    }
+
+    info!("Wrote facts for {} hosts to {}", results.len(), path);
+    Ok(())
+}
@@ -0,0 +1,110 @@
+// [modules/contract.rs] - KRUST - Marker lines modules print for the controller, parsed into module_result
+//
+// A module script reports its outcome with lines such as
+//
+//   KRUST::RESULT status=changed
+//   KRUST::FACT reboot_required=true
+//   KRUST::METRIC packages_updated=12
+//   KRUST::DATA {"rules": ["..."]}
+//
+// The lines are removed from stdout, so text output only shows what is meant for people.
+use serde::Serialize;
+use serde_json::{Map, Value};
+use tracing::debug;
+
+pub const RESULT: &str = "KRUST::RESULT ";
+pub const FACT: &str = "KRUST::FACT ";
+pub const METRIC: &str = "KRUST::METRIC ";
+pub const DATA: &str = "KRUST::DATA ";
+
+/// What a module reported through marker lines
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ModuleResult {
+    /// ok, changed, failed or skipped, from KRUST::RESULT
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    /// KRUST::FACT values and KRUST::DATA objects
+    #[serde(skip_serializing_if = "Map::is_empty")]
+    pub data: Map<String, Value>,
+    #[serde(skip_serializing_if = "Map::is_empty")]
+    pub metrics: Map<String, Value>,
+}
+
+impl ModuleResult {
+    pub fn fact(&self, key: &str) -> Option<&Value> {
+        self.data.get(key)
+    }
+}
+
+/// Remove the marker lines from a module's output and parse them; None when there are none
+pub fn extract(stdout: &mut String) -> Option<ModuleResult> {
+    if !stdout.contains("KRUST::") {
+        return None;
+    }
+    let mut parsed = ModuleResult::default();
+    let mut kept = Vec::new();
+    for line in stdout.lines() {
+        if !parse_line(line, &mut parsed) {
+            kept.push(line);
+        }
+    }
+    let mut text = kept.join("\n");
+    if !text.is_empty() && stdout.ends_with('\n') {
+        text.push('\n');
+    }
+    *stdout = text;
+    Some(parsed)
+}
+
+/// Returns false for lines that are not markers
+fn parse_line(line: &str, parsed: &mut ModuleResult) -> bool {
+    if let Some(rest) = line.strip_prefix(RESULT) {
+        for (key, value) in pairs(rest) {
+            match key {
+                "status" => parsed.status = Some(value.to_string()),
+                _ => {
+                    parsed.data.insert(key.to_string(), scalar(value));
+                }
+            }
+        }
+    } else if let Some(rest) = line.strip_prefix(FACT) {
+        for (key, value) in pairs(rest) {
+            parsed.data.insert(key.to_string(), scalar(value));
+        }
+    } else if let Some(rest) = line.strip_prefix(METRIC) {
+        for (key, value) in pairs(rest) {
+            parsed.metrics.insert(key.to_string(), scalar(value));
+        }
+    } else if let Some(rest) = line.strip_prefix(DATA) {
+        match serde_json::from_str(rest) {
+            Ok(Value::Object(object)) => parsed.data.extend(object),
+            Ok(other) => {
+                parsed.data.insert("data".to_string(), other);
+            }
+            Err(e) => debug!("Ignoring malformed {}line: {}", DATA, e),
+        }
+    } else {
+        return false;
+    }
+    true
+}
+
+/// `a=1 b=two` pairs; a lone pair's value runs to the end of the line, spaces and all
+fn pairs(text: &str) -> Vec<(&str, &str)> {
+    match text.split_once('=') {
+        Some((key, value)) if !key.contains(' ') && !value.contains('=') => vec![(key, value)],
+        _ => text.split_whitespace().filter_map(|pair| pair.split_once('=')).collect(),
+    }
+}
+
+/// true/false and numbers keep their type; everything else is a string
+fn scalar(value: &str) -> Value {
+    match value {
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        _ => value.parse::<i64>().map(Value::from)
+            .ok()
+            .or_else(|| value.parse::<f64>().ok().filter(|n| n.is_finite()).map(Value::from))
+            .unwrap_or_else(|| Value::from(value)),
+    }
+}
@@ -0,0 +1,669 @@
+// [modules/copy.rs] - KRUST - Upload a local file or directory tree over SFTP, skipping what is already there
+use anyhow::{bail, Result};
+use clap::Args;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::time::timeout;
+use tracing::debug;
+
+use super::contract::RESULT;
+use super::script::Script;
+use super::{run_follow_up, shell_quote};
+use crate::executor::{CommandExecutor, Transport};
+use crate::host_error::{HostError, LocalErrorKind};
+use crate::ssh_executor::SshHost;
+use crate::HostResult;
+
+/// Lines the destination inventory is reported on; they never reach the result's stdout
+const INVENTORY: &str = "KRUST::COPY ";
+/// Lines of the script installing a single file
+const FAILED: &str = "KRUST::COPY-FAILED ";
+const BACKUP: &str = "KRUST::COPY-BACKUP ";
+const CLEANUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Args, Debug, Clone)]
+pub struct CopyArgs {
+    /// Local file, or directory with -r; with a trailing / only the directory's contents are copied
+    pub src: String,
+
+    /// Absolute destination on each host; a trailing / puts a file inside the directory
+    pub dest: String,
+
+    /// Copy directories and everything below them
+    #[arg(short, long)]
+    pub recursive: bool,
+
+    /// Give remote files the local modification times
+    #[arg(long)]
+    pub preserve_times: bool,
+
+    /// Compare files by size and sha256 instead of size and modification time
+    #[arg(long)]
+    pub checksum: bool,
+
+    /// Recreate symlinks as symlinks
+    #[arg(long, conflicts_with = "copy_links")]
+    pub links: bool,
+
+    /// Copy what symlinks point to instead of the links
+    #[arg(long)]
+    pub copy_links: bool,
+
+    /// Owner of a copied file as user or user:group, set with chown (needs root; single files)
+    #[arg(long)]
+    pub owner: Option<String>,
+
+    /// Keep the file being replaced as <name>.krust-bak.<timestamp> (single files)
+    #[arg(long)]
+    pub backup: bool,
+}
+
+/// One change to make on a host, applied in order over a single SFTP session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Upload {
+    /// Create the directory if `create`, then set its mode
+    Dir { remote: String, mode: u32, create: bool },
+    /// Write the local file if `write`, then set its mode and, if given, its mtime
+    File { local: PathBuf, remote: String, mode: u32, mtime: Option<u64>, write: bool },
+    /// Write content rendered on the controller, then set its mode
+    Content { content: Vec<u8>, remote: String, mode: u32 },
+    /// Create a symlink to `target`, removing the link already there if `replace`
+    Symlink { remote: String, target: String, replace: bool },
+    /// Set a directory's mtime once everything inside it is written
+    Times { remote: String, mtime: u64 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Kind {
+    Dir,
+    File,
+    Symlink(String),
+}
+
+/// A local file, directory or link to copy, by its path below the copied root
+#[derive(Debug, Clone)]
+struct Entry {
+    relative: String,
+    local: PathBuf,
+    kind: Kind,
+    mode: u32,
+    size: u64,
+    mtime: u64,
+}
+
+/// The local side of a copy, scanned once per run
+#[derive(Debug)]
+pub struct Source {
+    args: CopyArgs,
+    /// Remote path the source root maps to
+    root: String,
+    entries: Vec<Entry>,
+    /// Local entries that cannot be copied, with the reason; they fail every host
+    problems: Vec<(String, String)>,
+    /// Entries left out on purpose (sockets, symlinks without --links), reported but not failed
+    skipped: Vec<(String, String)>,
+    /// sha256 of a single-file source, which is staged beside its destination and verified there
+    sha256: Option<String>,
+}
+
+/// Where the source root lands, following rsync: "dir/" copies the contents into dest,
+/// "dir" copies the directory itself into dest, and a file goes into dest when dest ends in /
+fn remote_root(args: &CopyArgs, is_dir: bool) -> String {
+    let dest = match args.dest.trim_end_matches('/') {
+        "" => "/",
+        dest => dest,
+    };
+    let name = Path::new(args.src.trim_end_matches('/')).file_name().map(|n| n.to_string_lossy().into_owned());
+    let inside = match is_dir {
+        true => !args.src.ends_with('/'),
+        false => args.dest.ends_with('/'),
+    };
+    match (inside, name) {
+        (true, Some(name)) => join(dest, &name),
+        _ => dest.to_string(),
+    }
+}
+
+fn join(dir: &str, name: &str) -> String {
+    match name {
+        "" => dir.to_string(),
+        _ if dir.is_empty() => name.to_string(),
+        _ if dir.ends_with('/') => format!("{}{}", dir, name),
+        _ => format!("{}/{}", dir, name),
+    }
+}
+
+pub fn build_command(args: &CopyArgs) -> Result<Script> {
+    if !args.dest.starts_with('/') {
+        bail!("copy destination must be absolute: {}", args.dest);
+    }
+    if args.dest.contains('\n') || args.dest.contains('\0') {
+        bail!("copy destination must be a single line");
+    }
+    let metadata = match args.copy_links {
+        true => fs::metadata(&args.src),
+        false => fs::symlink_metadata(&args.src),
+    };
+    let metadata = metadata.map_err(|e| anyhow::anyhow!("Cannot read {}: {}", args.src, e))?;
+    if metadata.is_dir() && !args.recursive {
+        bail!("{} is a directory (add -r to copy it)", args.src);
+    }
+    if metadata.is_dir() && (args.owner.is_some() || args.backup) {
+        bail!("--owner and --backup apply to single-file copies");
+    }
+    if let Some(ref owner) = args.owner {
+        check_owner(owner)?;
+    }
+
+    // The inventory of what is already at the destination: S lines with the raw mode in hex, size,
+    // mtime and path; L lines with link targets; C lines with checksums under --checksum, and
+    // always for a single file; O lines with a single file's owner by name and id
+    let inventory = format!(r#"parent=$(dirname "$root")
+if [ ! -d "$parent" ]; then
+    mkdir -p "$parent" || exit 1
+    echo "created: $parent"
+fi
+if [ -e "$root" ] || [ -L "$root" ]; then
+    find "$root" -exec stat -c '{INVENTORY}S	%f	%s	%Y	%n' {{}} + || true
+    find "$root" -type l | while IFS= read -r link; do
+        printf '{INVENTORY}L\t%s\t%s\n' "$link" "$(readlink "$link")"
+    done
+    if [ "$checksum" = 1 ]; then
+        find "$root" -type f -exec sha256sum {{}} + | sed 's/^/{INVENTORY}C	/'
+    fi
+    if [ "$single" = 1 ] && [ -f "$root" ]; then
+        stat -c '{INVENTORY}O	%U:%G	%u:%g	%n' "$root"
+    fi
+fi
+echo "{RESULT}status=ok"
+"#);
+
+    Ok(Script::new()
+        .var("root", remote_root(args, metadata.is_dir()))
+        .var("checksum", if args.checksum || !metadata.is_dir() { 1 } else { 0 })
+        .var("single", if metadata.is_dir() { 0 } else { 1 })
+        .section(&inventory))
+}
+
+/// user or user:group, as names or numeric ids
+fn check_owner(owner: &str) -> Result<()> {
+    let part = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+    let valid = match owner.split_once(':') {
+        Some((user, group)) => part(user) && part(group),
+        None => part(owner),
+    };
+    if !valid {
+        bail!("Invalid --owner: {:?} (expected user or user:group)", owner);
+    }
+    Ok(())
+}
+
+fn mode_of(metadata: &fs::Metadata) -> u32 {
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::PermissionsExt::mode(&metadata.permissions()) & 0o7777
+    }
+    #[cfg(not(unix))]
+    {
+        match (metadata.is_dir(), metadata.permissions().readonly()) {
+            (true, _) => 0o755,
+            (false, true) => 0o444,
+            (false, false) => 0o644,
+        }
+    }
+}
+
+impl Source {
+    /// Walk the local tree; unreadable or unsupported entries are noted, not fatal
+    pub fn scan(args: &CopyArgs) -> Self {
+        let is_dir = match args.copy_links {
+            true => fs::metadata(&args.src),
+            false => fs::symlink_metadata(&args.src),
+        }.is_ok_and(|m| m.is_dir());
+        let mut source = Source {
+            args: args.clone(),
+            root: remote_root(args, is_dir),
+            entries: Vec::new(),
+            problems: Vec::new(),
+            skipped: Vec::new(),
+            sha256: None,
+        };
+        let mut ancestors = HashSet::new();
+        source.visit(PathBuf::from(&args.src), String::new(), &mut ancestors);
+        if let Some(entry) = source.single_file() {
+            match sha256_file(&entry.local) {
+                Ok(sum) => source.sha256 = Some(sum),
+                Err(e) => source.problems.push((source.args.src.clone(), e.to_string())),
+            }
+        }
+        source
+    }
+
+    /// The source root when it is one regular file
+    fn single_file(&self) -> Option<&Entry> {
+        match self.entries.as_slice() {
+            [entry] if entry.kind == Kind::File && entry.relative.is_empty() => Some(entry),
+            _ => None,
+        }
+    }
+
+    fn visit(&mut self, local: PathBuf, relative: String, ancestors: &mut HashSet<PathBuf>) {
+        let shown = match relative.as_str() {
+            "" => self.args.src.clone(),
+            relative => relative.to_string(),
+        };
+        if relative.contains(['\n', '\t']) {
+            self.problems.push((shown, "names with tabs or newlines are not supported".to_string()));
+            return;
+        }
+        let metadata = match fs::symlink_metadata(&local) {
+            Ok(metadata) if metadata.file_type().is_symlink() && self.args.copy_links => fs::metadata(&local),
+            other => other,
+        };
+        let metadata = match metadata {
+            Ok(metadata) => metadata,
+            Err(e) => return self.problems.push((shown, e.to_string())),
+        };
+        let mtime = metadata.modified().ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |elapsed| elapsed.as_secs());
+        let entry = |kind| Entry { relative: relative.clone(), local: local.clone(), kind, mode: mode_of(&metadata), size: metadata.len(), mtime };
+
+        let file_type = metadata.file_type();
+        if file_type.is_symlink() {
+            match (self.args.links, fs::read_link(&local)) {
+                (true, Ok(target)) => self.entries.push(entry(Kind::Symlink(target.to_string_lossy().into_owned()))),
+                (true, Err(e)) => self.problems.push((shown, e.to_string())),
+                (false, _) => self.skipped.push((shown, "symlink (use --links or --copy-links)".to_string())),
+            }
+        } else if file_type.is_file() {
+            self.entries.push(entry(Kind::File));
+        } else if file_type.is_dir() {
+            // --copy-links can lead back into a directory being copied
+            let canonical = fs::canonicalize(&local).unwrap_or_else(|_| local.clone());
+            if !ancestors.insert(canonical.clone()) {
+                return self.problems.push((shown, "symlink loop".to_string()));
+            }
+            self.entries.push(entry(Kind::Dir));
+            match fs::read_dir(&local) {
+                Ok(children) => {
+                    let mut names: Vec<String> = children.filter_map(|child| child.ok())
+                        .map(|child| child.file_name().to_string_lossy().into_owned())
+                        .collect();
+                    names.sort();
+                    for name in names {
+                        self.visit(local.join(&name), join(&relative, &name), ancestors);
+                    }
+                }
+                Err(e) => self.problems.push((shown, e.to_string())),
+            }
+            ancestors.remove(&canonical);
+        } else {
+            self.skipped.push((shown, "not a regular file, directory or symlink".to_string()));
+        }
+    }
+
+    fn remote_path(&self, entry: &Entry) -> String {
+        join(&self.root, &entry.relative)
+    }
+}
+
+/// What the host already has at one path
+#[derive(Debug, Default, Clone)]
+struct Remote {
+    kind: Option<Kind>,
+    mode: u32,
+    size: u64,
+    mtime: u64,
+    sha256: Option<String>,
+    /// user:group by name and by id
+    owner: Vec<String>,
+}
+
+/// Parse the inventory lines out of the module's output; the other lines are returned as they were
+fn take_inventory(stdout: &str) -> (HashMap<String, Remote>, Vec<String>) {
+    let mut remote: HashMap<String, Remote> = HashMap::new();
+    let mut kept = Vec::new();
+    for line in stdout.lines() {
+        let Some(line) = line.strip_prefix(INVENTORY) else {
+            kept.push(line.to_string());
+            continue;
+        };
+        let fields: Vec<&str> = line.splitn(5, '\t').collect();
+        match fields.as_slice() {
+            ["S", raw, size, mtime, path] => {
+                let raw = u32::from_str_radix(raw, 16).unwrap_or(0);
+                let entry = remote.entry(path.to_string()).or_default();
+                entry.kind = match raw & 0o170000 {
+                    0o040000 => Some(Kind::Dir),
+                    0o100000 => Some(Kind::File),
+                    // Filled in by the L line
+                    0o120000 => Some(Kind::Symlink(String::new())),
+                    _ => None,
+                };
+                entry.mode = raw & 0o7777;
+                entry.size = size.parse().unwrap_or(0);
+                entry.mtime = mtime.parse().unwrap_or(0);
+            }
+            ["L", path, target @ ..] => {
+                let target = target.join("\t");
+                remote.entry(path.to_string()).or_default().kind = Some(Kind::Symlink(target));
+            }
+            ["O", names, ids, path @ ..] => {
+                remote.entry(path.join("\t")).or_default().owner = vec![names.to_string(), ids.to_string()];
+            }
+            ["C", rest @ ..] => {
+                if let Some((sum, path)) = rest.join("\t").split_once("  ") {
+                    remote.entry(path.to_string()).or_default().sha256 = Some(sum.to_string());
+                }
+            }
+            _ => debug!("Ignoring inventory line: {}", line),
+        }
+    }
+    (remote, kept)
+}
+
+fn sha256_file(path: &Path) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Created,
+    Updated,
+    /// Directories: created or fixed up, not counted as files
+    Directory,
+    Times,
+}
+
+/// What a host needs: the uploads with what each one counts as, the files already in place,
+/// and entries that cannot be copied there
+struct Plan {
+    uploads: Vec<(Upload, Action, String)>,
+    skipped: usize,
+    conflicts: Vec<(String, String)>,
+}
+
+/// "root" only asks for the user, "root:root" for both; names and ids both match
+fn owner_matches(wanted: &str, current: &[String]) -> bool {
+    current.iter().any(|current| match wanted.contains(':') {
+        true => wanted == current,
+        false => current.split(':').next() == Some(wanted),
+    })
+}
+
+fn plan(source: &Source, remote: &HashMap<String, Remote>) -> Plan {
+    let mut plan = Plan { uploads: Vec::new(), skipped: 0, conflicts: Vec::new() };
+    let preserve = source.args.preserve_times;
+    let mut times = Vec::new();
+    for entry in &source.entries {
+        let path = source.remote_path(entry);
+        let there = remote.get(&path);
+        let there_kind = there.and_then(|r| r.kind.as_ref());
+        let mut push = |upload, action| plan.uploads.push((upload, action, path.clone()));
+        match (&entry.kind, there_kind) {
+            (Kind::Dir, None) => push(Upload::Dir { remote: path.clone(), mode: entry.mode, create: true }, Action::Directory),
+            (Kind::Dir, Some(Kind::Dir)) => {
+                if there.is_some_and(|r| r.mode != entry.mode) {
+                    push(Upload::Dir { remote: path.clone(), mode: entry.mode, create: false }, Action::Directory);
+                }
+            }
+            (Kind::File, None) => push(Upload::File {
+                local: entry.local.clone(), remote: path.clone(), mode: entry.mode, mtime: preserve.then_some(entry.mtime), write: true,
+            }, Action::Created),
+            (Kind::File, Some(Kind::File)) => {
+                let there = there.cloned().unwrap_or_default();
+                let same = there.size == entry.size && match (&source.sha256, source.args.checksum) {
+                    (Some(sum), _) => there.sha256.as_ref() == Some(sum),
+                    (None, true) => there.sha256.is_some() && sha256_file(&entry.local).ok() == there.sha256,
+                    (None, false) => there.mtime == entry.mtime,
+                };
+                let stale_times = preserve && there.mtime != entry.mtime;
+                let stale_owner = source.args.owner.as_deref().is_some_and(|owner| !owner_matches(owner, &there.owner));
+                if !same || there.mode != entry.mode || stale_times || stale_owner {
+                    push(Upload::File {
+                        local: entry.local.clone(), remote: path.clone(), mode: entry.mode, mtime: preserve.then_some(entry.mtime), write: !same,
+                    }, Action::Updated);
+                } else {
+                    plan.skipped += 1;
+                }
+            }
+            (Kind::Symlink(target), None) => push(Upload::Symlink { remote: path.clone(), target: target.clone(), replace: false }, Action::Created),
+            (Kind::Symlink(target), Some(Kind::Symlink(current))) => match target == current {
+                true => plan.skipped += 1,
+                false => push(Upload::Symlink { remote: path.clone(), target: target.clone(), replace: true }, Action::Updated),
+            },
+            (_, Some(_)) => {
+                let what = match there_kind {
+                    Some(Kind::Dir) => "a directory",
+                    Some(Kind::File) => "a file",
+                    _ => "a symlink",
+                };
+                plan.conflicts.push((path.clone(), format!("exists as {}", what)));
+            }
+        }
+        if entry.kind == Kind::Dir && preserve {
+            times.push((Upload::Times { remote: path.clone(), mtime: entry.mtime }, Action::Times, path));
+        }
+    }
+    // Innermost directories last written, so their parents' times are set after them
+    plan.uploads.extend(times.into_iter().rev());
+    plan
+}
+
+/// Compare the host's inventory with the local tree and upload what differs over SFTP.
+/// Files that fail are reported one by one; the rest of the tree is still copied.
+pub async fn upload<E: CommandExecutor>(
+    transport: &Arc<Transport<E>>,
+    host: &SshHost,
+    result: &mut HostResult,
+    source: &Source,
+    limit: Duration,
+) {
+    if !result.success {
+        return;
+    }
+    let (remote, mut lines) = take_inventory(result.stdout.as_deref().unwrap_or_default());
+    let previous = source.single_file()
+        .and_then(|entry| remote.get(&source.remote_path(entry)))
+        .and_then(|there| there.sha256.clone());
+    let plan = plan(source, &remote);
+    let mut failures: Vec<(String, String)> = source.problems.iter().chain(&plan.conflicts).cloned().collect();
+
+    let mut changes: Vec<Upload> = plan.uploads.iter().map(|(upload, _, _)| upload.clone()).collect();
+    // A single file is written beside its destination and only renamed over it once verified
+    let staged = match (source.single_file(), changes.as_mut_slice()) {
+        (Some(_), [Upload::File { remote, write, .. }]) => {
+            let staging = staging_path(remote);
+            let dest = std::mem::replace(remote, staging.clone());
+            *write = true;
+            Some((dest, staging))
+        }
+        _ => None,
+    };
+    let outcomes = match changes.is_empty() {
+        true => Ok(Vec::new()),
+        false => apply(transport, host, changes, limit).await,
+    };
+    let mut outcomes = match outcomes {
+        Ok(outcomes) => outcomes,
+        Err(e) => {
+            if let Some((_, ref staging)) = staged {
+                discard(transport, host, staging).await;
+            }
+            result.fail(HostError::local(LocalErrorKind::Module, format!("copy: {}", e), None));
+            return;
+        }
+    };
+    let mut backup = None;
+    if let (Some((dest, staging)), Some(outcome)) = (&staged, outcomes.first_mut()) {
+        match outcome {
+            Ok(_) => match install(transport, host, source, dest, staging, limit).await {
+                Ok(saved) => backup = saved,
+                Err(e) => *outcome = Err(e),
+            },
+            Err(_) => discard(transport, host, staging).await,
+        }
+    }
+
+    let (mut created, mut updated, mut dirs, mut bytes) = (0u64, 0u64, 0u64, 0u64);
+    for ((_, action, path), outcome) in plan.uploads.iter().zip(outcomes) {
+        match outcome {
+            Ok(written) => {
+                bytes += written;
+                match action {
+                    Action::Created => { created += 1; lines.push(format!("created: {}", path)); }
+                    Action::Updated => { updated += 1; lines.push(format!("updated: {}", path)); }
+                    Action::Directory => dirs += 1,
+                    Action::Times => {}
+                }
+            }
+            Err(e) => failures.push((path.clone(), e)),
+        }
+    }
+    if let (Some(entry), 1) = (source.single_file(), plan.skipped) {
+        lines.push(format!("unchanged: {}", source.remote_path(entry)));
+    }
+    if let Some(ref backup) = backup {
+        lines.push(format!("backup: {}", backup));
+    }
+    for (path, reason) in &source.skipped {
+        lines.push(format!("skipped: {} ({})", path, reason));
+    }
+    for (path, error) in &failures {
+        lines.push(format!("failed: {}: {}", path, error));
+    }
+    lines.push(format!(
+        "created={} updated={} skipped={} failed={} bytes={}",
+        created, updated, plan.skipped, failures.len(), bytes
+    ));
+    result.set_stdout(lines.join("\n"));
+
+    let mut counts = json!({
+        "created": created,
+        "updated": updated,
+        "skipped": plan.skipped,
+        "failed": failures.len(),
+        "dirs_changed": dirs,
+        "bytes": bytes,
+    });
+    if let Some(ref sha256) = source.sha256 {
+        counts["sha256"] = sha256.as_str().into();
+        counts["previous_sha256"] = previous.into();
+        counts["backup"] = backup.into();
+    }
+    result.module_fields.insert("copy".to_string(), counts);
+    if !failures.is_empty() {
+        let failures: Vec<_> = failures.iter().map(|(path, error)| json!({"path": path, "error": error})).collect();
+        result.module_fields.insert("copy_failures".to_string(), failures.into());
+        result.success = false;
+    }
+    if let Some(ref mut module) = result.module_result {
+        module.status = Some(match (result.success, created + updated + dirs) {
+            (false, _) => "failed",
+            (true, 0) => "ok",
+            (true, _) => "changed",
+        }.to_string());
+    }
+}
+
+/// A hidden name in the destination's directory, so the final rename never crosses filesystems
+pub(crate) fn staging_path(dest: &str) -> String {
+    let (dir, name) = dest.rsplit_once('/').unwrap_or(("", dest));
+    format!("{}/.{}.krust-tmp.{:08x}", dir, name, rand::random::<u32>())
+}
+
+/// Check the staged file's sha256 on the host, give it its owner, keep the old file under
+/// --backup and rename the staged file into place. Returns the backup's path.
+async fn install<E: CommandExecutor>(
+    transport: &Arc<Transport<E>>,
+    host: &SshHost,
+    source: &Source,
+    dest: &str,
+    staging: &str,
+    limit: Duration,
+) -> Result<Option<String>, String> {
+    let steps = format!(r#"sum() {{
+    if command -v sha256sum >/dev/null 2>&1; then sha256sum "$1"; else shasum -a 256 "$1"; fi | cut -d' ' -f1
+}}
+fail() {{
+    rm -f "$tmp"
+    echo "{FAILED}$*"
+    exit 1
+}}
+[ "$(sum "$tmp")" = "$want" ] || fail "checksum mismatch after upload"
+if [ -n "$owner" ]; then
+    chown "$owner" "$tmp" || fail "chown $owner (needs root)"
+elif [ -e "$dest" ]; then
+    chown --reference="$dest" "$tmp" 2>/dev/null || true
+fi
+if [ "$backup" = 1 ] && [ -e "$dest" ]; then
+    backup="$dest.krust-bak.$(date +%Y%m%d%H%M%S)"
+    ln "$dest" "$backup" 2>/dev/null || cp -p "$dest" "$backup" || fail "could not back up $dest"
+    echo "{BACKUP}$backup"
+fi
+mv -f "$tmp" "$dest" || fail "could not rename the staged file over $dest"
+"#);
+    let script = Script::new()
+        .var("tmp", staging)
+        .var("dest", dest)
+        .var("want", source.sha256.as_deref().unwrap_or_default())
+        .var("owner", source.args.owner.as_deref().unwrap_or_default())
+        .var("backup", if source.args.backup { 1 } else { 0 })
+        .section(&steps);
+    let output = run_follow_up(transport, host, script.build(), limit).await.map_err(|e| {
+        match e.split_once(FAILED) {
+            Some((_, reason)) => reason.trim().to_string(),
+            None => e,
+        }
+    })?;
+    Ok(output.lines().find_map(|line| line.strip_prefix(BACKUP)).map(str::to_string))
+}
+
+/// Best-effort removal of a staged file whose upload did not complete
+pub(crate) async fn discard<E: CommandExecutor>(transport: &Arc<Transport<E>>, host: &SshHost, staging: &str) {
+    let command = format!("rm -f {}", shell_quote(staging));
+    if let Err(e) = run_follow_up(transport, host, command, CLEANUP_TIMEOUT).await {
+        debug!("{}: could not remove {}: {}", host.hostname, staging, e);
+    }
+}
+
+pub(crate) async fn apply<E: CommandExecutor>(
+    transport: &Arc<Transport<E>>,
+    host: &SshHost,
+    changes: Vec<Upload>,
+    limit: Duration,
+) -> Result<Vec<Result<u64, String>>, String> {
+    let addrs = transport.resolver.resolve(host).await.map_err(|e| e.to_string())?;
+    let task = tokio::task::spawn_blocking({
+        let (host, transport) = (host.clone(), Arc::clone(transport));
+        move || {
+            let auth = transport.auth.for_host(&host);
+            transport.executor.upload(&host, addrs, &auth, &changes).map_err(|e| e.to_string())
+        }
+    });
+    match timeout(limit, task).await {
+        Ok(Ok(outcome)) => outcome,
+        Ok(Err(e)) => Err(format!("Task panic: {}", e)),
+        Err(_) => Err(format!("upload timed out after {}s", limit.as_secs())),
+    }
+}
+
+/// One line after the run's summary, so idempotent re-runs show as all unchanged
+pub fn finish(results: &[HostResult], json: bool) {
+    if json || results.is_empty() {
+        return;
+    }
+    let status = |want: &str| results.iter()
+        .filter(|r| r.success && r.module_result.as_ref().and_then(|m| m.status.as_deref()) == Some(want))
+        .count();
+    let failed = results.iter().filter(|r| !r.success).count();
+    outln!("Copy: {} changed, {} unchanged, {} failed", status("changed"), status("ok"), failed);
+}
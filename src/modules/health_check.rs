@@ -0,0 +1,155 @@
+// [modules/health_check.rs] - KRUST - Connectivity and health probes
+use anyhow::{bail, Result};
+use clap::Args;
+use std::time::Duration;
+
+use super::script::Script;
+
+#[derive(Args, Debug)]
+pub struct HealthCheckArgs {
+    /// TCP port to probe, optionally as host:port (repeatable, defaults to localhost)
+    #[arg(long, value_delimiter = ',')]
+    pub tcp: Vec<String>,
+
+    /// HTTP(S) URL to request (repeatable)
+    #[arg(long)]
+    pub http: Vec<String>,
+
+    /// Expected HTTP status code for --http probes
+    #[arg(long, default_value_t = 200)]
+    pub expect_status: u16,
+
+    /// Process name that must be running (repeatable)
+    #[arg(long)]
+    pub process: Vec<String>,
+
+    /// Minimum free disk space, e.g. 10%
+    #[arg(long)]
+    pub min_disk_free: Option<String>,
+
+    /// Filesystem path checked by --min-disk-free
+    #[arg(long, default_value = "/")]
+    pub disk_path: String,
+
+    /// Timeout applied to each network probe
+    #[arg(long, default_value = "5s", value_parser = crate::parse_duration)]
+    pub probe_timeout: Duration,
+}
+
+struct TcpTarget {
+    host: String,
+    port: u16,
+}
+
+fn parse_tcp_target(spec: &str) -> Result<TcpTarget> {
+    let (host, port) = match spec.rsplit_once(':') {
+        Some((host, port)) => (host.trim(), port.trim()),
+        None => ("localhost", spec.trim()),
+    };
+
+    if host.is_empty() || !host.chars().all(|c| c.is_ascii_alphanumeric() || ".-_".contains(c)) {
+        bail!("Invalid TCP probe host: {}", spec);
+    }
+
+    match port.parse::<u16>() {
+        Ok(port) if port > 0 => Ok(TcpTarget { host: host.to_string(), port }),
+        _ => bail!("Invalid TCP probe port: {}", spec),
+    }
+}
+
+fn parse_percent(value: &str) -> Result<u8> {
+    let number = value.trim().trim_end_matches('%');
+    match number.parse::<u8>() {
+        Ok(percent) if percent <= 100 => Ok(percent),
+        _ => bail!("Invalid --min-disk-free value: {} (expected 0-100%)", value),
+    }
+}
+
+// Runs the probes in order (TCP, HTTP, process, disk), reporting each as a CHECK line
+const PROBES: &str = r#"failed=0
+report() {
+    echo "CHECK $1 $2 $3"
+    [ "$2" = PASS ] || failed=1
+}
+tcp_probe() {
+    if command -v nc >/dev/null 2>&1; then
+        nc -z -w "$T" "$1" "$2" >/dev/null 2>&1
+    else
+        timeout "$T" bash -c "exec 3<>/dev/tcp/$1/$2" >/dev/null 2>&1
+    fi
+}
+http_status() {
+    if command -v curl >/dev/null 2>&1; then
+        curl -s -o /dev/null -w '%{http_code}' --max-time "$T" "$1"
+    elif command -v wget >/dev/null 2>&1; then
+        wget -q -S -O /dev/null -T "$T" -t 1 "$1" 2>&1 | awk '/^ *HTTP\//{code=$2} END{print code}'
+    else
+        echo "no-http-client"
+    fi
+}
+for i in "${!tcp_hosts[@]}"; do
+    host=${tcp_hosts[$i]}
+    port=${tcp_ports[$i]}
+    if tcp_probe "$host" "$port"; then report "tcp:$port" PASS "$host:$port open"
+    else report "tcp:$port" FAIL "$host:$port unreachable"; fi
+done
+for u in "${urls[@]}"; do
+    code=$(http_status "$u") || true
+    code=${code:-000}
+    if [ "$code" = "$expect" ]; then report http PASS "$u status=$code"
+    else report http FAIL "$u status=$code expected=$expect"; fi
+done
+for name in "${processes[@]}"; do
+    if command -v pgrep >/dev/null 2>&1; then n=$(pgrep -x -- "$name" | wc -l)
+    else n=$(ps -e -o comm= | grep -Fxc -- "$name") || true; fi
+    if [ "$n" -gt 0 ]; then report "process:$name" PASS "$n running"
+    else report "process:$name" FAIL "not running"; fi
+done
+if [ -n "$min_free" ]; then
+    p=$disk_path
+    free=$(df -P "$p" 2>/dev/null | awk 'NR==2 { gsub("%", "", $5); print 100 - $5 }')
+    if [ -z "$free" ]; then report disk FAIL "cannot stat $p"
+    elif [ "$free" -ge "$min_free" ]; then report disk PASS "$p free=$free% min=$min_free%"
+    else report disk FAIL "$p free=$free% min=$min_free%"; fi
+fi
+exit $failed
+"#;
+
+pub fn build_command(args: &HealthCheckArgs) -> Result<Script> {
+    let tcp_targets = args.tcp.iter()
+        .map(|spec| parse_tcp_target(spec))
+        .collect::<Result<Vec<_>>>()?;
+
+    for url in &args.http {
+        if !(url.starts_with("http://") || url.starts_with("https://")) {
+            bail!("Invalid HTTP probe URL: {} (must start with http:// or https://)", url);
+        }
+    }
+
+    if !(100..=599).contains(&args.expect_status) {
+        bail!("Invalid --expect-status: {}", args.expect_status);
+    }
+
+    for name in &args.process {
+        if name.trim().is_empty() || name.contains('\n') {
+            bail!("Invalid process name: {:?}", name);
+        }
+    }
+
+    let min_disk_free = args.min_disk_free.as_deref().map(parse_percent).transpose()?;
+
+    if tcp_targets.is_empty() && args.http.is_empty() && args.process.is_empty() && min_disk_free.is_none() {
+        bail!("health-check needs at least one probe (--tcp, --http, --process or --min-disk-free)");
+    }
+
+    Ok(Script::new()
+        .var("T", args.probe_timeout.as_secs().max(1))
+        .array("tcp_hosts", tcp_targets.iter().map(|target| &target.host))
+        .array("tcp_ports", tcp_targets.iter().map(|target| target.port))
+        .array("urls", &args.http)
+        .var("expect", args.expect_status)
+        .array("processes", &args.process)
+        .var("disk_path", &args.disk_path)
+        .var("min_free", min_disk_free.map(|min| min.to_string()).unwrap_or_default())
+        .section(PROBES))
+}
@@ -0,0 +1,73 @@
+// [modules/kill.rs] - KRUST - Signal the remote processes a --capture-pid run left behind
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Args;
+use std::path::Path;
+
+use crate::history;
+use crate::ssh_executor::SshHost;
+
+/// Names `kill -s` accepts on every POSIX system
+const SIGNALS: &[&str] = &["TERM", "KILL", "INT", "HUP", "QUIT", "USR1", "USR2", "STOP", "CONT"];
+
+#[derive(Args, Debug)]
+pub struct KillArgs {
+    /// Results of a --capture-pid run: a file of --json or --pretty-json output, or a history run id
+    #[arg(long, value_name = "FILE|RUN_ID")]
+    pub from_journal: String,
+
+    /// Signal to send: TERM, KILL, INT, HUP, QUIT, USR1, USR2, STOP or CONT
+    #[arg(long, default_value = "TERM", value_parser = parse_signal)]
+    pub signal: String,
+}
+
+fn parse_signal(s: &str) -> Result<String, String> {
+    let name = s.trim_start_matches("SIG").to_ascii_uppercase();
+    match SIGNALS.contains(&name.as_str()) {
+        true => Ok(name),
+        false => Err(format!("Unknown signal '{}' (expected one of {})", s, SIGNALS.join(", "))),
+    }
+}
+
+/// One job per journal entry with a remote_pid. `known` hosts (--hosts, inventory) are used to
+/// find where a `hostname[:port]` connects to; others are connected to as written.
+pub fn build_jobs(args: &KillArgs, known: Vec<SshHost>) -> Result<Vec<(SshHost, String)>> {
+    let mut jobs = Vec::new();
+    for (target, pid) in recorded_pids(&args.from_journal)? {
+        let host = match known.iter().find(|host| host.to_string() == target) {
+            Some(host) => host.clone(),
+            None => SshHost::from_target(&target, None)
+                .with_context(|| format!("Invalid host {} in {}", target, args.from_journal))?,
+        };
+        let command = format!("kill -s {0} {1} && echo 'sent SIG{0} to {1}'", args.signal, pid);
+        jobs.push((host, command));
+    }
+    if jobs.is_empty() {
+        bail!("No results with a remote_pid in {} (was it run with --capture-pid?)", args.from_journal);
+    }
+    Ok(jobs)
+}
+
+/// (target, pid) of every result that recorded one, from NDJSON or a JSON array; results
+/// written before `target` existed give their hostname
+fn recorded_pids(journal: &str) -> Result<Vec<(String, u64)>> {
+    let path = match Path::new(journal).exists() {
+        true => Path::new(journal).to_path_buf(),
+        false => history::journal_path(journal)?,
+    };
+    let content = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read journal {}", path.display()))?;
+    let entries: Vec<serde_json::Value> = match content.trim_start().starts_with('[') {
+        true => serde_json::from_str(&content).with_context(|| format!("Invalid JSON in {}", path.display()))?,
+        // Summary and report lines in --json output carry no hostname and are skipped below
+        false => content.lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| serde_json::from_str(line).map_err(|e| anyhow!("Invalid line in {}: {}", path.display(), e)))
+            .collect::<Result<_>>()?,
+    };
+    Ok(entries.iter()
+        .filter_map(|entry| {
+            let target = entry.get("target").or_else(|| entry.get("hostname"))?.as_str()?;
+            Some((target.to_string(), entry.get("remote_pid")?.as_u64()?))
+        })
+        .collect())
+}
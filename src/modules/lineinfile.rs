@@ -0,0 +1,120 @@
+// [modules/lineinfile.rs] - KRUST - Idempotent single-line file edits
+use anyhow::{bail, Result};
+use clap::{Args, ValueEnum};
+
+use super::script::Script;
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineState {
+    Present,
+    Absent,
+}
+
+#[derive(Args, Debug)]
+pub struct LineInFileArgs {
+    /// Absolute path of the file to edit on each host
+    pub path: String,
+
+    /// Extended regex selecting the line to replace or remove
+    #[arg(long)]
+    pub regexp: Option<String>,
+
+    /// Line to insert or substitute for the first match
+    #[arg(long)]
+    pub line: Option<String>,
+
+    /// Whether the line should be present or absent
+    #[arg(long, value_enum, default_value_t = LineState::Present)]
+    pub state: LineState,
+
+    /// Keep a timestamped copy of the file before changing it
+    #[arg(long)]
+    pub backup: bool,
+}
+
+// Edits $f in place for $KRUST_STATE, leaving it untouched when nothing changes
+const EDIT: &str = r#"if [ ! -f "$f" ]; then
+    if [ "$KRUST_STATE" = absent ]; then
+        echo "unchanged: $f (file does not exist)"
+        exit 0
+    fi
+    echo "failed: $f does not exist" >&2
+    exit 1
+fi
+tmp=$(mktemp "$f.krust.XXXXXX") || exit 1
+trap 'rm -f "$tmp"' EXIT
+awk '
+BEGIN { re = ENVIRON["KRUST_RE"]; line = ENVIRON["KRUST_LINE"]; state = ENVIRON["KRUST_STATE"]; done = 0; seen = 0 }
+{
+    matched = (re != "") ? ($0 ~ re) : ($0 == line)
+    if (state == "absent") { if (!matched) print; next }
+    if ($0 == line) seen = 1
+    if (matched && !done) { print line; done = 1; next }
+    print
+}
+END { if (state == "present" && !done && !seen) print line }
+' "$f" > "$tmp" || exit 1
+if cmp -s "$f" "$tmp"; then
+    echo "unchanged: $f"
+    exit 0
+fi
+if [ "$backup" = 1 ]; then
+    bak="$f.krust-bak.$(date +%Y%m%d%H%M%S)"
+    cp -p "$f" "$bak" || exit 1
+    echo "backup: $bak"
+fi
+if chmod --reference="$f" "$tmp" 2>/dev/null && chown --reference="$f" "$tmp" 2>/dev/null; then
+    mv -f "$tmp" "$f" || exit 1
+else
+    # No GNU --reference: rewrite in place, which keeps the inode's mode and owner
+    cat "$tmp" > "$f" || exit 1
+fi
+echo "changed: $f"
+"#;
+
+fn check_single_line(name: &str, value: &str) -> Result<()> {
+    if value.contains('\n') || value.contains('\0') {
+        bail!("--{} must be a single line", name);
+    }
+    Ok(())
+}
+
+pub fn build_command(args: &LineInFileArgs) -> Result<Script> {
+    if !args.path.starts_with('/') {
+        bail!("lineinfile path must be absolute: {}", args.path);
+    }
+    check_single_line("path", &args.path)?;
+
+    if let Some(ref regexp) = args.regexp {
+        if regexp.is_empty() {
+            bail!("--regexp must not be empty");
+        }
+        check_single_line("regexp", regexp)?;
+    }
+    if let Some(ref line) = args.line {
+        check_single_line("line", line)?;
+    }
+
+    match args.state {
+        LineState::Present if args.line.is_none() => bail!("--state present requires --line"),
+        LineState::Absent if args.line.is_none() && args.regexp.is_none() => {
+            bail!("--state absent requires --regexp or --line")
+        }
+        _ => {}
+    }
+
+    let state = match args.state {
+        LineState::Present => "present",
+        LineState::Absent => "absent",
+    };
+
+    // Regexp and line reach awk through the environment, never through awk's
+    // own string parsing, so only the shell quoting has to be right.
+    Ok(Script::new()
+        .var("f", &args.path)
+        .export("KRUST_RE", args.regexp.as_deref().unwrap_or(""))
+        .export("KRUST_LINE", args.line.as_deref().unwrap_or(""))
+        .export("KRUST_STATE", state)
+        .var("backup", if args.backup { 1 } else { 0 })
+        .section(EDIT))
+}
@@ -0,0 +1,123 @@
+// [modules/mac_status.rs] - KRUST - SELinux / AppArmor state, and SELinux mode changes
+use anyhow::{bail, Result};
+use clap::{Args, ValueEnum};
+
+use super::contract::{FACT, METRIC, RESULT};
+use super::script::Script;
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelinuxMode {
+    Permissive,
+    Enforcing,
+}
+
+#[derive(Args, Debug)]
+pub struct MacStatusArgs {
+    /// Switch SELinux to this mode at runtime (SELinux hosts only)
+    #[arg(long, value_enum)]
+    pub set_mode: Option<SelinuxMode>,
+
+    /// With --set-mode, also write the mode to /etc/selinux/config
+    #[arg(long, requires = "set_mode")]
+    pub persist_mode: bool,
+
+    /// Confirm --set-mode, which changes what the hosts' security policy enforces
+    #[arg(long)]
+    pub i_know_what_im_doing: bool,
+}
+
+// Denials of the last 24 hours, from the audit log (current and last rotated file) or, for
+// root without auditd, the kernel journal; "unknown" when neither can be read
+const DETECT: &str = r#"count_denials() {
+    since=$(( $(date +%s) - 86400 ))
+    if [ -r /var/log/audit/audit.log ]; then
+        cat /var/log/audit/audit.log.1 /var/log/audit/audit.log 2>/dev/null | awk -v since="$since" -v re="$1" '
+            $0 ~ re && match($0, /audit\([0-9]+/) { if (substr($0, RSTART + 6, RLENGTH - 6) + 0 >= since) n++ }
+            END { print n + 0 }'
+    elif [ "$(id -u)" = 0 ] && command -v journalctl >/dev/null 2>&1; then
+        journalctl -k -q --since "24 hours ago" 2>/dev/null | awk -v re="$1" '$0 ~ re { n++ } END { print n + 0 }'
+    else
+        echo unknown
+    fi
+}
+mac=none
+mode=none
+denials=0
+extra=
+if command -v getenforce >/dev/null 2>&1 && [ "$(getenforce 2>/dev/null)" != "" ]; then
+    mac=selinux
+    mode=$(getenforce | tr 'A-Z' 'a-z')
+    policy=$(sestatus 2>/dev/null | awk -F': *' '/^Loaded policy name/ { print $2 }')
+    [ -n "$policy" ] || policy=$(awk -F= '$1 == "SELINUXTYPE" { print $2 }' /etc/selinux/config 2>/dev/null)
+    extra=" policy=${policy:-unknown}"
+    [ "$mode" = disabled ] || denials=$(count_denials 'avc: +denied')
+elif [ "$(cat /sys/module/apparmor/parameters/enabled 2>/dev/null)" = Y ]; then
+    mac=apparmor
+    profiles=/sys/kernel/security/apparmor/profiles
+    if [ -r "$profiles" ]; then
+        loaded=$(wc -l < "$profiles" | tr -d ' ')
+        enforced=$(grep -c '(enforce)$' "$profiles") || true
+        if [ "$enforced" -gt 0 ]; then mode=enforce; elif [ "$loaded" -gt 0 ]; then mode=complain; else mode=no-profiles; fi
+        extra=" profiles_loaded=$loaded profiles_enforced=$enforced"
+    else
+        mode=enabled
+        extra=" profiles_loaded=unknown profiles_enforced=unknown"
+    fi
+    denials=$(count_denials 'apparmor="DENIED"')
+fi
+"#;
+
+pub fn build_command(args: &MacStatusArgs) -> Result<Script> {
+    let report = format!(r#"echo "mac=$mac mode=$mode denials_24h=$denials$extra"
+echo "{FACT}mac=$mac mac_mode=$mode"
+[ "$denials" = unknown ] || echo "{METRIC}denials_24h=$denials"
+"#);
+
+    let action = match args.set_mode {
+        None => format!("{report}echo \"{RESULT}status=ok\"\n"),
+        Some(_) if !args.i_know_what_im_doing => {
+            bail!("--set-mode changes the hosts' security enforcement; add --i-know-what-im-doing to confirm")
+        }
+        Some(want) => {
+            let (want, setenforce) = match want {
+                SelinuxMode::Permissive => ("permissive", 0),
+                SelinuxMode::Enforcing => ("enforcing", 1),
+            };
+            format!(r#"want={want}
+changed=0
+case $mac in
+    selinux) ;;
+    none) echo "failed: --set-mode needs SELinux, and this host has no MAC system" >&2; exit 1 ;;
+    *) echo "failed: --set-mode needs SELinux, and this host runs $mac" >&2; exit 1 ;;
+esac
+if [ "$mode" = disabled ]; then
+    echo "failed: SELinux is disabled; enabling it takes a config change, a relabel and a reboot" >&2
+    exit 1
+fi
+if [ "$mode" != "$want" ]; then
+    setenforce {setenforce} || {{ echo "failed: setenforce {setenforce} (needs root)" >&2; exit 1; }}
+    echo "mode: $mode -> $want"
+    mode=$(getenforce | tr 'A-Z' 'a-z')
+    changed=1
+fi
+if [ {persist} = 1 ]; then
+    config=/etc/selinux/config
+    if ! grep -q "^SELINUX=$want\$" "$config" 2>/dev/null; then
+        if grep -q '^SELINUX=' "$config" 2>/dev/null; then
+            sed -i "s/^SELINUX=.*/SELINUX=$want/" "$config" || exit 1
+        else
+            echo "SELINUX=$want" >> "$config" || exit 1
+        fi
+        echo "persisted: SELINUX=$want in $config"
+        changed=1
+    fi
+fi
+{report}[ "$changed" = 1 ] && echo "{RESULT}status=changed" || echo "{RESULT}status=ok"
+"#,
+                persist = if args.persist_mode { 1 } else { 0 },
+            )
+        }
+    };
+
+    Ok(Script::new().section(DETECT).section(&action))
+}
@@ -0,0 +1,318 @@
+// [modules/mod.rs] - KRUST - Built-in command modules
+//
+// A module turns a handful of validated local arguments into a single shell
+// script that is executed on every host like any other krust command. All
+// validation happens here, before a single SSH connection is opened.
+use anyhow::{bail, Result};
+use clap::{Args, Subcommand};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::debug;
+
+use archive_fetch::FetchPolicy;
+use script::Script;
+use crate::executor::{CommandExecutor, Transport};
+use crate::reboot::RebootPolicy;
+use crate::ssh_executor::SshHost;
+use crate::HostResult;
+
+pub mod archive_fetch;
+pub mod checksum;
+pub mod collect_facts;
+pub mod contract;
+pub mod copy;
+pub mod health_check;
+pub mod kill;
+pub mod lineinfile;
+pub mod mac_status;
+pub mod os_update;
+pub mod ping;
+pub mod ports;
+pub mod process;
+pub mod reboot_wait;
+pub mod script;
+pub mod swap;
+pub mod sysctl;
+pub mod template;
+pub mod timecheck;
+
+/// Prefix that keeps color out of whatever a module script runs
+pub const NO_COLOR_ENV: &str = "env NO_COLOR=1 TERM=dumb ";
+
+#[derive(Args, Debug)]
+pub struct ModulesArgs {
+    #[command(subcommand)]
+    pub action: ModulesAction,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ModulesAction {
+    /// Print the script a module would run on every host, without connecting to any
+    Show {
+        #[command(subcommand)]
+        module: Module,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Module {
+    /// Connect and authenticate to every host, reporting connect and auth times
+    Ping(ping::PingArgs),
+    /// Run connectivity and health probes (TCP, HTTP, process, disk)
+    HealthCheck(health_check::HealthCheckArgs),
+    /// Ensure a line is present in (or absent from) a file
+    #[command(name = "lineinfile")]
+    LineInFile(lineinfile::LineInFileArgs),
+    /// Render a local template per host and install it remotely
+    Template(template::TemplateArgs),
+    /// Hash remote files and optionally verify them against expected sums
+    Checksum(checksum::ChecksumArgs),
+    /// Find processes by command line or signal them
+    Process(process::ProcessArgs),
+    /// Report clock offset and NTP synchronization state
+    #[command(name = "timecheck")]
+    TimeCheck(timecheck::TimeCheckArgs),
+    /// List listening sockets and audit expected/denied ports
+    Ports(ports::PortsArgs),
+    /// Gather system facts as JSON, optionally assembled into a local CSV
+    CollectFacts(collect_facts::CollectFactsArgs),
+    /// Upgrade packages and report every package that changed
+    OsUpdate(os_update::OsUpdateArgs),
+    /// Reboot hosts and wait until each one is back on a new boot
+    RebootWait(reboot_wait::RebootWaitArgs),
+    /// Signal the remote processes recorded by a --capture-pid run
+    Kill(kill::KillArgs),
+    /// Report swap usage, or create and remove swapfiles
+    Swap(swap::SwapArgs),
+    /// Set, persist or verify kernel parameters
+    Sysctl(sysctl::SysctlArgs),
+    /// Report SELinux/AppArmor state, or switch SELinux between permissive and enforcing
+    MacStatus(mac_status::MacStatusArgs),
+    /// Pack a remote directory into a tarball and download it
+    ArchiveFetch(archive_fetch::ArchiveFetchArgs),
+    /// Upload a file or directory tree, skipping files already in place
+    Copy(copy::CopyArgs),
+}
+
+impl Module {
+    /// Subcommand name as typed on the command line
+    pub fn name(&self) -> &'static str {
+        match self {
+            Module::Ping(_) => "ping",
+            Module::HealthCheck(_) => "health-check",
+            Module::LineInFile(_) => "lineinfile",
+            Module::Template(_) => "template",
+            Module::Checksum(_) => "checksum",
+            Module::Process(_) => "process",
+            Module::TimeCheck(_) => "timecheck",
+            Module::Ports(_) => "ports",
+            Module::CollectFacts(_) => "collect-facts",
+            Module::OsUpdate(_) => "os-update",
+            Module::RebootWait(_) => "reboot-wait",
+            Module::Kill(_) => "kill",
+            Module::Swap(_) => "swap",
+            Module::Sysctl(_) => "sysctl",
+            Module::MacStatus(_) => "mac-status",
+            Module::ArchiveFetch(_) => "archive-fetch",
+            Module::Copy(_) => "copy",
+        }
+    }
+    
+    /// Validate the module arguments and generate the remote command for each host.
+    /// Without `color` (krust's own output is not a terminal, or is JSON) the scripts run
+    /// with NO_COLOR and TERM=dumb, so tools on the host keep escape codes out of results.
+    pub fn build_jobs(&self, hosts: Vec<SshHost>, color: bool) -> Result<Vec<(SshHost, String)>> {
+        let command = match self {
+            Module::Ping(args) => ping::build_command(args),
+            Module::Kill(args) => return Ok(without_color(kill::build_jobs(args, hosts)?, color)),
+            _ => self.script()?.build(),
+        };
+
+        debug!("Command to execute: {}", command);
+        Ok(without_color(hosts.into_iter().map(|host| (host, command.clone())).collect(), color))
+    }
+
+    /// The bash script every host runs, as `krust module show` prints it. Kill builds a different
+    /// command per host, and ping runs no script.
+    pub fn script(&self) -> Result<Script> {
+        match self {
+            Module::HealthCheck(args) => health_check::build_command(args),
+            Module::LineInFile(args) => lineinfile::build_command(args),
+            Module::Checksum(args) => checksum::build_command(args),
+            Module::Process(args) => process::build_command(args),
+            Module::TimeCheck(args) => timecheck::build_command(args),
+            Module::Ports(args) => ports::build_command(args),
+            Module::CollectFacts(args) => collect_facts::build_command(args),
+            Module::OsUpdate(args) => os_update::build_command(args),
+            Module::RebootWait(args) => reboot_wait::build_command(args),
+            Module::Swap(args) => swap::build_command(args),
+            Module::Sysctl(args) => sysctl::build_command(args),
+            Module::MacStatus(args) => mac_status::build_command(args),
+            Module::ArchiveFetch(args) => archive_fetch::build_command(args),
+            Module::Copy(args) => copy::build_command(args),
+            Module::Template(args) => template::build_command(args),
+            Module::Ping(_) => bail!("ping runs no script: it stops once authenticated (or runs `true` with --exec)"),
+            Module::Kill(_) => bail!("kill builds a different command for every host"),
+        }
+    }
+    
+    /// How the controller waits for a host to come back when the module rebooted it
+    pub fn reboot_policy(&self) -> Option<RebootPolicy> {
+        match self {
+            Module::OsUpdate(args) if args.reboot_and_wait => Some(RebootPolicy {
+                timeout: args.reboot_timeout,
+                post_check: None,
+            }),
+            Module::RebootWait(args) => reboot_wait::reboot_policy(args),
+            _ => None,
+        }
+    }
+    
+    /// Where the controller downloads what the module left on each host
+    pub fn fetch_policy(&self) -> Option<FetchPolicy> {
+        match self {
+            Module::ArchiveFetch(args) => Some(archive_fetch::fetch_policy(args)),
+            _ => None,
+        }
+    }
+    
+    /// The local tree a copy uploads, scanned once for all hosts
+    pub fn copy_source(&self) -> Option<Arc<copy::Source>> {
+        match self {
+            Module::Copy(args) => Some(Arc::new(copy::Source::scan(args))),
+            _ => None,
+        }
+    }
+    
+    /// The template and variables a template run renders every host's file from
+    pub fn template_source(&self) -> Option<Arc<template::Source>> {
+        match self {
+            Module::Template(args) => Some(Arc::new(template::Source::load(args))),
+            _ => None,
+        }
+    }
+    
+    /// --timeout when the user gave none, for modules that expect a quick answer
+    pub fn default_timeout(&self) -> Option<Duration> {
+        match self {
+            Module::Ping(_) => Some(ping::DEFAULT_TIMEOUT),
+            _ => None,
+        }
+    }
+    
+    /// Whether the module finds its own hosts, so none need to be given
+    pub fn brings_hosts(&self) -> bool {
+        matches!(self, Module::Kill(_))
+    }
+    
+    /// Whether a run takes the run lock without --lock; ping changes nothing and must not wait
+    pub fn locks_by_default(&self) -> bool {
+        !matches!(self, Module::Ping(_))
+    }
+    
+    /// Cap on hosts running at once imposed by the module, given the number of hosts
+    pub fn max_in_flight(&self, hosts: usize) -> Option<usize> {
+        match self {
+            Module::RebootWait(args) if !args.per_group => args.max_unavailable.map(|max| max.resolve(hosts)),
+            _ => None,
+        }
+    }
+    
+    /// Cap on each inventory group's hosts running at once, given the group's size, when the
+    /// module limits groups rather than the whole run
+    pub fn max_in_flight_per_group(&self, hosts: usize) -> Option<usize> {
+        match self {
+            Module::RebootWait(args) if args.per_group => args.max_unavailable.map(|max| max.resolve(hosts)),
+            _ => None,
+        }
+    }
+    
+    /// Non-zero exit codes the module uses to report a state rather than a failure
+    fn ok_exit_codes(&self) -> &'static [i32] {
+        match self {
+            Module::RebootWait(args) => reboot_wait::ok_exit_codes(args),
+            _ => &[],
+        }
+    }
+    
+    /// Controller-side interpretation of a host's output, applied before it is reported
+    pub fn post_process(&self, result: &mut HostResult) {
+        if let Some(code) = result.exit_code {
+            if self.ok_exit_codes().contains(&code) {
+                result.success = true;
+            }
+        }
+        
+        match self {
+            Module::Ping(_) => ping::post_process(result),
+            Module::TimeCheck(args) => timecheck::post_process(args, result),
+            Module::CollectFacts(_) => collect_facts::post_process(result),
+            Module::OsUpdate(args) => os_update::post_process(args, result),
+            Module::RebootWait(args) if args.check => reboot_wait::post_process(result),
+            _ => {}
+        }
+    }
+    
+    /// Controller-side work over the complete result set, after all hosts finished
+    pub fn finish(&self, results: &[HostResult], json: bool) -> Result<()> {
+        match self {
+            Module::CollectFacts(args) => collect_facts::finish(args, results, json),
+            Module::Copy(_) => {
+                copy::finish(results, json);
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Run a controller-side follow-up command on a host once its module output is in, returning
+/// its output; a non-zero exit is an error carrying what it wrote to stdout and stderr
+pub(crate) async fn run_follow_up<E: CommandExecutor>(
+    transport: &Arc<Transport<E>>,
+    host: &SshHost,
+    command: String,
+    limit: Duration,
+) -> Result<String, String> {
+    let addrs = transport.resolver.resolve(host).await.map_err(|e| e.to_string())?;
+    let task = tokio::task::spawn_blocking({
+        let (host, transport) = (host.clone(), Arc::clone(transport));
+        move || {
+            let auth = transport.auth.for_host(&host);
+            transport.executor.execute(&host, addrs, &auth, &command, None)
+        }
+    });
+    match tokio::time::timeout(limit, task).await {
+        Ok(Ok(Ok(output))) if output.exit_code == 0 => Ok(output.text()),
+        Ok(Ok(Ok(output))) => {
+            let said: Vec<String> = [output.text(), output.stderr_text()].into_iter().filter(|text| !text.is_empty()).collect();
+            Err(format!("exit {}: {}", output.exit_code, said.join("\n").trim()))
+        }
+        Ok(Ok(Err(e))) => Err(e.to_string()),
+        Ok(Err(e)) => Err(format!("Task panic: {}", e)),
+        Err(_) => Err(format!("timed out after {}s", limit.as_secs())),
+    }
+}
+
+/// Run each command under NO_COLOR_ENV unless color is wanted; ping's empty command stays empty
+fn without_color(jobs: Vec<(SshHost, String)>, color: bool) -> Vec<(SshHost, String)> {
+    if color {
+        return jobs;
+    }
+    jobs.into_iter()
+        .map(|(host, command)| match command.is_empty() {
+            true => (host, command),
+            false => (host, format!("{}{}", NO_COLOR_ENV, command)),
+        })
+        .collect()
+}
+
+/// Quote a value for safe interpolation into a POSIX shell script
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// Wrap a generated bash script so it runs the same regardless of the login shell
+pub fn wrap_script(script: &str) -> String {
+    format!("bash -c {}", shell_quote(script))
+}
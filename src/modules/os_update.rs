@@ -0,0 +1,399 @@
+// [modules/os_update.rs] - KRUST - Package upgrades with a per-package change report
+use anyhow::{bail, Result};
+use clap::Args;
+use std::time::Duration;
+
+use super::contract::{FACT, METRIC, RESULT};
+use super::script::Script;
+use crate::reboot::{BOOT_STATE_COMMAND, REBOOT_MARKER};
+use crate::HostResult;
+
+#[derive(Args, Debug)]
+pub struct OsUpdateArgs {
+    /// Only report what would be upgraded
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Only apply security updates
+    #[arg(long)]
+    pub security_only: bool,
+
+    /// Schedule a reboot (in one minute) when the host reports one is required
+    #[arg(long)]
+    pub reboot: bool,
+
+    /// Reboot right away when required and wait until the host is back on a new boot
+    #[arg(long, conflicts_with = "reboot")]
+    pub reboot_and_wait: bool,
+
+    /// How long --reboot-and-wait waits for a host to come back
+    #[arg(long, default_value = "10m", value_parser = crate::parse_duration)]
+    pub reboot_timeout: Duration,
+
+    /// Package name globs to leave untouched (comma-separated), e.g. 'linux-image-*,postgresql-*'
+    #[arg(long, value_delimiter = ',')]
+    pub exclude: Vec<String>,
+
+    /// How long to wait for another process holding the package manager lock (e.g. 10m)
+    #[arg(long, default_value = "0s", value_parser = crate::parse_duration)]
+    pub pkg_lock_wait: Duration,
+}
+
+const UPDATED_PREFIX: &str = "UPDATED: ";
+const WOULD_UPDATE_PREFIX: &str = "WOULD-UPDATE: ";
+const EXCLUDED_PREFIX: &str = "EXCLUDED: ";
+
+fn validate_exclude(pattern: &str) -> Result<()> {
+    let valid = !pattern.is_empty()
+        && pattern.chars().all(|c| c.is_ascii_alphanumeric() || "-_.+:*?".contains(c));
+    if !valid {
+        bail!("Invalid --exclude pattern {:?}: use package names with * and ? wildcards", pattern);
+    }
+    Ok(())
+}
+
+// Every manager is reduced to "name<TAB>old<TAB>new" lines. Real runs diff an
+// installed-package snapshot taken before and after the upgrade, so the report
+// reflects what actually changed rather than what the manager planned.
+const DETECT: &str = r#"pm=""
+for candidate in apt-get dnf yum zypper apk pacman; do
+    if command -v "$candidate" >/dev/null 2>&1; then pm=${candidate%-get}; break; fi
+done
+if [ -z "$pm" ]; then
+    echo "No supported package manager found" >&2
+    exit 2
+fi
+export DEBIAN_FRONTEND=noninteractive LC_ALL=C
+# apk prints name-version-rN as one word; the version starts at the last "-<digit>...-rN"
+split_apk() {
+    awk -v field="$1" '{ if (match($field, /-[0-9][^-]*-r[0-9]+$/)) print substr($field, 1, RSTART - 1) "\t" substr($field, RSTART + 1) "\t" $3 }'
+}
+snapshot() {
+    case $pm in
+    apt) dpkg-query -W -f='${Package}\t${Version}\n' 2>/dev/null ;;
+    apk) apk info -v 2>/dev/null | split_apk 1 | cut -f1,2 ;;
+    pacman) pacman -Q 2>/dev/null | awk '{ print $1 "\t" $2 }' ;;
+    *) rpm -qa --qf '%{NAME}\t%{VERSION}-%{RELEASE}\n' 2>/dev/null ;;
+    esac | sort -u -t "$(printf '\t')" -k1,1
+}
+refresh() {
+    case $pm in
+    apt) apt-get update -qq ;;
+    apk) apk update -q ;;
+    pacman) pacman -Sy --noconfirm ;;
+    zypper) zypper -n -q refresh ;;
+    *) $pm -q makecache ;;
+    esac >/dev/null 2>&1 || echo "warning: $pm metadata refresh failed" >&2
+}
+pending() {
+    case $pm in
+    apt)
+        apt-get -s upgrade 2>/dev/null | awk -v security="$security_only" '
+            /^Inst / {
+                if (security && $0 !~ /-security/) next
+                old = "none"; new = $3
+                if ($3 ~ /^\[/) { old = $3; new = $4 }
+                gsub(/[][()]/, "", old); gsub(/[][()]/, "", new)
+                print $2 "\t" old "\t" new
+            }'
+        ;;
+    apk)
+        apk version -l '<' 2>/dev/null | awk '$2 == "<"' | split_apk 1
+        ;;
+    pacman)
+        pacman -Qu 2>/dev/null | awk '$3 == "->" && $5 != "[ignored]" { print $1 "\t" $2 "\t" $4 }'
+        ;;
+    zypper)
+        if [ "$security_only" = 1 ]; then
+            zypper --xmlout -n patch --category security --dry-run 2>/dev/null
+        else
+            zypper --xmlout -n update --dry-run 2>/dev/null
+        fi | awk '
+            function attr(name) {
+                if (!match($0, name "=\"[^\"]*\"")) return ""
+                return substr($0, RSTART + length(name) + 2, RLENGTH - length(name) - 3)
+            }
+            /<solvable / && /type="package"/ && /edition-old=/ { print attr("name") "\t" attr("edition-old") "\t" attr("edition") }'
+        ;;
+    *)
+        flag=""
+        [ "$security_only" = 1 ] && flag=--security
+        $pm -q check-update $flag 2>/dev/null | awk 'NF == 3 && $1 ~ /\./ { print $1 "\t" $2 }' |
+            while IFS="$(printf '\t')" read -r name new; do
+                name=${name%.*}
+                old=$(rpm -q --qf '%{VERSION}-%{RELEASE}\n' "$name" 2>/dev/null | tail -n 1)
+                case $old in ""|*"not installed"*) old=none ;; esac
+                printf '%s\t%s\t%s\n' "$name" "$old" "${new#*:}"
+            done
+        ;;
+    esac
+}
+excluded() {
+    local pattern
+    for pattern in "${excludes[@]}"; do
+        case $1 in $pattern) return 0 ;; esac
+    done
+    return 1
+}
+# Split "name<TAB>old<TAB>new" lines on stdin into the upgrade set and the excluded set
+split_excluded() {
+    local name old new
+    while IFS="$(printf '\t')" read -r name old new; do
+        if excluded "$name"; then
+            printf '%s\t%s\t%s\n' "$name" "$old" "$new" >> "$skipped"
+        else
+            printf '%s\t%s\t%s\n' "$name" "$old" "$new"
+        fi
+    done
+}
+lock_holder() {
+    local files pid
+    case $pm in
+    apt) files="/var/lib/dpkg/lock-frontend /var/lib/dpkg/lock /var/lib/apt/lists/lock /var/cache/apt/archives/lock" ;;
+    apk) files="/lib/apk/db/lock" ;;
+    pacman) files="/var/lib/pacman/db.lck" ;;
+    zypper) files="/run/zypp.pid /var/run/zypp.pid" ;;
+    *) files="/var/run/yum.pid /var/lib/rpm/.rpm.lock /var/lib/dnf/rpmdb_lock.pid" ;;
+    esac
+    if command -v fuser >/dev/null 2>&1; then
+        pid=$(fuser $files 2>/dev/null | awk '{ print $1; exit }')
+    else
+        pid=$(pgrep -x 'apt|apt-get|aptitude|dpkg|unattended-upgr|yum|dnf|zypper|apk|pacman' | head -n 1)
+    fi
+    [ -n "$pid" ] || return 1
+    echo "PID $pid ($(ps -o args= -p "$pid" 2>/dev/null | cut -c1-80))"
+}
+wait_for_lock() {
+    local holder waited=0 announced=""
+    while holder=$(lock_holder); do
+        if [ "$waited" -ge "$lock_wait" ]; then
+            echo "package lock still held by $holder after ${waited}s" >&2
+            exit 2
+        fi
+        if [ "$holder" != "$announced" ]; then
+            echo "waiting for package lock held by $holder" >&2
+            announced=$holder
+        fi
+        sleep 5
+        waited=$((waited + 5))
+    done
+}
+reboot_required() {
+    case $pm in
+    apt) [ -f /var/run/reboot-required ] ;;
+    zypper)
+        [ -f /run/reboot-needed ] && return 0
+        zypper -q needs-rebooting >/dev/null 2>&1
+        [ $? -eq 102 ]
+        ;;
+    # Both remove the running kernel's modules when a new kernel is installed
+    apk|pacman) [ ! -d "/lib/modules/$(uname -r)" ] ;;
+    *) command -v needs-restarting >/dev/null 2>&1 && ! needs-restarting -r >/dev/null 2>&1 ;;
+    esac
+}
+"#;
+
+/// Upgrades (or, with --dry-run, lists) the packages, leaving the changes as
+/// `name<TAB>old<TAB>new` lines in $changes and the upgrade's exit code in $status
+const UPGRADE: &str = r#"if [ "$security_only" = 1 ]; then
+    case $pm in
+    apk|pacman)
+        echo "security-only not supported by $pm, skipping"
+        echo "${result_marker}status=skipped"
+        exit 0
+        ;;
+    esac
+fi
+wait_for_lock
+refresh
+
+skipped=$(mktemp) || exit 2
+cleanup() { rm -f "$skipped"; }
+trap cleanup EXIT
+
+if [ "$dry_run" = 1 ]; then
+    changes=$(pending | split_excluded)
+    prefix=$would_update_prefix
+    status=0
+else
+    before=$(mktemp) && after=$(mktemp) && log=$(mktemp) || exit 2
+    held=""
+    locked=()
+    # Holds and locks placed here are released on every exit path, including a failed upgrade
+    cleanup() {
+        [ -n "$held" ] && apt-mark unhold $held >/dev/null 2>&1
+        [ ${#locked[@]} -gt 0 ] && zypper -q removelock "${locked[@]}" >/dev/null 2>&1
+        rm -f "$skipped" "$before" "$after" "$log"
+    }
+    [ ${#excludes[@]} -gt 0 ] && pending | split_excluded > /dev/null
+    snapshot > "$before"
+    wait_for_lock
+    status=0
+    case $pm in
+    apt)
+        if [ ${#excludes[@]} -gt 0 ]; then
+            already=$(apt-mark showhold 2>/dev/null)
+            for pkg in $(cut -f1 "$before"); do
+                excluded "$pkg" || continue
+                echo "$already" | grep -qx "$pkg" || held="$held $pkg"
+            done
+            [ -n "$held" ] && apt-mark hold $held
+        fi
+        if [ "$security_only" = 1 ]; then
+            packages=$(pending | split_excluded | cut -f1)
+            if [ -n "$packages" ]; then
+                apt-get install -y -q --only-upgrade -o Dpkg::Options::=--force-confold $packages
+            fi
+        else
+            apt-get upgrade -y -q -o Dpkg::Options::=--force-confold
+        fi
+        ;;
+    apk)
+        if [ ${#excludes[@]} -gt 0 ]; then
+            packages=$(pending | split_excluded | cut -f1)
+            [ -z "$packages" ] || apk upgrade $packages
+        else
+            apk upgrade --available
+        fi
+        ;;
+    pacman)
+        flags=()
+        for pattern in "${excludes[@]}"; do flags+=(--ignore "$pattern"); done
+        pacman -Syu --noconfirm "${flags[@]}"
+        ;;
+    zypper)
+        existing=$(zypper -q locks 2>/dev/null)
+        for pattern in "${excludes[@]}"; do
+            echo "$existing" | grep -qF "| $pattern " && continue
+            zypper -q addlock "$pattern" && locked+=("$pattern")
+        done
+        if [ "$security_only" = 1 ]; then
+            zypper --non-interactive patch --category security
+        else
+            zypper --non-interactive update
+        fi
+        ;;
+    *)
+        flags=()
+        [ "$security_only" = 1 ] && flags+=(--security)
+        for pattern in "${excludes[@]}"; do flags+=("--exclude=$pattern"); done
+        $pm upgrade -y -q "${flags[@]}"
+        ;;
+    esac > "$log" 2>&1 || status=$?
+    # zypper reports "reboot needed" and similar informational states as 100-103
+    case $pm:$status in zypper:10[0-3]) status=0 ;; esac
+    snapshot > "$after"
+    changes=$(join -t "$(printf '\t')" -a 2 -e none -o 0,1.2,2.2 "$before" "$after" | awk -F'\t' '$2 != $3')
+    prefix=$updated_prefix
+fi
+"#;
+
+/// The per-package lines, the summary line, metrics, facts and the module status
+const REPORT: &str = r#"count=0
+if [ -n "$changes" ]; then
+    count=$(echo "$changes" | wc -l)
+    echo "$changes" | awk -F'\t' -v prefix="$prefix" '{ print prefix $1 " " $2 " -> " $3 }'
+fi
+excluded_count=$(sort -u "$skipped" | wc -l)
+sort -u "$skipped" | awk -F'\t' -v prefix="$excluded_prefix" '{ print prefix $1 " " $2 " -> " $3 }'
+
+needs_reboot=no
+reboot_required && needs_reboot=yes
+if [ "$dry_run" = 1 ]; then
+    echo "would_update=$count excluded=$excluded_count package_manager=$pm reboot_required=$needs_reboot"
+    echo "${metric_marker}packages_pending=$count packages_excluded=$excluded_count"
+else
+    echo "updated=$count excluded=$excluded_count package_manager=$pm reboot_required=$needs_reboot"
+    echo "${metric_marker}packages_updated=$count packages_excluded=$excluded_count"
+fi
+echo "${fact_marker}package_manager=$pm"
+[ "$needs_reboot" = yes ] && echo "${fact_marker}reboot_required=true" || echo "${fact_marker}reboot_required=false"
+if [ "$status" -ne 0 ]; then
+    echo "${result_marker}status=failed"
+elif [ "$dry_run" = 0 ] && [ "$count" -gt 0 ]; then
+    echo "${result_marker}status=changed"
+else
+    echo "${result_marker}status=ok"
+fi
+"#;
+
+/// Fails with the tail of the upgrade log, or reboots when the host needs it and that was asked for
+const FINISH: &str = r#"if [ "$status" -ne 0 ]; then
+    tail -n 20 "$log" >&2
+    echo "$pm upgrade failed with exit code $status" >&2
+    exit "$status"
+fi
+if [ "$dry_run" = 0 ] && [ "$needs_reboot" = yes ]; then
+    if [ "$reboot" = wait ]; then
+        # Detach so this session returns its output before the host goes down
+        echo "${reboot_marker}$(boot_state)"
+        nohup sh -c 'sleep 2; shutdown -r now "krust os-update"' >/dev/null 2>&1 &
+    elif [ "$reboot" = 1 ]; then
+        shutdown -r +1 "krust os-update" >/dev/null 2>&1 && echo "reboot scheduled in 1 minute"
+    fi
+fi
+"#;
+
+pub fn build_command(args: &OsUpdateArgs) -> Result<Script> {
+    if args.reboot_and_wait && args.reboot_timeout.is_zero() {
+        bail!("--reboot-timeout must be greater than zero");
+    }
+    for pattern in &args.exclude {
+        validate_exclude(pattern)?;
+    }
+
+
+    Ok(Script::new()
+        .var("dry_run", u8::from(args.dry_run))
+        .var("security_only", u8::from(args.security_only))
+        .var("reboot", if args.reboot_and_wait { "wait" } else if args.reboot { "1" } else { "0" })
+        .array("excludes", &args.exclude)
+        .var("lock_wait", args.pkg_lock_wait.as_secs())
+        .var("updated_prefix", UPDATED_PREFIX)
+        .var("would_update_prefix", WOULD_UPDATE_PREFIX)
+        .var("excluded_prefix", EXCLUDED_PREFIX)
+        .var("reboot_marker", REBOOT_MARKER)
+        .var("result_marker", RESULT)
+        .var("metric_marker", METRIC)
+        .var("fact_marker", FACT)
+        .section("boot_state() {")
+        .section(BOOT_STATE_COMMAND)
+        .section("}")
+        .section(DETECT)
+        .section(UPGRADE)
+        .section(REPORT)
+        .section(FINISH))
+}
+
+/// Expose the UPDATED / WOULD-UPDATE lines as an `updated_packages` array in JSON output
+pub fn post_process(args: &OsUpdateArgs, result: &mut HostResult) {
+    let Some(ref stdout) = result.stdout else {
+        return;
+    };
+
+    let packages = parse_changes(stdout, &[UPDATED_PREFIX, WOULD_UPDATE_PREFIX]);
+    let excluded = parse_changes(stdout, &[EXCLUDED_PREFIX]);
+
+    result.reboot_required = result.module_result.as_ref()
+        .and_then(|module_result| module_result.fact("reboot_required"))
+        .and_then(serde_json::Value::as_bool);
+    result.module_fields.insert("dry_run".to_string(), serde_json::Value::from(args.dry_run));
+    result.module_fields.insert("updated_packages".to_string(), serde_json::Value::Array(packages));
+    result.module_fields.insert("excluded_packages".to_string(), serde_json::Value::Array(excluded));
+}
+
+/// Parse "<prefix>name old -> new" lines into {name, from, to} objects
+fn parse_changes(stdout: &str, prefixes: &[&str]) -> Vec<serde_json::Value> {
+    stdout.lines()
+        .filter_map(|line| prefixes.iter().find_map(|prefix| line.strip_prefix(prefix)))
+        .filter_map(|change| {
+            let (name, versions) = change.split_once(' ')?;
+            let (old, new) = versions.split_once(" -> ")?;
+            Some(serde_json::json!({
+                "name": name,
+                "from": if old == "none" { None } else { Some(old) },
+                "to": new,
+            }))
+        })
+        .collect()
+}
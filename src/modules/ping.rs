@@ -0,0 +1,47 @@
+// [modules/ping.rs] - KRUST - Connect and authenticate to every host without running anything
+use clap::Args;
+use std::time::Duration;
+
+use crate::HostResult;
+
+/// --timeout when none is given: a reachable host answers well within this
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Args, Debug)]
+pub struct PingArgs {
+    /// Also run `true` once authenticated, to check a session can be opened
+    #[arg(long)]
+    pub exec: bool,
+}
+
+/// The empty command stops after authentication
+pub fn build_command(args: &PingArgs) -> String {
+    match args.exec {
+        true => "true".to_string(),
+        false => String::new(),
+    }
+}
+
+/// Report the connection phases in place of the (empty) output
+pub fn post_process(result: &mut HostResult) {
+    let Some(timings) = result.timings else {
+        return;
+    };
+    let phases = [("connect", timings.connect_ms), ("auth", timings.auth_ms), ("exec", timings.exec_ms)];
+    let summary: Vec<String> = phases.iter()
+        .filter_map(|(name, ms)| ms.map(|ms| format!("{} {}ms", name, ms)))
+        .collect();
+    for (name, ms) in phases {
+        // A successful host already carries auth_ms
+        if name == "auth" && result.auth_ms.is_some() {
+            continue;
+        }
+        if let Some(ms) = ms {
+            result.module_fields.insert(format!("{}_ms", name), serde_json::Value::from(ms));
+        }
+    }
+    if result.success {
+        result.stdout = Some(summary.join(", "));
+        result.stdout_lines = None;
+    }
+}
@@ -0,0 +1,84 @@
+// [modules/ports.rs] - KRUST - Listening socket audit
+use anyhow::{bail, Result};
+use clap::Args;
+
+use super::script::Script;
+
+#[derive(Args, Debug)]
+pub struct PortsArgs {
+    /// Ports that must be listening (comma-separated)
+    #[arg(long, value_delimiter = ',')]
+    pub expect: Vec<u16>,
+
+    /// Ports that must not be listening (comma-separated)
+    #[arg(long, value_delimiter = ',')]
+    pub deny: Vec<u16>,
+}
+
+// Both tools are normalized to "proto local_addr:port process". ss prints a
+// Netid column when several protocols are requested; the process column is
+// missing entirely when we lack permission to see other users' sockets.
+const LIST_SOCKETS: &str = r#"list_sockets() {
+    if command -v ss >/dev/null 2>&1; then
+        ss -tulnp 2>/dev/null | awk '
+            $1 == "Netid" || $1 == "State" { next }
+            {
+                if ($1 == "tcp" || $1 == "udp") { proto = $1; local = $5 } else { proto = "tcp"; local = $4 }
+                proc = "-"
+                if (match($0, /users:\(\("[^"]+"/)) { proc = substr($0, RSTART + 9, RLENGTH - 10) }
+                print proto, local, proc
+            }'
+    elif command -v netstat >/dev/null 2>&1; then
+        netstat -tulnp 2>/dev/null | awk '
+            $1 ~ /^(tcp|udp)6?$/ {
+                proto = substr($1, 1, 3)
+                if (proto == "tcp" && $6 != "LISTEN") next
+                proc = "-"
+                if ($NF ~ /\//) { split($NF, p, "/"); proc = p[2] }
+                print proto, $4, proc
+            }'
+    else
+        echo "no ss or netstat available" >&2
+        exit 2
+    fi
+}
+"#;
+
+// Compares what is listening with the $expect and $deny ports
+const AUDIT: &str = r#"sockets=$(list_sockets | sort -u) || exit 2
+[ -n "$sockets" ] && echo "$sockets"
+listening() {
+    echo "$sockets" | awk -v port="$1" '{ n = split($2, a, ":"); if (a[n] == port) found = 1 } END { exit !found }'
+}
+failed=0
+for port in "${expect[@]}"; do
+    if ! listening "$port"; then
+        echo "EXPECTED-MISSING $port"
+        failed=1
+    fi
+done
+for port in "${deny[@]}"; do
+    if listening "$port"; then
+        echo "DENIED-OPEN $port"
+        failed=1
+    fi
+done
+exit $failed
+"#;
+
+pub fn build_command(args: &PortsArgs) -> Result<Script> {
+    for port in args.expect.iter().chain(&args.deny) {
+        if *port == 0 {
+            bail!("Invalid port: 0");
+        }
+    }
+    if let Some(port) = args.expect.iter().find(|port| args.deny.contains(port)) {
+        bail!("Port {} cannot be both expected and denied", port);
+    }
+
+    Ok(Script::new()
+        .array("expect", &args.expect)
+        .array("deny", &args.deny)
+        .section(LIST_SOCKETS)
+        .section(AUDIT))
+}
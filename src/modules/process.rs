@@ -0,0 +1,169 @@
+// [modules/process.rs] - KRUST - Process inspection and targeted kill
+use anyhow::{bail, Context, Result};
+use clap::{Args, Subcommand};
+use regex::Regex;
+use std::time::Duration;
+
+use super::script::Script;
+
+/// Command lines a pattern must never match: init and the sshd we are connected through
+const PROTECTED_COMMANDS: &[&str] = &[
+    "init",
+    "/sbin/init",
+    "/lib/systemd/systemd",
+    "/usr/lib/systemd/systemd --switched-root --system",
+    "sshd",
+    "/usr/sbin/sshd -D",
+];
+
+const SIGNALS: &[&str] = &[
+    "HUP", "INT", "QUIT", "KILL", "USR1", "USR2", "TERM", "CONT", "STOP",
+];
+
+#[derive(Args, Debug)]
+pub struct ProcessArgs {
+    #[command(subcommand)]
+    pub action: ProcessAction,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ProcessAction {
+    /// List processes whose full command line matches a regex
+    Find {
+        /// Extended regex matched against the full command line
+        #[arg(long)]
+        name: String,
+    },
+    /// Signal matching processes and wait for them to exit
+    Kill {
+        /// Extended regex matched against the full command line
+        #[arg(long)]
+        name: String,
+
+        /// Signal to send (name or number)
+        #[arg(long, default_value = "TERM")]
+        signal: String,
+
+        /// How long to wait for processes to exit
+        #[arg(long, default_value = "30s", value_parser = crate::parse_duration)]
+        timeout: Duration,
+
+        /// Escalate to KILL for processes still running after the timeout
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+fn validate_pattern(pattern: &str) -> Result<()> {
+    if pattern.trim().is_empty() || pattern.contains('\n') {
+        bail!("Process pattern must be a non-empty single line");
+    }
+
+    let regex = Regex::new(pattern)
+        .with_context(|| format!("Invalid process pattern: {}", pattern))?;
+    if let Some(hit) = PROTECTED_COMMANDS.iter().find(|cmd| regex.is_match(cmd)) {
+        bail!("Refusing pattern {:?}: it matches {:?} (PID 1 and sshd are never targeted)", pattern, hit);
+    }
+
+    Ok(())
+}
+
+fn normalize_signal(signal: &str) -> Result<String> {
+    let upper = signal.trim().to_uppercase();
+    let name = upper.strip_prefix("SIG").unwrap_or(&upper);
+
+    if SIGNALS.contains(&name) {
+        return Ok(name.to_string());
+    }
+    match name.parse::<u8>() {
+        Ok(number) if (1..=64).contains(&number) => Ok(number.to_string()),
+        _ => bail!("Unsupported signal: {}", signal),
+    }
+}
+
+// The marker keeps our own shell, its subshells, and helper awk out of the match set
+const MATCHER: &str = r#"# krust-process-module
+list_matches() {
+    ps -eo pid=,args= | awk -v self="$$" '
+        BEGIN { re = ENVIRON["KRUST_PATTERN"] }
+        {
+            pid = $1; line = $0; sub(/^ *[0-9]+ +/, "", line)
+            if (pid == self || index(line, "krust-process-module")) next
+            if (line ~ re) print pid
+        }'
+}
+pids=$(list_matches)
+for pid in $pids; do
+    comm=$(ps -o comm= -p "$pid" 2>/dev/null) || true
+    if [ "$pid" = 1 ] || [ "$comm" = sshd ]; then
+        echo "refused: pattern matches PID $pid ($comm)" >&2
+        exit 2
+    fi
+done
+count=$(echo $pids | wc -w)
+alive() {
+    kill -0 "$1" 2>/dev/null && [ "$(ps -o stat= -p "$1" 2>/dev/null | cut -c1)" != Z ]
+}
+"#;
+
+// Prints what ps knows about each matched PID
+const FIND: &str = r#"for pid in $pids; do
+    ps -o pid=,rss=,pcpu=,etimes=,args= -p "$pid" 2>/dev/null |
+        awk '{ cmd = $0; sub(/^ *[0-9]+ +[0-9]+ +[0-9.]+ +[0-9]+ +/, "", cmd);
+               printf "pid=%s rss_kb=%s cpu=%s uptime_s=%s cmd=%s\n", $1, $2, $3, $4, cmd }'
+done
+echo "matched=$count"
+"#;
+
+// Sends $signal, waits up to $timeout seconds and, with $force, escalates to KILL
+const KILL: &str = r#"if [ "$count" -eq 0 ]; then
+    echo "signaled=0 exited=0 remaining=0"
+    exit 0
+fi
+# Some may have exited since they were listed
+kill -s "$signal" $pids 2>/dev/null || true
+waited=0
+while [ "$waited" -lt "$timeout" ]; do
+    running=0
+    for pid in $pids; do alive "$pid" && running=$((running + 1)); done
+    [ "$running" -eq 0 ] && break
+    sleep 1
+    waited=$((waited + 1))
+done
+remaining=""
+for pid in $pids; do alive "$pid" && remaining="$remaining $pid"; done
+escalated=false
+if [ -n "$remaining" ] && [ "$force" = 1 ]; then
+    kill -s KILL $remaining 2>/dev/null || true
+    escalated=true
+    sleep 1
+    still=""
+    for pid in $remaining; do alive "$pid" && still="$still $pid"; done
+    remaining=$still
+fi
+left=$(echo $remaining | wc -w)
+echo "signaled=$count exited=$((count - left)) remaining=$left signal=$signal escalated=$escalated waited_s=$waited"
+[ "$left" -eq 0 ]
+"#;
+
+pub fn build_command(args: &ProcessArgs) -> Result<Script> {
+    match &args.action {
+        ProcessAction::Find { name } => {
+            validate_pattern(name)?;
+            Ok(Script::new()
+                .export("KRUST_PATTERN", name)
+                .section(MATCHER)
+                .section(FIND))
+        }
+        ProcessAction::Kill { name, signal, timeout, force } => {
+            validate_pattern(name)?;
+            Ok(Script::new()
+                .export("KRUST_PATTERN", name)
+                .var("signal", normalize_signal(signal)?)
+                .var("timeout", timeout.as_secs())
+                .var("force", if *force { 1 } else { 0 })
+                .section(MATCHER)
+                .section(KILL))
+        }
+    }
+}
@@ -0,0 +1,144 @@
+// [modules/reboot_wait.rs] - KRUST - Reboot hosts and wait until they are back on a new boot
+use anyhow::{bail, Result};
+use clap::{Args, ValueEnum};
+use std::time::Duration;
+
+use super::contract::RESULT;
+use super::script::Script;
+use crate::reboot::{RebootPolicy, BOOT_STATE_COMMAND, REBOOT_MARKER};
+use crate::HostResult;
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailWhen {
+    /// Fail hosts that need a reboot
+    Required,
+}
+
+/// Upper bound on hosts rebooting at the same time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaxUnavailable {
+    Count(usize),
+    Percent(u8),
+}
+
+impl MaxUnavailable {
+    /// Number of hosts allowed down at once for a run over `hosts` hosts (at least one)
+    pub fn resolve(self, hosts: usize) -> usize {
+        match self {
+            MaxUnavailable::Count(count) => count,
+            MaxUnavailable::Percent(percent) => hosts * percent as usize / 100,
+        }
+        .max(1)
+    }
+}
+
+fn parse_max_unavailable(s: &str) -> Result<MaxUnavailable, String> {
+    let parsed = match s.strip_suffix('%') {
+        Some(percent) => percent.parse::<u8>().ok()
+            .filter(|p| (1..=100).contains(p))
+            .map(MaxUnavailable::Percent),
+        None => s.parse::<usize>().ok()
+            .filter(|count| *count > 0)
+            .map(MaxUnavailable::Count),
+    };
+    parsed.ok_or_else(|| format!("Invalid max unavailable: {} (use a count like 2 or a percentage like 20%)", s))
+}
+
+#[derive(Args, Debug)]
+pub struct RebootWaitArgs {
+    /// How long to wait for each host to come back
+    #[arg(long, default_value = "10m", value_parser = crate::parse_duration)]
+    pub reboot_timeout: Duration,
+
+    /// Rolling reboot: hosts allowed to be down at once, as a count or percentage (e.g. 2, 20%)
+    #[arg(long, value_parser = parse_max_unavailable, conflicts_with = "check")]
+    pub max_unavailable: Option<MaxUnavailable>,
+
+    /// Apply --max-unavailable to each inventory group on its own; hosts in no group form one group
+    #[arg(long, requires = "max_unavailable")]
+    pub per_group: bool,
+
+    /// Command that must succeed on a host after it is back before the next host reboots
+    #[arg(long, conflicts_with = "check")]
+    pub post_check: Option<String>,
+
+    /// Only report whether a reboot is required; nothing is rebooted
+    #[arg(long)]
+    pub check: bool,
+
+    /// With --check, count hosts in this state as failed (for CI gating)
+    #[arg(long, value_enum, requires = "check")]
+    pub fail_when: Option<FailWhen>,
+}
+
+// Exits 1 when a reboot is required; the controller decides whether that is a failure
+const CHECK_SCRIPT: &str = r#"required=false
+if [ -f /var/run/reboot-required ]; then
+    required=true
+elif command -v needs-restarting >/dev/null 2>&1; then
+    needs-restarting -r >/dev/null 2>&1 || required=true
+elif command -v zypper >/dev/null 2>&1; then
+    # 102 means a reboot is needed; any other failure says nothing either way
+    zypper -q needs-rebooting >/dev/null 2>&1 || [ $? -ne 102 ] || required=true
+elif [ -d /lib/modules ] && [ ! -d "/lib/modules/$(uname -r)" ]; then
+    required=true
+fi
+[ -f /var/run/reboot-required.pkgs ] && sed 's/^/package: /' /var/run/reboot-required.pkgs
+echo "KRUST::FACT reboot_required=$required"
+echo "KRUST::RESULT status=ok"
+[ "$required" = false ]
+"#;
+
+pub fn build_command(args: &RebootWaitArgs) -> Result<Script> {
+    if args.check {
+        return Ok(Script::new().section(CHECK_SCRIPT));
+    }
+    if args.reboot_timeout.is_zero() {
+        bail!("--reboot-timeout must be greater than zero");
+    }
+    if args.post_check.as_deref().is_some_and(|cmd| cmd.trim().is_empty()) {
+        bail!("--post-check must not be empty");
+    }
+
+    // The boot state is captured before the reboot is issued; the reboot itself is
+    // detached so this session returns cleanly instead of racing the shutdown.
+    let reboot = format!(r#"state=$({BOOT_STATE_COMMAND}) || exit 2
+echo "{REBOOT_MARKER}$state"
+echo "{RESULT}status=changed"
+nohup sh -c 'sleep 2; shutdown -r now "krust reboot-wait"' >/dev/null 2>&1 &
+"#);
+
+    Ok(Script::new().section(&reboot))
+}
+
+pub fn reboot_policy(args: &RebootWaitArgs) -> Option<RebootPolicy> {
+    if args.check {
+        return None;
+    }
+    Some(RebootPolicy {
+        timeout: args.reboot_timeout,
+        post_check: args.post_check.clone(),
+    })
+}
+
+/// Exit codes that still mean the module ran successfully
+pub fn ok_exit_codes(args: &RebootWaitArgs) -> &'static [i32] {
+    match (args.check, args.fail_when) {
+        // "reboot required" is an answer, not a failure of the check
+        (true, None) => &[1],
+        _ => &[],
+    }
+}
+
+/// Surface the reboot_required fact as the result's reboot_required field
+pub fn post_process(result: &mut HostResult) {
+    let required = result.module_result.as_ref()
+        .and_then(|module_result| module_result.fact("reboot_required"))
+        .and_then(serde_json::Value::as_bool);
+
+    if required.is_none() && result.exit_code == Some(1) {
+        // The check never got as far as reporting, so exit 1 was a real error
+        result.success = false;
+    }
+    result.reboot_required = required;
+}
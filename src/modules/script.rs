@@ -0,0 +1,74 @@
+// [modules/script.rs] - KRUST - Module scripts assembled from fixed fragments and quoted values
+use std::fmt::Display;
+
+use super::{shell_quote, wrap_script};
+
+/// A module's bash script. Fragments added with `section` are fixed text written in the module;
+/// anything that came from the user reaches the script only through `var`, `export` or
+/// `array`, quoted, and is referred to by name from the sections.
+///
+/// Every script starts with `set -e`, so a command failing where no section checks it stops
+/// the script instead of running on; sections mark the failures they expect with `|| true`.
+#[derive(Debug, Clone)]
+pub struct Script {
+    text: String,
+}
+
+impl Default for Script {
+    fn default() -> Self {
+        Script { text: "set -e\n".to_string() }
+    }
+}
+
+impl Script {
+    pub fn new() -> Self {
+        Script::default()
+    }
+
+    /// `name=<value>`, quoted
+    pub fn var(self, name: &str, value: impl Display) -> Self {
+        self.assign("", name, &shell_quote(&value.to_string()))
+    }
+
+    /// `export name=<value>`, for values read by awk or another program through the environment
+    pub fn export(self, name: &str, value: impl Display) -> Self {
+        self.assign("export ", name, &shell_quote(&value.to_string()))
+    }
+
+    /// `name=(<value> ...)`, each value quoted
+    pub fn array<T: Display>(self, name: &str, values: impl IntoIterator<Item = T>) -> Self {
+        let quoted: Vec<String> = values.into_iter().map(|value| shell_quote(&value.to_string())).collect();
+        self.assign("", name, &format!("({})", quoted.join(" ")))
+    }
+
+    /// Fixed script text, ended with a newline when it lacks one. Never build a section from
+    /// user input: give that to `var` and use the variable.
+    pub fn section(mut self, text: &str) -> Self {
+        self.text.push_str(text);
+        if !text.is_empty() && !text.ends_with('\n') {
+            self.text.push('\n');
+        }
+        self
+    }
+
+    fn assign(mut self, prefix: &str, name: &str, quoted: &str) -> Self {
+        debug_assert!(
+            name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+            "invalid shell variable name {:?}",
+            name
+        );
+        self.text.push_str(&format!("{}{}={}\n", prefix, name, quoted));
+        self
+    }
+
+    /// The script as written, e.g. for `krust module show`
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// The command sent to the host: the script under bash, whatever the login shell
+    pub fn build(&self) -> String {
+        wrap_script(&self.text)
+    }
+}
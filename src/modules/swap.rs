@@ -0,0 +1,219 @@
+// [modules/swap.rs] - KRUST - Swap status, and swapfiles created or removed idempotently
+use anyhow::{bail, Result};
+use clap::{Args, Subcommand};
+
+use super::contract::{METRIC, RESULT};
+use super::script::Script;
+
+/// Smallest swapfile worth creating
+const MIN_SIZE_MB: u64 = 16;
+/// Free space the filesystem must keep after the swapfile is written
+const RESERVE_MB: u64 = 256;
+/// Comment line written above the fstab entries this module manages
+const FSTAB_MARKER: &str = "# managed by krust swap";
+
+#[derive(Args, Debug)]
+pub struct SwapArgs {
+    #[command(subcommand)]
+    pub action: SwapAction,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SwapAction {
+    /// Report swap devices and files with their size and usage, one line per host
+    Status,
+    /// Create, enable and persist a swapfile; nothing changes when it is already in place
+    Create {
+        /// Size of the swapfile, e.g. 512M or 4G
+        #[arg(long, value_parser = parse_size_mb)]
+        size: u64,
+
+        /// Absolute path of the swapfile
+        #[arg(long, default_value = "/swapfile")]
+        file: String,
+    },
+    /// Turn a swapfile off and remove its fstab entry
+    Disable {
+        /// Absolute path of the swapfile
+        #[arg(long, default_value = "/swapfile")]
+        file: String,
+
+        /// Delete the file as well
+        #[arg(long)]
+        remove: bool,
+    },
+}
+
+/// "512M" or "4G" in MiB
+pub fn parse_size_mb(s: &str) -> Result<u64, String> {
+    let upper = s.trim().to_uppercase();
+    let (number, multiplier) = match upper.strip_suffix('G') {
+        Some(number) => (number, 1024),
+        None => (upper.strip_suffix('M').unwrap_or(""), 1),
+    };
+    match number.parse::<u64>().ok().and_then(|value| value.checked_mul(multiplier)) {
+        Some(size) if size >= MIN_SIZE_MB => Ok(size),
+        Some(_) => Err(format!("Swap size {} is below the {}M minimum", s, MIN_SIZE_MB)),
+        None => Err(format!("Invalid swap size: {} (use M or G, e.g. 512M or 4G)", s)),
+    }
+}
+
+fn check_file(file: &str) -> Result<()> {
+    if !file.starts_with('/') || file.ends_with('/') {
+        bail!("Swapfile path must be an absolute file path: {}", file);
+    }
+    if file.chars().any(|c| c.is_control() || c.is_whitespace()) {
+        bail!("Swapfile path must not contain whitespace: {:?}", file);
+    }
+    Ok(())
+}
+
+// "total_mb=4096 used_mb=12 free_mb=4084 devices=/swapfile:file:4096:12,..." with the totals of
+// free -m and the areas from /proc/swaps, which every Linux has, unlike swapon --show
+const REPORT: &str = r#"swap_line() {
+    devices=$(awk 'NR > 1 { printf "%s%s:%s:%d:%d", (NR > 2 ? "," : ""), $1, $2, $3 / 1024, $4 / 1024 }' /proc/swaps)
+    free -m | awk -v devices="${devices:-none}" '/^Swap:/ {
+        printf "total_mb=%d used_mb=%d free_mb=%d devices=%s\n", $2, $3, $4, devices }'
+}
+swap_mb() {
+    free -m | awk '/^Swap:/ { print $2 }'
+}
+is_active() {
+    awk -v f="$1" 'NR > 1 && $1 == f { found = 1 } END { exit !found }' /proc/swaps
+}
+need_root() {
+    if [ "$(id -u)" != 0 ]; then
+        echo "swap $1 needs root; connect as root or with --user root" >&2
+        exit 1
+    fi
+}
+"#;
+
+pub fn build_command(args: &SwapArgs) -> Result<Script> {
+    let script = match args.action {
+        SwapAction::Status => Script::new().section(REPORT).section(&format!(r#"[ -r /proc/swaps ] || {{ echo "No /proc/swaps: not a Linux host" >&2; exit 2; }}
+echo "$(swap_line)"
+echo "{METRIC}swap_total_mb=$(swap_mb) swap_used_mb=$(free -m | awk '/^Swap:/ {{ print $3 }}')"
+echo "{RESULT}status=ok"
+"#)),
+        SwapAction::Create { size, ref file } => {
+            check_file(file)?;
+            let create = format!(r#"need_root create
+dir=$(dirname "$f")
+before=$(swap_mb)
+echo "before: $(swap_line)"
+changed=0
+if is_active "$f"; then
+    active_mb=$(awk -v f="$f" 'NR > 1 && $1 == f {{ print int($3 / 1024) }}' /proc/swaps)
+    # The kernel does not count the header page, so allow a little slack
+    if [ $((active_mb + 1)) -lt "$size_mb" ] || [ "$active_mb" -gt "$size_mb" ]; then
+        echo "failed: $f is already active with ${{active_mb}} MiB; run 'swap disable' first to resize it" >&2
+        exit 1
+    fi
+else
+    fstype=$(stat -f -c %T "$dir" 2>/dev/null) || true
+    case $fstype in
+        btrfs)
+            echo "failed: $dir is on btrfs, where a plain swapfile does not work (it needs a NOCOW file: btrfs filesystem mkswapfile)" >&2
+            exit 1 ;;
+        zfs)
+            echo "failed: $dir is on ZFS, which cannot hold a swapfile (use a zvol or another filesystem)" >&2
+            exit 1 ;;
+    esac
+    if [ -e "$f" ]; then
+        # Only an existing swap area of the right size is reused; anything else may be someone's data
+        signature=$(blkid -p -s TYPE -o value "$f" 2>/dev/null) || true
+        if [ -z "$signature" ]; then
+            page=$(getconf PAGESIZE 2>/dev/null || echo 4096)
+            signature=$(dd if="$f" bs=1 skip=$((page - 10)) count=10 2>/dev/null) || true
+            [ "$signature" = SWAPSPACE2 ] && signature=swap
+        fi
+        existing_mb=$(( $(stat -c %s "$f") / 1048576 ))
+        if [ "$signature" != swap ] || [ "$existing_mb" -ne "$size_mb" ]; then
+            echo "failed: $f exists and is not a ${{size_mb}} MiB swap area; remove it or choose another --file" >&2
+            exit 1
+        fi
+    else
+        avail_mb=$(df -Pm "$dir" | awk 'NR == 2 {{ print $4 }}')
+        if [ -z "$avail_mb" ] || [ "$avail_mb" -lt $((size_mb + {RESERVE_MB})) ]; then
+            echo "failed: ${{avail_mb:-unknown}} MiB free on $dir, need ${{size_mb}} MiB plus {RESERVE_MB} MiB to spare" >&2
+            exit 1
+        fi
+        # Restrictive from the first byte: swap can hold any process's memory
+        (umask 077; fallocate -l "${{size_mb}}M" "$f" 2>/dev/null || dd if=/dev/zero of="$f" bs=1M count="$size_mb" status=none) || {{
+            rm -f "$f"
+            echo "failed: could not write $f" >&2
+            exit 1
+        }}
+        mkswap "$f" >/dev/null || {{ rm -f "$f"; echo "failed: mkswap $f" >&2; exit 1; }}
+        echo "created: $f (${{size_mb}} MiB)"
+        changed=1
+    fi
+    chmod 600 "$f"
+    swapon "$f" || {{ echo "failed: swapon $f (fallocate'd files need a filesystem that supports them; try again after removing $f)" >&2; exit 1; }}
+    echo "enabled: $f"
+    changed=1
+fi
+if [ "$(stat -c %a "$f")" != 600 ]; then
+    chmod 600 "$f" && echo "permissions: $f set to 0600"
+    changed=1
+fi
+if ! awk -v f="$f" '$1 == f {{ found = 1 }} END {{ exit !found }}' /etc/fstab; then
+    printf '%s\n%s none swap sw 0 0\n' "$marker" "$f" >> /etc/fstab || exit 1
+    echo "persisted: $f in /etc/fstab"
+    changed=1
+fi
+echo "after: $(swap_line)"
+echo "{METRIC}swap_total_mb_before=$before swap_total_mb=$(swap_mb)"
+[ "$changed" = 1 ] && echo "{RESULT}status=changed" || echo "{RESULT}status=ok"
+"#);
+            Script::new()
+                .var("f", file)
+                .var("size_mb", size)
+                .var("marker", FSTAB_MARKER)
+                .section(REPORT)
+                .section(&create)
+        }
+        SwapAction::Disable { ref file, remove } => {
+            check_file(file)?;
+            let disable = format!(r#"need_root disable
+before=$(swap_mb)
+echo "before: $(swap_line)"
+changed=0
+if is_active "$f"; then
+    swapoff "$f" || {{ echo "failed: swapoff $f (not enough free memory to take its pages back?)" >&2; exit 1; }}
+    echo "disabled: $f"
+    changed=1
+fi
+if awk -v f="$f" '$1 == f {{ found = 1 }} END {{ exit !found }}' /etc/fstab; then
+    tmp=$(mktemp /etc/fstab.krust.XXXXXX) || exit 1
+    # Drop the entry and the marker line written above it
+    awk -v f="$f" -v marker="$marker" '
+        $0 == marker {{ held = $0; next }}
+        $1 == f {{ held = ""; next }}
+        {{ if (held != "") print held; held = ""; print }}
+        END {{ if (held != "") print held }}' /etc/fstab > "$tmp" || {{ rm -f "$tmp"; exit 1; }}
+    cat "$tmp" > /etc/fstab && rm -f "$tmp" || exit 1
+    echo "unpersisted: $f from /etc/fstab"
+    changed=1
+fi
+if [ "$remove" = 1 ] && [ -e "$f" ]; then
+    rm -f "$f" || exit 1
+    echo "removed: $f"
+    changed=1
+fi
+echo "after: $(swap_line)"
+echo "{METRIC}swap_total_mb_before=$before swap_total_mb=$(swap_mb)"
+[ "$changed" = 1 ] && echo "{RESULT}status=changed" || echo "{RESULT}status=ok"
+"#);
+            Script::new()
+                .var("f", file)
+                .var("marker", FSTAB_MARKER)
+                .var("remove", if remove { 1 } else { 0 })
+                .section(REPORT)
+                .section(&disable)
+        }
+    };
+
+    Ok(script)
+}
@@ -0,0 +1,243 @@
+// [modules/sysctl.rs] - KRUST - Kernel parameters set at runtime, persisted, or verified against an expected set
+use anyhow::{bail, Context, Result};
+use clap::{Args, Subcommand};
+use std::collections::BTreeMap;
+
+use super::contract::{METRIC, RESULT};
+use super::script::Script;
+
+/// Drop-in that --persist-settings keeps up to date
+const PERSIST_FILE: &str = "/etc/sysctl.d/99-krust.conf";
+
+#[derive(Args, Debug)]
+pub struct SysctlArgs {
+    #[command(subcommand)]
+    pub action: SysctlAction,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SysctlAction {
+    /// Apply key=value settings at runtime; keys already at their value are left alone
+    Set {
+        /// Settings such as net.ipv4.ip_forward=1 vm.swappiness=10
+        #[arg(required = true)]
+        settings: Vec<String>,
+
+        /// Also write the settings to /etc/sysctl.d/99-krust.conf, so they survive a reboot
+        #[arg(long)]
+        persist_settings: bool,
+    },
+    /// Compare current values with an expected set and fail hosts that drifted
+    Verify {
+        /// Expected key=value settings, added to those of --file
+        settings: Vec<String>,
+
+        /// Local file with the expected settings, in sysctl.conf syntax
+        #[arg(long)]
+        file: Option<String>,
+    },
+}
+
+/// Keys are dot-separated names of letters, digits, '_' and '-', so they map onto a path under
+/// /proc/sys and can never leave it
+pub fn check_key(key: &str) -> Result<()> {
+    let segment = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !key.split('.').all(segment) {
+        bail!("Invalid sysctl key: {:?} (expected a name like net.ipv4.ip_forward)", key);
+    }
+    Ok(())
+}
+
+fn check_value(key: &str, value: &str) -> Result<()> {
+    if value.is_empty() || value.chars().any(|c| c.is_control() && c != '\t') {
+        bail!("Invalid value for {}: {:?} (must be a non-empty single line)", key, value);
+    }
+    Ok(())
+}
+
+/// The kernel separates multi-part values with tabs; compare them with single spaces
+fn normalize_value(value: &str) -> String {
+    value.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn parse_setting(setting: &str) -> Result<(String, String)> {
+    let (key, value) = setting.split_once('=')
+        .with_context(|| format!("Invalid setting (expected key=value): {}", setting))?;
+    let (key, value) = (key.trim(), normalize_value(value));
+    check_key(key)?;
+    check_value(key, &value)?;
+    Ok((key.to_string(), value))
+}
+
+/// Settings of a sysctl.conf file: `key = value` lines, '#' and ';' comments. A later line for
+/// the same key wins, as with `sysctl -p`.
+pub fn parse_conf(content: &str) -> Result<BTreeMap<String, String>> {
+    let mut settings = BTreeMap::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        // systemd's "-key = value" only means "ignore errors applying it"
+        let (key, value) = parse_setting(line.strip_prefix('-').unwrap_or(line))
+            .with_context(|| format!("Line {}", number + 1))?;
+        settings.insert(key, value);
+    }
+    Ok(settings)
+}
+
+fn collect(settings: &[String]) -> Result<BTreeMap<String, String>> {
+    let mut collected = BTreeMap::new();
+    for setting in settings {
+        let (key, value) = parse_setting(setting)?;
+        collected.insert(key, value);
+    }
+    Ok(collected)
+}
+
+/// "key=value" lines, for the scripts to loop over; neither part can hold a newline
+fn setting_lines(settings: &BTreeMap<String, String>) -> String {
+    settings.iter().map(|(key, value)| format!("{}={}\n", key, value)).collect()
+}
+
+const READ: &str = r#"sysctl_path() {
+    echo "/proc/sys/$(echo "$1" | tr . /)"
+}
+current_value() {
+    awk '{ $1 = $1; printf "%s%s", (NR > 1 ? " " : ""), $0 }' "$(sysctl_path "$1")" 2>/dev/null || true
+}
+"#;
+
+pub fn build_command(args: &SysctlArgs) -> Result<Script> {
+    match args.action {
+        SysctlAction::Set { ref settings, persist_settings } => Ok(set_script(&collect(settings)?, persist_settings)),
+        SysctlAction::Verify { ref settings, ref file } => {
+            let mut expected = match file {
+                Some(path) => {
+                    let content = std::fs::read_to_string(path)
+                        .with_context(|| format!("Failed to read expected settings: {}", path))?;
+                    parse_conf(&content).with_context(|| format!("Invalid settings file {}", path))?
+                }
+                None => BTreeMap::new(),
+            };
+            expected.extend(collect(settings)?);
+            if expected.is_empty() {
+                bail!("sysctl verify needs settings to compare: key=value arguments or --file");
+            }
+            Ok(verify_script(&expected))
+        }
+    }
+}
+
+fn set_script(settings: &BTreeMap<String, String>, persist: bool) -> Script {
+    let set = format!(r##"changed=0
+failed=0
+while IFS= read -r setting; do
+    key=${{setting%%=*}}
+    want=${{setting#*=}}
+    path=$(sysctl_path "$key")
+    if [ ! -e "$path" ]; then
+        echo "failed: unknown key $key" >&2
+        failed=1
+        continue
+    fi
+    was=$(current_value "$key")
+    if [ "$was" = "$want" ]; then
+        echo "unchanged: $key = $was"
+        continue
+    fi
+    if ! printf '%s\n' "$want" 2>/dev/null > "$path"; then
+        echo "failed: $key could not be set to $want (needs root?)" >&2
+        failed=1
+        continue
+    fi
+    now=$(current_value "$key")
+    if [ "$now" != "$want" ]; then
+        echo "failed: $key is $now after writing $want" >&2
+        failed=1
+        continue
+    fi
+    echo "changed: $key $was -> $want"
+    changed=$((changed + 1))
+done <<KRUST_SETTINGS
+$settings
+KRUST_SETTINGS
+# A value the kernel refused would only fail again at boot
+if [ "$persist" = 1 ] && [ "$failed" = 0 ]; then
+    mkdir -p "$(dirname "$conf")" || exit 1
+    tmp=$(mktemp "$conf.krust.XXXXXX") || exit 1
+    trap 'rm -f "$tmp"' EXIT
+    # Other keys already in the file stay; ours replace their old line, or are appended
+    [ -f "$conf" ] || echo "# Managed by krust sysctl set --persist-settings" > "$tmp"
+    {{ [ -f "$conf" ] && cat "$conf"; true; }} | KRUST_SETTINGS="$settings" awk '
+        BEGIN {{
+            n = split(ENVIRON["KRUST_SETTINGS"], lines, "\n")
+            for (i = 1; i <= n; i++) if (lines[i] != "") {{
+                key = substr(lines[i], 1, index(lines[i], "=") - 1)
+                want[key] = substr(lines[i], index(lines[i], "=") + 1)
+                order[++count] = key
+            }}
+        }}
+        {{
+            line = $0
+            sub(/^[ \t]*-?/, "", line)
+            key = line
+            sub(/[ \t]*=.*/, "", key)
+            if (index(line, "=") && (key in want)) {{
+                if (!(key in done)) print key " = " want[key]
+                done[key] = 1
+                next
+            }}
+            print
+        }}
+        END {{ for (i = 1; i <= count; i++) if (!(order[i] in done)) print order[i] " = " want[order[i]] }}' >> "$tmp" || exit 1
+    if cmp -s "$conf" "$tmp"; then
+        echo "unchanged: $conf"
+    else
+        chmod 644 "$tmp" && mv -f "$tmp" "$conf" || exit 1
+        echo "persisted: $conf"
+        changed=$((changed + 1))
+    fi
+fi
+echo "{METRIC}sysctl_changed=$changed"
+[ "$failed" = 1 ] && {{ echo "{RESULT}status=failed"; exit 1; }}
+[ "$changed" -gt 0 ] && echo "{RESULT}status=changed" || echo "{RESULT}status=ok"
+"##);
+    Script::new()
+        .var("settings", setting_lines(settings).trim_end())
+        .var("persist", if persist { 1 } else { 0 })
+        .var("conf", PERSIST_FILE)
+        .section(READ)
+        .section(&set)
+}
+
+// Keys are sorted and only drift is printed, so hosts with the same drift have the same output
+fn verify_script(expected: &BTreeMap<String, String>) -> Script {
+    let verify = format!(r#"drift=0
+checked=0
+while IFS= read -r setting; do
+    key=${{setting%%=*}}
+    want=${{setting#*=}}
+    checked=$((checked + 1))
+    if [ ! -e "$(sysctl_path "$key")" ]; then
+        echo "$key current=missing expected=$want"
+        drift=$((drift + 1))
+        continue
+    fi
+    now=$(current_value "$key")
+    if [ "$now" != "$want" ]; then
+        echo "$key current=$now expected=$want"
+        drift=$((drift + 1))
+    fi
+done <<KRUST_SETTINGS
+$settings
+KRUST_SETTINGS
+echo "{METRIC}sysctl_checked=$checked sysctl_drift=$drift"
+[ "$drift" = 0 ] || {{ echo "{RESULT}status=failed"; exit 1; }}
+echo "{RESULT}status=ok"
+"#);
+    Script::new()
+        .var("settings", setting_lines(expected).trim_end())
+        .section(READ)
+        .section(&verify)
+}
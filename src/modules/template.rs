@@ -0,0 +1,243 @@
+// [modules/template.rs] - KRUST - Render local templates and upload them to hosts over SFTP
+use anyhow::{bail, Context, Result};
+use clap::Args;
+use minijinja::{Environment, UndefinedBehavior, Value};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::debug;
+
+use super::contract::RESULT;
+use super::copy::{self, Upload};
+use super::run_follow_up;
+use super::script::Script;
+use crate::executor::{CommandExecutor, Transport};
+use crate::host_error::{HostError, LocalErrorKind};
+use crate::ssh_executor::{HostSource, SshHost};
+use crate::HostResult;
+
+/// The line a host reports the sha256 of the file already at the destination on, empty when there is none
+const CURRENT: &str = "KRUST::TEMPLATE ";
+const FAILED: &str = "KRUST::TEMPLATE-FAILED ";
+
+#[derive(Args, Debug, Clone)]
+pub struct TemplateArgs {
+    /// Local template file (Jinja syntax)
+    pub src: String,
+
+    /// Absolute destination path on each host
+    pub dest: String,
+
+    /// Template variable as key=value (repeatable)
+    #[arg(long = "var")]
+    pub vars: Vec<String>,
+
+    /// YAML or JSON file with template variables
+    #[arg(long)]
+    pub var_file: Option<String>,
+
+    /// Remote command validating the rendered file before it is moved into place ({dest} is the staged file)
+    #[arg(long)]
+    pub validate_cmd: Option<String>,
+}
+
+fn load_vars(args: &TemplateArgs) -> Result<BTreeMap<String, Value>> {
+    let mut vars = BTreeMap::new();
+
+    if let Some(ref path) = args.var_file {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read var file: {}", path))?;
+        let parsed: BTreeMap<String, serde_yaml::Value> = serde_yaml::from_str(&content)
+            .with_context(|| format!("Var file must be a mapping of names to values: {}", path))?;
+        for (key, value) in parsed {
+            vars.insert(key, Value::from_serialize(&value));
+        }
+    }
+
+    for var in &args.vars {
+        let (key, value) = var.split_once('=')
+            .with_context(|| format!("Invalid --var (expected key=value): {}", var))?;
+        if key.trim().is_empty() {
+            bail!("Invalid --var (empty name): {}", var);
+        }
+        vars.insert(key.trim().to_string(), Value::from(value));
+    }
+
+    Ok(vars)
+}
+
+/// The template and the run's variables, loaded once; each host's file is rendered from them
+#[derive(Debug)]
+pub struct Source {
+    args: TemplateArgs,
+    template: String,
+    vars: BTreeMap<String, Value>,
+    /// Why the template or its variables could not be loaded; it fails every host
+    problem: Option<String>,
+}
+
+impl Source {
+    pub fn load(args: &TemplateArgs) -> Self {
+        let loaded = std::fs::read_to_string(&args.src)
+            .with_context(|| format!("Failed to read template: {}", args.src))
+            .and_then(|template| Ok((template, load_vars(args)?)));
+        match loaded {
+            Ok((template, vars)) => Source { args: args.clone(), template, vars, problem: None },
+            Err(e) => Source { args: args.clone(), template: String::new(), vars: BTreeMap::new(), problem: Some(format!("{:#}", e)) },
+        }
+    }
+
+    /// Render the file for one host. The host's inventory group and tags and the user krust logs
+    /// in as are variables too, which --var and --var-file override; hostname, port and address
+    /// always name the host.
+    pub fn render(&self, host: &SshHost, user: &str, tags: &[String]) -> Result<String> {
+        if let Some(ref problem) = self.problem {
+            bail!("{}", problem);
+        }
+        let mut env = Environment::new();
+        env.set_undefined_behavior(UndefinedBehavior::Strict);
+        env.set_keep_trailing_newline(true);
+        env.add_template("template", &self.template)
+            .with_context(|| format!("Invalid template: {}", self.args.src))?;
+
+        let group = match host.source {
+            HostSource::Inventory { ref group, .. } | HostSource::Command { ref group, .. } => group.clone(),
+            HostSource::Cli => None,
+        };
+        let mut context = BTreeMap::new();
+        context.insert("user".to_string(), Value::from(user));
+        context.insert("group".to_string(), Value::from(group));
+        context.insert("tags".to_string(), Value::from_serialize(tags));
+        context.extend(self.vars.clone());
+        context.insert("hostname".to_string(), Value::from(host.hostname.clone()));
+        context.insert("port".to_string(), Value::from(host.port));
+        context.insert("address".to_string(), Value::from(host.address.clone()));
+
+        let rendered = env.get_template("template")?.render(&context)
+            .with_context(|| format!("Failed to render {} for {}", self.args.src, host.hostname))?;
+        debug!("Rendered {} for {} ({} bytes)", self.args.src, host.hostname, rendered.len());
+        Ok(rendered)
+    }
+}
+
+/// Every host reports what is at the destination; the rendered file is uploaded afterwards, and
+/// only to hosts where it differs
+pub fn build_command(args: &TemplateArgs) -> Result<Script> {
+    if !args.dest.starts_with('/') {
+        bail!("template destination must be absolute: {}", args.dest);
+    }
+    if args.dest.contains('\n') || args.dest.contains('\0') {
+        bail!("template destination must be a single line");
+    }
+    let current = format!(r#"if [ -f "$dest" ]; then
+    if command -v sha256sum >/dev/null 2>&1; then sha256sum "$dest"; else shasum -a 256 "$dest"; fi | cut -d' ' -f1 | sed 's/^/{CURRENT}/'
+else
+    echo "{CURRENT}"
+fi
+echo "{RESULT}status=ok"
+"#);
+    Ok(Script::new().var("dest", &args.dest).section(&current))
+}
+
+/// Render the host's file and, when it differs from the one there, upload it beside the
+/// destination, run --validate-cmd on it and rename it into place
+pub async fn upload<E: CommandExecutor>(
+    transport: &Arc<Transport<E>>,
+    host: &SshHost,
+    result: &mut HostResult,
+    source: &Source,
+    tags: &[String],
+    limit: Duration,
+) {
+    if !result.success {
+        return;
+    }
+    let dest = &source.args.dest;
+    let mut lines = Vec::new();
+    let mut previous = None;
+    for line in result.stdout.as_deref().unwrap_or_default().lines() {
+        match line.strip_prefix(CURRENT) {
+            Some(sum) => previous = Some(sum.trim().to_string()).filter(|sum| !sum.is_empty()),
+            None => lines.push(line.to_string()),
+        }
+    }
+    let rendered = match source.render(host, &transport.auth.for_host(host).user, tags) {
+        Ok(rendered) => rendered,
+        Err(e) => return result.fail(HostError::local(LocalErrorKind::Module, format!("template: {:#}", e), None)),
+    };
+    let sha256 = format!("{:x}", Sha256::digest(rendered.as_bytes()));
+
+    let changed = previous.as_deref() != Some(sha256.as_str());
+    if changed {
+        if let Err(e) = install(transport, host, source, rendered.into_bytes(), &sha256, limit).await {
+            return result.fail(HostError::local(LocalErrorKind::Module, format!("template: {}", e), None));
+        }
+        lines.push(format!("changed: {}", dest));
+    } else {
+        lines.push(format!("unchanged: {}", dest));
+    }
+    result.set_stdout(lines.join("\n"));
+    result.module_fields.insert("template".to_string(), json!({ "sha256": sha256, "previous_sha256": previous }));
+    if let Some(ref mut module) = result.module_result {
+        module.status = Some(if changed { "changed" } else { "ok" }.to_string());
+    }
+}
+
+/// Upload the rendered file to a staging name over SFTP, then check it on the host and move it over
+/// the destination, keeping the destination's mode and owner
+async fn install<E: CommandExecutor>(
+    transport: &Arc<Transport<E>>,
+    host: &SshHost,
+    source: &Source,
+    content: Vec<u8>,
+    sha256: &str,
+    limit: Duration,
+) -> Result<(), String> {
+    let staging = copy::staging_path(&source.args.dest);
+    let upload = Upload::Content { content, remote: staging.clone(), mode: 0o600 };
+    let uploaded = match copy::apply(transport, host, vec![upload], limit).await {
+        Ok(mut outcomes) => outcomes.pop().unwrap_or_else(|| Err("no upload outcome".to_string())),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = uploaded {
+        copy::discard(transport, host, &staging).await;
+        return Err(e);
+    }
+
+    // The --validate-cmd is the user's own shell command and runs through eval, with $tmp
+    // standing for {dest}
+    let steps = format!(r#"sum() {{
+    if command -v sha256sum >/dev/null 2>&1; then sha256sum "$1"; else shasum -a 256 "$1"; fi | cut -d' ' -f1
+}}
+fail() {{
+    rm -f "$tmp"
+    echo "{FAILED}$*"
+    exit 1
+}}
+[ "$(sum "$tmp")" = "$want" ] || fail "checksum mismatch after upload"
+if [ -n "$validate" ] && ! ( eval "$validate" ) >&2; then
+    fail "validation rejected $dest"
+fi
+if [ -e "$dest" ]; then
+    chmod --reference="$dest" "$tmp" 2>/dev/null || true
+    chown --reference="$dest" "$tmp" 2>/dev/null || true
+else
+    chmod 644 "$tmp"
+fi
+mv -f "$tmp" "$dest" || fail "could not rename the staged file over $dest"
+"#);
+    let script = Script::new()
+        .var("tmp", &staging)
+        .var("dest", &source.args.dest)
+        .var("want", sha256)
+        .var("validate", source.args.validate_cmd.as_deref().map(|cmd| cmd.replace("{dest}", "\"$tmp\"")).unwrap_or_default())
+        .section(&steps);
+    run_follow_up(transport, host, script.build(), limit).await.map(drop).map_err(|e| {
+        match e.split_once(FAILED) {
+            Some((_, reason)) => reason.trim().to_string(),
+            None => e,
+        }
+    })
+}
@@ -0,0 +1,125 @@
+// [modules/timecheck.rs] - KRUST - Clock offset and time synchronization check
+use anyhow::{bail, Result};
+use chrono::{DateTime, Duration as ChronoDuration, TimeZone, Utc};
+use clap::Args;
+use std::time::Duration;
+
+use super::script::Script;
+use crate::host_error::{HostError, LocalErrorKind};
+use crate::HostResult;
+
+#[derive(Args, Debug)]
+pub struct TimeCheckArgs {
+    /// Maximum tolerated clock offset (e.g. 500ms, 2s)
+    #[arg(long, default_value = "500ms", value_parser = crate::parse_duration)]
+    pub max_offset: Duration,
+}
+
+const REMOTE_START_PREFIX: &str = "remote_start_ms=";
+const REMOTE_CLOCK_PREFIX: &str = "remote_ms=";
+
+/// The host's clock, read first and again as the script exits, so the time the checks took can be
+/// told apart from the time the output spent on the wire
+const CLOCK: &str = r#"echo "remote_start_ms=$(date +%s%3N)"
+trap 'echo "remote_ms=$(date +%s%3N)"' EXIT
+"#;
+
+const SYNC_STATUS: &str = r#"offset=unknown
+source=none
+synced=false
+if command -v chronyc >/dev/null 2>&1 && tracking=$(chronyc tracking 2>/dev/null); then
+    source=chrony
+    offset=$(echo "$tracking" | awk -F': ' '/^System time/ {
+        split($2, f, " "); ms = f[1] * 1000; if ($2 ~ /slow/) ms = -ms; printf "%.0f", ms }')
+    echo "$tracking" | grep -q '^Leap status *: Normal' && synced=true
+elif command -v ntpq >/dev/null 2>&1 && peers=$(ntpq -pn 2>/dev/null); then
+    source=ntpd
+    peer=$(echo "$peers" | awk '/^\*/ { printf "%.0f", $9 }')
+    if [ -n "$peer" ]; then offset=$peer; synced=true; fi
+elif command -v timedatectl >/dev/null 2>&1; then
+    if [ "$(timedatectl show -p NTPSynchronized --value 2>/dev/null)" = yes ]; then
+        source=timesyncd
+        synced=true
+        offset=$(timedatectl timesync-status 2>/dev/null | awk '/Offset:/ {
+            v = $2; sub(/^\+/, "", v); ms = v + 0
+            if (v ~ /us$/) ms = ms / 1000; else if (v ~ /[^m]s$/) ms = ms * 1000
+            printf "%.0f", ms }')
+    fi
+fi
+[ -n "$offset" ] || offset=unknown
+echo "offset_ms=$offset source=$source synced=$synced"
+"#;
+
+const CHECK: &str = r#"[ "$synced" = true ] || exit 1
+case "$offset" in
+    unknown) exit 0 ;;
+    -*) abs=${offset#-} ;;
+    *) abs=$offset ;;
+esac
+[ "$abs" -le "$max_offset_ms" ]
+"#;
+
+pub fn build_command(args: &TimeCheckArgs) -> Result<Script> {
+    let max_offset_ms = args.max_offset.as_millis();
+    if max_offset_ms == 0 {
+        bail!("--max-offset must be greater than zero");
+    }
+
+    Ok(Script::new()
+        .var("max_offset_ms", max_offset_ms)
+        .section(CLOCK)
+        .section(SYNC_STATUS)
+        .section(CHECK))
+}
+
+/// Host clock minus controller clock at the moment the host last read it. Output leaves the host
+/// right after that read, so it is taken to have happened one-way latency before the output was
+/// read: half of what the exec phase took beyond the script's own run time.
+fn controller_skew_ms(remote_start_ms: Option<i64>, remote_ms: i64, read_at: DateTime<Utc>, exec_ms: Option<u64>) -> Option<i64> {
+    let remote = Utc.timestamp_millis_opt(remote_ms).single()?;
+    let ran_ms = remote_start_ms.map_or(0, |start| (remote_ms - start).max(0));
+    let latency_ms = exec_ms.map_or(0, |exec| (exec as i64 - ran_ms).max(0) / 2);
+    Some((remote - (read_at - ChronoDuration::milliseconds(latency_ms))).num_milliseconds())
+}
+
+/// Replace the remote clock markers with the skew measured against the controller clock
+pub fn post_process(args: &TimeCheckArgs, result: &mut HostResult) {
+    let Some(ref stdout) = result.stdout else {
+        return;
+    };
+
+    let clock = |prefix: &str| stdout.lines()
+        .find_map(|line| line.strip_prefix(prefix))
+        .and_then(|value| value.trim().parse::<i64>().ok());
+    let Some(remote_ms) = clock(REMOTE_CLOCK_PREFIX) else {
+        return;
+    };
+    let exec_ms = result.timings.and_then(|timings| timings.exec_ms);
+    let skew_ms = controller_skew_ms(clock(REMOTE_START_PREFIX), remote_ms, result.finished_at, exec_ms);
+
+    let lines: Vec<String> = stdout.lines()
+        .filter(|line| !line.starts_with(REMOTE_START_PREFIX) && !line.starts_with(REMOTE_CLOCK_PREFIX))
+        .map(|line| match skew_ms {
+            Some(skew) if line.starts_with("offset_ms=") => format!("{} controller_skew_ms={}", line, skew),
+            _ => line.to_string(),
+        })
+        .collect();
+
+    if let Some(skew) = skew_ms {
+        if skew.unsigned_abs() as u128 > args.max_offset.as_millis() && result.success {
+            result.fail(HostError::local(LocalErrorKind::Module, format!(
+                "Clock skew versus controller is {}ms (max {}ms)",
+                skew,
+                args.max_offset.as_millis()
+            ), None));
+        }
+    }
+
+    let output = lines.join("\n");
+    result.stdout_lines = if output.contains('\n') {
+        Some(lines)
+    } else {
+        None
+    };
+    result.stdout = Some(output);
+}
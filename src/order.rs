@@ -0,0 +1,91 @@
+// [order.rs] - KRUST - Order in which hosts are started
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use std::collections::{BTreeMap, HashMap};
+use tracing::{debug, info, warn};
+
+use crate::ssh_executor::{normalize_name, SshHost};
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostOrder {
+    /// Alphabetical by hostname, then port
+    Sorted,
+    /// Random order, so no rack or datacenter is always hit first (see --seed)
+    Shuffle,
+    /// As given: --hosts first, then inventory file order
+    Inventory,
+}
+
+/// Drop duplicate hosts and put them in start order, with `prefer`red hosts moved to the front
+pub fn order_hosts(
+    hosts: Vec<SshHost>,
+    order: HostOrder,
+    seed: Option<u64>,
+    prefer: &[String],
+) -> Result<Vec<SshHost>> {
+    if seed.is_some() && order != HostOrder::Shuffle {
+        bail!("--seed only applies to --order shuffle");
+    }
+
+    // First occurrence of each connection address wins, so inventory order and aliases survive
+    let mut seen: HashMap<(String, u16), usize> = HashMap::new();
+    let mut kept: Vec<SshHost> = Vec::with_capacity(hosts.len());
+    for host in hosts {
+        match seen.get(&(host.address.clone(), host.port)) {
+            Some(&index) => warn_merged(&kept[index], &host),
+            None => {
+                seen.insert((host.address.clone(), host.port), kept.len());
+                kept.push(host);
+            }
+        }
+    }
+    let mut hosts = kept;
+    warn_port_conflicts(&hosts);
+
+    match order {
+        HostOrder::Sorted => hosts.sort(),
+        HostOrder::Shuffle => {
+            let seed = seed.unwrap_or_else(|| {
+                let seed = rand::thread_rng().gen();
+                info!("Shuffled hosts with seed {} (--seed {} repeats this order)", seed, seed);
+                seed
+            });
+            hosts.shuffle(&mut StdRng::seed_from_u64(seed));
+        }
+        HostOrder::Inventory => {}
+    }
+
+    let prefer: Vec<String> = prefer.iter().map(|name| normalize_name(name)).collect();
+    if let Some(missing) = prefer.iter().find(|name| !hosts.iter().any(|host| &host.hostname == *name)) {
+        bail!("--prefer host '{}' is not in the host list", missing);
+    }
+    // Stable sort keeps the chosen order within the preferred and the remaining hosts
+    hosts.sort_by_key(|host| prefer.iter().position(|name| *name == host.hostname).unwrap_or(prefer.len()));
+    Ok(hosts)
+}
+
+/// A second definition of a connection target is dropped; say so when it named the host differently
+fn warn_merged(kept: &SshHost, dropped: &SshHost) {
+    match kept.hostname == dropped.hostname {
+        true => debug!("{} from {} is already listed from {}", dropped, dropped.source, kept.source),
+        false => warn!(
+            "{} ({}) and {} ({}) both connect to {}; running it once as {}",
+            kept.hostname, kept.source, dropped.hostname, dropped.source, kept.target(), kept.hostname
+        ),
+    }
+}
+
+/// The same name on several ports is usually a typo in one place; every port is still run
+fn warn_port_conflicts(hosts: &[SshHost]) {
+    let mut ports: BTreeMap<&str, Vec<&SshHost>> = BTreeMap::new();
+    for host in hosts {
+        ports.entry(&host.hostname).or_default().push(host);
+    }
+    for (hostname, hosts) in ports.iter().filter(|(_, hosts)| hosts.len() > 1) {
+        let ports: Vec<String> = hosts.iter().map(|host| format!("{} ({})", host.port, host.source)).collect();
+        warn!("{} is listed with ports {}; each is run as a separate host", hostname, ports.join(", "));
+    }
+}
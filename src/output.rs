@@ -0,0 +1,110 @@
+// [output.rs] - KRUST - Result printing that stops quietly once stdout is closed
+use std::fmt;
+use std::io::{stderr, stdout, ErrorKind, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// Exit code after stdout went away, as if killed by SIGPIPE (128 + 13)
+pub const EXIT_BROKEN_PIPE: i32 = 141;
+
+static CLOSED: AtomicBool = AtomicBool::new(false);
+
+/// The progress line currently drawn on stderr. Its lock is the one writer lock: stdout writes
+/// take it too, so erasing and redrawing progress never lands inside a result line.
+static PROGRESS: Mutex<Option<String>> = Mutex::new(None);
+
+/// Write to stdout; once the reader is gone (`krust ... | head`) further output is dropped.
+/// The text is formatted first and written at once, stepping around the progress line.
+pub fn write_stdout(args: fmt::Arguments) {
+    if CLOSED.load(Ordering::Relaxed) {
+        return;
+    }
+    let text = fmt::format(args);
+    // Tests read what they print through `capture`; nothing reaches the real stdout
+    if cfg!(test) {
+        #[cfg(test)]
+        capture::write(&text);
+        return;
+    }
+    let progress = PROGRESS.lock().unwrap();
+    if progress.is_some() {
+        let _ = stderr().write_all(b"\r\x1b[K");
+    }
+    let mut out = stdout().lock();
+    if let Err(e) = out.write_all(text.as_bytes()).and_then(|()| out.flush()) {
+        if e.kind() == ErrorKind::BrokenPipe {
+            CLOSED.store(true, Ordering::Relaxed);
+        }
+    }
+    if let Some(ref line) = *progress {
+        let _ = stderr().write_all(line.as_bytes());
+    }
+}
+
+/// Draw `line` (with its own leading `\r`) as the progress line on stderr, replacing the last one
+pub fn progress(line: String) {
+    if cfg!(test) {
+        return;
+    }
+    let mut progress = PROGRESS.lock().unwrap();
+    let _ = stderr().write_all(line.as_bytes());
+    *progress = Some(line);
+}
+
+/// Erase the progress line for good, e.g. before the summary
+pub fn clear_progress() {
+    if PROGRESS.lock().unwrap().take().is_some() {
+        let _ = stderr().write_all(b"\r\x1b[K");
+    }
+}
+
+/// True once a write to stdout failed with a broken pipe
+pub fn stdout_closed() -> bool {
+    CLOSED.load(Ordering::Relaxed)
+}
+
+/// `print!` that never panics on a closed stdout
+macro_rules! out {
+    ($($arg:tt)*) => {
+        $crate::output::write_stdout(format_args!($($arg)*))
+    };
+}
+
+/// `println!` that never panics on a closed stdout
+macro_rules! outln {
+    () => {
+        $crate::output::write_stdout(format_args!("\n"))
+    };
+    ($($arg:tt)*) => {
+        $crate::output::write_stdout(format_args!("{}\n", format_args!($($arg)*)))
+    };
+}
+
+/// Stdout of the current test thread, so tests can read what a run printed. Tests never write
+/// to the real stdout: what is printed outside `start`/`finish` is dropped, as is the progress
+/// line, so `cargo test` shows only the test report.
+#[cfg(test)]
+pub mod capture {
+    use std::cell::RefCell;
+
+    thread_local! {
+        static BUFFER: RefCell<Option<String>> = const { RefCell::new(None) };
+    }
+
+    pub fn start() {
+        BUFFER.with(|buffer| *buffer.borrow_mut() = Some(String::new()));
+    }
+
+    /// Everything printed since `start`; stops capturing
+    pub fn finish() -> String {
+        BUFFER.with(|buffer| buffer.borrow_mut().take()).unwrap_or_default()
+    }
+
+    pub(super) fn write(text: &str) {
+        BUFFER.with(|buffer| {
+            if let Some(ref mut captured) = *buffer.borrow_mut() {
+                captured.push_str(text);
+            }
+        })
+    }
+}
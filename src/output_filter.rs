@@ -0,0 +1,52 @@
+// [output_filter.rs] - KRUST - Presentation filters that hide hosts by their output
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use regex::Regex;
+
+use crate::{Cli, HostResult};
+
+/// What JSON output does with hosts that do not match
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchMode {
+    /// Leave non-matching hosts out
+    Filter,
+    /// Keep every host and tag it with "matched"
+    Annotate,
+}
+
+/// Line-based --match/--no-match filter on stdout
+pub struct OutputFilter {
+    include: Option<Regex>,
+    exclude: Option<Regex>,
+    pub mode: MatchMode,
+}
+
+impl OutputFilter {
+    pub fn from_args(args: &Cli) -> Result<Option<Self>> {
+        let compile = |flag: &str, pattern: &Option<String>| {
+            pattern.as_deref()
+                .map(|p| Regex::new(p).with_context(|| format!("Invalid {}: {}", flag, p)))
+                .transpose()
+        };
+        let include = compile("--match", &args.match_regex)?;
+        let exclude = compile("--no-match", &args.no_match_regex)?;
+
+        if include.is_none() && exclude.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(OutputFilter { include, exclude, mode: args.match_mode }))
+    }
+
+    /// A host matches when some stdout line matches --match and no line matches --no-match
+    pub fn matches(&self, result: &HostResult) -> bool {
+        let stdout = result.stdout.as_deref().unwrap_or("");
+        let any_line = |regex: &Regex| stdout.lines().any(|line| regex.is_match(line));
+
+        self.include.as_ref().is_none_or(any_line) && !self.exclude.as_ref().is_some_and(any_line)
+    }
+
+    /// Whether a stdout line should be highlighted in text output
+    pub fn highlights(&self, line: &str) -> bool {
+        self.include.as_ref().is_some_and(|regex| regex.is_match(line))
+    }
+}
@@ -0,0 +1,441 @@
+// [persist.rs] - KRUST - --persist: a per-user daemon that keeps authenticated sessions for later runs
+//
+// The daemon owns the SSH sessions; a --persist run hands it each command over a unix socket and
+// gets the output back, much like ssh's ControlMaster. A password or key passphrase in a request is only
+// used to authenticate a new session and is dropped with it; nothing is written to disk.
+use anyhow::{anyhow, bail, Context, Result};
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::ssh_executor::{ExecError, ExecErrorKind, ExecOutput, SshAuth, SshHost};
+
+/// How long a new daemon gets to start listening
+const STARTUP_WAIT: Duration = Duration::from_secs(3);
+
+#[derive(Serialize, Deserialize)]
+enum Request {
+    Exec(ExecRequest),
+    Stop,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ExecRequest {
+    hostname: String,
+    /// `address[:port]`
+    target: String,
+    addrs: Vec<SocketAddr>,
+    user: String,
+    methods: Vec<WireMethod>,
+    command: String,
+    /// Base64
+    input: Option<String>,
+    idle_secs: u64,
+}
+
+/// AuthMethod on the wire; only ever sent over the user-only socket
+#[derive(Serialize, Deserialize)]
+enum WireMethod {
+    Password(String),
+    KeyFile { key: PathBuf, certificate: Option<PathBuf>, passphrase: Option<String> },
+    Agent,
+}
+
+#[derive(Serialize, Deserialize)]
+enum Response {
+    Done {
+        /// Base64
+        output: String,
+        /// Base64; a daemon from before stderr was read apart sends none
+        #[serde(default)]
+        stderr: String,
+        #[serde(default)]
+        bytes_stderr: u64,
+        exit_code: i32,
+        auth_ms: u64,
+        auth_method: String,
+    },
+    Failed { kind: ExecErrorKind, message: String },
+    Stopped { sessions: usize },
+}
+
+/// $XDG_RUNTIME_DIR/krust/persist.sock, else a per-user directory under the temp dir
+fn socket_path() -> Result<PathBuf> {
+    let dir = match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(runtime) => PathBuf::from(runtime).join("krust"),
+        None => {
+            let user = std::env::var("USER").unwrap_or_else(|_| "user".to_string());
+            std::env::temp_dir().join(format!("krust-{}", user))
+        }
+    };
+    Ok(dir.join("persist.sock"))
+}
+
+fn encode(data: &[u8]) -> String {
+    base64::engine::general_purpose::STANDARD.encode(data)
+}
+
+fn decode(text: &str) -> Result<Vec<u8>> {
+    base64::engine::general_purpose::STANDARD.decode(text).context("Invalid base64 from the persist daemon")
+}
+
+#[cfg(unix)]
+pub use unix::{execute, run_daemon, stop};
+
+#[cfg(not(unix))]
+pub fn execute(
+    _host: &SshHost,
+    _addrs: &[SocketAddr],
+    _auth: &SshAuth,
+    _command: &str,
+    _input: Option<&[u8]>,
+    _idle: Duration,
+) -> Option<Result<ExecOutput, ExecError>> {
+    None
+}
+
+#[cfg(not(unix))]
+pub fn run_daemon() -> Result<()> {
+    bail!("--persist is only supported on Unix controllers")
+}
+
+#[cfg(not(unix))]
+pub fn stop() -> Result<Option<usize>> {
+    bail!("--persist is only supported on Unix controllers")
+}
+
+#[cfg(unix)]
+mod unix {
+    use super::*;
+    use ssh2::Session;
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, ErrorKind, Write};
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::os::unix::process::CommandExt;
+    use std::process::{Command, Stdio};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex, OnceLock};
+    use std::time::Instant;
+    use tracing::{debug, warn};
+
+    use crate::secret::Secret;
+    use crate::spill::{self, Capture};
+    use crate::ssh_executor::{self, AuthMethod, ChannelOutput, Connection};
+    use crate::timings::{self, Phase};
+
+    /// Run one command through the daemon, starting it if needed. None when the daemon cannot
+    /// be reached, so the caller connects directly.
+    pub fn execute(
+        host: &SshHost,
+        addrs: &[SocketAddr],
+        auth: &SshAuth,
+        command: &str,
+        input: Option<&[u8]>,
+        idle: Duration,
+    ) -> Option<Result<ExecOutput, ExecError>> {
+        static UNREACHABLE: AtomicBool = AtomicBool::new(false);
+        let request = Request::Exec(ExecRequest {
+            hostname: host.hostname.clone(),
+            target: host.target(),
+            addrs: addrs.to_vec(),
+            user: auth.user.clone(),
+            methods: auth.methods.iter().map(|method| match method {
+                AuthMethod::Password(password) => WireMethod::Password(password.expose().to_string()),
+                AuthMethod::KeyFile { key, certificate, passphrase } => WireMethod::KeyFile {
+                    key: key.clone(),
+                    certificate: certificate.clone(),
+                    passphrase: passphrase.as_ref().map(|passphrase| passphrase.expose().to_string()),
+                },
+                AuthMethod::Agent => WireMethod::Agent,
+            }).collect(),
+            command: command.to_string(),
+            input: input.map(encode),
+            idle_secs: idle.as_secs().max(1),
+        });
+        timings::begin(Phase::Exec);
+        let response = connect(true).and_then(|stream| exchange(stream, &request));
+        timings::end();
+        match response {
+            Ok(Response::Done { output, stderr, bytes_stderr, exit_code, auth_ms, auth_method }) => {
+                Some(done(host, &output, &stderr, bytes_stderr, exit_code, auth_ms, auth_method))
+            }
+            Ok(Response::Failed { kind, message }) => Some(Err(ExecError::new(kind, anyhow!(message)))),
+            Ok(Response::Stopped { .. }) => None,
+            Err(e) => {
+                if !UNREACHABLE.swap(true, Ordering::Relaxed) {
+                    warn!("Persist daemon unavailable, connecting directly: {:#}", e);
+                }
+                None
+            }
+        }
+    }
+
+    /// Received output goes through the spill budget like output read off a channel
+    fn done(host: &SshHost, output: &str, stderr: &str, bytes_stderr: u64, exit_code: i32, auth_ms: u64, auth_method: String) -> Result<ExecOutput, ExecError> {
+        let channel_error = |e: anyhow::Error| ExecError::new(ExecErrorKind::Channel, e);
+        let mut capture = Capture::new(spill::budget(), host);
+        capture.push(&decode(output).map_err(channel_error)?).map_err(|e| channel_error(e.into()))?;
+        let captured = capture.finish().map_err(|e| channel_error(e.into()))?;
+        let stderr = decode(stderr).map_err(channel_error)?;
+        let bytes_stderr = bytes_stderr.max(stderr.len() as u64);
+        Ok(ExecOutput { output: captured.output, spilled: captured.spilled, stderr, bytes_stderr, exit_code, auth_ms, auth_method, ssh_details: None })
+    }
+
+    /// `krust persist-stop`: the number of sessions closed, or None when no daemon was running
+    pub fn stop() -> Result<Option<usize>> {
+        let Ok(stream) = connect(false) else {
+            return Ok(None);
+        };
+        match exchange(stream, &Request::Stop)? {
+            Response::Stopped { sessions } => Ok(Some(sessions)),
+            _ => bail!("Unexpected answer from the persist daemon"),
+        }
+    }
+
+    fn exchange(mut stream: UnixStream, request: &Request) -> Result<Response> {
+        let mut line = serde_json::to_string(request)?;
+        line.push('\n');
+        stream.write_all(line.as_bytes())?;
+        let mut reply = String::new();
+        BufReader::new(stream).read_line(&mut reply)?;
+        serde_json::from_str(&reply).context("The persist daemon closed the connection")
+    }
+
+    /// Connect to the daemon, starting one when `start` is set and none is listening
+    fn connect(start: bool) -> Result<UnixStream> {
+        let path = socket_path()?;
+        if let Ok(stream) = UnixStream::connect(&path) {
+            return Ok(stream);
+        }
+        if !start {
+            bail!("No persist daemon at {}", path.display());
+        }
+        // Shares the start with any other client racing us: only one daemon can bind
+        static STARTED: OnceLock<()> = OnceLock::new();
+        STARTED.get_or_init(|| {
+            debug!("Starting persist daemon at {}", path.display());
+            let spawned = std::env::current_exe().and_then(|exe| {
+                Command::new(exe)
+                    .arg("__persist-daemon")
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    // Its own process group, so Ctrl-C on this run does not reach it
+                    .process_group(0)
+                    .spawn()
+            });
+            if let Err(e) = spawned {
+                debug!("Failed to start persist daemon: {}", e);
+            }
+        });
+        let started = Instant::now();
+        loop {
+            match UnixStream::connect(&path) {
+                Ok(stream) => return Ok(stream),
+                Err(e) if started.elapsed() > STARTUP_WAIT => {
+                    return Err(e).with_context(|| format!("No persist daemon listening at {}", path.display()));
+                }
+                Err(_) => std::thread::sleep(Duration::from_millis(20)),
+            }
+        }
+    }
+
+    /// A kept session; the mutex lets one command use it at a time
+    struct Cached {
+        session: Mutex<Session>,
+        auth_method: String,
+        last_used: Mutex<Instant>,
+    }
+
+    /// (user, address, port)
+    type SessionKey = (String, String, u16);
+
+    #[derive(Default)]
+    struct Daemon {
+        sessions: Mutex<HashMap<SessionKey, Vec<Arc<Cached>>>>,
+        idle: Mutex<Duration>,
+        last_request: Mutex<Option<Instant>>,
+    }
+
+    /// `krust __persist-daemon`: serve until stopped or idle for the latest --persist duration
+    pub fn run_daemon() -> Result<()> {
+        let path = socket_path()?;
+        let dir = path.parent().context("Invalid persist socket path")?;
+        std::fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+        std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))?;
+        if UnixStream::connect(&path).is_ok() {
+            debug!("A persist daemon is already listening at {}", path.display());
+            return Ok(());
+        }
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).with_context(|| format!("Failed to listen on {}", path.display()))?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+
+        let daemon = Arc::new(Daemon::default());
+        *daemon.last_request.lock().unwrap() = Some(Instant::now());
+        *daemon.idle.lock().unwrap() = Duration::from_secs(60);
+        std::thread::spawn({
+            let daemon = Arc::clone(&daemon);
+            let path = path.clone();
+            move || loop {
+                std::thread::sleep(Duration::from_secs(1));
+                if daemon.expire() {
+                    debug!("Persist daemon idle, exiting");
+                    let _ = std::fs::remove_file(&path);
+                    std::process::exit(0);
+                }
+            }
+        });
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    debug!("Persist daemon accept failed: {}", e);
+                    continue;
+                }
+            };
+            let daemon = Arc::clone(&daemon);
+            let path = path.clone();
+            std::thread::spawn(move || {
+                if let Err(e) = daemon.serve(stream, &path) {
+                    debug!("Persist daemon request failed: {:#}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    impl Daemon {
+        fn serve(&self, stream: UnixStream, path: &std::path::Path) -> Result<()> {
+            let mut line = String::new();
+            BufReader::new(&stream).read_line(&mut line)?;
+            let request: Request = serde_json::from_str(&line)?;
+            *self.last_request.lock().unwrap() = Some(Instant::now());
+            let response = match request {
+                Request::Exec(request) => {
+                    *self.idle.lock().unwrap() = Duration::from_secs(request.idle_secs);
+                    self.exec(request)
+                }
+                Request::Stop => {
+                    let sessions = self.sessions.lock().unwrap().drain().map(|(_, kept)| kept.len()).sum();
+                    let _ = std::fs::remove_file(path);
+                    reply(&stream, &Response::Stopped { sessions })?;
+                    std::process::exit(0);
+                }
+            };
+            reply(&stream, &response)
+        }
+
+        fn exec(&self, request: ExecRequest) -> Response {
+            let failed = |e: ExecError| Response::Failed { kind: e.kind, message: e.to_string() };
+            let mut host = match SshHost::from_target(&request.target, None) {
+                Ok(host) => host,
+                Err(e) => return failed(ExecError::new(ExecErrorKind::Resolve, e)),
+            };
+            host.hostname = request.hostname.clone();
+            let input = match request.input.as_deref().map(decode).transpose() {
+                Ok(input) => input,
+                Err(e) => return failed(ExecError::new(ExecErrorKind::Channel, e)),
+            };
+            let key = (request.user.clone(), host.address.clone(), host.port);
+
+            // A free kept session whose connection still opens channels; dead ones are dropped
+            let kept: Vec<Arc<Cached>> = self.sessions.lock().unwrap().get(&key).cloned().unwrap_or_default();
+            for cached in kept {
+                let Ok(session) = cached.session.try_lock() else {
+                    continue;
+                };
+                match session.channel_session() {
+                    Ok(channel) => {
+                        debug!("{}: reusing persisted session", host.hostname);
+                        *cached.last_used.lock().unwrap() = Instant::now();
+                        let result = ssh_executor::run_on_channel(&session, channel, &host, &request.command, input.as_deref());
+                        *cached.last_used.lock().unwrap() = Instant::now();
+                        return finish(result, 0, format!("persisted:{}", cached.auth_method));
+                    }
+                    Err(e) => {
+                        debug!("{}: persisted session is gone ({}), reconnecting", host.hostname, e);
+                        drop(session);
+                        self.forget(&key, &cached);
+                    }
+                }
+            }
+
+            let auth = SshAuth::with_methods(request.user, request.methods.into_iter().map(|method| match method {
+                WireMethod::Password(password) => AuthMethod::Password(Secret::new(password)),
+                WireMethod::KeyFile { key, certificate, passphrase } => AuthMethod::KeyFile { key, certificate, passphrase: passphrase.map(Secret::new) },
+                WireMethod::Agent => AuthMethod::Agent,
+            }).collect());
+            let opened = ssh_executor::open_session(&host, request.addrs, &Connection::default(), &auth);
+            drop(auth);
+            let (session, auth_ms, auth_method) = match opened {
+                Ok(opened) => opened,
+                Err(e) => return failed(e),
+            };
+            // Nothing to run: the connection check was the point (krust ping)
+            let result = match request.command.is_empty() {
+                true => Ok(ChannelOutput { stdout: spill::Captured { output: Vec::new(), spilled: None }, stderr: Vec::new(), bytes_stderr: 0, exit_code: 0 }),
+                false => session.channel_session()
+                    .map_err(anyhow::Error::from)
+                    .and_then(|channel| ssh_executor::run_on_channel(&session, channel, &host, &request.command, input.as_deref())),
+            };
+            let cached = Arc::new(Cached {
+                session: Mutex::new(session),
+                auth_method: auth_method.clone(),
+                last_used: Mutex::new(Instant::now()),
+            });
+            self.sessions.lock().unwrap().entry(key).or_default().push(cached);
+            finish(result, auth_ms, auth_method)
+        }
+
+        fn forget(&self, key: &SessionKey, cached: &Arc<Cached>) {
+            let mut sessions = self.sessions.lock().unwrap();
+            if let Some(kept) = sessions.get_mut(key) {
+                kept.retain(|other| !Arc::ptr_eq(other, cached));
+                if kept.is_empty() {
+                    sessions.remove(key);
+                }
+            }
+        }
+
+        /// Drop sessions unused for the idle time; true once nothing is left to wait for
+        fn expire(&self) -> bool {
+            let idle = *self.idle.lock().unwrap();
+            let mut sessions = self.sessions.lock().unwrap();
+            for kept in sessions.values_mut() {
+                kept.retain(|cached| cached.session.try_lock().is_err() || cached.last_used.lock().unwrap().elapsed() < idle);
+            }
+            sessions.retain(|_, kept| !kept.is_empty());
+            sessions.is_empty() && self.last_request.lock().unwrap().is_none_or(|last| last.elapsed() >= idle)
+        }
+    }
+
+    fn finish(result: Result<ChannelOutput>, auth_ms: u64, auth_method: String) -> Response {
+        match result {
+            // The daemon never sets --max-total-output, so nothing was spilled
+            Ok(ran) => Response::Done {
+                output: encode(&ran.stdout.output),
+                stderr: encode(&ran.stderr),
+                bytes_stderr: ran.bytes_stderr,
+                exit_code: ran.exit_code,
+                auth_ms,
+                auth_method,
+            },
+            Err(e) => Response::Failed { kind: ExecErrorKind::Channel, message: e.to_string() },
+        }
+    }
+
+    fn reply(mut stream: &UnixStream, response: &Response) -> Result<()> {
+        let mut line = serde_json::to_string(response)?;
+        line.push('\n');
+        match stream.write_all(line.as_bytes()) {
+            // The run gave up on this host (--timeout); nothing to tell
+            Err(e) if e.kind() == ErrorKind::BrokenPipe => Ok(()),
+            other => Ok(other?),
+        }
+    }
+}
@@ -0,0 +1,41 @@
+// [precheck.rs] - KRUST - Cheap TCP reachability probe run before SSH
+use futures::stream::{self, StreamExt};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tracing::debug;
+
+use crate::resolver::Resolver;
+use crate::ssh_executor::SshHost;
+
+/// Probes in flight at once; a bare TCP connect is cheap compared to an SSH session
+const PROBE_PARALLELISM: usize = 256;
+
+/// Try a TCP connect to every host; returns the unreachable ones with the reason.
+/// Hosts that fail to resolve are left to the SSH attempt, which reports the typed DNS error.
+pub async fn unreachable_hosts(
+    hosts: &[SshHost],
+    resolver: &Resolver,
+    probe_timeout: Duration,
+) -> HashMap<SshHost, String> {
+    let probes = hosts.iter().cloned().map(|host| async move {
+        let Ok(addrs) = resolver.resolve(&host).await else {
+            return (host, None);
+        };
+        let error = match timeout(probe_timeout, TcpStream::connect(&addrs[..])).await {
+            Ok(Ok(_)) => None,
+            Ok(Err(e)) => Some(e.to_string()),
+            Err(_) => Some(format!("no TCP answer within {}ms", probe_timeout.as_millis())),
+        };
+        (host, error)
+    });
+
+    let unreachable: HashMap<_, _> = stream::iter(probes)
+        .buffer_unordered(PROBE_PARALLELISM)
+        .filter_map(|(host, error)| async move { error.map(|e| (host, e)) })
+        .collect()
+        .await;
+    debug!("Pre-check: {} of {} hosts unreachable", unreachable.len(), hosts.len());
+    unreachable
+}
@@ -0,0 +1,218 @@
+// [proxy.rs] - KRUST - Reach hosts through a SOCKS5 or HTTP CONNECT proxy
+use anyhow::{bail, Context, Result};
+use base64::Engine;
+use std::io::{Read, Write};
+use std::net::{IpAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::secret::Secret;
+
+/// Connecting to the proxy itself, like a direct connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest CONNECT response header we read before giving up on the proxy
+const MAX_HTTP_HEADER: usize = 16 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scheme {
+    /// Hostnames are resolved locally and the proxy is given addresses
+    Socks5,
+    /// The proxy resolves hostnames, as with `ssh -D` to a network we cannot resolve
+    Socks5h,
+    /// HTTP CONNECT; the proxy resolves hostnames
+    Http,
+}
+
+/// --proxy: `socks5://`, `socks5h://` or `http://`, with optional `user:password@`
+#[derive(Debug, Clone)]
+pub struct Proxy {
+    pub scheme: Scheme,
+    /// `host:port` of the proxy
+    pub address: String,
+    credentials: Option<(String, Secret)>,
+}
+
+/// clap value parser for --proxy
+pub fn parse(s: &str) -> Result<Proxy, String> {
+    let (scheme, rest) = s.split_once("://")
+        .ok_or_else(|| format!("Invalid proxy '{}' (expected socks5://, socks5h:// or http://)", s))?;
+    let (scheme, default_port) = match scheme.to_ascii_lowercase().as_str() {
+        "socks5" => (Scheme::Socks5, 1080),
+        "socks5h" => (Scheme::Socks5h, 1080),
+        "http" => (Scheme::Http, 8080),
+        other => return Err(format!("Unsupported proxy scheme '{}' (expected socks5, socks5h or http)", other)),
+    };
+    let rest = rest.trim_end_matches('/');
+    let (credentials, address) = match rest.rsplit_once('@') {
+        Some((userinfo, address)) => {
+            let (user, password) = userinfo.split_once(':').unwrap_or((userinfo, ""));
+            (Some((user.to_string(), Secret::new(password.to_string()))), address)
+        }
+        None => (None, rest),
+    };
+    if address.is_empty() {
+        return Err(format!("Invalid proxy '{}': no proxy host", s));
+    }
+    // host, host:port, [v6] or [v6]:port
+    let has_port = match address.rfind(']') {
+        Some(end) => address[end..].contains(':'),
+        None => address.contains(':'),
+    };
+    let address = match has_port {
+        true => address.to_string(),
+        false => format!("{}:{}", address, default_port),
+    };
+    Ok(Proxy { scheme, address, credentials })
+}
+
+impl Proxy {
+    /// Whether hostnames are left for the proxy to resolve
+    pub fn resolves_remotely(&self) -> bool {
+        self.scheme != Scheme::Socks5
+    }
+
+    /// Open a tunnel to `host:port`, where `host` is a name or an address
+    pub fn connect(&self, host: &str, port: u16) -> Result<TcpStream> {
+        let mut stream = self.open()?;
+        stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+        stream.set_write_timeout(Some(CONNECT_TIMEOUT))?;
+        match self.scheme {
+            Scheme::Socks5 | Scheme::Socks5h => self.socks5(&mut stream, host, port)?,
+            Scheme::Http => self.http_connect(&mut stream, host, port)?,
+        }
+        Ok(stream)
+    }
+
+    fn open(&self) -> Result<TcpStream> {
+        let addrs = self.address.to_socket_addrs()
+            .with_context(|| format!("proxy {} could not be resolved", self.address))?;
+        let mut last_error = None;
+        for addr in addrs {
+            match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        match last_error {
+            Some(e) => bail!("proxy {} unreachable: {}", self.address, e),
+            None => bail!("proxy {} could not be resolved", self.address),
+        }
+    }
+
+    /// RFC 1928 CONNECT, with RFC 1929 username/password auth when credentials are given
+    fn socks5(&self, stream: &mut TcpStream, host: &str, port: u16) -> Result<()> {
+        let greeting: &[u8] = match self.credentials {
+            Some(_) => &[5, 2, 0, 2],
+            None => &[5, 1, 0],
+        };
+        stream.write_all(greeting)?;
+        let mut choice = [0u8; 2];
+        stream.read_exact(&mut choice).with_context(|| format!("proxy {} closed the SOCKS greeting", self.address))?;
+        match (choice, &self.credentials) {
+            ([5, 0], _) => {}
+            ([5, 2], Some((user, password))) => {
+                let mut request = vec![1, user.len() as u8];
+                request.extend_from_slice(user.as_bytes());
+                request.push(password.expose().len() as u8);
+                request.extend_from_slice(password.expose().as_bytes());
+                stream.write_all(&request)?;
+                let mut status = [0u8; 2];
+                stream.read_exact(&mut status)?;
+                if status[1] != 0 {
+                    bail!("proxy {} rejected the username or password", self.address);
+                }
+            }
+            ([5, 0xff], _) | ([5, 2], None) => bail!("proxy {} requires authentication we did not offer", self.address),
+            _ => bail!("proxy {} is not a SOCKS5 proxy", self.address),
+        }
+
+        let mut request = vec![5, 1, 0];
+        match host.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => {
+                request.push(1);
+                request.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                request.push(4);
+                request.extend_from_slice(&ip.octets());
+            }
+            Err(_) => {
+                if host.len() > 255 {
+                    bail!("Hostname too long for SOCKS5: {}", host);
+                }
+                request.push(3);
+                request.push(host.len() as u8);
+                request.extend_from_slice(host.as_bytes());
+            }
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request)?;
+
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply).with_context(|| format!("proxy {} closed the connection", self.address))?;
+        if reply[1] != 0 {
+            bail!("proxy refused connection to {}:{} ({})", host, port, socks5_reply(reply[1]));
+        }
+        // Skip the bound address the proxy reports
+        let bound = match reply[3] {
+            1 => 4,
+            4 => 16,
+            3 => {
+                let mut len = [0u8; 1];
+                stream.read_exact(&mut len)?;
+                len[0] as usize
+            }
+            other => bail!("proxy {} sent an unknown address type {}", self.address, other),
+        };
+        let mut skipped = vec![0u8; bound + 2];
+        stream.read_exact(&mut skipped)?;
+        Ok(())
+    }
+
+    fn http_connect(&self, stream: &mut TcpStream, host: &str, port: u16) -> Result<()> {
+        let authority = match host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(_)) => format!("[{}]:{}", host, port),
+            _ => format!("{}:{}", host, port),
+        };
+        let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+        if let Some((user, password)) = &self.credentials {
+            let token = base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, password.expose()));
+            request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes())?;
+
+        // Byte by byte: whatever follows the header already belongs to the SSH server
+        let mut header = Vec::new();
+        let mut byte = [0u8; 1];
+        while !header.ends_with(b"\r\n\r\n") {
+            if header.len() >= MAX_HTTP_HEADER {
+                bail!("proxy {} sent an oversized CONNECT response", self.address);
+            }
+            stream.read_exact(&mut byte).with_context(|| format!("proxy {} closed the connection", self.address))?;
+            header.push(byte[0]);
+        }
+        let header = String::from_utf8_lossy(&header);
+        let status_line = header.lines().next().unwrap_or_default();
+        let status = status_line.split_whitespace().nth(1);
+        match status {
+            Some("200") => Ok(()),
+            Some("407") => bail!("proxy {} rejected the username or password ({})", self.address, status_line),
+            Some(_) => bail!("proxy refused connection to {} ({})", authority, status_line),
+            None => bail!("proxy {} is not an HTTP proxy", self.address),
+        }
+    }
+}
+
+fn socks5_reply(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
@@ -0,0 +1,167 @@
+// [proxy_command.rs] - KRUST - Reach hosts through a local command, like ssh's ProxyCommand
+use anyhow::{bail, Context, Result};
+use std::process::Child;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::ssh_executor::SshHost;
+
+/// How much of the command's stderr is kept to explain a failure
+const STDERR_TAIL: usize = 4096;
+
+/// --proxy-command, e.g. `aws ssm start-session --target {host} ...`
+#[derive(Debug, Clone)]
+pub struct ProxyCommand {
+    template: String,
+}
+
+/// clap value parser for --proxy-command
+pub fn parse(s: &str) -> Result<ProxyCommand, String> {
+    match s.trim().is_empty() {
+        true => Err("--proxy-command is empty".to_string()),
+        false => Ok(ProxyCommand { template: s.to_string() }),
+    }
+}
+
+impl ProxyCommand {
+    /// The command line for `host`, with `{host}` and `{port}` filled in
+    pub fn render(&self, host: &SshHost) -> String {
+        self.template.replace("{host}", &host.address).replace("{port}", &host.port.to_string())
+    }
+
+    /// Start the command for `host`; SSH talks to its stdin and stdout over a socket pair
+    #[cfg(unix)]
+    pub fn spawn(&self, host: &SshHost) -> Result<ProxyStream> {
+        use std::io::Read;
+        use std::os::fd::OwnedFd;
+        use std::os::unix::net::UnixStream;
+        use std::process::{Command, Stdio};
+
+        let command = self.render(host);
+        let (ours, theirs) = UnixStream::pair().context("Failed to create a socket pair for the proxy command")?;
+        let stdin = OwnedFd::from(theirs.try_clone()?);
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(&command)
+            .stdin(Stdio::from(stdin))
+            .stdout(Stdio::from(OwnedFd::from(theirs)))
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to start proxy command: {}", command))?;
+
+        // Drained on a thread of its own so a chatty command never blocks on a full pipe
+        let stderr = Arc::new(Stderr::default());
+        if let Some(mut pipe) = child.stderr.take() {
+            let stderr = Arc::clone(&stderr);
+            std::thread::spawn(move || {
+                let mut buf = [0u8; 1024];
+                while let Ok(n @ 1..) = pipe.read(&mut buf) {
+                    let mut tail = stderr.tail.lock().unwrap();
+                    tail.extend_from_slice(&buf[..n]);
+                    let excess = tail.len().saturating_sub(STDERR_TAIL);
+                    tail.drain(..excess);
+                }
+                stderr.closed.store(true, Ordering::Release);
+            });
+        }
+        Ok(ProxyStream {
+            socket: ours,
+            child: Arc::new(ProxyChild { command, child: Mutex::new(child), stderr }),
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn spawn(&self, _host: &SshHost) -> Result<ProxyStream> {
+        bail!("--proxy-command is only supported on Unix controllers")
+    }
+}
+
+/// The socket SSH runs over; the command is killed once the session holding it is dropped
+pub struct ProxyStream {
+    #[cfg(unix)]
+    socket: std::os::unix::net::UnixStream,
+    /// Never built: spawn fails on other platforms
+    #[cfg(not(unix))]
+    socket: std::net::TcpStream,
+    child: Arc<ProxyChild>,
+}
+
+impl ProxyStream {
+    pub fn child(&self) -> Arc<ProxyChild> {
+        Arc::clone(&self.child)
+    }
+}
+
+#[cfg(unix)]
+impl std::os::fd::AsRawFd for ProxyStream {
+    fn as_raw_fd(&self) -> std::os::fd::RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+#[cfg(windows)]
+impl std::os::windows::io::AsRawSocket for ProxyStream {
+    fn as_raw_socket(&self) -> std::os::windows::io::RawSocket {
+        self.socket.as_raw_socket()
+    }
+}
+
+/// A running proxy command
+pub struct ProxyChild {
+    command: String,
+    child: Mutex<Child>,
+    stderr: Arc<Stderr>,
+}
+
+/// The end of the command's stderr, and whether it has all been read
+#[derive(Default)]
+struct Stderr {
+    tail: Mutex<Vec<u8>>,
+    closed: AtomicBool,
+}
+
+impl ProxyChild {
+    /// Why SSH lost its connection, when the command has exited. The socket closes a moment
+    /// before the exit can be collected, and the last words may still be in the stderr pipe,
+    /// so both get up to 200ms.
+    pub fn failure(&self) -> Result<()> {
+        let mut status = None;
+        for _ in 0..20 {
+            if status.is_none() {
+                status = self.child.lock().unwrap().try_wait().ok().flatten();
+            }
+            if status.is_some() && self.stderr.closed.load(Ordering::Acquire) {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let Some(status) = status else {
+            return Ok(());
+        };
+        let exited = match status.code() {
+            Some(code) => format!("proxy command exited with code {}", code),
+            None => "proxy command was killed by a signal".to_string(),
+        };
+        let stderr = String::from_utf8_lossy(&self.stderr.tail.lock().unwrap()).trim().to_string();
+        match stderr.is_empty() {
+            true => bail!("{}: {}", exited, self.command),
+            false => bail!("{}: {}", exited, stderr),
+        }
+    }
+
+    /// Stop the command, e.g. when the attempt timed out while SSH was still using it
+    pub fn kill(&self) {
+        let mut child = self.child.lock().unwrap();
+        if let Ok(None) = child.try_wait() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+impl Drop for ProxyChild {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
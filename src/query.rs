@@ -0,0 +1,42 @@
+// [query.rs] - KRUST - JMESPath --query over JSON result records
+use jmespath::Expression;
+use serde_json::Value;
+use tracing::warn;
+
+pub type Query = Expression<'static>;
+
+pub fn parse_query(expression: &str) -> Result<Query, String> {
+    jmespath::compile(expression).map_err(|e| format!("Invalid JMESPath expression: {}", e))
+}
+
+fn search(query: &Query, value: &Value) -> Result<Value, String> {
+    let found = query.search(value).map_err(|e| e.to_string())?;
+    serde_json::to_value(&*found).map_err(|e| e.to_string())
+}
+
+/// Apply the query to one NDJSON record. A boolean result filters (true keeps the
+/// record as is), null drops it, anything else replaces the record. Evaluation
+/// errors pass the record through unchanged.
+pub fn apply_to_record(query: &Query, record: Value) -> Option<Value> {
+    match search(query, &record) {
+        Ok(Value::Bool(true)) => Some(record),
+        Ok(Value::Bool(false)) | Ok(Value::Null) => None,
+        Ok(projected) => Some(projected),
+        Err(e) => {
+            let host = record.get("hostname").and_then(Value::as_str).unwrap_or("?");
+            warn!("--query failed on {}, printing the record unchanged: {}", host, e);
+            Some(record)
+        }
+    }
+}
+
+/// Apply the query to the whole --pretty-json array
+pub fn apply_to_array(query: &Query, records: Value) -> Value {
+    match search(query, &records) {
+        Ok(result) => result,
+        Err(e) => {
+            warn!("--query failed, printing the results unchanged: {}", e);
+            records
+        }
+    }
+}
@@ -0,0 +1,187 @@
+// [reboot.rs] - KRUST - Controller-side wait for hosts coming back from a reboot
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{sleep, timeout, Instant};
+use tracing::debug;
+
+use crate::modules::wrap_script;
+use crate::executor::{CommandExecutor, Transport};
+use crate::ssh_executor::SshHost;
+use crate::host_error::{HostError, LocalErrorKind};
+use crate::HostResult;
+
+/// Prints "<boot id> <boot time>": Linux has a random boot_id, BSD and macOS use the boot timestamp
+pub const BOOT_STATE_COMMAND: &str = r#"if [ -r /proc/sys/kernel/random/boot_id ]; then
+    id=$(cat /proc/sys/kernel/random/boot_id)
+    sec=$(( $(date +%s) - $(cut -d. -f1 /proc/uptime) ))
+    echo "$id $(date -u -d "@$sec" +%Y-%m-%dT%H:%M:%SZ)"
+else
+    sec=$(sysctl -n kern.boottime | sed 's/.*sec = \([0-9]*\).*/\1/')
+    echo "$sec $(date -u -r "$sec" +%Y-%m-%dT%H:%M:%SZ)"
+fi"#;
+
+/// Line a remote script prints, followed by the boot state, right before it reboots the host
+pub const REBOOT_MARKER: &str = "REBOOT-SCHEDULED ";
+
+/// Boot identity of a host at one point in time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootState {
+    pub boot_id: String,
+    pub boot_time: Option<String>,
+}
+
+impl BootState {
+    fn parse(output: &str) -> Option<Self> {
+        let mut fields = output.split_whitespace();
+        let boot_id = fields.next()?.to_string();
+        Some(BootState { boot_id, boot_time: fields.next().map(str::to_string) })
+    }
+}
+
+/// How the controller follows up on a host the module rebooted
+#[derive(Debug, Clone)]
+pub struct RebootPolicy {
+    /// How long the host has to come back (and pass the post-check)
+    pub timeout: Duration,
+    /// Command that must succeed on the host after it is back
+    pub post_check: Option<String>,
+}
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Run `command` over a fresh connection, giving up after PROBE_TIMEOUT
+async fn probe<E: CommandExecutor>(
+    transport: &Arc<Transport<E>>,
+    host: &SshHost,
+    command: String,
+) -> Result<(String, i32), String> {
+    let addrs = transport.resolver.resolve(host).await.map_err(|e| e.to_string())?;
+    let run = tokio::task::spawn_blocking({
+        let host = host.clone();
+        let transport = Arc::clone(transport);
+        move || {
+            let auth = transport.auth.for_host(&host);
+            transport.executor.execute(&host, addrs, &auth, &command, None)
+        }
+    });
+
+    match timeout(PROBE_TIMEOUT, run).await {
+        Ok(Ok(result)) => result.map(|out| (out.text(), out.exit_code)).map_err(|e| e.to_string()),
+        Ok(Err(e)) => Err(format!("Task panic: {}", e)),
+        Err(_) => Err(format!("timed out after {}s", PROBE_TIMEOUT.as_secs())),
+    }
+}
+
+/// Read the current boot state over a fresh connection; None while the host is unreachable
+pub async fn probe_boot_state<E: CommandExecutor>(transport: &Arc<Transport<E>>, host: &SshHost) -> Option<BootState> {
+    match probe(transport, host, wrap_script(BOOT_STATE_COMMAND)).await {
+        Ok((output, 0)) => BootState::parse(&output),
+        Ok((output, code)) => {
+            debug!("Boot id probe on {} exited {}: {}", host.hostname, code, output);
+            None
+        }
+        Err(e) => {
+            debug!("Boot id probe on {} failed: {}", host.hostname, e);
+            None
+        }
+    }
+}
+
+fn record_boot_state(result: &mut HostResult, suffix: &str, state: &BootState) {
+    result.module_fields.insert(format!("boot_id_{}", suffix), state.boot_id.clone().into());
+    result.module_fields.insert(format!("boot_time_{}", suffix), state.boot_time.clone().into());
+}
+
+/// Poll until the host answers with a boot id different from `before`.
+/// Fails the result when the host stays down or comes back without having rebooted.
+async fn wait_for_new_boot<E: CommandExecutor>(
+    transport: &Arc<Transport<E>>,
+    host: &SshHost,
+    result: &mut HostResult,
+    before: &BootState,
+    reboot_timeout: Duration,
+    started: Instant,
+) {
+    let mut seen_unchanged = false;
+    record_boot_state(result, "before", before);
+
+    while started.elapsed() < reboot_timeout {
+        sleep(POLL_INTERVAL).await;
+
+        match probe_boot_state(transport, host).await {
+            Some(after) if after.boot_id != before.boot_id => {
+                debug!("{} is back after {}s", host.hostname, started.elapsed().as_secs());
+                record_boot_state(result, "after", &after);
+                result.module_fields.insert(
+                    "reboot_wait_ms".to_string(),
+                    (started.elapsed().as_millis() as u64).into(),
+                );
+                return;
+            }
+            Some(_) => seen_unchanged = true,
+            None => {}
+        }
+    }
+
+    let message = if seen_unchanged {
+        format!("Reboot did not occur: boot id unchanged after {}s", reboot_timeout.as_secs())
+    } else {
+        format!("Host did not come back within {}s", reboot_timeout.as_secs())
+    };
+    result.fail(HostError::local(LocalErrorKind::Module, message, None));
+}
+
+/// Run the post-check until it succeeds or the deadline passes
+async fn run_post_check<E: CommandExecutor>(
+    transport: &Arc<Transport<E>>,
+    host: &SshHost,
+    result: &mut HostResult,
+    command: &str,
+    deadline: Instant,
+) {
+    let last_output = loop {
+        let output = match probe(transport, host, command.to_string()).await {
+            Ok((_, 0)) => {
+                result.module_fields.insert("post_check".to_string(), "passed".into());
+                return;
+            }
+            Ok((output, code)) => format!("exit {}: {}", code, output.trim()),
+            Err(e) => e,
+        };
+
+        if Instant::now() + POLL_INTERVAL >= deadline {
+            break output;
+        }
+        sleep(POLL_INTERVAL).await;
+    };
+
+    result.module_fields.insert("post_check".to_string(), "failed".into());
+    result.fail(HostError::local(LocalErrorKind::Module, format!("Post-check failed after reboot: {}", last_output), None));
+}
+
+/// Wait for the reboot a module scheduled, if its output carries the reboot marker.
+/// Returns false when a rebooted host did not come back healthy.
+pub async fn wait_after_module<E: CommandExecutor>(
+    transport: &Arc<Transport<E>>,
+    host: &SshHost,
+    result: &mut HostResult,
+    policy: &RebootPolicy,
+) -> bool {
+    if !result.success {
+        return true;
+    }
+    let before = result.stdout.as_deref()
+        .and_then(|out| out.lines().find_map(|line| line.strip_prefix(REBOOT_MARKER)))
+        .and_then(BootState::parse);
+    let Some(before) = before else {
+        return true;
+    };
+
+    let started = Instant::now();
+    wait_for_new_boot(transport, host, result, &before, policy.timeout, started).await;
+    if let (true, Some(command)) = (result.success, &policy.post_check) {
+        run_post_check(transport, host, result, command, started + policy.timeout).await;
+    }
+    result.success
+}
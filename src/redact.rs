@@ -0,0 +1,78 @@
+// [redact.rs] - KRUST - Mask secrets in command output before any result is printed or saved
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde_json::Value;
+use std::borrow::Cow;
+use tracing::debug;
+
+use crate::secret::Secret;
+
+pub const REDACTED: &str = "***REDACTED***";
+
+/// Passwords shorter than this are not masked literally; "x" would blank out every x
+const MIN_SECRET_LEN: usize = 4;
+
+/// --redact patterns, the config file's `redact` list and the run's passwords
+#[derive(Debug)]
+pub struct Redactor {
+    text: Vec<Regex>,
+    /// The same patterns for raw output kept as base64
+    bytes: Vec<regex::bytes::Regex>,
+}
+
+impl Redactor {
+    /// None when there is nothing to redact
+    pub fn new(patterns: &[Regex], configured: &[String], secrets: &[&Secret]) -> Result<Option<Self>> {
+        let mut text = patterns.to_vec();
+        for pattern in configured {
+            text.push(Regex::new(pattern).with_context(|| format!("Invalid redact pattern in config: {}", pattern))?);
+        }
+        for secret in secrets {
+            match secret.expose().len() >= MIN_SECRET_LEN {
+                true => text.push(Regex::new(&regex::escape(secret.expose()))?),
+                false => debug!("Password shorter than {} characters, not redacting it from output", MIN_SECRET_LEN),
+            }
+        }
+        if text.is_empty() {
+            return Ok(None);
+        }
+        let bytes = text.iter()
+            .map(|regex| regex::bytes::Regex::new(regex.as_str()))
+            .collect::<Result<_, _>>()?;
+        Ok(Some(Redactor { text, bytes }))
+    }
+
+    pub fn text<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut text = Cow::Borrowed(text);
+        for regex in &self.text {
+            if let Cow::Owned(replaced) = regex.replace_all(&text, REDACTED) {
+                text = Cow::Owned(replaced);
+            }
+        }
+        text
+    }
+
+    pub fn string(&self, text: &mut String) {
+        if let Cow::Owned(replaced) = self.text(text) {
+            *text = replaced;
+        }
+    }
+
+    pub fn bytes(&self, data: &mut Vec<u8>) {
+        for regex in &self.bytes {
+            if let Cow::Owned(replaced) = regex.replace_all(data, REDACTED.as_bytes()) {
+                *data = replaced;
+            }
+        }
+    }
+
+    /// Every string in a JSON value, e.g. module data
+    pub fn value(&self, value: &mut Value) {
+        match value {
+            Value::String(text) => self.string(text),
+            Value::Array(items) => items.iter_mut().for_each(|item| self.value(item)),
+            Value::Object(map) => map.values_mut().for_each(|item| self.value(item)),
+            _ => {}
+        }
+    }
+}
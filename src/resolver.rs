@@ -0,0 +1,188 @@
+// [resolver.rs] - KRUST - Bounded, cached DNS resolution shared by one run
+use anyhow::anyhow;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::net::lookup_host;
+use tokio::sync::Semaphore;
+use tokio::time::timeout;
+use futures::future::join_all;
+use tracing::{debug, info};
+
+use crate::ssh_executor::{normalize_name, ExecError, ExecErrorKind, SshHost};
+
+/// Lookups in flight at once; each one holds a blocking-pool thread inside getaddrinfo
+const RESOLVE_PARALLELISM: usize = 32;
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Parse a --resolve override: "host=ip"
+pub fn parse_override(s: &str) -> Result<(String, IpAddr), String> {
+    let (host, ip) = s.split_once('=')
+        .ok_or_else(|| format!("Invalid override '{}' (expected HOST=IP)", s))?;
+    let host = host.trim();
+    if host.is_empty() {
+        return Err(format!("Invalid override '{}': empty hostname", s));
+    }
+    let ip = ip.trim().parse::<IpAddr>()
+        .map_err(|_| format!("Invalid override '{}': '{}' is not an IP address", s, ip.trim()))?;
+    Ok((host.to_string(), ip))
+}
+
+/// Resolves each hostname at most once per run, so retries, hooks and reboot probes reuse the answer.
+/// Only successes and NXDOMAIN are cached; a timed-out lookup is tried again on the next attempt.
+pub struct Resolver {
+    overrides: HashMap<String, IpAddr>,
+    cache: Mutex<HashMap<String, Result<Vec<IpAddr>, String>>>,
+    permits: Semaphore,
+    /// Names are left to the --proxy to resolve
+    remote: bool,
+}
+
+impl Resolver {
+    pub fn new(overrides: &[(String, IpAddr)]) -> Self {
+        Resolver {
+            overrides: overrides.iter().map(|(name, ip)| (normalize_name(name), *ip)).collect(),
+            cache: Mutex::new(HashMap::new()),
+            permits: Semaphore::new(RESOLVE_PARALLELISM),
+            remote: false,
+        }
+    }
+
+    /// Leave hostnames to the proxy: `resolve` returns no addresses for them, unless --resolve pins one
+    pub fn remote(mut self) -> Self {
+        self.remote = true;
+        self
+    }
+
+    /// Socket addresses to try for `host`, in resolver order
+    pub async fn resolve(&self, host: &SshHost) -> Result<Vec<SocketAddr>, ExecError> {
+        if self.remote && !self.overrides.contains_key(&host.address) && host.address.parse::<IpAddr>().is_err() {
+            return Ok(Vec::new());
+        }
+        let ips = self.lookup(&host.address).await?;
+        Ok(ips.into_iter().map(|ip| SocketAddr::new(ip, host.port)).collect())
+    }
+
+    async fn lookup(&self, hostname: &str) -> Result<Vec<IpAddr>, ExecError> {
+        if let Some(ip) = self.overrides.get(hostname) {
+            return Ok(vec![*ip]);
+        }
+        if let Ok(ip) = hostname.parse::<IpAddr>() {
+            return Ok(vec![ip]);
+        }
+        if let Some(cached) = self.cached(hostname) {
+            return cached;
+        }
+
+        let _permit = self.permits.acquire().await.expect("resolver semaphore closed");
+        // Another task may have resolved the same name while we waited for a slot
+        if let Some(cached) = self.cached(hostname) {
+            return cached;
+        }
+
+        let started = Instant::now();
+        let result = match timeout(RESOLVE_TIMEOUT, lookup_host((hostname, 0))).await {
+            Ok(Ok(addrs)) => {
+                let mut ips: Vec<IpAddr> = Vec::new();
+                for addr in addrs {
+                    if !ips.contains(&addr.ip()) {
+                        ips.push(addr.ip());
+                    }
+                }
+                if ips.is_empty() {
+                    Err((ExecErrorKind::DnsNotFound, "No addresses found for host".to_string()))
+                } else {
+                    Ok(ips)
+                }
+            }
+            Ok(Err(e)) => Err((classify(&e), format!("Failed to resolve hostname: {}", e))),
+            Err(_) => Err((
+                ExecErrorKind::DnsTimeout,
+                format!("DNS lookup timed out after {}s", RESOLVE_TIMEOUT.as_secs()),
+            )),
+        };
+        debug!("Resolved {} in {}ms: {:?}", hostname, started.elapsed().as_millis(), result);
+
+        let mut cache = self.cache.lock().unwrap();
+        match result {
+            Ok(ips) => {
+                cache.insert(hostname.to_string(), Ok(ips.clone()));
+                Ok(ips)
+            }
+            Err((ExecErrorKind::DnsNotFound, message)) => {
+                cache.insert(hostname.to_string(), Err(message.clone()));
+                Err(ExecError::new(ExecErrorKind::DnsNotFound, anyhow!(message)))
+            }
+            Err((kind, message)) => Err(ExecError::new(kind, anyhow!(message))),
+        }
+    }
+
+    /// --dedup-by-ip: keep the first of the hosts that would connect to the same address and port,
+    /// logging the aliases merged into it. Hosts that do not resolve are kept for the run to report.
+    pub async fn dedup_by_address(&self, hosts: Vec<SshHost>) -> Vec<SshHost> {
+        let resolved = join_all(hosts.iter().map(|host| self.resolve(host))).await;
+        let mut first: HashMap<SocketAddr, usize> = HashMap::new();
+        let mut merged: Vec<Vec<String>> = vec![Vec::new(); hosts.len()];
+        let mut addresses: Vec<Option<SocketAddr>> = vec![None; hosts.len()];
+        let mut keep = vec![true; hosts.len()];
+
+        for (index, addrs) in resolved.iter().enumerate() {
+            // The address a connection would try first identifies the machine
+            let Some(addr) = addrs.as_ref().ok().and_then(|addrs| addrs.first()) else {
+                continue;
+            };
+            match first.get(addr) {
+                Some(&kept) => {
+                    keep[index] = false;
+                    // Exact repeats are dropped later anyway and are not worth a message
+                    let alias = hosts[index].to_string();
+                    if hosts[index] != hosts[kept] && !merged[kept].contains(&alias) {
+                        merged[kept].push(alias);
+                    }
+                }
+                None => {
+                    first.insert(*addr, index);
+                    addresses[index] = Some(*addr);
+                }
+            }
+        }
+
+        for (index, aliases) in merged.iter().enumerate().filter(|(_, aliases)| !aliases.is_empty()) {
+            if let Some(addr) = addresses[index] {
+                info!("Merged {} into {}: all connect to {}", aliases.join(", "), hosts[index], addr);
+            }
+        }
+        hosts.into_iter().zip(keep).filter_map(|(host, keep)| keep.then_some(host)).collect()
+    }
+
+    #[cfg(test)]
+    pub fn is_cached(&self, hostname: &str) -> bool {
+        self.cache.lock().unwrap().contains_key(hostname)
+    }
+
+    fn cached(&self, hostname: &str) -> Option<Result<Vec<IpAddr>, ExecError>> {
+        let cache = self.cache.lock().unwrap();
+        cache.get(hostname).map(|entry| match entry {
+            Ok(ips) => Ok(ips.clone()),
+            Err(message) => Err(ExecError::new(ExecErrorKind::DnsNotFound, anyhow!(message.clone()))),
+        })
+    }
+}
+
+/// std surfaces getaddrinfo failures as uncategorized errors, so the gai_strerror text is all there is
+fn classify(error: &std::io::Error) -> ExecErrorKind {
+    let message = error.to_string();
+    const NOT_FOUND: &[&str] = &[
+        "Name or service not known",
+        "No address associated with hostname",
+        "nodename nor servname provided",
+    ];
+    if NOT_FOUND.iter().any(|text| message.contains(text)) {
+        ExecErrorKind::DnsNotFound
+    } else if message.contains("Temporary failure in name resolution") {
+        ExecErrorKind::DnsTimeout
+    } else {
+        ExecErrorKind::Resolve
+    }
+}
@@ -0,0 +1,54 @@
+// [retry.rs] - KRUST - Command failures worth another attempt, and the record of each attempt
+use serde::Serialize;
+
+use crate::ssh_executor::ExecErrorKind;
+
+/// Exit codes that are retried like connection errors (--retry-on-exit-code, --retry-on-nonzero).
+/// Empty by default: a command that ran and failed is not retried, as it may not be idempotent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetryExit {
+    /// Bit N set: exit code N is retried
+    codes: [u64; 4],
+    nonzero: bool,
+}
+
+impl RetryExit {
+    pub fn new(codes: &[u8], nonzero: bool) -> Self {
+        let mut retry = RetryExit { codes: [0; 4], nonzero };
+        for &code in codes {
+            retry.codes[code as usize / 64] |= 1 << (code % 64);
+        }
+        retry
+    }
+
+    pub fn matches(&self, exit_code: i32) -> bool {
+        match u8::try_from(exit_code) {
+            Ok(0) => false,
+            Ok(code) => self.nonzero || self.codes[code as usize / 64] & (1 << (code % 64)) != 0,
+            // Outside 0-255 only on Windows hosts, which --retry-on-exit-code cannot name
+            Err(_) => self.nonzero,
+        }
+    }
+}
+
+/// One try at a host, kept when the host needed more than one
+#[derive(Debug, Clone, Serialize)]
+pub struct Attempt {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_kind: Option<ExecErrorKind>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub duration_ms: u64,
+}
+
+impl Attempt {
+    pub fn exited(exit_code: i32, duration_ms: u64) -> Self {
+        Attempt { exit_code: Some(exit_code), error_kind: None, error: None, duration_ms }
+    }
+
+    pub fn failed(error_kind: Option<ExecErrorKind>, error: &str, duration_ms: u64) -> Self {
+        Attempt { exit_code: None, error_kind, error: Some(error.to_string()), duration_ms }
+    }
+}
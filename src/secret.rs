@@ -0,0 +1,88 @@
+// [secret.rs] - KRUST - Passwords: zeroed on drop, hidden from Debug and from the process arguments
+use std::fmt;
+use zeroize::Zeroizing;
+
+/// A password or passphrase. The memory is zeroed when the last copy is dropped, and `{:?}`
+/// prints a placeholder, so a stray debug log of `Cli` or `SshAuth` cannot leak it.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(Zeroizing<String>);
+
+impl Secret {
+    pub fn new(value: String) -> Self {
+        Secret(Zeroizing::new(value))
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(<redacted>)")
+    }
+}
+
+/// clap value parser: the value goes straight into a `Secret`
+pub fn parse(value: &str) -> Result<Secret, String> {
+    Ok(Secret::new(value.to_string()))
+}
+
+/// Overwrite `secret` where it appears as an option value in our own argv (`-p S`, `-pS`,
+/// `--password S`, `--password=S`), so it no longer shows in `ps` or /proc/PID/cmdline
+pub fn scrub_argv(secret: &Secret) {
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    argv::scrub(secret.expose().as_bytes());
+    #[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+    let _ = secret;
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+mod argv {
+    use std::ffi::{c_char, c_int, CStr};
+    use std::sync::atomic::{AtomicIsize, AtomicPtr, Ordering};
+
+    static ARGC: AtomicIsize = AtomicIsize::new(0);
+    static ARGV: AtomicPtr<*mut c_char> = AtomicPtr::new(std::ptr::null_mut());
+
+    /// glibc calls `.init_array` entries with the real argc/argv before main
+    #[used]
+    #[link_section = ".init_array"]
+    static CAPTURE: extern "C" fn(c_int, *mut *mut c_char, *mut *mut c_char) = capture;
+
+    extern "C" fn capture(argc: c_int, argv: *mut *mut c_char, _envp: *mut *mut c_char) {
+        ARGC.store(argc as isize, Ordering::Relaxed);
+        ARGV.store(argv, Ordering::Relaxed);
+    }
+
+    pub fn scrub(secret: &[u8]) {
+        let argv = ARGV.load(Ordering::Relaxed);
+        if argv.is_null() || secret.is_empty() {
+            return;
+        }
+        let mut value_follows = false;
+        for i in 1..ARGC.load(Ordering::Relaxed) {
+            // SAFETY: argv holds argc valid, NUL-terminated, writable strings for the whole run
+            let arg = unsafe { *argv.offset(i) };
+            if arg.is_null() {
+                break;
+            }
+            let bytes = unsafe { CStr::from_ptr(arg) }.to_bytes();
+            let start = if value_follows {
+                Some(0)
+            } else if bytes.starts_with(b"--password=") {
+                Some("--password=".len())
+            } else if bytes.starts_with(b"-p") && !bytes.starts_with(b"--") {
+                Some(2)
+            } else {
+                None
+            };
+            value_follows = bytes == b"-p" || bytes == b"--password";
+            if let Some(start) = start.filter(|&start| &bytes[start..] == secret) {
+                let len = bytes.len() - start;
+                // SAFETY: overwrites only the bytes of this argument, keeping its length and NUL
+                unsafe { std::ptr::write_bytes(arg.add(start), b'x', len) };
+            }
+        }
+    }
+}
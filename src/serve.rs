@@ -0,0 +1,444 @@
+// [serve.rs] - KRUST - HTTP API for starting runs and following their results (--features serve)
+use anyhow::{bail, Context, Result};
+use axum::body::{Body, Bytes};
+use axum::extract::{Path, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::Router;
+use clap::{Args, CommandFactory};
+use futures::stream;
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::timings::Started;
+use crate::{history, Cli, HostResult, Progress, Run, EXIT_INTERRUPTED};
+
+#[derive(Args, Debug)]
+pub struct ServeArgs {
+    /// Address to listen on; the API is plain HTTP, so keep it on loopback or behind a TLS proxy
+    #[arg(long, default_value = "127.0.0.1:8070")]
+    pub listen: SocketAddr,
+
+    /// File holding the bearer token clients must send
+    #[arg(long)]
+    pub token_file: PathBuf,
+
+    /// Directory of the inventories requests may name; without it, requests give their hosts
+    #[arg(long, value_name = "DIR")]
+    pub inventory_dir: Option<PathBuf>,
+}
+
+/// Modules served over the API: those that only act on the hosts. The others read or write
+/// files on this machine at paths their arguments name (copy's source, archive-fetch's --out,
+/// template's source and --var-file, checksum's and sysctl's files, kill's journal,
+/// collect-facts' --save-cache and --csv-output).
+const SERVED_MODULES: [&str; 10] = [
+    "ping", "health-check", "lineinfile", "process", "timecheck", "ports", "os-update", "reboot-wait", "swap", "mac-status",
+];
+
+/// Body of POST /runs. Everything else comes from the server's own configuration: there is no
+/// field for a password, so hosts are reached with the server's keys or agent.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RunRequest {
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// File name of an inventory in the server's --inventory-dir
+    pub inventory: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub user: Option<String>,
+    pub command: Option<String>,
+    pub module: Option<String>,
+    /// The module's own arguments, as on the command line
+    #[serde(default)]
+    pub args: Vec<String>,
+    pub concurrency: Option<usize>,
+    /// As --timeout, e.g. "30s"
+    pub timeout: Option<String>,
+}
+
+impl RunRequest {
+    /// The request as krust arguments (without the program name), the form `history rerun` replays
+    pub fn argv(&self, run_id: &str) -> Result<Vec<String>, String> {
+        let mut argv = vec![format!("--run-id={}", run_id), "--yes".to_string()];
+        if !self.hosts.is_empty() {
+            argv.push(format!("--hosts={}", self.hosts.join(",")));
+        }
+        let options = [
+            ("inventory", self.inventory.clone()),
+            ("tags", (!self.tags.is_empty()).then(|| self.tags.join(","))),
+            ("user", self.user.clone()),
+            ("concurrency", self.concurrency.map(|n| n.to_string())),
+            ("timeout", self.timeout.clone()),
+        ];
+        argv.extend(options.into_iter().filter_map(|(flag, value)| Some(format!("--{}={}", flag, value?))));
+
+        match (&self.command, &self.module) {
+            (Some(_), Some(_)) => return Err("Give either command or module, not both".to_string()),
+            (None, None) => return Err("A command or a module is required".to_string()),
+            (Some(command), None) if command == "-" => return Err("command cannot be read from standard input".to_string()),
+            (Some(command), None) if !self.args.is_empty() => {
+                return Err(format!("args are for modules; put them in the command: {}", command));
+            }
+            (Some(command), None) => argv.push(format!("--command={}", command)),
+            (None, Some(module)) => {
+                if !SERVED_MODULES.contains(&module.as_str()) {
+                    return Err(format!("Module {} is not served over the API (served: {})", module, SERVED_MODULES.join(", ")));
+                }
+                if let Some(flag) = krust_flag(&self.args) {
+                    return Err(format!("{} is not accepted in module args; runs take only the request's fields", flag));
+                }
+                argv.push(module.clone());
+                argv.extend(self.args.iter().cloned());
+            }
+        }
+        Ok(argv)
+    }
+}
+
+/// The first of `args` that clap would read as one of krust's own flags rather than the module's
+fn krust_flag(args: &[String]) -> Option<&str> {
+    let cli = Cli::command();
+    let global: Vec<_> = cli.get_arguments().filter(|arg| arg.is_global_set()).collect();
+    args.iter()
+        .take_while(|arg| *arg != "--")
+        .find(|arg| match arg.strip_prefix("--") {
+            Some(long) => {
+                let name = long.split('=').next().unwrap_or_default();
+                global.iter().any(|flag| flag.get_long() == Some(name))
+            }
+            None => arg.strip_prefix('-').is_some_and(|shorts| {
+                shorts.chars().any(|c| global.iter().any(|flag| flag.get_short() == Some(c)))
+            }),
+        })
+        .map(String::as_str)
+}
+
+/// What a run has produced so far, shared by the task running it and the clients following it
+#[derive(Default)]
+struct Tally {
+    results: Vec<HostResult>,
+    progress: Progress,
+    exit_code: Option<i32>,
+    duration_ms: Option<u64>,
+}
+
+/// The run this server started last, kept after it ends until the next one starts
+struct Current {
+    id: String,
+    command: String,
+    cancel: CancellationToken,
+    tally: watch::Sender<Tally>,
+}
+
+/// GET /runs/{id}/summary
+#[derive(Debug, Serialize)]
+struct Summary {
+    id: String,
+    status: &'static str,
+    command: String,
+    #[serde(flatten)]
+    progress: Progress,
+    exit_code: Option<i32>,
+    duration_ms: Option<u64>,
+}
+
+pub(crate) struct ServerState {
+    token: String,
+    /// The server's own --private-key, --certificate and --config, given to every run
+    flags: Vec<String>,
+    /// The server's --user, for requests that name none
+    user: Option<String>,
+    inventory_dir: Option<PathBuf>,
+    shutdown: CancellationToken,
+    current: Mutex<Option<Arc<Current>>>,
+    /// Held while a run is being prepared, so two requests cannot both start one
+    starting: tokio::sync::Mutex<()>,
+}
+
+type Shared = Arc<ServerState>;
+
+impl ServerState {
+    /// `flags` are krust flags given to every run, before the request's own
+    pub(crate) fn new(token: String, flags: Vec<String>, user: Option<String>, inventory_dir: Option<PathBuf>) -> Shared {
+        Arc::new(ServerState {
+            token,
+            flags,
+            user,
+            inventory_dir,
+            shutdown: CancellationToken::new(),
+            current: Mutex::new(None),
+            starting: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    /// The path of an inventory a request names: a file directly in --inventory-dir
+    fn inventory(&self, name: &str) -> Result<String, String> {
+        let Some(ref dir) = self.inventory_dir else {
+            return Err("This server takes no inventory names (no --inventory-dir); give hosts".to_string());
+        };
+        match std::path::Path::new(name).file_name() {
+            Some(file) if file == name && !name.starts_with('.') => Ok(dir.join(name).display().to_string()),
+            _ => Err(format!("Invalid inventory name: {} (a file name in the server's inventory directory)", name)),
+        }
+    }
+}
+
+/// An error response: a status and {"error": message}
+struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = serde_json::json!({ "error": self.1 }).to_string();
+        let mut response = json_response(self.0, body);
+        if self.0 == StatusCode::UNAUTHORIZED {
+            response.headers_mut().insert(header::WWW_AUTHENTICATE, header::HeaderValue::from_static("Bearer"));
+        }
+        response
+    }
+}
+
+fn json_response(status: StatusCode, body: String) -> Response {
+    (status, [(header::CONTENT_TYPE, "application/json")], body).into_response()
+}
+
+fn ndjson_response(body: Body) -> Response {
+    (StatusCode::OK, [(header::CONTENT_TYPE, "application/x-ndjson")], body).into_response()
+}
+
+/// Compared in constant time, so response times do not give the token away
+fn authorize(state: &ServerState, headers: &HeaderMap) -> Result<(), ApiError> {
+    let given = headers.get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    let same = given.len() == state.token.len()
+        && given.bytes().zip(state.token.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0;
+    match same {
+        true => Ok(()),
+        false => Err(ApiError(StatusCode::UNAUTHORIZED, "Missing or wrong bearer token".to_string())),
+    }
+}
+
+fn read_token(path: &std::path::Path) -> Result<String> {
+    let token = std::fs::read_to_string(path).with_context(|| format!("Cannot read {}", path.display()))?;
+    match token.trim() {
+        "" => bail!("{} holds no token", path.display()),
+        token => Ok(token.to_string()),
+    }
+}
+
+/// The flags of `krust serve` that carry over to its runs
+fn server_flags(outer: &Cli) -> Vec<String> {
+    let keys = outer.private_key.iter().map(|key| format!("--private-key={}", key));
+    let certificates = outer.certificate.iter().map(|certificate| format!("--certificate={}", certificate));
+    let config = outer.config.iter().map(|config| format!("--config={}", config));
+    keys.chain(certificates).chain(config).collect()
+}
+
+/// Serve the API until Ctrl-C, which stops taking requests and cancels the run in progress;
+/// a second Ctrl-C quits without waiting for it
+pub async fn run(args: &ServeArgs, outer: &Cli) -> Result<i32> {
+    if outer.password.is_some() || outer.ask_pass || outer.ask_become_pass {
+        bail!("krust serve takes no password: its runs authenticate with keys or the agent");
+    }
+    let token = read_token(&args.token_file)?;
+    let listener = tokio::net::TcpListener::bind(args.listen).await
+        .with_context(|| format!("Cannot listen on {}", args.listen))?;
+    if !args.listen.ip().is_loopback() {
+        warn!("Listening on {} over plain HTTP: the bearer token crosses the network unencrypted", args.listen);
+    }
+    let state = ServerState::new(token, server_flags(outer), outer.user.clone(), args.inventory_dir.clone());
+    let shutdown = state.shutdown.clone();
+    tokio::spawn(async move {
+        while tokio::signal::ctrl_c().await.is_ok() {
+            if shutdown.is_cancelled() {
+                std::process::exit(EXIT_INTERRUPTED);
+            }
+            warn!("Stopping: cancelling the run in progress (Ctrl-C again to quit)");
+            shutdown.cancel();
+        }
+    });
+
+    info!("Listening on http://{}", listener.local_addr()?);
+    serve(listener, Arc::clone(&state)).await?;
+
+    // Let the cancelled run record itself in the history before exiting
+    let current = state.current.lock().unwrap().clone();
+    if let Some(current) = current {
+        let _ = current.tally.subscribe().wait_for(|tally| tally.exit_code.is_some()).await;
+    }
+    Ok(0)
+}
+
+/// Answer requests until the server's shutdown is cancelled
+pub(crate) async fn serve(listener: tokio::net::TcpListener, state: Shared) -> Result<()> {
+    let shutdown = state.shutdown.clone();
+    let app = Router::new()
+        .route("/runs", post(start_run))
+        .route("/runs/{id}", get(run_results))
+        .route("/runs/{id}/summary", get(run_summary))
+        .with_state(state);
+    axum::serve(listener, app).with_graceful_shutdown(shutdown.cancelled_owned()).await?;
+    Ok(())
+}
+
+/// POST /runs: start a run and answer with its ID once every host is known; one run at a time
+async fn start_run(State(state): State<Shared>, headers: HeaderMap, body: Bytes) -> Result<Response, ApiError> {
+    authorize(&state, &headers)?;
+    let bad_request = |message: String| ApiError(StatusCode::BAD_REQUEST, message);
+    let value: serde_json::Value = serde_json::from_slice(&body).map_err(|e| bad_request(format!("Invalid JSON: {}", e)))?;
+    if let Some(field) = value.as_object().and_then(|fields| fields.keys().find(|key| key.contains("pass"))) {
+        return Err(bad_request(format!("{}: passwords are not accepted over the API; the server uses its keys or agent", field)));
+    }
+    let mut request: RunRequest = serde_json::from_value(value).map_err(|e| bad_request(e.to_string()))?;
+
+    let _starting = state.starting.lock().await;
+    if state.shutdown.is_cancelled() {
+        return Err(ApiError(StatusCode::SERVICE_UNAVAILABLE, "The server is shutting down".to_string()));
+    }
+    let previous = state.current.lock().unwrap().clone();
+    if let Some(running) = previous.as_ref().filter(|current| current.tally.borrow().exit_code.is_none()) {
+        return Err(ApiError(StatusCode::CONFLICT, format!("Run {} is still in progress", running.id)));
+    }
+
+    let started = Started::now();
+    // Runs started within the same second share a generated ID; the earlier one is in the history by now
+    let id = history::unused_run_id(history::new_run_id(started.at));
+    request.user = request.user.or_else(|| state.user.clone());
+    request.inventory = request.inventory.map(|name| state.inventory(&name)).transpose().map_err(bad_request)?;
+    let argv: Vec<String> = state.flags.iter().cloned().chain(request.argv(&id).map_err(bad_request)?).collect();
+    let argv_with_name = || std::iter::once("krust".to_string()).chain(argv.iter().cloned());
+    let clap_error = |e: clap::Error| bad_request(e.render().to_string().trim().to_string());
+    let args = Cli::try_parse_args(argv_with_name()).map_err(clap_error)?;
+    // Arguments a module rejects make its name the remote command on the command line; not here
+    if request.module.is_some() && args.module().is_none() {
+        return Err(<Cli as clap::Parser>::try_parse_from(argv_with_name()).err().map_or_else(|| bad_request("Invalid module args".to_string()), clap_error));
+    }
+    let run = Run::new(args).await.map_err(|e| bad_request(format!("{:#}", e)))?;
+
+    let total = run.total();
+    let current = Arc::new(Current {
+        id: id.clone(),
+        command: request.command.clone().unwrap_or_else(|| format!("module {}", request.module.as_deref().unwrap_or_default())),
+        cancel: state.shutdown.child_token(),
+        tally: watch::Sender::new(Tally { progress: Progress::new(total), ..Tally::default() }),
+    });
+    *state.current.lock().unwrap() = Some(Arc::clone(&current));
+    info!("Run {} started on {} host(s): {}", id, total, current.command);
+    tokio::spawn(follow(run, current, started, argv));
+
+    let body = serde_json::json!({ "id": id, "total": total }).to_string();
+    let mut response = json_response(StatusCode::ACCEPTED, body);
+    if let Ok(location) = header::HeaderValue::from_str(&format!("/runs/{}", id)) {
+        response.headers_mut().insert(header::LOCATION, location);
+    }
+    Ok(response)
+}
+
+/// Drive the run, publishing each result, then record it in the history like any other run
+async fn follow(run: Run, current: Arc<Current>, started: Started, argv: Vec<String>) {
+    use futures::StreamExt;
+    let mut results = std::pin::pin!(run.run_streaming(current.cancel.clone()));
+    while let Some(result) = results.next().await {
+        current.tally.send_modify(|tally| {
+            tally.progress.record(&result);
+            tally.results.push(result);
+        });
+    }
+    let exit_code = {
+        let finished = &current.tally.borrow().results;
+        let exit_code = run.exit_code(finished, current.cancel.is_cancelled());
+        history::record(&run.record(current.id.clone(), started, finished, exit_code, argv), finished);
+        exit_code
+    };
+    info!("Run {} finished with exit code {}", current.id, exit_code);
+    current.tally.send_modify(|tally| {
+        tally.exit_code = Some(exit_code);
+        tally.duration_ms = Some(started.elapsed_ms());
+    });
+}
+
+/// This server's current run, if it is `id`
+fn current(state: &ServerState, id: &str) -> Option<Arc<Current>> {
+    state.current.lock().unwrap().clone().filter(|current| current.id == id)
+}
+
+/// GET /runs/{id}: the results as NDJSON, followed until the run ends; earlier runs come from
+/// their history journal
+async fn run_results(State(state): State<Shared>, headers: HeaderMap, Path(id): Path<String>) -> Result<Response, ApiError> {
+    authorize(&state, &headers)?;
+    if let Some(current) = current(&state, &id) {
+        let lines = stream::unfold((current.tally.subscribe(), 0), |(mut tally, sent)| async move {
+            loop {
+                let (chunk, count, done) = {
+                    let tally = tally.borrow_and_update();
+                    let chunk: String = tally.results[sent..].iter()
+                        .filter_map(|result| serde_json::to_string(result).ok())
+                        .map(|line| line + "\n")
+                        .collect();
+                    (chunk, tally.results.len(), tally.exit_code.is_some())
+                };
+                if count > sent {
+                    return Some((Ok::<_, Infallible>(chunk), (tally, count)));
+                }
+                if done || tally.changed().await.is_err() {
+                    return None;
+                }
+            }
+        });
+        return Ok(ndjson_response(Body::from_stream(lines)));
+    }
+    let journal = safe_id(&id).then(|| history::journal_path(&id).ok()).flatten()
+        .and_then(|path| std::fs::read_to_string(path).ok())
+        .ok_or_else(|| not_found(&id))?;
+    Ok(ndjson_response(Body::from(journal)))
+}
+
+/// GET /runs/{id}/summary: progress while running, the outcome once done
+async fn run_summary(State(state): State<Shared>, headers: HeaderMap, Path(id): Path<String>) -> Result<Response, ApiError> {
+    authorize(&state, &headers)?;
+    let summary = match current(&state, &id) {
+        Some(current) => {
+            let tally = current.tally.borrow();
+            Summary {
+                id,
+                status: if tally.exit_code.is_some() { "finished" } else { "running" },
+                command: current.command.clone(),
+                progress: tally.progress,
+                exit_code: tally.exit_code,
+                duration_ms: tally.duration_ms,
+            }
+        }
+        None => {
+            let record = safe_id(&id).then(|| history::find(&id).ok()).flatten().ok_or_else(|| not_found(&id))?;
+            Summary {
+                status: "finished",
+                command: record.command,
+                progress: Progress { completed: record.hosts, total: record.hosts, failed: record.failed },
+                exit_code: Some(record.exit_code),
+                duration_ms: Some(record.duration_ms),
+                id,
+            }
+        }
+    };
+    let body = serde_json::to_string(&summary).map_err(|e| ApiError(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(json_response(StatusCode::OK, body))
+}
+
+/// IDs are used in journal file names: nothing that could leave the runs directory
+fn safe_id(id: &str) -> bool {
+    crate::parse_run_id(id).is_ok()
+}
+
+fn not_found(id: &str) -> ApiError {
+    ApiError(StatusCode::NOT_FOUND, format!("No run with id {}", id))
+}
@@ -0,0 +1,110 @@
+// [shell.rs] - KRUST - Which shell runs the command on the remote host
+use anyhow::{bail, Result};
+use base64::Engine;
+
+use crate::modules::{shell_quote, Module};
+
+/// First line of output under --capture-pid, followed by the PID
+pub const PID_MARKER: &str = "__KRUST_PID__=";
+
+/// --shell: how the command line is handed to the host
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RemoteShell {
+    /// Send the command as-is to the account's login shell
+    #[default]
+    Auto,
+    /// Run under `sh -c`, whatever the login shell
+    Sh,
+    /// Windows PowerShell
+    Powershell,
+    /// Windows cmd.exe
+    Cmd,
+}
+
+impl RemoteShell {
+    fn name(self) -> &'static str {
+        match self {
+            RemoteShell::Auto => "auto",
+            RemoteShell::Sh => "sh",
+            RemoteShell::Powershell => "powershell",
+            RemoteShell::Cmd => "cmd",
+        }
+    }
+
+    pub fn is_windows(self) -> bool {
+        matches!(self, RemoteShell::Powershell | RemoteShell::Cmd)
+    }
+
+    /// The command line to send for `command`
+    pub fn wrap(self, command: &str) -> String {
+        match self {
+            RemoteShell::Auto => command.to_string(),
+            RemoteShell::Sh => format!("sh -c {}", shell_quote(command)),
+            // Windows OpenSSH hands the line to cmd.exe first, whose quoting rules differ from
+            // PowerShell's; a Base64 UTF-16LE script survives both untouched
+            RemoteShell::Powershell => {
+                let script: Vec<u8> = command.encode_utf16().flat_map(u16::to_le_bytes).collect();
+                format!(
+                    "powershell -NoProfile -NonInteractive -EncodedCommand {}",
+                    base64::engine::general_purpose::STANDARD.encode(script)
+                )
+            }
+            RemoteShell::Cmd => format!("cmd /d /s /c \"{}\"", command),
+        }
+    }
+
+    /// --capture-pid: print the shell's PID, then `exec` the command in a shell that keeps it,
+    /// so the exit code, stdin and stdout are the command's own
+    pub fn with_pid(self, command: &str) -> Result<String> {
+        let shell = match self {
+            RemoteShell::Auto => "\"${SHELL:-sh}\"",
+            RemoteShell::Sh => "sh",
+            _ => bail!("--capture-pid needs a POSIX shell on the host, not --shell {}", self.name()),
+        };
+        Ok(format!("echo {}$$; exec {} -c {}", PID_MARKER, shell, shell_quote(command)))
+    }
+
+    /// --quote-args: the command line that gives the remote shell exactly these arguments;
+    /// plain words are left as they are
+    pub fn quote_args(self, args: &[String]) -> Result<String> {
+        if self.is_windows() {
+            bail!("--quote-args quotes for a POSIX shell, not --shell {}", self.name());
+        }
+        let plain = |arg: &String| !arg.is_empty()
+            && arg.chars().all(|c| c.is_ascii_alphanumeric() || "-_./=:,@%+".contains(c));
+        let words: Vec<String> = args.iter()
+            .map(|arg| if plain(arg) { arg.clone() } else { shell_quote(arg) })
+            .collect();
+        Ok(words.join(" "))
+    }
+
+    /// Modules generate POSIX shell scripts; refuse them for Windows targets up front.
+    /// `ping` runs nothing and works everywhere.
+    pub fn check_module(self, module: &Module) -> Result<()> {
+        if self.is_windows() && !matches!(module, Module::Ping(_)) {
+            bail!(
+                "The {} module runs a POSIX shell script and does not support Windows hosts (--shell {})",
+                module.name(),
+                self.name()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Take the --capture-pid line off the front of the output
+pub fn take_pid(mut output: Vec<u8>) -> (Vec<u8>, Option<u32>) {
+    let Some((pid, line)) = pid_line(&output) else {
+        return (output, None);
+    };
+    output.drain(..line);
+    (output, pid)
+}
+
+/// The PID on a --capture-pid line at the start of `output`, and the line's length
+pub fn pid_line(output: &[u8]) -> Option<(Option<u32>, usize)> {
+    let rest = output.strip_prefix(PID_MARKER.as_bytes())?;
+    let end = rest.iter().position(|&b| b == b'\n').unwrap_or(rest.len());
+    let pid = std::str::from_utf8(&rest[..end]).ok().and_then(|pid| pid.trim().parse().ok());
+    Some((pid, (PID_MARKER.len() + end + 1).min(output.len())))
+}
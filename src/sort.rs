@@ -0,0 +1,56 @@
+// [sort.rs] - KRUST - Deterministic ordering of collected results
+use clap::ValueEnum;
+use std::cmp::Ordering;
+
+use crate::HostResult;
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    /// Natural order, so web2 comes before web10
+    Hostname,
+    /// Fastest hosts first
+    Duration,
+    /// Failures first, then by hostname
+    Status,
+}
+
+/// Compare strings treating runs of digits as numbers
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a, b);
+    loop {
+        let (Some(ca), Some(cb)) = (a.chars().next(), b.chars().next()) else {
+            return a.len().cmp(&b.len());
+        };
+        let ordering = if ca.is_ascii_digit() && cb.is_ascii_digit() {
+            let (na, ra) = split_digits(a);
+            let (nb, rb) = split_digits(b);
+            a = ra;
+            b = rb;
+            let (na, nb) = (na.trim_start_matches('0'), nb.trim_start_matches('0'));
+            na.len().cmp(&nb.len()).then_with(|| na.cmp(nb))
+        } else {
+            a = &a[ca.len_utf8()..];
+            b = &b[cb.len_utf8()..];
+            ca.cmp(&cb)
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+}
+
+fn split_digits(s: &str) -> (&str, &str) {
+    let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    s.split_at(end)
+}
+
+pub fn sort_results(results: &mut [HostResult], key: SortKey) {
+    // One name on several ports sorts by port
+    let by_host = |a: &HostResult, b: &HostResult| natural_cmp(&a.hostname, &b.hostname)
+        .then_with(|| natural_cmp(&a.target, &b.target));
+    match key {
+        SortKey::Hostname => results.sort_by(by_host),
+        SortKey::Duration => results.sort_by(|a, b| a.duration_ms.cmp(&b.duration_ms).then_with(|| by_host(a, b))),
+        SortKey::Status => results.sort_by(|a, b| a.success.cmp(&b.success).then_with(|| by_host(a, b))),
+    }
+}
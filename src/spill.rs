@@ -0,0 +1,218 @@
+// [spill.rs] - KRUST - Account for captured output and spill it to disk past --max-total-output
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use tracing::{debug, info, warn};
+
+use crate::ssh_executor::SshHost;
+
+/// Captured output across the run: what is held in memory and where spilled output goes
+pub struct Budget {
+    /// --max-total-output; None keeps everything in memory
+    limit: Option<u64>,
+    /// Output in memory, of hosts still running and of finished results
+    held: AtomicU64,
+    spilled_bytes: AtomicU64,
+    spilled_hosts: AtomicUsize,
+    next_file: AtomicUsize,
+    dir: PathBuf,
+}
+
+/// A finished command's output: in memory, or empty with the file it went to
+#[derive(Debug)]
+pub struct Captured {
+    pub output: Vec<u8>,
+    pub spilled: Option<Spilled>,
+}
+
+/// A host's output that went to a file instead of memory
+#[derive(Debug, Clone)]
+pub struct Spilled {
+    pub path: PathBuf,
+    pub bytes: u64,
+}
+
+static BUDGET: OnceLock<Budget> = OnceLock::new();
+
+/// The run's budget; unlimited unless `configure` was called first
+pub fn budget() -> &'static Budget {
+    BUDGET.get_or_init(|| Budget::new(None))
+}
+
+/// Set --max-total-output for the run. The spill directory is removed when the guard is
+/// dropped, unless `keep` (--keep-spill) is set.
+pub fn configure(limit: Option<u64>, keep: bool) -> SpillGuard {
+    if BUDGET.set(Budget::new(limit)).is_err() {
+        debug!("Output budget already set up");
+    }
+    SpillGuard { keep }
+}
+
+pub struct SpillGuard {
+    keep: bool,
+}
+
+impl Drop for SpillGuard {
+    fn drop(&mut self) {
+        let budget = budget();
+        if !budget.dir.exists() {
+            return;
+        }
+        if self.keep {
+            info!("Spilled output kept in {}", budget.dir.display());
+        } else if let Err(e) = fs::remove_dir_all(&budget.dir) {
+            warn!("Failed to remove {}: {}", budget.dir.display(), e);
+        }
+    }
+}
+
+impl Budget {
+    pub fn new(limit: Option<u64>) -> Self {
+        Budget {
+            limit,
+            held: AtomicU64::new(0),
+            spilled_bytes: AtomicU64::new(0),
+            spilled_hosts: AtomicUsize::new(0),
+            next_file: AtomicUsize::new(0),
+            dir: std::env::temp_dir().join(format!("krust-spill-{}", std::process::id())),
+        }
+    }
+
+    /// The same, spilling into `dir`
+    #[cfg(test)]
+    pub fn in_dir(limit: Option<u64>, dir: PathBuf) -> Self {
+        Budget { dir, ..Budget::new(limit) }
+    }
+
+    /// Bytes of output in memory right now
+    pub fn held(&self) -> u64 {
+        self.held.load(Ordering::Relaxed)
+    }
+
+    /// "312M/1G in memory, 4 hosts spilled", for the progress line; None without a limit
+    pub fn describe(&self) -> Option<String> {
+        let limit = self.limit?;
+        let mut text = format!("{}/{} in memory", format_size(self.held()), format_size(limit));
+        match self.spilled_hosts.load(Ordering::Relaxed) {
+            0 => {}
+            1 => text.push_str(", 1 host spilled"),
+            hosts => text.push_str(&format!(", {} hosts spilled", hosts)),
+        }
+        Some(text)
+    }
+
+    /// (hosts, bytes) written to spill files, for --stats
+    pub fn spilled(&self) -> (usize, u64) {
+        (self.spilled_hosts.load(Ordering::Relaxed), self.spilled_bytes.load(Ordering::Relaxed))
+    }
+
+    /// Give back a finished output that no result keeps, e.g. an attempt about to be retried
+    pub fn discard(&self, captured: Captured) {
+        let len = captured.output.len() as u64;
+        let _ = self.held.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |held| Some(held.saturating_sub(len)));
+        if let Some(spilled) = captured.spilled {
+            let _ = fs::remove_file(&spilled.path);
+            self.spilled_bytes.fetch_sub(spilled.bytes, Ordering::Relaxed);
+            self.spilled_hosts.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    fn would_exceed(&self, more: usize) -> bool {
+        self.limit.is_some_and(|limit| self.held() + more as u64 > limit)
+    }
+
+    fn create_file(&self, host: &SshHost) -> io::Result<(PathBuf, File)> {
+        // Output can hold anything the hosts print, so only we may read it
+        let mut dir = fs::DirBuilder::new();
+        dir.recursive(true);
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut dir, 0o700);
+        dir.create(&self.dir)?;
+        let path = self.dir.join(format!("{:04}-{}.out", self.next_file.fetch_add(1, Ordering::Relaxed), host.safe_file_name()));
+        let file = File::create(&path)?;
+        Ok((path, file))
+    }
+}
+
+/// One command's output: in memory until the budget runs out, then in a file
+pub struct Capture<'a> {
+    budget: &'a Budget,
+    host: &'a SshHost,
+    memory: Vec<u8>,
+    file: Option<(PathBuf, File)>,
+    bytes: u64,
+}
+
+impl<'a> Capture<'a> {
+    pub fn new(budget: &'a Budget, host: &'a SshHost) -> Self {
+        Capture { budget, host, memory: Vec::with_capacity(4096), file: None, bytes: 0 }
+    }
+
+    pub fn push(&mut self, data: &[u8]) -> io::Result<()> {
+        self.bytes += data.len() as u64;
+        if self.file.is_none() && self.budget.would_exceed(data.len()) {
+            self.spill()?;
+        }
+        match self.file {
+            Some((_, ref mut file)) => file.write_all(data),
+            None => {
+                self.memory.extend_from_slice(data);
+                self.budget.held.fetch_add(data.len() as u64, Ordering::Relaxed);
+                Ok(())
+            }
+        }
+    }
+
+    /// Move what is in memory to a new file and write there from now on
+    fn spill(&mut self) -> io::Result<()> {
+        let (path, mut file) = self.budget.create_file(self.host)?;
+        file.write_all(&self.memory)?;
+        debug!("{}: output over --max-total-output, spilling to {}", self.host.hostname, path.display());
+        self.budget.held.fetch_sub(self.memory.len() as u64, Ordering::Relaxed);
+        self.memory = Vec::new();
+        self.file = Some((path, file));
+        self.budget.spilled_hosts.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Output in memory stays counted as held, since the result keeps it
+    pub fn finish(mut self) -> io::Result<Captured> {
+        let output = std::mem::take(&mut self.memory);
+        let spilled = match self.file.take() {
+            Some((path, mut file)) => {
+                file.flush()?;
+                self.budget.spilled_bytes.fetch_add(self.bytes, Ordering::Relaxed);
+                Some(Spilled { path, bytes: self.bytes })
+            }
+            None => None,
+        };
+        Ok(Captured { output, spilled })
+    }
+}
+
+impl Drop for Capture<'_> {
+    /// An abandoned capture (the command failed) gives its memory back and removes its file
+    fn drop(&mut self) {
+        self.budget.held.fetch_sub(self.memory.len() as u64, Ordering::Relaxed);
+        if let Some((ref path, _)) = self.file {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// 1536 -> "1.5K", in the units --max-total-output accepts
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [(&str, u64); 3] = [("G", 1 << 30), ("M", 1 << 20), ("K", 1 << 10)];
+    for (unit, size) in UNITS {
+        if bytes >= size {
+            let value = bytes as f64 / size as f64;
+            return match value.fract() < 0.05 {
+                true => format!("{:.0}{}", value, unit),
+                false => format!("{:.1}{}", value, unit),
+            };
+        }
+    }
+    format!("{}B", bytes)
+}
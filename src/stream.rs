@@ -0,0 +1,279 @@
+// [stream.rs] - KRUST - A run as a stream of results in completion order, with cancellation and progress
+use anyhow::{bail, Result};
+use futures::future::BoxFuture;
+use futures::stream::{self, FuturesUnordered, Stream, StreamExt};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+use tracing::error;
+
+use crate::executor::{CommandExecutor, Transport};
+use crate::host_error::{HostError, LocalErrorKind};
+use crate::limiter::Limiter;
+use crate::modules::{self, Module};
+use crate::ssh_executor::{ExecErrorKind, SshHost};
+use crate::timings::Started;
+use crate::{
+    execute_with_retries, history, precheck, prepare, reboot, Cli, ExecSettings, Hook, HostResult, Prepared,
+    ResultChecks, DEADLINE_GRACE,
+};
+
+/// Hosts done out of the run's total, and how many of them failed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Progress {
+    pub completed: usize,
+    pub total: usize,
+    pub failed: usize,
+}
+
+impl Progress {
+    pub fn new(total: usize) -> Self {
+        Progress { total, ..Progress::default() }
+    }
+
+    pub fn record(&mut self, result: &HostResult) {
+        self.completed += 1;
+        if !result.success {
+            self.failed += 1;
+        }
+    }
+}
+
+/// The jobs' results as each host finishes, redacted, post-processed and checked. Dropping the
+/// stream abandons the hosts still running. Cancelling `cancel` lets running hosts finish but
+/// starts no more: the rest come back failed as skipped, like the hosts after a rebooted one
+/// that did not come back.
+pub(crate) fn results<'a, E: CommandExecutor>(
+    jobs: Vec<(SshHost, String)>,
+    transport: Arc<Transport<E>>,
+    input: Option<Arc<Vec<u8>>>,
+    limiter: Arc<Limiter>,
+    args: &'a Cli,
+    checks: &'a ResultChecks,
+    cancel: &CancellationToken,
+) -> impl Stream<Item = HostResult> + 'a {
+    let cancel = cancel.clone();
+    let pre_check = args.pre_check.then_some(args.pre_check_timeout);
+    let launched = async move {
+        let unreachable = match pre_check {
+            Some(limit) => {
+                let hosts: Vec<SshHost> = jobs.iter().map(|(host, _)| host.clone()).collect();
+                precheck::unreachable_hosts(&hosts, &transport.resolver, limit).await
+            }
+            None => HashMap::new(),
+        };
+        launch(jobs, transport, input, limiter, args, &checks.tags, unreachable, cancel)
+    };
+    stream::once(launched).flatten().map(move |mut result| {
+        checks.redact(&mut result);
+        if let Some(module) = args.module() {
+            module.post_process(&mut result);
+        }
+        checks.apply(&mut result);
+        result
+    })
+}
+
+/// One task per host, polled by the stream; nothing starts until it is
+#[allow(clippy::too_many_arguments)]
+fn launch<E: CommandExecutor>(
+    jobs: Vec<(SshHost, String)>,
+    transport: Arc<Transport<E>>,
+    input: Option<Arc<Vec<u8>>>,
+    limiter: Arc<Limiter>,
+    args: &Cli,
+    tags: &HashMap<String, Vec<String>>,
+    mut unreachable: HashMap<SshHost, String>,
+    cancel: CancellationToken,
+) -> FuturesUnordered<BoxFuture<'static, HostResult>> {
+    let settings = ExecSettings::from_args(args);
+    let (timeout, retries) = (settings.timeout, settings.retries);
+    let reboot_policy = args.module().and_then(Module::reboot_policy);
+    let fetch_policy = args.module().and_then(Module::fetch_policy);
+    let copy_source = args.module().and_then(Module::copy_source);
+    let template_source = args.module().and_then(Module::template_source);
+    // Cancelled when a rebooted host fails to come back, as well as with `cancel`
+    let rollout = cancel.child_token();
+    let pre_hook = Hook::new(&args.pre_cmd, args.pre_local, args.shell);
+    let post_hook = Hook::new(&args.post_cmd, args.post_local, args.shell);
+    let tasks = FuturesUnordered::new();
+
+    for (host, cmd) in jobs {
+        let unreachable_reason = unreachable.remove(&host);
+        let limiter = Arc::clone(&limiter);
+        let transport = Arc::clone(&transport);
+        let reboot_policy = reboot_policy.clone();
+        let fetch_policy = fetch_policy.clone();
+        let copy_source = copy_source.clone();
+        let template_source = template_source.clone();
+        let host_tags = tags.get(&host.to_string()).cloned().unwrap_or_default();
+        let (cancel, rollout) = (cancel.clone(), rollout.clone());
+        let (pre_hook, post_hook) = (pre_hook.clone(), post_hook.clone());
+        let input = input.clone();
+
+        let cutoff = limiter.deadline().map(|deadline| deadline + DEADLINE_GRACE);
+        let deadline_host = host.clone();
+
+        let task = async move {
+            if let Some(reason) = unreachable_reason {
+                let message = format!("Unreachable (pre-check): {}", reason);
+                return HostResult::not_started(host, HostError::exec(ExecErrorKind::Unreachable, message, None));
+            }
+            let permit = tokio::select! {
+                biased;
+                _ = rollout.cancelled() => None,
+                permit = limiter.acquire(&host) => Some(permit),
+            };
+            let Some(permit) = permit.filter(|_| !rollout.is_cancelled()) else {
+                let message = match cancel.is_cancelled() {
+                    true => "Skipped: the run was cancelled",
+                    false => "Skipped: a rebooted host did not come back, rollout aborted",
+                };
+                return HostResult::not_started(host, HostError::local(LocalErrorKind::Skipped, message, None));
+            };
+            let Some(permit) = permit else {
+                return HostResult::deadline_exceeded(host);
+            };
+            if let Some(ref hook) = pre_hook {
+                if let Err(e) = hook.run(&transport, &host, timeout, retries).await {
+                    let message = format!("Pre-command failed: {}", e);
+                    return HostResult::not_started(host, HostError::local(LocalErrorKind::Hook, message, None));
+                }
+            }
+            let mut result = execute_with_retries(&transport, host.clone(), cmd, input, settings, permit.setup_slot()).await;
+            limiter.record(&result);
+            // Keep the permit while the host reboots so concurrency (or --max-sessions) bounds how many are down at once
+            if let Some(ref policy) = reboot_policy {
+                if !reboot::wait_after_module(&transport, &host, &mut result, policy).await {
+                    error!("{} did not come back healthy, aborting rollout", host.hostname);
+                    rollout.cancel();
+                }
+            }
+            if let Some(ref policy) = fetch_policy {
+                modules::archive_fetch::fetch(&transport, &host, &mut result, policy, timeout).await;
+            }
+            if let Some(ref source) = copy_source {
+                modules::copy::upload(&transport, &host, &mut result, source, timeout).await;
+            }
+            if let Some(ref source) = template_source {
+                modules::template::upload(&transport, &host, &mut result, source, &host_tags, timeout).await;
+            }
+            if let (true, Some(hook)) = (result.success, &post_hook) {
+                result.post_error = hook.run(&transport, &host, timeout, retries).await.err();
+            }
+            result
+        };
+        // Hosts still running after the deadline's grace period are abandoned
+        tasks.push(Box::pin(async move {
+            match cutoff {
+                Some(cutoff) => tokio::time::timeout_at(cutoff, task).await
+                    .unwrap_or_else(|_| HostResult::deadline_exceeded(deadline_host)),
+                None => task.await,
+            }
+        }) as BoxFuture<'static, HostResult>);
+    }
+    tasks
+}
+
+/// krust for programs that embed it, configured with the command line's own flags:
+///
+/// ```no_run
+/// # async fn example() -> anyhow::Result<()> {
+/// use futures::StreamExt;
+/// use krust::{CancellationToken, Cli, Run};
+///
+/// let run = Run::new(Cli::try_parse_args(["krust", "--hosts", "web1,web2", "uptime"])?).await?;
+/// let cancel = CancellationToken::new();
+/// let mut results = std::pin::pin!(run.run_streaming(cancel.clone()));
+/// while let Some(result) = results.next().await {
+///     println!("{}", serde_json::to_string(&result)?);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Nothing is printed and no run history is written; output flags such as --json are ignored.
+pub struct Run {
+    args: Cli,
+    prepared: Prepared,
+}
+
+impl Run {
+    /// Check the flags, load the hosts and build every job, as `krust` does before connecting
+    pub async fn new(args: Cli) -> Result<Self> {
+        if (args.subcommand.is_some() && args.module().is_none()) || args.list_hosts || args.watch.is_some() {
+            bail!("Only command and module runs can be embedded: no other subcommand, --list-hosts or --watch");
+        }
+        let run_id = args.run_id.clone().unwrap_or_else(|| history::new_run_id(Started::now().at));
+        match prepare(&args, &run_id).await? {
+            Some(prepared) => Ok(Run { args, prepared }),
+            None => bail!("--list-hosts does not run anything"),
+        }
+    }
+
+    /// Hosts the run will try
+    pub fn total(&self) -> usize {
+        self.prepared.jobs.len()
+    }
+
+    /// Each host's result as soon as it has one. Dropping the stream abandons the hosts still
+    /// running; cancelling `cancel` starts no more hosts and reports the rest as skipped.
+    pub fn run_streaming(&self, cancel: CancellationToken) -> impl Stream<Item = HostResult> + '_ {
+        let Prepared { ref jobs, ref transport, ref input, ref limiter, ref checks, .. } = self.prepared;
+        results(jobs.clone(), Arc::clone(transport), input.clone(), Arc::clone(limiter), &self.args, checks, &cancel)
+    }
+
+    /// Every host's result, in completion order
+    pub async fn run(&self, cancel: CancellationToken) -> Vec<HostResult> {
+        self.run_with_progress(cancel, |_| {}).await
+    }
+
+    /// Every host's result, calling `progress` after each one
+    pub async fn run_with_progress(&self, cancel: CancellationToken, mut progress: impl FnMut(Progress)) -> Vec<HostResult> {
+        let mut tally = Progress::new(self.total());
+        self.run_streaming(cancel)
+            .inspect(|result| {
+                tally.record(result);
+                progress(tally);
+            })
+            .collect()
+            .await
+    }
+}
+
+/// What `krust serve` needs to report and record the runs it starts
+#[cfg(feature = "serve")]
+impl Run {
+    /// The run's exit code as `krust` would give it
+    pub(crate) fn exit_code(&self, results: &[HostResult], cancelled: bool) -> i32 {
+        if cancelled || results.iter().any(|r| r.error_kind == Some(ExecErrorKind::Deadline)) {
+            crate::EXIT_ABORTED
+        } else if results.iter().all(|r| r.success) {
+            0
+        } else {
+            1
+        }
+    }
+
+    /// The run's history entry; `argv` is what `krust history rerun` replays
+    pub(crate) fn record(&self, id: String, started: Started, results: &[HostResult], exit_code: i32, argv: Vec<String>) -> crate::RunRecord {
+        let command = match self.args.module() {
+            Some(module) => format!("module {}", module.name()),
+            None => self.prepared.command_line.clone(),
+        };
+        crate::RunRecord {
+            id,
+            timestamp: started.at,
+            user: self.prepared.ssh_user.clone(),
+            command,
+            hosts: results.len(),
+            failed: results.iter().filter(|r| !r.success).count(),
+            duration_ms: started.elapsed_ms(),
+            exit_code,
+            inventory: self.prepared.inventory.clone(),
+            annotations: self.prepared.checks.annotations.clone(),
+            args: argv,
+        }
+    }
+}
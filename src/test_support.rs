@@ -0,0 +1,254 @@
+// [test_support.rs] - KRUST - Scripted executor for exercising the run loop without SSH servers
+use anyhow::anyhow;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::executor::{CommandExecutor, Transport};
+use crate::limiter::{self, Limiter};
+use crate::modules::copy::Upload;
+use crate::resolver::Resolver;
+use crate::secret::Secret;
+use crate::ssh_executor::{AuthMethod, ExecError, ExecErrorKind, ExecOutput, HostAuth, SshAuth, SshHost};
+use crate::timings::{self, Phase};
+use crate::{Cli, ResultChecks};
+
+#[derive(Debug, Clone)]
+pub enum Reply {
+    /// The command ran: output and exit code
+    Output(&'static str, i32),
+    /// The command ran: stdout, stderr and exit code
+    Streams(&'static str, &'static str, i32),
+    /// The attempt failed at this stage
+    Fail(ExecErrorKind),
+}
+
+#[derive(Debug, Clone)]
+struct Step {
+    delay: Duration,
+    reply: Reply,
+}
+
+/// Answers each host from a script of replies, in order; the last reply repeats once the script
+/// runs out, and hosts without a script succeed with "ok". Downloads get the host's file, if any;
+/// uploads are recorded and succeed unless their path was set to fail.
+#[derive(Default)]
+pub struct MockExecutor {
+    scripts: Mutex<HashMap<String, VecDeque<Step>>>,
+    calls: Mutex<HashMap<String, usize>>,
+    files: Mutex<HashMap<String, Vec<u8>>>,
+    commands: Mutex<Vec<(String, String)>>,
+    inputs: Mutex<Vec<(String, Vec<u8>)>>,
+    stopped: Mutex<Vec<String>>,
+    uploads: Mutex<Vec<(String, Upload)>>,
+    failing_paths: Mutex<HashSet<String>>,
+}
+
+impl MockExecutor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn reply(self, host: &str, reply: Reply) -> Self {
+        self.reply_after(host, Duration::ZERO, reply)
+    }
+
+    /// Queue a reply that takes `delay` to arrive, e.g. to trip --timeout
+    pub fn reply_after(self, host: &str, delay: Duration, reply: Reply) -> Self {
+        self.scripts.lock().unwrap()
+            .entry(host.to_string())
+            .or_default()
+            .push_back(Step { delay, reply });
+        self
+    }
+
+    /// What any download from `host` receives
+    pub fn file(self, host: &str, content: &[u8]) -> Self {
+        self.files.lock().unwrap().insert(host.to_string(), content.to_vec());
+        self
+    }
+
+    /// Make uploads to this remote path fail
+    pub fn fail_upload(self, path: &str) -> Self {
+        self.failing_paths.lock().unwrap().insert(path.to_string());
+        self
+    }
+
+    /// Uploads applied so far, as (host, change)
+    pub fn uploads(&self) -> Vec<(String, Upload)> {
+        self.uploads.lock().unwrap().clone()
+    }
+
+    /// Commands run so far, as (host, command)
+    pub fn commands(&self) -> Vec<(String, String)> {
+        self.commands.lock().unwrap().clone()
+    }
+
+    /// --stdin payloads received so far, as (host, input)
+    pub fn inputs(&self) -> Vec<(String, Vec<u8>)> {
+        self.inputs.lock().unwrap().clone()
+    }
+
+    /// Hosts whose scripted delay was cut short because the run gave up on them at --timeout
+    pub fn stopped(&self) -> Vec<String> {
+        self.stopped.lock().unwrap().clone()
+    }
+
+    /// How many times `host` was attempted
+    pub fn calls(&self, host: &str) -> usize {
+        self.calls.lock().unwrap().get(host).copied().unwrap_or(0)
+    }
+}
+
+impl CommandExecutor for MockExecutor {
+    fn execute(
+        &self,
+        host: &SshHost,
+        _addrs: Vec<SocketAddr>,
+        _auth: &SshAuth,
+        command: &str,
+        input: Option<&[u8]>,
+    ) -> Result<ExecOutput, ExecError> {
+        if let Some(input) = input {
+            self.inputs.lock().unwrap().push((host.hostname.clone(), input.to_vec()));
+        }
+        *self.calls.lock().unwrap().entry(host.hostname.clone()).or_default() += 1;
+        self.commands.lock().unwrap().push((host.hostname.clone(), command.to_string()));
+        let step = {
+            let mut scripts = self.scripts.lock().unwrap();
+            match scripts.get_mut(&host.hostname) {
+                Some(script) if script.len() > 1 => script.pop_front(),
+                Some(script) => script.front().cloned(),
+                None => None,
+            }
+        };
+        let step = step.unwrap_or(Step { delay: Duration::ZERO, reply: Reply::Output("ok", 0) });
+        // Connecting takes no time here, but is marked like the SSH thread does
+        timings::begin(Phase::Connect);
+
+        // The scripted delay stands in for the command running
+        if let Reply::Output(..) | Reply::Streams(..) = step.reply {
+            limiter::connected();
+        }
+        // and stops like the SSH thread's channel loop once the run abandons it
+        timings::begin(Phase::Exec);
+        let until = Instant::now() + step.delay;
+        while Instant::now() < until {
+            if timings::abandoned() {
+                self.stopped.lock().unwrap().push(host.hostname.clone());
+                return Err(ExecError::new(ExecErrorKind::Channel, anyhow!("mock: abandoned")));
+            }
+            std::thread::sleep(Duration::from_millis(5).min(until - Instant::now()));
+        }
+        timings::end();
+        match step.reply {
+            Reply::Output(output, exit_code) => Ok(ExecOutput {
+                output: output.as_bytes().to_vec(),
+                exit_code,
+                ..ExecOutput::empty(0, "password".to_string(), None)
+            }),
+            Reply::Streams(output, stderr, exit_code) => Ok(ExecOutput {
+                output: output.as_bytes().to_vec(),
+                stderr: stderr.as_bytes().to_vec(),
+                bytes_stderr: stderr.len() as u64,
+                exit_code,
+                ..ExecOutput::empty(0, "password".to_string(), None)
+            }),
+            Reply::Fail(kind) => Err(ExecError::new(kind, anyhow!("mock {:?} failure", kind))),
+        }
+    }
+
+    fn download(
+        &self,
+        host: &SshHost,
+        _addrs: Vec<SocketAddr>,
+        _auth: &SshAuth,
+        remote: &str,
+        local: &Path,
+    ) -> Result<u64, ExecError> {
+        let content = self.files.lock().unwrap().get(&host.hostname).cloned()
+            .ok_or_else(|| ExecError::new(ExecErrorKind::Channel, anyhow!("mock: no such file {}", remote)))?;
+        std::fs::write(local, &content).map_err(|e| ExecError::new(ExecErrorKind::Channel, e.into()))?;
+        Ok(content.len() as u64)
+    }
+
+    fn upload(
+        &self,
+        host: &SshHost,
+        _addrs: Vec<SocketAddr>,
+        _auth: &SshAuth,
+        changes: &[Upload],
+    ) -> Result<Vec<Result<u64, String>>, ExecError> {
+        let failing = self.failing_paths.lock().unwrap().clone();
+        let mut uploads = self.uploads.lock().unwrap();
+        Ok(changes.iter().map(|change| {
+            uploads.push((host.hostname.clone(), change.clone()));
+            match change {
+                Upload::File { remote, .. } if failing.contains(remote) => Err("mock: permission denied".to_string()),
+                Upload::File { local, write: true, .. } => Ok(std::fs::metadata(local).map_or(0, |m| m.len())),
+                Upload::Content { remote, .. } if failing.contains(remote) => Err("mock: permission denied".to_string()),
+                Upload::Content { content, .. } => Ok(content.len() as u64),
+                _ => Ok(0),
+            }
+        }).collect())
+    }
+}
+
+/// Parse a command line the way main() does; hosts should be IP literals so no DNS is involved
+pub fn cli(args: &[&str]) -> Cli {
+    Cli::try_parse_args(std::iter::once("krust").chain(args.iter().copied())).unwrap()
+}
+
+pub fn transport(executor: MockExecutor) -> Arc<Transport<MockExecutor>> {
+    let auth = SshAuth::with_methods("tester".to_string(), vec![AuthMethod::Password(Secret::new("x".to_string()))]);
+    Arc::new(Transport {
+        executor,
+        auth: HostAuth::new(Arc::new(auth)),
+        resolver: Resolver::new(&[]),
+    })
+}
+
+/// A data directory shared by every test that writes run history; XDG_DATA_HOME is process-wide,
+/// so it is set once rather than per test
+pub fn data_home() -> &'static Path {
+    static HOME: OnceLock<tempfile::TempDir> = OnceLock::new();
+    HOME.get_or_init(|| {
+        let home = tempfile::tempdir().unwrap();
+        std::env::set_var("XDG_DATA_HOME", home.path());
+        home
+    }).path()
+}
+
+/// One job per host running the command from `args`
+pub fn jobs(args: &Cli) -> Vec<(SshHost, String)> {
+    args.target_hosts.iter()
+        .map(|target| (SshHost::from_target(target, None).unwrap(), crate::command_text(args).unwrap()))
+        .collect()
+}
+
+/// The run's limiter as main builds it, without inventory groups or module caps
+pub fn limiter(args: &Cli) -> Arc<Limiter> {
+    let limiter = match args.adaptive_concurrency {
+        true => Limiter::adaptive(args.concurrency),
+        false => Limiter::fixed(args.concurrency),
+    };
+    Arc::new(limiter
+        .with_rate(args.connect_rate)
+        .with_deadline(args.deadline)
+        .with_scope(args.concurrency_scope, args.max_sessions))
+}
+
+pub fn no_checks() -> ResultChecks {
+    ResultChecks {
+        redactor: None,
+        assertions: None,
+        filter: None,
+        tags: HashMap::new(),
+        commands: HashMap::new(),
+        run_id: None,
+        annotations: BTreeMap::new(),
+        legacy_stderr: false,
+    }
+}
@@ -0,0 +1,202 @@
+// [timings.rs] - KRUST - Where an attempt's time went: resolve, connect, auth and exec
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Instant;
+
+use crate::bench::Latency;
+use crate::proxy_command::ProxyChild;
+use crate::shell;
+use crate::HostResult;
+
+/// Milliseconds per phase of the last attempt; resolve is summed over attempts.
+/// A phase cut short by a failure or timeout holds the time spent in it until then.
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resolve_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connect_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exec_ms: Option<u64>,
+}
+
+/// When something began, on both clocks: the wall clock says when, for `timestamp`, and the
+/// monotonic one says how long, so NTP stepping the controller's clock mid-run cannot make a
+/// duration jump or go negative
+#[derive(Debug, Clone, Copy)]
+pub struct Started {
+    pub at: DateTime<Utc>,
+    instant: Instant,
+}
+
+impl Started {
+    pub fn now() -> Self {
+        Started { at: Utc::now(), instant: Instant::now() }
+    }
+
+    #[cfg(test)]
+    pub fn from_parts(at: DateTime<Utc>, instant: Instant) -> Self {
+        Started { at, instant }
+    }
+
+    /// Milliseconds since the start; zero, not a wrapped huge number, for a start in the future
+    pub fn elapsed_ms(&self) -> u64 {
+        Instant::now().saturating_duration_since(self.instant).as_millis().try_into().unwrap_or(u64::MAX)
+    }
+
+    /// The wall-clock end of something that took `duration_ms`: the start plus the measured
+    /// duration, so it never comes before the start even if the clock was stepped back
+    pub fn finished_at(&self, duration_ms: u64) -> DateTime<Utc> {
+        ChronoDuration::try_milliseconds(duration_ms.try_into().unwrap_or(i64::MAX))
+            .and_then(|duration| self.at.checked_add_signed(duration))
+            .unwrap_or(DateTime::<Utc>::MAX_UTC)
+    }
+}
+
+/// The phases timed on the SSH thread; resolving happens in the run loop
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// TCP connect and SSH handshake
+    Connect,
+    Auth,
+    Exec,
+}
+
+impl Timings {
+    fn slot(&mut self, phase: Phase) -> &mut Option<u64> {
+        match phase {
+            Phase::Connect => &mut self.connect_ms,
+            Phase::Auth => &mut self.auth_ms,
+            Phase::Exec => &mut self.exec_ms,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Marks {
+    current: Option<(Phase, Instant)>,
+    done: Timings,
+    remote_pid: Option<u32>,
+    proxy: Weak<ProxyChild>,
+}
+
+/// Phase marks for one attempt. The SSH thread writes them, and the run loop can read them
+/// even when it gave up on the thread at --timeout. The --capture-pid PID is kept here too,
+/// since a command that timed out is the one most likely left running, and so is the
+/// --proxy-command child, which the run loop stops to free a thread stuck on it.
+#[derive(Default)]
+pub struct PhaseClock {
+    marks: Mutex<Marks>,
+    abandoned: AtomicBool,
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Arc<PhaseClock>>> = const { RefCell::new(None) };
+}
+
+/// Clears the thread's clock when dropped
+pub struct Entered(());
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        CURRENT.with(|current| current.borrow_mut().take());
+    }
+}
+
+impl PhaseClock {
+    /// Make this the clock that `begin` writes to on the current thread, like entering a span
+    pub fn enter(self: &Arc<Self>) -> Entered {
+        CURRENT.with(|current| *current.borrow_mut() = Some(Arc::clone(self)));
+        Entered(())
+    }
+
+    /// Completed phases, plus the one still running with its time so far
+    pub fn snapshot(&self) -> Timings {
+        let marks = self.marks.lock().unwrap();
+        let mut timings = marks.done;
+        if let Some((phase, started)) = marks.current {
+            *timings.slot(phase) = Some(started.elapsed().as_millis() as u64);
+        }
+        timings
+    }
+
+    pub fn remote_pid(&self) -> Option<u32> {
+        self.marks.lock().unwrap().remote_pid
+    }
+
+    /// Kill the attempt's --proxy-command, if it is still running
+    pub fn stop_proxy(&self) {
+        let proxy = self.marks.lock().unwrap().proxy.upgrade();
+        if let Some(proxy) = proxy {
+            proxy.kill();
+        }
+    }
+
+    /// The run loop gave up on the attempt at --timeout; the SSH thread stops at its next pass
+    /// over the channel instead of feeding --stdin and reading output nobody will look at
+    pub fn abandon(&self) {
+        self.abandoned.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Whether the run loop gave up on the current thread's attempt
+pub fn abandoned() -> bool {
+    CURRENT.with(|current| current.borrow().as_ref().is_some_and(|clock| clock.abandoned.load(Ordering::Relaxed)))
+}
+
+/// End the running phase, if any, and start `phase` on the current thread's clock
+pub fn begin(phase: Phase) {
+    with_clock(|marks| {
+        finish_current(marks);
+        marks.current = Some((phase, Instant::now()));
+    });
+}
+
+/// Note the --capture-pid PID when `chunk` is the start of a command's output
+pub fn note_output(chunk: &[u8]) {
+    if let Some((Some(pid), _)) = shell::pid_line(chunk) {
+        with_clock(|marks| marks.remote_pid = Some(pid));
+    }
+}
+
+/// Remember the current attempt's --proxy-command child
+pub fn note_proxy(child: &Arc<ProxyChild>) {
+    with_clock(|marks| marks.proxy = Arc::downgrade(child));
+}
+
+/// End the running phase
+pub fn end() {
+    with_clock(finish_current);
+}
+
+fn with_clock(f: impl FnOnce(&mut Marks)) {
+    CURRENT.with(|current| {
+        if let Some(ref clock) = *current.borrow() {
+            f(&mut clock.marks.lock().unwrap());
+        }
+    });
+}
+
+fn finish_current(marks: &mut Marks) {
+    if let Some((phase, started)) = marks.current.take() {
+        *marks.done.slot(phase) = Some(started.elapsed().as_millis() as u64);
+    }
+}
+
+/// Latency of each phase across hosts, for --stats
+pub fn summarize(results: &[HostResult]) -> [(&'static str, Option<Latency>); 4] {
+    let phase = |pick: fn(&Timings) -> Option<u64>| {
+        Latency::of(results.iter().filter_map(|r| r.timings.as_ref().and_then(pick)))
+    };
+    [
+        ("resolve", phase(|t| t.resolve_ms)),
+        ("connect", phase(|t| t.connect_ms)),
+        ("auth", phase(|t| t.auth_ms)),
+        ("exec", phase(|t| t.exec_ms)),
+    ]
+}
@@ -0,0 +1,162 @@
+// [watch.rs] - KRUST - Repeat a run on an interval and track per-host status
+use chrono::{DateTime, Local};
+use std::collections::BTreeMap;
+use std::io::{stdout, IsTerminal};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{sleep_until, Instant};
+use tokio_util::sync::CancellationToken;
+
+use crate::limiter::Limiter;
+use crate::output;
+use crate::sort::natural_cmp;
+use crate::executor::{CommandExecutor, Transport};
+use crate::ssh_executor::SshHost;
+use crate::{run_parallel, Cli, HostResult, ResultChecks, EXIT_ABORTED};
+
+struct HostStatus {
+    success: bool,
+    consecutive_failures: u64,
+    last_change: DateTime<Local>,
+    detail: String,
+}
+
+/// Latest state of every host across iterations
+#[derive(Default)]
+struct StatusTable {
+    hosts: BTreeMap<String, HostStatus>,
+}
+
+impl StatusTable {
+    fn update(&mut self, results: &[HostResult]) {
+        let now = Local::now();
+        for result in results {
+            let detail = if result.success {
+                result.stdout.as_deref().and_then(|out| out.lines().next()).unwrap_or("").to_string()
+            } else {
+                result.error_message().map(str::to_string)
+                    .or_else(|| result.stdout.as_deref().and_then(|out| out.lines().last()).map(str::to_string))
+                    .or_else(|| result.exit_code.map(|code| format!("exit {}", code)))
+                    .unwrap_or_default()
+            };
+            let detail = detail.chars().take(60).collect();
+
+            match self.hosts.get_mut(&result.target) {
+                Some(status) => {
+                    if status.success != result.success {
+                        status.last_change = now;
+                    }
+                    status.success = result.success;
+                    status.consecutive_failures = if result.success { 0 } else { status.consecutive_failures + 1 };
+                    status.detail = detail;
+                }
+                None => {
+                    self.hosts.insert(result.target.clone(), HostStatus {
+                        success: result.success,
+                        consecutive_failures: u64::from(!result.success),
+                        last_change: now,
+                        detail,
+                    });
+                }
+            }
+        }
+    }
+
+    fn render(&self, iteration: u64, interval: Duration, use_color: bool) {
+        if use_color {
+            // Clear the screen and redraw from the top
+            out!("\x1b[2J\x1b[H");
+        }
+        let failing = self.hosts.values().filter(|s| !s.success).count();
+        outln!(
+            "Every {}s | iteration {} | {} hosts, {} failing | {}",
+            interval.as_secs(),
+            iteration,
+            self.hosts.len(),
+            failing,
+            Local::now().format("%H:%M:%S")
+        );
+        outln!();
+
+        let width = self.hosts.keys().map(String::len).max().unwrap_or(4).max(4);
+        outln!("{:<width$}  {:<6}  {:>5}  {:<8}  LAST OUTPUT", "HOST", "STATUS", "FAILS", "CHANGED");
+        let mut hosts: Vec<_> = self.hosts.iter().collect();
+        hosts.sort_by(|a, b| natural_cmp(a.0, b.0));
+        for (host, status) in hosts {
+            let label = match (status.success, use_color) {
+                (true, true) => "\x1b[32mOK    \x1b[0m",
+                (false, true) => "\x1b[31mFAIL  \x1b[0m",
+                (true, false) => "OK    ",
+                (false, false) => "FAIL  ",
+            };
+            outln!(
+                "{:<width$}  {}  {:>5}  {:<8}  {}",
+                host,
+                label,
+                status.consecutive_failures,
+                status.last_change.format("%H:%M:%S"),
+                status.detail
+            );
+        }
+        outln!();
+    }
+}
+
+/// Run the jobs every `interval` until a stop condition is met; returns the last iteration
+#[allow(clippy::too_many_arguments)]
+pub async fn run<E: CommandExecutor>(
+    jobs: Vec<(SshHost, String)>,
+    transport: Arc<Transport<E>>,
+    input: Option<Arc<Vec<u8>>>,
+    limiter: Arc<Limiter>,
+    args: &Cli,
+    checks: &ResultChecks,
+    interval: Duration,
+    cancel: &CancellationToken,
+) -> (Vec<HostResult>, i32) {
+    let use_color = !args.no_color && stdout().is_terminal();
+    let mut table = StatusTable::default();
+    let mut iteration = 0;
+
+    loop {
+        iteration += 1;
+        let next_run = Instant::now() + interval;
+        let (results, exit_code) = run_parallel(
+            jobs.clone(),
+            Arc::clone(&transport),
+            input.clone(),
+            Arc::clone(&limiter),
+            args,
+            checks,
+            Some(iteration),
+            cancel,
+        ).await;
+
+        if !args.json {
+            table.update(&results);
+            table.render(iteration, interval, use_color);
+        }
+
+        let all_succeeded = results.iter().all(|r| r.success);
+        let done = (args.until_success && all_succeeded)
+            || (args.until_fail && !all_succeeded)
+            || args.iterations.is_some_and(|max| iteration >= max)
+            || limiter.deadline_passed()
+            || cancel.is_cancelled()
+            || output::stdout_closed();
+        if done {
+            return (results, exit_code);
+        }
+
+        match limiter.deadline() {
+            Some(deadline) if deadline < next_run => {
+                sleep_until(deadline).await;
+                return (results, EXIT_ABORTED);
+            }
+            _ => tokio::select! {
+                _ = sleep_until(next_run) => {}
+                _ = cancel.cancelled() => return (results, EXIT_ABORTED),
+            },
+        }
+    }
+}
@@ -0,0 +1,344 @@
+// [where_clause.rs] - KRUST - --where expressions over cached host facts
+use regex::Regex;
+use serde_json::{Map, Value};
+use std::cmp::Ordering;
+
+/// A parsed --where expression, e.g. `os_name == "Ubuntu" && memory_total_gb < 8`
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare(Operand, Op, Operand),
+    Matches(Operand, Regex),
+    /// A bare operand: true unless missing, false, zero or empty
+    Truthy(Operand),
+}
+
+#[derive(Debug, Clone)]
+pub enum Operand {
+    Field(String),
+    Literal(Value),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    /// Array membership or substring
+    Contains,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Num(f64),
+    LParen,
+    RParen,
+    And,
+    Or,
+    Not,
+    Op(&'static str),
+}
+
+/// clap value parser for --where
+pub fn parse(s: &str) -> Result<Expr, String> {
+    let tokens = tokenize(s).map_err(|e| format!("Invalid --where '{}': {}", s, e))?;
+    let mut parser = Parser { tokens, pos: 0 };
+    let expr = parser.or().map_err(|e| format!("Invalid --where '{}': {}", s, e))?;
+    if let Some(token) = parser.tokens.get(parser.pos) {
+        return Err(format!("Invalid --where '{}': unexpected {:?}", s, token));
+    }
+    Ok(expr)
+}
+
+/// Longest first, so `<=` is not read as `<` then `=`
+const SYMBOLS: [(&str, Token); 13] = [
+    ("&&", Token::And),
+    ("||", Token::Or),
+    ("==", Token::Op("==")),
+    ("!=", Token::Op("!=")),
+    ("<=", Token::Op("<=")),
+    (">=", Token::Op(">=")),
+    ("=~", Token::Op("=~")),
+    ("<", Token::Op("<")),
+    (">", Token::Op(">")),
+    ("!", Token::Not),
+    ("(", Token::LParen),
+    (")", Token::RParen),
+    ("=", Token::Op("==")),
+];
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = s.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    'scan: while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        let rest: String = chars[i..chars.len().min(i + 2)].iter().collect();
+        for (symbol, token) in &SYMBOLS {
+            if rest.starts_with(symbol) {
+                tokens.push(token.clone());
+                i += symbol.chars().count();
+                continue 'scan;
+            }
+        }
+        match c {
+            '"' | '\'' => {
+                let mut text = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        None => return Err("unterminated string".to_string()),
+                        Some(&q) if q == c => break,
+                        Some('\\') if chars.get(i + 1).is_some() => {
+                            text.push(chars[i + 1]);
+                            i += 2;
+                        }
+                        Some(&other) => {
+                            text.push(other);
+                            i += 1;
+                        }
+                    }
+                }
+                tokens.push(Token::Str(text));
+                i += 1;
+            }
+            c if c.is_ascii_digit() || (c == '-' && next.is_some_and(|n| n.is_ascii_digit())) => {
+                let start = i;
+                i += 1;
+                while chars.get(i).is_some_and(|c| c.is_ascii_digit() || *c == '.') {
+                    i += 1;
+                }
+                let text: String = chars[start..i].iter().collect();
+                tokens.push(Token::Num(text.parse().map_err(|_| format!("bad number {}", text))?));
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let start = i;
+                while chars.get(i).is_some_and(|c| c.is_ascii_alphanumeric() || *c == '_') {
+                    i += 1;
+                }
+                let word: String = chars[start..i].iter().collect();
+                tokens.push(match word.as_str() {
+                    "contains" => Token::Op("contains"),
+                    _ => Token::Ident(word),
+                });
+            }
+            other => return Err(format!("unexpected '{}'", other)),
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        let found = self.tokens.get(self.pos) == Some(token);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut left = self.and()?;
+        while self.eat(&Token::Or) {
+            left = Expr::Or(Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut left = self.unary()?;
+        while self.eat(&Token::And) {
+            left = Expr::And(Box::new(left), Box::new(self.unary()?));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.eat(&Token::Not) {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.eat(&Token::LParen) {
+            let inner = self.or()?;
+            if !self.eat(&Token::RParen) {
+                return Err("missing ')'".to_string());
+            }
+            return Ok(inner);
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Expr, String> {
+        let left = self.operand()?;
+        let op = match self.tokens.get(self.pos) {
+            Some(Token::Op(op)) => *op,
+            _ => return Ok(Expr::Truthy(left)),
+        };
+        self.pos += 1;
+        let op = match op {
+            "=~" => {
+                return match self.next() {
+                    Some(Token::Str(pattern)) => Regex::new(&pattern)
+                        .map(|regex| Expr::Matches(left, regex))
+                        .map_err(|e| format!("bad regex: {}", e)),
+                    _ => Err("=~ needs a quoted regex".to_string()),
+                };
+            }
+            "==" => Op::Eq,
+            "!=" => Op::Ne,
+            "<" => Op::Lt,
+            "<=" => Op::Le,
+            ">" => Op::Gt,
+            ">=" => Op::Ge,
+            _ => Op::Contains,
+        };
+        Ok(Expr::Compare(left, op, self.operand()?))
+    }
+
+    fn operand(&mut self) -> Result<Operand, String> {
+        match self.next() {
+            Some(Token::Ident(word)) => Ok(match word.as_str() {
+                "true" => Operand::Literal(Value::Bool(true)),
+                "false" => Operand::Literal(Value::Bool(false)),
+                "null" => Operand::Literal(Value::Null),
+                _ => Operand::Field(word),
+            }),
+            Some(Token::Str(text)) => Ok(Operand::Literal(Value::String(text))),
+            Some(Token::Num(number)) => Ok(Operand::Literal(serde_json::json!(number))),
+            Some(token) => Err(format!("expected a fact or value, found {:?}", token)),
+            None => Err("expression ends too early".to_string()),
+        }
+    }
+}
+
+impl Expr {
+    /// Whether a host with these facts is selected; missing facts compare as null
+    pub fn matches(&self, facts: &Map<String, Value>) -> bool {
+        match self {
+            Expr::Or(left, right) => left.matches(facts) || right.matches(facts),
+            Expr::And(left, right) => left.matches(facts) && right.matches(facts),
+            Expr::Not(inner) => !inner.matches(facts),
+            Expr::Truthy(operand) => truthy(&operand.value(facts)),
+            Expr::Matches(operand, regex) => match operand.value(facts) {
+                Value::Array(items) => items.iter().any(|item| text(item).is_some_and(|t| regex.is_match(&t))),
+                value => text(&value).is_some_and(|t| regex.is_match(&t)),
+            },
+            Expr::Compare(left, op, right) => compare(&left.value(facts), *op, &right.value(facts)),
+        }
+    }
+
+    /// Fact names the expression refers to, to catch typos up front
+    pub fn fields(&self) -> Vec<&str> {
+        match self {
+            Expr::Or(left, right) | Expr::And(left, right) => {
+                let mut fields = left.fields();
+                fields.extend(right.fields());
+                fields
+            }
+            Expr::Not(inner) => inner.fields(),
+            Expr::Truthy(single) | Expr::Matches(single, _) => single.field().into_iter().collect(),
+            Expr::Compare(left, _, right) => left.field().into_iter().chain(right.field()).collect(),
+        }
+    }
+}
+
+impl Operand {
+    fn field(&self) -> Option<&str> {
+        match self {
+            Operand::Field(name) => Some(name),
+            Operand::Literal(_) => None,
+        }
+    }
+
+    fn value(&self, facts: &Map<String, Value>) -> Value {
+        match self {
+            Operand::Field(name) => facts.get(name).cloned().unwrap_or(Value::Null),
+            Operand::Literal(value) => value.clone(),
+        }
+    }
+}
+
+fn truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(b) => *b,
+        Value::Number(n) => n.as_f64() != Some(0.0),
+        Value::String(s) => !s.is_empty(),
+        Value::Array(items) => !items.is_empty(),
+        Value::Object(_) => true,
+    }
+}
+
+fn text(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+/// Numbers compare numerically, also when a fact holds a number as text ("22.04" >= 20)
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+fn order(left: &Value, right: &Value) -> Option<Ordering> {
+    match (number(left), number(right)) {
+        (Some(l), Some(r)) => l.partial_cmp(&r),
+        _ => match (left, right) {
+            (Value::String(l), Value::String(r)) => Some(l.cmp(r)),
+            _ => None,
+        },
+    }
+}
+
+fn equal(left: &Value, right: &Value) -> bool {
+    match (left, right) {
+        (Value::Null, Value::Null) => true,
+        (Value::Null, _) | (_, Value::Null) => false,
+        _ => order(left, right) == Some(Ordering::Equal) || left == right,
+    }
+}
+
+fn compare(left: &Value, op: Op, right: &Value) -> bool {
+    match op {
+        Op::Eq => equal(left, right),
+        Op::Ne => !equal(left, right),
+        Op::Lt => order(left, right) == Some(Ordering::Less),
+        Op::Le => matches!(order(left, right), Some(Ordering::Less | Ordering::Equal)),
+        Op::Gt => order(left, right) == Some(Ordering::Greater),
+        Op::Ge => matches!(order(left, right), Some(Ordering::Greater | Ordering::Equal)),
+        Op::Contains => match (left, right) {
+            (Value::Array(items), _) => items.iter().any(|item| equal(item, right)),
+            (Value::String(haystack), Value::String(needle)) => haystack.contains(needle.as_str()),
+            _ => false,
+        },
+    }
+}
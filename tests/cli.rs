@@ -0,0 +1,132 @@
+// [cli.rs] - KRUST - Tests of the built binary that need no SSH server
+use std::process::{Command, Output, Stdio};
+
+use serde_json::Value;
+
+/// Run the built binary with an empty HOME and no agent, so nothing of the developer's takes part
+fn krust(args: &[&str]) -> Output {
+    let home = tempfile::tempdir().expect("create empty home");
+    Command::new(env!("CARGO_BIN_EXE_krust"))
+        .args(["--no-color"])
+        .args(args)
+        .env("HOME", home.path())
+        .env("XDG_DATA_HOME", home.path())
+        .env_remove("SSH_AUTH_SOCK")
+        .output()
+        .expect("run krust")
+}
+
+/// Nothing listens on port 1, so connecting fails at once
+const CLOSED: &str = "127.0.0.1:1";
+
+#[test]
+fn json_logs_go_to_stderr_with_host_and_attempt() {
+    let output = krust(&["--json", "--log-format", "json", "-v", "-p", "secret", "--retries", "1", "--hosts", CLOSED, "true"]);
+
+    // stdout holds the result and nothing else
+    let result: Value = serde_json::from_slice(&output.stdout).expect("one JSON result on stdout");
+    assert_eq!(result["error_kind"], "connect");
+
+    let logs: Vec<Value> = String::from_utf8_lossy(&output.stderr).lines()
+        .map(|line| serde_json::from_str(line).unwrap_or_else(|e| panic!("{}: {}", e, line)))
+        .collect();
+    assert!(logs.iter().all(|log| log["spans"][0]["name"] == "run" && log["spans"][0]["run_id"].is_string()));
+    let attempts: Vec<&Value> = logs.iter()
+        .filter(|log| log["span"]["name"] == "attempt")
+        .map(|log| &log["span"])
+        .collect();
+    assert!(attempts.iter().all(|span| span["host"] == "127.0.0.1"));
+    assert!(attempts.iter().any(|span| span["attempt"] == 1));
+    assert!(attempts.iter().any(|span| span["attempt"] == 2));
+}
+
+#[test]
+fn closed_stdout_ends_the_run_quietly() {
+    let home = tempfile::tempdir().expect("create empty home");
+    let args = ["--no-color", "-p", "secret", "--retries", "0", "--hosts", "127.0.0.1:1,127.0.0.2:1,127.0.0.3:1", "true"];
+    let command = |program: &str| {
+        let mut command = Command::new(program);
+        command.env("HOME", home.path()).env("XDG_DATA_HOME", home.path()).env_remove("SSH_AUTH_SOCK");
+        command
+    };
+    let quiet = |stderr: &[u8]| {
+        let stderr = String::from_utf8_lossy(stderr);
+        assert!(!stderr.contains("panicked") && !stderr.contains("Broken pipe"), "{}", stderr);
+    };
+
+    // As in a shell pipeline: the pipeline succeeds and nothing is complained about
+    let piped = command("sh")
+        .args(["-c", r#""$0" "$@" | head -0"#, env!("CARGO_BIN_EXE_krust")])
+        .args(args)
+        .output()
+        .expect("run krust | head -0");
+    assert_eq!(piped.status.code(), Some(0));
+    quiet(&piped.stderr);
+
+    // krust itself exits the way a process killed by SIGPIPE would
+    let mut child = command(env!("CARGO_BIN_EXE_krust"))
+        .args(args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("run krust");
+    drop(child.stdout.take());
+    let output = child.wait_with_output().expect("wait for krust");
+    assert_eq!(output.status.code(), Some(141));
+    quiet(&output.stderr);
+}
+
+#[test]
+fn confirm_without_a_terminal_refuses_unless_yes() {
+    // setsid leaves krust without a controlling terminal, as under cron or CI
+    let run = |extra: &[&str]| {
+        let home = tempfile::tempdir().expect("create empty home");
+        Command::new("setsid")
+            .args(["-w", env!("CARGO_BIN_EXE_krust"), "--no-color", "--json", "-p", "secret", "--retries", "0", "--confirm"])
+            .args(extra)
+            .args(["--hosts", CLOSED, "true"])
+            .env("HOME", home.path())
+            .env("XDG_DATA_HOME", home.path())
+            .env_remove("SSH_AUTH_SOCK")
+            .stdin(Stdio::null())
+            .output()
+            .expect("run krust under setsid")
+    };
+
+    let refused = run(&[]);
+    assert_eq!(refused.status.code(), Some(1));
+    assert!(refused.stdout.is_empty(), "no host was contacted: {}", String::from_utf8_lossy(&refused.stdout));
+    assert!(String::from_utf8_lossy(&refused.stderr).contains("--confirm needs a terminal; pass --yes"));
+
+    let confirmed = run(&["--yes"]);
+    let result: Value = serde_json::from_slice(&confirmed.stdout).expect("one JSON result on stdout");
+    assert_eq!(result["error_kind"], "connect");
+}
+
+#[test]
+fn completions_print_a_script_per_shell() {
+    let script = |shell: &str| {
+        let output = krust(&["completions", shell]);
+        assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+        String::from_utf8(output.stdout).expect("UTF-8 script")
+    };
+
+    let bash = script("bash");
+    assert!(bash.starts_with("_krust() {"), "{}", bash);
+    // --hosts completes from the inventory through the hidden helper
+    assert!(bash.contains("_krust_with_hosts") && bash.contains("krust __complete-hosts"));
+    assert!(script("zsh").starts_with("#compdef krust\n"));
+    assert!(script("fish").contains("-l hosts -x -a '(krust __complete-hosts 2>/dev/null)'"));
+
+    // Piped into a reader that stops early, like `| head`, it ends without an error
+    let mut child = Command::new(env!("CARGO_BIN_EXE_krust"))
+        .args(["completions", "bash"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("run krust completions");
+    drop(child.stdout.take());
+    let output = child.wait_with_output().expect("wait for krust");
+    assert_eq!(output.status.code(), Some(141));
+    assert!(output.stderr.is_empty(), "{}", String::from_utf8_lossy(&output.stderr));
+}
@@ -0,0 +1,154 @@
+// [e2e/harness.rs] - KRUST - OpenSSH containers and a krust runner for end-to-end tests
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use serde_json::Value;
+use tempfile::TempDir;
+use testcontainers::core::{IntoContainerPort, WaitFor};
+use testcontainers::runners::AsyncRunner;
+use testcontainers::{ContainerAsync, GenericImage, ImageExt};
+
+pub const USER: &str = "krust";
+pub const PASSWORD: &str = "krust-e2e";
+
+/// The suite needs a Docker daemon, so it only runs when asked to with E2E=1
+pub fn e2e_enabled() -> bool {
+    let enabled = std::env::var("E2E").is_ok_and(|value| value == "1");
+    if !enabled {
+        eprintln!("skipping end-to-end test; set E2E=1 to run it against Docker");
+    }
+    enabled
+}
+
+/// A throwaway ed25519 keypair, deleted with the temporary directory
+pub struct Keypair {
+    _dir: TempDir,
+    pub private_key: PathBuf,
+    pub public_key: String,
+}
+
+impl Keypair {
+    pub fn generate() -> Self {
+        let dir = tempfile::tempdir().expect("create key directory");
+        let private_key = dir.path().join("id_ed25519");
+        let status = Command::new("ssh-keygen")
+            .args(["-q", "-t", "ed25519", "-N", "", "-C", "krust-e2e", "-f"])
+            .arg(&private_key)
+            .status()
+            .expect("run ssh-keygen");
+        assert!(status.success(), "ssh-keygen failed");
+        let public_key = std::fs::read_to_string(private_key.with_extension("pub"))
+            .expect("read public key")
+            .trim()
+            .to_string();
+        Keypair { _dir: dir, private_key, public_key }
+    }
+}
+
+/// A running sshd accepting `USER` with `PASSWORD` or the keypair; stopped on drop
+pub struct SshContainer {
+    _container: ContainerAsync<GenericImage>,
+    pub port: u16,
+    pub keypair: Keypair,
+}
+
+impl SshContainer {
+    /// linuxserver/openssh-server: small and quick to start
+    pub async fn openssh() -> Self {
+        let keypair = Keypair::generate();
+        let container = GenericImage::new("linuxserver/openssh-server", "latest")
+            .with_exposed_port(2222.tcp())
+            .with_wait_for(WaitFor::message_on_stdout("[ls.io-init] done."))
+            .with_env_var("USER_NAME", USER)
+            .with_env_var("PUBLIC_KEY", keypair.public_key.clone())
+            .with_env_var("PASSWORD_ACCESS", "true")
+            .with_env_var("USER_PASSWORD", PASSWORD)
+            .start()
+            .await
+            .expect("start openssh-server container");
+        let port = container.get_host_port_ipv4(2222.tcp()).await.expect("mapped ssh port");
+        SshContainer { _container: container, port, keypair }
+    }
+
+    /// Debian with the distribution's sshd, for module scripts that expect a full userland
+    pub async fn debian() -> Self {
+        let keypair = Keypair::generate();
+        let setup = format!(
+            "apt-get update -qq && apt-get install -y -qq openssh-server procps iproute2 >/dev/null \
+             && useradd -m -s /bin/bash {user} && echo '{user}:{password}' | chpasswd \
+             && mkdir -p /home/{user}/.ssh /run/sshd \
+             && echo \"$PUBLIC_KEY\" > /home/{user}/.ssh/authorized_keys \
+             && chown -R {user}: /home/{user}/.ssh && chmod 600 /home/{user}/.ssh/authorized_keys \
+             && exec /usr/sbin/sshd -D -e",
+            user = USER,
+            password = PASSWORD,
+        );
+        let container = GenericImage::new("debian", "bookworm-slim")
+            .with_exposed_port(22.tcp())
+            .with_wait_for(WaitFor::message_on_stderr("Server listening on"))
+            .with_env_var("PUBLIC_KEY", keypair.public_key.clone())
+            .with_cmd(["sh", "-c", setup.as_str()])
+            .start()
+            .await
+            .expect("start debian container");
+        let port = container.get_host_port_ipv4(22.tcp()).await.expect("mapped ssh port");
+        SshContainer { _container: container, port, keypair }
+    }
+
+    pub fn target(&self) -> String {
+        format!("127.0.0.1:{}", self.port)
+    }
+
+    /// krust against this container with key authentication, plus `args`
+    pub fn krust_with_key(&self, args: &[&str]) -> Run {
+        let key = self.keypair.private_key.to_string_lossy().into_owned();
+        let mut full = vec!["-u", USER, "-k", key.as_str()];
+        full.extend_from_slice(args);
+        krust(&self.target(), &full)
+    }
+
+    /// krust against this container with password authentication, plus `args`
+    pub fn krust_with_password(&self, args: &[&str]) -> Run {
+        let mut full = vec!["-u", USER, "-p", PASSWORD];
+        full.extend_from_slice(args);
+        krust(&self.target(), &full)
+    }
+}
+
+/// Run the built binary against `target` with JSON output. HOME and the agent socket are
+/// cleared so the developer's own keys never take part.
+pub fn krust(target: &str, args: &[&str]) -> Run {
+    let home = tempfile::tempdir().expect("create empty home");
+    let output = Command::new(env!("CARGO_BIN_EXE_krust"))
+        .args(["--no-color", "--json", "--hosts", target])
+        .args(args)
+        .env("HOME", home.path())
+        .env("XDG_DATA_HOME", home.path())
+        .env_remove("SSH_AUTH_SOCK")
+        .output()
+        .expect("run krust");
+    Run::new(output, home.path())
+}
+
+/// What one krust invocation printed, with the single host result parsed
+pub struct Run {
+    pub exit_code: Option<i32>,
+    pub result: Value,
+    pub stderr: String,
+}
+
+impl Run {
+    fn new(output: Output, home: &Path) -> Self {
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        let result = serde_json::from_str(stdout.trim()).unwrap_or_else(|e| {
+            panic!("krust did not print one JSON result ({}); home {}\nstdout: {}\nstderr: {}",
+                e, home.display(), stdout, stderr)
+        });
+        Run { exit_code: output.status.code(), result, stderr }
+    }
+
+    pub fn stdout(&self) -> &str {
+        self.result["stdout"].as_str().unwrap_or_default()
+    }
+}
@@ -0,0 +1,147 @@
+// [e2e/main.rs] - KRUST - End-to-end tests against real sshd containers (E2E=1, needs Docker)
+mod harness;
+
+use harness::{e2e_enabled, SshContainer, USER};
+
+#[tokio::test]
+async fn runs_a_command_with_key_auth() {
+    if !e2e_enabled() {
+        return;
+    }
+    let sshd = SshContainer::openssh().await;
+
+    let run = sshd.krust_with_key(&["whoami"]);
+
+    assert_eq!(run.exit_code, Some(0), "{}", run.stderr);
+    assert_eq!(run.result["success"], true);
+    assert_eq!(run.stdout(), USER);
+    assert!(run.result["auth_method"].as_str().unwrap().starts_with("publickey:"));
+}
+
+#[tokio::test]
+async fn runs_a_command_with_password_auth() {
+    if !e2e_enabled() {
+        return;
+    }
+    let sshd = SshContainer::openssh().await;
+
+    let run = sshd.krust_with_password(&["echo", "hello"]);
+
+    assert_eq!(run.exit_code, Some(0), "{}", run.stderr);
+    assert_eq!(run.stdout(), "hello");
+    assert_eq!(run.result["auth_method"], "password");
+}
+
+#[tokio::test]
+async fn wrong_password_is_an_auth_error() {
+    if !e2e_enabled() {
+        return;
+    }
+    let sshd = SshContainer::openssh().await;
+
+    let run = harness::krust(&sshd.target(), &["-u", USER, "-p", "not-the-password", "--retries", "3", "true"]);
+
+    assert_eq!(run.exit_code, Some(1));
+    assert_eq!(run.result["error_kind"], "auth");
+}
+
+#[tokio::test]
+async fn non_zero_exit_codes_are_reported() {
+    if !e2e_enabled() {
+        return;
+    }
+    let sshd = SshContainer::openssh().await;
+
+    let run = sshd.krust_with_key(&["exit 7"]);
+
+    assert_eq!(run.exit_code, Some(1));
+    assert_eq!(run.result["success"], false);
+    assert_eq!(run.result["exit_code"], 7);
+}
+
+#[tokio::test]
+async fn remote_stderr_is_kept_apart_from_stdout() {
+    if !e2e_enabled() {
+        return;
+    }
+    let sshd = SshContainer::openssh().await;
+
+    let run = sshd.krust_with_key(&["echo out; echo err >&2"]);
+
+    assert_eq!(run.stdout(), "out");
+    assert_eq!(run.result["stderr"], "err");
+    assert_eq!(run.result["bytes_stderr"], 4);
+    assert!(run.result.get("error").is_none());
+}
+
+#[tokio::test]
+async fn bare_invocations_still_run_like_run_subcommands() {
+    if !e2e_enabled() {
+        return;
+    }
+    let sshd = SshContainer::openssh().await;
+
+    // Old-style invocations as cron jobs have them, each with its `run` form
+    let jobs: [(&[&str], &[&str]); 6] = [
+        (&["hostname"], &["run", "--", "hostname"]),
+        // Named like a module that cannot take these arguments
+        (&["sysctl", "-n", "kernel.ostype"], &["run", "--", "sysctl", "-n", "kernel.ostype"]),
+        (&["kill", "-l", "9"], &["run", "--", "kill", "-l", "9"]),
+        (&["--timeout", "20s", "df", "-P", "/"], &["run", "--timeout", "20s", "--", "df", "-P", "/"]),
+        (&["--retries", "1", "--quote-args", "printf", "%s|", "a  b", "-n"], &["run", "--retries", "1", "--quote-args", "--", "printf", "%s|", "a  b", "-n"]),
+        (&["--command", "cd /tmp && pwd"], &["run", "--command", "cd /tmp && pwd"]),
+    ];
+    for (bare, run) in jobs {
+        let (bare, run) = (sshd.krust_with_key(bare), sshd.krust_with_key(run));
+        assert_eq!(bare.exit_code, Some(0), "{}", bare.stderr);
+        assert_eq!((run.exit_code, run.stdout()), (bare.exit_code, bare.stdout()), "{}", run.stderr);
+    }
+}
+
+#[tokio::test]
+async fn slow_commands_time_out() {
+    if !e2e_enabled() {
+        return;
+    }
+    let sshd = SshContainer::openssh().await;
+
+    let run = sshd.krust_with_key(&["--timeout", "2s", "--retries", "0", "sleep 30"]);
+
+    assert_eq!(run.exit_code, Some(1));
+    assert_eq!(run.result["error"]["message"], "Command timeout");
+    assert_eq!(run.result["error"]["kind"], "timeout");
+    assert!(run.result["duration_ms"].as_u64().unwrap() < 10_000);
+}
+
+#[tokio::test]
+async fn collect_facts_returns_json_on_debian() {
+    if !e2e_enabled() {
+        return;
+    }
+    let sshd = SshContainer::debian().await;
+
+    let run = sshd.krust_with_key(&["collect-facts"]);
+
+    assert_eq!(run.exit_code, Some(0), "{}", run.stderr);
+    let facts: serde_json::Value = serde_json::from_str(run.stdout()).expect("facts are JSON");
+    assert_eq!(facts["os_name"], "Debian GNU/Linux");
+    assert_eq!(facts["os_version"], "12");
+    assert!(facts["cpu_count"].as_u64().unwrap() >= 1);
+}
+
+#[tokio::test]
+async fn archive_fetch_downloads_a_verified_tarball() {
+    if !e2e_enabled() {
+        return;
+    }
+    let sshd = SshContainer::debian().await;
+    let out = tempfile::tempdir().unwrap();
+
+    let run = sshd.krust_with_key(&["archive-fetch", "/etc/apt", "--exclude", "trusted.gpg.d/*", "--out", out.path().to_str().unwrap()]);
+
+    assert_eq!(run.exit_code, Some(0), "{}", run.stderr);
+    let file = run.result["archive_file"].as_str().unwrap();
+    let bytes = std::fs::metadata(file).unwrap().len();
+    assert_eq!(run.result["archive_bytes"], bytes);
+    assert!(file.ends_with(".tar.gz") && file.starts_with(out.path().to_str().unwrap()));
+}